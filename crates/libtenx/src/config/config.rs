@@ -16,7 +16,7 @@ use crate::{
     checks,
     config::default_config,
     error::{self, TenxError},
-    model,
+    model, session_store,
 };
 use state;
 
@@ -131,7 +131,7 @@ pub struct Context {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
-/// Configuration for a specific model provider (Claude, OpenAI, or Google).
+/// Configuration for a specific model provider (Claude, OpenAI, Google, or a local server).
 pub enum Model {
     Claude {
         /// The name of the model.
@@ -142,6 +142,9 @@ pub enum Model {
         key: String,
         /// The environment variable to load the API key from.
         key_env: String,
+        /// The base URL for the API. The provider's public API is used if empty.
+        #[serde(default)]
+        api_base: String,
     },
     OpenAi {
         /// The name of the model.
@@ -160,6 +163,10 @@ pub enum Model {
         no_system_prompt: bool,
         /// Reasoning effort for OpenAI o1 and o3 models.
         reasoning_effort: Option<ReasoningEffort>,
+        /// Whether the model edits files through function calls to an editor tool, rather than
+        /// the tags dialect.
+        #[serde(default)]
+        editor: bool,
     },
     Google {
         /// The name of the model.
@@ -170,11 +177,46 @@ pub enum Model {
         key: String,
         /// The environment variable to load the API key from.
         key_env: String,
+        /// The base URL for the API. The provider's public API is used if empty.
+        #[serde(default)]
+        api_base: String,
+        /// Whether the model can stream responses.
+        can_stream: bool,
+        /// Whether the model edits files through function calls to an editor tool, rather than
+        /// the tags dialect.
+        #[serde(default)]
+        editor: bool,
+    },
+    Local {
+        /// The name of the model.
+        name: String,
+        /// The model to request from the server. If empty, the first model the server lists is
+        /// used.
+        api_model: String,
+        /// The root URL of the server, for example "http://localhost:11434".
+        api_base: String,
+        /// The kind of server.
+        server: LocalServer,
         /// Whether the model can stream responses.
         can_stream: bool,
+        /// The size of the model's context window in tokens. Requests are only checked against
+        /// the window if it's set.
+        #[serde(default)]
+        context_window: Option<usize>,
     },
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// The kind of local inference server a model is served by.
+pub enum LocalServer {
+    /// Ollama, using its native API.
+    #[default]
+    Ollama,
+    /// The llama.cpp server, using its OpenAI-compatible API.
+    LlamaCpp,
+}
+
 impl Model {
     /// Loads API key from environment if key is empty and key_env is specified.
     pub fn load_env(mut self) -> Self {
@@ -201,6 +243,7 @@ impl Model {
                 }
                 self
             }
+            Model::Local { .. } => self,
         }
     }
 
@@ -210,6 +253,7 @@ impl Model {
             Model::Claude { name, .. } => name,
             Model::OpenAi { name, .. } => name,
             Model::Google { name, .. } => name,
+            Model::Local { name, .. } => name,
         }
     }
 
@@ -219,6 +263,7 @@ impl Model {
             Model::Claude { .. } => "claude",
             Model::OpenAi { .. } => "openai",
             Model::Google { .. } => "google",
+            Model::Local { .. } => "local",
         }
     }
 
@@ -228,6 +273,7 @@ impl Model {
             Model::Claude { api_model, .. } => api_model,
             Model::OpenAi { api_model, .. } => api_model,
             Model::Google { api_model, .. } => api_model,
+            Model::Local { api_model, .. } => api_model,
         }
     }
 
@@ -243,6 +289,7 @@ impl Model {
     pub fn text_config(&self, verbose: bool) -> String {
         match self {
             Model::Claude {
+                api_base,
                 api_model,
                 key,
                 key_env,
@@ -254,6 +301,7 @@ impl Model {
                     Self::abbreviate_key(key)
                 };
                 [
+                    format!("api_base = {api_base}"),
                     format!("api_model = {api_model}"),
                     format!("key = {key}"),
                     format!("key_env = {key_env}"),
@@ -267,6 +315,7 @@ impl Model {
                 key_env,
                 no_system_prompt,
                 can_stream,
+                editor,
                 ..
             } => {
                 let key = if verbose {
@@ -281,14 +330,17 @@ impl Model {
                     format!("key_env = {key_env}"),
                     format!("no_system_prompt = {no_system_prompt}"),
                     format!("stream = {can_stream}"),
+                    format!("editor = {editor}"),
                 ]
                 .join("\n")
             }
             Model::Google {
+                api_base,
                 api_model,
                 key,
                 key_env,
                 can_stream,
+                editor,
                 ..
             } => {
                 let key = if verbose {
@@ -297,20 +349,45 @@ impl Model {
                     Self::abbreviate_key(key)
                 };
                 [
+                    format!("api_base = {api_base}"),
                     format!("api_model = {api_model}"),
                     format!("key = {key}"),
                     format!("key_env = {key_env}"),
                     format!("stream = {can_stream}"),
+                    format!("editor = {editor}"),
                 ]
                 .join("\n")
             }
+            Model::Local {
+                api_model,
+                api_base,
+                server,
+                can_stream,
+                context_window,
+                ..
+            } => [
+                format!("api_base = {api_base}"),
+                format!("api_model = {api_model}"),
+                format!("server = {server:?}"),
+                format!("stream = {can_stream}"),
+                format!(
+                    "context_window = {}",
+                    context_window.map_or("unknown".to_string(), |w| w.to_string())
+                ),
+            ]
+            .join("\n"),
         }
     }
 
-    /// Converts ModelConfig to a Claude, OpenAi, or Google model.
+    /// Converts ModelConfig to a Claude, OpenAi, Google, or Local model.
     pub fn to_model(&self, no_stream: bool) -> error::Result<model::Model> {
         match self {
-            Model::Claude { api_model, key, .. } => {
+            Model::Claude {
+                api_model,
+                key,
+                api_base,
+                ..
+            } => {
                 if api_model.is_empty() {
                     return Err(TenxError::Model("Empty API model name".into()));
                 }
@@ -321,6 +398,7 @@ impl Model {
                     name: self.name().to_string(),
                     api_model: api_model.clone(),
                     anthropic_key: key.clone(),
+                    api_base: api_base.clone(),
                     streaming: !no_stream,
                 }))
             }
@@ -331,25 +409,42 @@ impl Model {
                 can_stream,
                 no_system_prompt,
                 reasoning_effort,
+                editor,
                 ..
-            } => Ok(model::Model::OpenAi(model::OpenAi {
-                name: self.name().to_string(),
-                api_model: api_model.clone(),
-                openai_key: key.clone(),
-                api_base: api_base.clone(),
-                streaming: *can_stream && !no_stream,
-                no_system_prompt: *no_system_prompt,
-                reasoning_effort: match reasoning_effort {
+            } => {
+                let reasoning_effort = match reasoning_effort {
                     Some(ReasoningEffort::Low) => Some(model::ReasoningEffort::Low),
                     Some(ReasoningEffort::Medium) => Some(model::ReasoningEffort::Medium),
                     Some(ReasoningEffort::High) => Some(model::ReasoningEffort::High),
                     None => None,
-                },
-            })),
+                };
+                if *editor {
+                    return Ok(model::Model::OpenAiEditor(model::OpenAiEditor {
+                        name: self.name().to_string(),
+                        api_model: api_model.clone(),
+                        openai_key: key.clone(),
+                        api_base: api_base.clone(),
+                        streaming: *can_stream && !no_stream,
+                        no_system_prompt: *no_system_prompt,
+                        reasoning_effort,
+                    }));
+                }
+                Ok(model::Model::OpenAi(model::OpenAi {
+                    name: self.name().to_string(),
+                    api_model: api_model.clone(),
+                    openai_key: key.clone(),
+                    api_base: api_base.clone(),
+                    streaming: *can_stream && !no_stream,
+                    no_system_prompt: *no_system_prompt,
+                    reasoning_effort,
+                }))
+            }
             Model::Google {
                 api_model,
                 key,
+                api_base,
                 can_stream,
+                editor,
                 ..
             } => {
                 if api_model.is_empty() {
@@ -358,17 +453,56 @@ impl Model {
                 if key.is_empty() {
                     return Err(TenxError::Model("Empty Google API key".into()));
                 }
+                if *editor {
+                    return Ok(model::Model::GoogleEditor(model::GoogleEditor {
+                        name: self.name().to_string(),
+                        api_model: api_model.clone(),
+                        api_key: key.clone(),
+                        api_base: api_base.clone(),
+                        streaming: *can_stream && !no_stream,
+                    }));
+                }
                 Ok(model::Model::Google(model::Google {
                     name: self.name().to_string(),
                     api_model: api_model.clone(),
                     api_key: key.clone(),
+                    api_base: api_base.clone(),
+                    streaming: *can_stream && !no_stream,
+                }))
+            }
+            Model::Local {
+                api_model,
+                api_base,
+                server,
+                can_stream,
+                context_window,
+                ..
+            } => {
+                if api_base.is_empty() {
+                    return Err(TenxError::Model("Empty local server URL".into()));
+                }
+                Ok(model::Model::Local(model::Local {
+                    name: self.name().to_string(),
+                    api_model: api_model.clone(),
+                    api_base: api_base.clone(),
+                    server: server.to_model(),
                     streaming: *can_stream && !no_stream,
+                    context_window: *context_window,
                 }))
             }
         }
     }
 }
 
+impl LocalServer {
+    fn to_model(self) -> model::LocalServer {
+        match self {
+            LocalServer::Ollama => model::LocalServer::Ollama,
+            LocalServer::LlamaCpp => model::LocalServer::LlamaCpp,
+        }
+    }
+}
+
 /// Project configuration.
 #[optional_struct]
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -382,6 +516,32 @@ pub struct Project {
     /// over-ridden, Tenx respects .gitignore, .ignore and .git/info/exclude files.
     #[serde(default)]
     pub include: Vec<String>,
+
+    /// If set, every change the model makes is committed to this branch of the project's git
+    /// repository, and reverting a step resets the branch to match. The repository is switched to
+    /// the branch before the first change, which fails if it has uncommitted changes.
+    #[serde(default)]
+    pub git_branch: Option<String>,
+
+    /// How to treat files the user edited between steps. By default, edits are folded into the
+    /// session history, so reverting a step keeps them. Set to `Fail` to stop instead.
+    #[serde(default)]
+    pub external_edits: state::ExternalEdits,
+
+    /// Glob patterns for files the model may not modify, relative to the project root. Protected
+    /// files can still be viewed and included in context. For example: ["tests/**", "*.lock"].
+    #[serde(default)]
+    pub protected: Vec<String>,
+}
+
+#[optional_struct]
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Configuration for applying model patches.
+pub struct Patches {
+    /// Names of strategies whose patches are applied atomically: if any operation in a patch
+    /// fails, the whole patch is undone. For example: ["code", "fix"].
+    #[serde(default)]
+    pub atomic: Vec<String>,
 }
 
 #[optional_struct]
@@ -498,6 +658,11 @@ pub struct Config {
     #[optional_wrap]
     pub checks: Checks,
 
+    /// Patch configuration.
+    #[optional_rename(OptionalPatches)]
+    #[optional_wrap]
+    pub patches: Patches,
+
     /// Mode configuration
     pub modes: HashMap<ModeSpec, ModeConfig>,
 
@@ -664,13 +829,34 @@ impl Config {
     }
 
     /// Construct the default state for the project, including the project root directory, and
-    /// a memory overlay for files prefixed with "::".
+    /// a memory overlay for files prefixed with "::". If a session store is configured, snapshot
+    /// contents are journaled to disk alongside the session.
     pub fn state(&self) -> error::Result<state::State> {
         let s = state::State::default()
-            .with_directory(&self.project.root, self.project.include.clone())?;
+            .with_external_edits(self.project.external_edits)
+            .with_protected(self.project.protected.clone())?;
+        let mut s = match self.project.git_branch {
+            Some(ref branch) => {
+                s.with_git(&self.project.root, self.project.include.clone(), branch)?
+            }
+            None => s.with_directory(&self.project.root, self.project.include.clone())?,
+        };
+        if let Some(dir) = self.journal_dir() {
+            s.set_journal(dir)?;
+        }
         Ok(s)
     }
 
+    /// The directory the snapshot journal for the current project's session is stored in, or
+    /// None if there is no session store.
+    pub fn journal_dir(&self) -> Option<PathBuf> {
+        if self.session_store_dir.as_os_str().is_empty() {
+            return None;
+        }
+        let name = session_store::path_to_filename(&self.project_root());
+        Some(session_store::journal_dir(&self.session_store_dir, &name))
+    }
+
     pub fn project_files(&self) -> error::Result<Vec<PathBuf>> {
         let ret =
             state::files::list_files(self.project.root.clone(), self.project.include.clone())?;
@@ -742,13 +928,32 @@ impl Config {
                 name,
                 api_model,
                 key,
+                api_base,
                 ..
             } => Ok(model::Model::Claude(model::Claude {
                 name: name.clone(),
                 api_model: api_model.clone(),
                 anthropic_key: key.clone(),
+                api_base: api_base.clone(),
                 streaming: !self.models.no_stream,
             })),
+            Model::OpenAi {
+                api_model,
+                key,
+                api_base,
+                can_stream,
+                no_system_prompt,
+                editor: true,
+                ..
+            } => Ok(model::Model::OpenAiEditor(model::OpenAiEditor {
+                name: name.clone(),
+                api_model: api_model.clone(),
+                openai_key: key.clone(),
+                api_base: api_base.clone(),
+                streaming: can_stream && !self.models.no_stream,
+                no_system_prompt,
+                reasoning_effort: None,
+            })),
             Model::OpenAi {
                 api_model,
                 key,
@@ -769,13 +974,45 @@ impl Config {
                 name,
                 api_model,
                 key,
+                api_base,
+                can_stream,
+                editor: true,
+                ..
+            } => Ok(model::Model::GoogleEditor(model::GoogleEditor {
+                name: name.clone(),
+                api_model: api_model.clone(),
+                api_key: key.clone(),
+                api_base: api_base.clone(),
+                streaming: can_stream && !self.models.no_stream,
+            })),
+            Model::Google {
+                name,
+                api_model,
+                key,
+                api_base,
                 can_stream,
                 ..
             } => Ok(model::Model::Google(model::Google {
                 name: name.clone(),
                 api_model: api_model.clone(),
                 api_key: key.clone(),
+                api_base: api_base.clone(),
+                streaming: can_stream && !self.models.no_stream,
+            })),
+            Model::Local {
+                name,
+                api_model,
+                api_base,
+                server,
+                can_stream,
+                context_window,
+            } => Ok(model::Model::Local(model::Local {
+                name,
+                api_model,
+                api_base,
+                server: server.to_model(),
                 streaming: can_stream && !self.models.no_stream,
+                context_window,
             })),
         }
     }
@@ -905,6 +1142,39 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_editor_models() -> error::Result<()> {
+        let project = testutils::test_project();
+        let config = parse_config(
+            "",
+            r#"(models: (default: "gpt", custom: [
+                open_ai(name: "gpt", api_model: "gpt-4o", key: "k", key_env: "", api_base: "http://localhost", can_stream: true, no_system_prompt: false, reasoning_effort: None, editor: true),
+                google(name: "gemini", api_model: "gemini-2.0-flash", key: "k", key_env: "", can_stream: false),
+            ]))"#,
+            &project.config.cwd()?,
+        )?;
+        assert!(matches!(
+            config.active_model()?,
+            model::Model::OpenAiEditor(_)
+        ));
+
+        // Models use the tags dialect unless the editor flag is set
+        let mut google = config
+            .model_confs()
+            .into_iter()
+            .find(|m| m.name() == "gemini")
+            .unwrap();
+        assert!(matches!(google.to_model(false)?, model::Model::Google(_)));
+        if let Model::Google { ref mut editor, .. } = google {
+            *editor = true;
+        }
+        assert!(matches!(
+            google.to_model(false)?,
+            model::Model::GoogleEditor(_)
+        ));
+        Ok(())
+    }
+
     macro_rules! set_config {
         ($config:expr, $($field:ident).+, $value:expr) => {
             $config.$($field).+ = $value;
@@ -975,6 +1245,9 @@ mod tests {
                     "subdir/*.txt".to_string(),
                     "!**/ignore.rs".to_string(),
                 ],
+                git_branch: None,
+                external_edits: state::ExternalEdits::Fold,
+                protected: vec![],
             },
             ..Default::default()
         };
@@ -1001,6 +1274,9 @@ mod tests {
                     "!**/ignore.rs".to_string(),
                     "!subdir/*.txt".to_string(),
                 ],
+                git_branch: None,
+                external_edits: state::ExternalEdits::Fold,
+                protected: vec![],
             },
             ..Default::default()
         };
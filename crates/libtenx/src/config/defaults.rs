@@ -2,6 +2,7 @@ use std::env;
 use std::path::{Path, PathBuf};
 
 use super::config::*;
+use crate::model::{ANTHROPIC_API_BASE, GOOGLE_API_BASE};
 
 const DEFAULT_STEP_LIMIT: usize = 16;
 
@@ -43,6 +44,10 @@ const GROQ_LLAMA31_8B_INSTANT: &str = "llama-3.1-8b-instant";
 const GROQ_DEEPSEEK_R1: &str = "deepseek-r1-distill-llama-70b";
 const GROQ_API_KEY: &str = "GROQ_API_KEY";
 
+const OLLAMA_HOST: &str = "OLLAMA_HOST";
+const OLLAMA_API_BASE: &str = "http://localhost:11434";
+const LLAMACPP_API_BASE: &str = "http://localhost:8080";
+
 /// Returns true if the directory is a git repository
 fn is_git_repo(dir: &Path) -> bool {
     dir.join(".git").is_dir()
@@ -63,7 +68,18 @@ fn find_project_root(current_dir: &Path) -> PathBuf {
     current_dir.to_path_buf()
 }
 
-/// Returns the default set of model configurations based on available API keys
+/// Returns the root URL of the local Ollama server, respecting Ollama's own `OLLAMA_HOST`
+/// variable, which may omit the scheme.
+fn ollama_api_base() -> String {
+    match env::var(OLLAMA_HOST) {
+        Ok(host) if host.contains("://") => host,
+        Ok(host) if !host.is_empty() => format!("http://{host}"),
+        _ => OLLAMA_API_BASE.to_string(),
+    }
+}
+
+/// Returns the default set of model configurations based on available API keys. Local servers
+/// need no key, so they are always included.
 fn default_models() -> Vec<Model> {
     let mut models = Vec::new();
 
@@ -74,30 +90,35 @@ fn default_models() -> Vec<Model> {
                 api_model: ANTHROPIC_CLAUDE_OPUS4.to_string(),
                 key: "".to_string(),
                 key_env: ANTHROPIC_API_KEY.to_string(),
+                api_base: ANTHROPIC_API_BASE.to_string(),
             },
             Model::Claude {
                 name: "sonnet".to_string(),
                 api_model: ANTHROPIC_CLAUDE_SONNET4.to_string(),
                 key: "".to_string(),
                 key_env: ANTHROPIC_API_KEY.to_string(),
+                api_base: ANTHROPIC_API_BASE.to_string(),
             },
             Model::Claude {
                 name: "sonnet37".to_string(),
                 api_model: ANTHROPIC_CLAUDE_SONNET37.to_string(),
                 key: "".to_string(),
                 key_env: ANTHROPIC_API_KEY.to_string(),
+                api_base: ANTHROPIC_API_BASE.to_string(),
             },
             Model::Claude {
                 name: "sonnet35".to_string(),
                 api_model: ANTHROPIC_CLAUDE_SONNET35.to_string(),
                 key: "".to_string(),
                 key_env: ANTHROPIC_API_KEY.to_string(),
+                api_base: ANTHROPIC_API_BASE.to_string(),
             },
             Model::Claude {
                 name: "haiku".to_string(),
                 api_model: ANTHROPIC_CLAUDE_HAIKU.to_string(),
                 key: "".to_string(),
                 key_env: ANTHROPIC_API_KEY.to_string(),
+                api_base: ANTHROPIC_API_BASE.to_string(),
             },
         ]);
     }
@@ -112,6 +133,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "deepseek-reasoner".to_string(),
@@ -122,6 +144,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
         ]);
     }
@@ -137,6 +160,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "llama-8b-turbo".to_string(),
@@ -147,6 +171,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "llama-70b".to_string(),
@@ -157,6 +182,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "llama33-70b".to_string(),
@@ -167,6 +193,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "qwq".to_string(),
@@ -177,6 +204,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
         ]);
     }
@@ -192,6 +220,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: false,
                 no_system_prompt: true,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "o1-mini".to_string(),
@@ -202,6 +231,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: false,
                 no_system_prompt: true,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "o3-mini-low".to_string(),
@@ -212,6 +242,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: false,
                 no_system_prompt: true,
                 reasoning_effort: Some(ReasoningEffort::Low),
+                editor: false,
             },
             Model::OpenAi {
                 name: "o3-mini-medium".to_string(),
@@ -222,6 +253,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: false,
                 no_system_prompt: true,
                 reasoning_effort: Some(ReasoningEffort::Medium),
+                editor: false,
             },
             Model::OpenAi {
                 name: "o3-mini-high".to_string(),
@@ -232,6 +264,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: false,
                 no_system_prompt: true,
                 reasoning_effort: Some(ReasoningEffort::High),
+                editor: false,
             },
             Model::OpenAi {
                 name: "gpt4o".to_string(),
@@ -242,6 +275,18 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
+            },
+            Model::OpenAi {
+                name: "gpt4o-editor".to_string(),
+                api_model: OPENAI_GPT4O.to_string(),
+                key: "".to_string(),
+                key_env: OPENAI_API_KEY.to_string(),
+                api_base: OPENAI_API_BASE.to_string(),
+                can_stream: true,
+                no_system_prompt: false,
+                reasoning_effort: None,
+                editor: true,
             },
             Model::OpenAi {
                 name: "gpt4o-mini".to_string(),
@@ -252,6 +297,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: false,
                 reasoning_effort: None,
+                editor: false,
             },
         ]);
     }
@@ -267,6 +313,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: true,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "groq-llama31-8b".to_string(),
@@ -277,6 +324,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: true,
                 reasoning_effort: None,
+                editor: false,
             },
             Model::OpenAi {
                 name: "groq-deepseek-r1".to_string(),
@@ -287,6 +335,7 @@ fn default_models() -> Vec<Model> {
                 can_stream: true,
                 no_system_prompt: true,
                 reasoning_effort: None,
+                editor: false,
             },
         ]);
     }
@@ -301,6 +350,7 @@ fn default_models() -> Vec<Model> {
             can_stream: true,
             no_system_prompt: false,
             reasoning_effort: None,
+            editor: false,
         });
     }
 
@@ -311,32 +361,68 @@ fn default_models() -> Vec<Model> {
                 api_model: GOOGLEAI_GEMINI_EXP.to_string(),
                 key: "".to_string(),
                 key_env: GOOGLEAI_API_KEY.to_string(),
+                api_base: GOOGLE_API_BASE.to_string(),
                 can_stream: false,
+                editor: false,
             },
             Model::Google {
                 name: "gemini-flash".to_string(),
                 api_model: GOOGLEAI_GEMINI_FLASH.to_string(),
                 key: "".to_string(),
                 key_env: GOOGLEAI_API_KEY.to_string(),
+                api_base: GOOGLE_API_BASE.to_string(),
+                can_stream: false,
+                editor: false,
+            },
+            Model::Google {
+                name: "gemini-flash-editor".to_string(),
+                api_model: GOOGLEAI_GEMINI_FLASH.to_string(),
+                key: "".to_string(),
+                key_env: GOOGLEAI_API_KEY.to_string(),
+                api_base: GOOGLE_API_BASE.to_string(),
                 can_stream: false,
+                editor: true,
             },
             Model::Google {
                 name: "gemini-flash-lite".to_string(),
                 api_model: GOOGLEAI_GEMINI_FLASH_LITE.to_string(),
                 key: "".to_string(),
                 key_env: GOOGLEAI_API_KEY.to_string(),
+                api_base: GOOGLE_API_BASE.to_string(),
                 can_stream: false,
+                editor: false,
             },
             Model::Google {
                 name: "gemini-flash-thinking-exp".to_string(),
                 api_model: GOOGLEAI_GEMINI_THINKING_EXP.to_string(),
                 key: "".to_string(),
                 key_env: GOOGLEAI_API_KEY.to_string(),
+                api_base: GOOGLE_API_BASE.to_string(),
                 can_stream: false,
+                editor: false,
             },
         ]);
     }
 
+    models.extend_from_slice(&[
+        Model::Local {
+            name: "ollama".to_string(),
+            api_model: "".to_string(),
+            api_base: ollama_api_base(),
+            server: LocalServer::Ollama,
+            can_stream: true,
+            context_window: None,
+        },
+        Model::Local {
+            name: "llamacpp".to_string(),
+            api_model: "".to_string(),
+            api_base: LLAMACPP_API_BASE.to_string(),
+            server: LocalServer::LlamaCpp,
+            can_stream: true,
+            context_window: None,
+        },
+    ]);
+
     models
 }
 
@@ -409,6 +495,9 @@ pub fn default_config<P: AsRef<Path>>(current_dir: P) -> Config {
             Project {
                 include: vec![],
                 root,
+                git_branch: None,
+                external_edits: state::ExternalEdits::Fold,
+                protected: vec![],
             }
         },
         session_store_dir: home_config_dir().join("state"),
@@ -28,7 +28,7 @@ use serde::{Deserialize, Serialize};
 use crate::{config::Config, error::Result, session::Session};
 
 /// An individual context item.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 /// Represents a single piece of context information to include in a prompt. Each ContextProvider
 /// can provide multiple ContextItems.
 pub struct ContextItem {
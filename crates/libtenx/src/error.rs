@@ -55,6 +55,20 @@ pub enum TenxError {
     /// We've exceeded the max retries trying to send a request.
     #[error("Max retries exceeded: {0}")]
     MaxRetries(u64),
+
+    /// A file was edited outside of tenx since it was last patched.
+    #[error("File changed outside of tenx: {path}\n{diff}")]
+    Conflict { path: String, diff: String },
+
+    /// A request is larger than the model's context window, even after trimming context.
+    #[error("Request to {model} is about {tokens} tokens, over its budget of {budget}. Largest parts:\n{largest}Remove some context, or edit fewer files.")]
+    ContextBudget {
+        model: String,
+        tokens: usize,
+        budget: usize,
+        /// The largest parts of the request, one per line
+        largest: String,
+    },
 }
 
 impl TenxError {
@@ -90,6 +104,18 @@ impl From<state::Error> for TenxError {
             state::Error::Io(e) => TenxError::Io(e),
             state::Error::NotFound { msg, path } => TenxError::NotFound { msg, path },
             state::Error::Internal(e) => TenxError::Internal(e),
+            state::Error::Branch(e) => TenxError::Internal(e),
+            e @ state::Error::Revert(_) => TenxError::Internal(e.to_string()),
+            state::Error::Diff(e) => TenxError::ResponseParse {
+                user: format!("Invalid diff: {e}"),
+                model: e,
+            },
+            state::Error::Git(error) => TenxError::Exec {
+                cmd: "git".to_string(),
+                error,
+            },
+            state::Error::Conflict { path, diff } => TenxError::Conflict { path, diff },
+            e @ state::Error::NotText { .. } => TenxError::Io(e.to_string()),
         }
     }
 }
@@ -1,17 +1,19 @@
 //! This module implements the Claude model provider for the tenx system.
 use std::{collections::HashMap, convert::From};
 
-use misanthropy::{Anthropic, Content, ContentBlockDelta, Role, StreamEvent};
+use misanthropy::{Content, Role};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use tracing::{trace, warn};
+use serde_json::{self, Value};
+use tracing::trace;
 
 use crate::{
     checks::CheckResult,
     context::ContextItem,
     error::{Result, TenxError},
     events::*,
+    model::http::{self, JsonStream},
     model::tags,
+    model::tokens,
     model::ModelProvider,
     session::ModelResponse,
     throttle::Throttle,
@@ -21,6 +23,15 @@ use super::Chat;
 
 const MAX_TOKENS: u32 = 8192;
 
+/// The base URL of the Anthropic API.
+pub const ANTHROPIC_API_BASE: &str = "https://api.anthropic.com/v1";
+
+/// The version of the Anthropic API that requests are written against.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// The name of the API in error messages.
+const API: &str = "Anthropic API";
+
 /// A model that interacts with the Anthropic API. The general design of the model is to:
 ///
 /// - Have a large, cached system prompt with many examples.
@@ -33,15 +44,24 @@ pub struct ClaudeChat {
     pub api_model: String,
     /// The Anthropic API key
     pub anthropic_key: String,
+    /// The base URL of the API
+    pub api_base: String,
     /// Whether to stream responses
     pub streaming: bool,
     /// The messages request being built
     request: misanthropy::MessagesRequest,
+    /// The index of the message holding the context, which stays the same across steps
+    context_message: Option<usize>,
 }
 
 impl ClaudeChat {
-    /// Creates a new ClaudeChat configured for the given model, API key, and streaming setting.
-    pub fn new(api_model: String, anthropic_key: String, streaming: bool) -> Self {
+    /// Creates a new ClaudeChat configured for the given model, API key, and settings.
+    pub fn new(
+        api_model: String,
+        anthropic_key: String,
+        api_base: String,
+        streaming: bool,
+    ) -> Self {
         let request = misanthropy::MessagesRequest {
             model: api_model.clone(),
             max_tokens: MAX_TOKENS,
@@ -63,37 +83,41 @@ impl ClaudeChat {
         Self {
             api_model,
             anthropic_key,
+            api_base,
             streaming,
             request,
+            context_message: None,
         }
     }
-    async fn stream_response(
-        &self,
-        api_key: String,
-        req: &misanthropy::MessagesRequest,
-        sender: Option<EventSender>,
-    ) -> Result<misanthropy::MessagesResponse> {
-        let anthropic = Anthropic::new(&api_key);
-        let mut streamed_response = anthropic.messages_stream(req)?;
-        while let Some(event) = streamed_response.next().await {
-            let event = event?;
-            match event {
-                StreamEvent::ContentBlockDelta {
-                    delta: ContentBlockDelta::TextDelta { text },
-                    ..
-                } => {
-                    send_event(&sender, Event::Snippet(text))?;
-                }
-                StreamEvent::Error { error } => {
-                    warn!("Error in stream: {:?}", error);
+
+    /// Sets cache breakpoints on the stable prefix of the request, so that later steps in an
+    /// action re-read it from the cache instead of paying for it in full. The breakpoints are at
+    /// the end of the system prompt, the end of the context, and the end of the history before
+    /// the latest user message. This stays within the API's limit of four breakpoints.
+    fn set_cache_breakpoints(&mut self) {
+        fn set(content: &mut [Content], cache: bool) {
+            for block in content.iter_mut() {
+                if let Content::Text(text) = block {
+                    text.cache_control = None;
                 }
-                StreamEvent::MessageStop => {
-                    // The message has ended, but we don't need to do anything special here
+            }
+            if let Some(Content::Text(text)) = content.last_mut() {
+                if cache {
+                    text.cache_control = Some(misanthropy::CacheControl::Ephemeral);
                 }
-                _ => {} // Ignore other event types
             }
         }
-        Ok(streamed_response.response)
+
+        set(&mut self.request.system, true);
+        let history = self
+            .request
+            .messages
+            .iter()
+            .rposition(|m| m.role == Role::Assistant);
+        for (i, message) in self.request.messages.iter_mut().enumerate() {
+            let cache = Some(i) == self.context_message || Some(i) == history;
+            set(&mut message.content, cache);
+        }
     }
 
     fn extract_changes(&self, req: &misanthropy::MessagesRequest) -> Result<ModelResponse> {
@@ -114,6 +138,91 @@ impl ClaudeChat {
     }
 }
 
+/// Sends a messages request to the Anthropic API at `api_base`, and returns the response. If the
+/// request asks for a stream, each piece of text is sent as a snippet event as it arrives, and
+/// otherwise the whole response is sent as a model response event. The misanthropy client always
+/// talks to the public API, so only its request and response types are used.
+pub(super) async fn send_messages(
+    api_base: &str,
+    key: &str,
+    request: &misanthropy::MessagesRequest,
+    sender: &Option<EventSender>,
+) -> Result<misanthropy::MessagesResponse> {
+    let url = format!("{}/messages", api_base.trim_end_matches('/'));
+    let req = reqwest::Client::new()
+        .post(url)
+        .header("x-api-key", key)
+        .header("anthropic-version", ANTHROPIC_VERSION);
+    let resp = http::post(API, req, serde_json::to_string(request)?).await?;
+    if request.stream {
+        return stream_response(resp, sender).await;
+    }
+    let body = resp
+        .text()
+        .await
+        .map_err(|e| TenxError::Model(e.to_string()))?;
+    let resp: misanthropy::MessagesResponse = serde_json::from_str(&body)
+        .map_err(|e| TenxError::Model(format!("Invalid response from {API}: {e}")))?;
+    send_event(sender, Event::ModelResponse(resp.format_content()))?;
+    Ok(resp)
+}
+
+/// Assembles a response from a stream of server-sent events, sending each piece of text as a
+/// snippet event. The input of a tool use arrives as pieces of JSON, which are parsed once the
+/// stream ends.
+async fn stream_response(
+    resp: reqwest::Response,
+    sender: &Option<EventSender>,
+) -> Result<misanthropy::MessagesResponse> {
+    let mut stream = JsonStream::new(API, resp);
+    let mut message = Value::Null;
+    let mut blocks: Vec<Value> = Vec::new();
+    // The tool use input JSON received so far, for each block
+    let mut inputs: Vec<String> = Vec::new();
+    while let Some(event) = stream.next().await? {
+        match event["type"].as_str() {
+            Some("message_start") => message = event["message"].clone(),
+            Some("content_block_start") => {
+                blocks.push(event["content_block"].clone());
+                inputs.push(String::new());
+            }
+            Some("content_block_delta") => {
+                let (Some(block), Some(input)) = (blocks.last_mut(), inputs.last_mut()) else {
+                    continue;
+                };
+                if let Some(text) = event["delta"]["text"].as_str() {
+                    let current = block["text"].as_str().unwrap_or_default();
+                    block["text"] = Value::String(format!("{current}{text}"));
+                    send_event(sender, Event::Snippet(text.to_string()))?;
+                } else if let Some(json) = event["delta"]["partial_json"].as_str() {
+                    input.push_str(json);
+                }
+            }
+            Some("message_delta") => {
+                for (key, value) in event["delta"].as_object().into_iter().flatten() {
+                    message[key] = value.clone();
+                }
+                for (key, value) in event["usage"].as_object().into_iter().flatten() {
+                    message["usage"][key] = value.clone();
+                }
+            }
+            _ => {}
+        }
+    }
+    if message.is_null() {
+        return Err(TenxError::Model("No response received from stream".into()));
+    }
+    for (block, input) in blocks.iter_mut().zip(&inputs) {
+        if !input.is_empty() {
+            block["input"] = serde_json::from_str(input)
+                .map_err(|e| TenxError::Model(format!("Invalid tool input from {API}: {e}")))?;
+        }
+    }
+    message["content"] = Value::Array(blocks);
+    serde_json::from_value(message)
+        .map_err(|e| TenxError::Model(format!("Invalid stream from {API}: {e}")))
+}
+
 impl ClaudeChat {
     /// Helper to add or append a message with the given role.
     fn add_message_with_role(&mut self, role: misanthropy::Role, text: &str) -> Result<()> {
@@ -145,7 +254,7 @@ impl Chat for ClaudeChat {
             .system
             .push(misanthropy::Content::Text(misanthropy::Text {
                 text: prompt.into(),
-                cache_control: Some(misanthropy::CacheControl::Ephemeral),
+                cache_control: None,
             }));
         Ok(())
     }
@@ -159,7 +268,9 @@ impl Chat for ClaudeChat {
     }
 
     fn add_context(&mut self, ctx: &ContextItem) -> Result<()> {
-        self.add_user_message(&tags::render_context(ctx)?)
+        self.add_user_message(&tags::render_context(ctx)?)?;
+        self.context_message = Some(self.request.messages.len() - 1);
+        Ok(())
     }
 
     fn add_editable(&mut self, path: &str, data: &str) -> Result<()> {
@@ -207,23 +318,15 @@ impl Chat for ClaudeChat {
         self.request.model = self.api_model.clone();
         self.request.max_tokens = MAX_TOKENS;
         self.request.stream = self.streaming;
+        self.set_cache_breakpoints();
 
         trace!(
             "Sending request: {}",
             serde_json::to_string_pretty(&self.request)?
         );
 
-        let resp = if self.streaming {
-            self.stream_response(self.anthropic_key.clone(), &self.request, sender.clone())
-                .await?
-        } else {
-            let anthropic = Anthropic::new(&self.anthropic_key);
-            let resp = anthropic.messages(&self.request).await?;
-            if let Some(text) = resp.format_content().into() {
-                send_event(&sender, Event::ModelResponse(text))?;
-            }
-            resp
-        };
+        let resp =
+            send_messages(&self.api_base, &self.anthropic_key, &self.request, &sender).await?;
 
         trace!("Got response: {}", serde_json::to_string_pretty(&resp)?);
 
@@ -259,6 +362,8 @@ pub struct Claude {
     pub api_model: String,
     /// The Anthropic API key
     pub anthropic_key: String,
+    /// The base URL of the API. Defaults to `ANTHROPIC_API_BASE` if empty.
+    pub api_base: String,
     /// Whether to stream responses
     pub streaming: bool,
 }
@@ -318,9 +423,15 @@ impl ModelProvider for Claude {
     }
 
     fn chat(&self) -> Option<Box<dyn Chat>> {
+        let api_base = if self.api_base.is_empty() {
+            ANTHROPIC_API_BASE.to_string()
+        } else {
+            self.api_base.clone()
+        };
         Some(Box::new(ClaudeChat::new(
             self.api_model.clone(),
             self.anthropic_key.clone(),
+            api_base,
             self.streaming,
         )))
     }
@@ -328,11 +439,21 @@ impl ModelProvider for Claude {
     fn api_model(&self) -> String {
         self.api_model.clone()
     }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        tokens::estimate(text, tokens::CLAUDE_CHARS_PER_TOKEN)
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        Some(tokens::CLAUDE_WINDOW)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutils::mock_server::{MockResponse, MockServer};
+    use serde_json::json;
 
     fn text_of_first_content(message: &misanthropy::Message) -> &str {
         if let Content::Text(text_content) = &message.content[0] {
@@ -347,6 +468,7 @@ mod tests {
         let mut chat = ClaudeChat::new(
             "claude-3-opus-20240229".to_string(),
             "fake-key".to_string(),
+            ANTHROPIC_API_BASE.to_string(),
             false,
         );
 
@@ -397,6 +519,7 @@ mod tests {
         let mut chat = ClaudeChat::new(
             "claude-3-opus-20240229".to_string(),
             "fake-key".to_string(),
+            ANTHROPIC_API_BASE.to_string(),
             false,
         );
 
@@ -432,4 +555,189 @@ mod tests {
             "<check_error>\nError: test2 failed\nDetails: different error\n</check_error>"
         ));
     }
+
+    #[test]
+    fn test_cache_breakpoints() {
+        fn cached(content: &[Content]) -> Vec<bool> {
+            content
+                .iter()
+                .map(|c| {
+                    matches!(
+                        c,
+                        Content::Text(misanthropy::Text {
+                            cache_control: Some(misanthropy::CacheControl::Ephemeral),
+                            ..
+                        })
+                    )
+                })
+                .collect()
+        }
+
+        let mut chat = ClaudeChat::new(
+            "claude-3-opus-20240229".to_string(),
+            "fake-key".to_string(),
+            ANTHROPIC_API_BASE.to_string(),
+            false,
+        );
+        chat.add_system_prompt("Extra instructions").unwrap();
+        for name in ["one", "two"] {
+            chat.add_context(&ContextItem {
+                ty: "text".into(),
+                source: name.into(),
+                body: "context".into(),
+            })
+            .unwrap();
+        }
+        chat.add_agent_message("Got it.").unwrap();
+        chat.add_user_prompt("First prompt").unwrap();
+        chat.add_agent_comment("First reply").unwrap();
+        chat.add_editable("a.rs", "fn a() {}").unwrap();
+        chat.add_user_prompt("Second prompt").unwrap();
+        chat.set_cache_breakpoints();
+
+        // Only the last system block is a breakpoint
+        assert_eq!(cached(&chat.request.system), vec![false, true]);
+        let breakpoints: Vec<_> = chat
+            .request
+            .messages
+            .iter()
+            .map(|m| cached(&m.content))
+            .collect();
+        assert_eq!(
+            breakpoints,
+            vec![
+                // The context
+                vec![true],
+                vec![false],
+                vec![false],
+                // The history before the latest prompt
+                vec![true],
+                vec![false],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_usage_values() {
+        let usage = ClaudeUsage {
+            input_tokens: Some(10),
+            output_tokens: Some(5),
+            cache_creation_input_tokens: Some(100),
+            cache_read_input_tokens: Some(1000),
+        };
+        let values = usage.values();
+        assert_eq!(values["cache_creation_input_tokens"], 100);
+        assert_eq!(values["cache_read_input_tokens"], 1000);
+        assert_eq!(usage.totals(), (1110, 5));
+    }
+
+    fn message(text: &str) -> Value {
+        json!({
+            "id": "msg_mock",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-mock",
+            "content": [{"type": "text", "text": text}],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        })
+    }
+
+    fn stream(chunks: &[&str]) -> MockResponse {
+        let mut start = message("");
+        start["content"] = json!([]);
+        start["stop_reason"] = Value::Null;
+        start["usage"]["output_tokens"] = json!(1);
+        let mut events = vec![
+            json!({"type": "message_start", "message": start}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+        ];
+        for chunk in chunks {
+            events.push(json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": chunk}}));
+        }
+        events.extend([
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "end_turn", "stop_sequence": null}, "usage": {"output_tokens": 5}}),
+            json!({"type": "message_stop"}),
+        ]);
+        MockResponse::Stream(events.iter().map(Value::to_string).collect())
+    }
+
+    fn chat(server: &MockServer, streaming: bool) -> ClaudeChat {
+        let mut chat = ClaudeChat::new("claude-mock".into(), "key".into(), server.url(), streaming);
+        chat.add_user_prompt("Say hello").unwrap();
+        chat
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, message("<comment>\nHello\n</comment>")),
+            stream(&["<comment>\nHel", "lo\n</comment>"]),
+        ])
+        .await
+        .unwrap();
+
+        for streaming in [false, true] {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+            let mut chat = chat(&server, streaming);
+            let resp = chat.send(Some(tx)).await.unwrap();
+            assert_eq!(resp.comment.as_deref(), Some("Hello"));
+            assert_eq!(resp.usage.unwrap().totals(), (10, 5));
+            // The response is merged into the conversation
+            assert_eq!(chat.request.messages.len(), 2);
+
+            let mut snippets = 0;
+            while let Ok(event) = rx.try_recv() {
+                if let Event::Snippet(_) = event {
+                    snippets += 1;
+                }
+            }
+            assert_eq!(snippets, if streaming { 2 } else { 0 });
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("key"));
+        assert_eq!(
+            requests[0].header("anthropic-version"),
+            Some(ANTHROPIC_VERSION)
+        );
+        assert_eq!(requests[0].json()["model"], "claude-mock");
+        assert!(!requests[0].json()["stream"].as_bool().unwrap_or(false));
+        assert!(requests[1].json()["stream"].as_bool().unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_send_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::error(400, "Bad request"),
+            MockResponse::rate_limited(Some(7)),
+            MockResponse::error(529, "Overloaded"),
+            MockResponse::Stream(vec![
+                json!({"type": "error", "error": {"type": "api_error", "message": "Stream failed"}})
+                    .to_string(),
+            ]),
+        ])
+        .await
+        .unwrap();
+
+        match chat(&server, false).send(None).await {
+            Err(TenxError::Model(msg)) => assert!(msg.contains("Bad request")),
+            other => panic!("expected a model error, got {other:?}"),
+        }
+        assert!(matches!(
+            chat(&server, true).send(None).await,
+            Err(TenxError::Throttle(Throttle::RetryAfter(7)))
+        ));
+        assert!(matches!(
+            chat(&server, false).send(None).await,
+            Err(TenxError::Throttle(Throttle::Backoff))
+        ));
+        match chat(&server, true).send(None).await {
+            Err(TenxError::Model(msg)) => assert_eq!(msg, "Stream failed"),
+            other => panic!("expected a model error, got {other:?}"),
+        }
+    }
 }
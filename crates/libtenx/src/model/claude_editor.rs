@@ -1,14 +1,14 @@
 //! This module implements the Claude model provider with text editor capabilities for the tenx system.
-use misanthropy::{tools, Anthropic, Content, ContentBlockDelta, Role, StreamEvent};
+use misanthropy::{Content, Role};
 use serde_json;
-use tracing::{trace, warn};
+use tracing::trace;
 
-use super::claude::ClaudeUsage;
+use super::claude::{self, ClaudeUsage, ANTHROPIC_API_BASE};
 use crate::{
     context::ContextItem,
     error::{Result, TenxError},
     events::*,
-    model::ModelProvider,
+    model::{editor, tokens, ModelProvider},
     session::ModelResponse,
     throttle::Throttle,
 };
@@ -25,6 +25,8 @@ pub struct ClaudeEditorChat {
     pub api_model: String,
     /// The Anthropic API key
     pub anthropic_key: String,
+    /// The base URL of the API
+    pub api_base: String,
     /// Whether to stream responses
     pub streaming: bool,
     /// The messages request being built
@@ -32,35 +34,6 @@ pub struct ClaudeEditorChat {
 }
 
 impl ClaudeEditorChat {
-    async fn stream_response(
-        &self,
-        api_key: String,
-        req: &misanthropy::MessagesRequest,
-        sender: Option<EventSender>,
-    ) -> Result<misanthropy::MessagesResponse> {
-        let anthropic = Anthropic::new(&api_key);
-        let mut streamed_response = anthropic.messages_stream(req)?;
-        while let Some(event) = streamed_response.next().await {
-            let event = event?;
-            match event {
-                StreamEvent::ContentBlockDelta {
-                    delta: ContentBlockDelta::TextDelta { text },
-                    ..
-                } => {
-                    send_event(&sender, Event::Snippet(text))?;
-                }
-                StreamEvent::Error { error } => {
-                    warn!("Error in stream: {:?}", error);
-                }
-                StreamEvent::MessageStop => {
-                    // The message has ended, but we don't need to do anything special here
-                }
-                _ => {} // Ignore other event types
-            }
-        }
-        Ok(streamed_response.response)
-    }
-
     fn extract_changes(&self, req: &misanthropy::MessagesRequest) -> Result<ModelResponse> {
         let last_message = match req.messages.last() {
             Some(message) if message.role == Role::Assistant => message,
@@ -85,49 +58,9 @@ impl ClaudeEditorChat {
 
         // Process tool uses
         let mut patch = state::Patch::default();
-
         for content in &last_message.content {
             if let Content::ToolUse(tool_use) = content {
-                match serde_json::from_value::<tools::TextEditor>(tool_use.input.clone()) {
-                    Ok(edit) => match edit {
-                        tools::TextEditor::Create { path, file_text } => {
-                            patch = patch.with_write(path, file_text);
-                        }
-                        tools::TextEditor::StrReplace {
-                            path,
-                            old_str,
-                            new_str,
-                        } => {
-                            patch = patch.with_replace(path, old_str, new_str);
-                        }
-                        tools::TextEditor::Insert {
-                            path,
-                            insert_line,
-                            new_str,
-                        } => {
-                            patch = patch.with_insert(path, insert_line, new_str);
-                        }
-                        tools::TextEditor::View { path, view_range } => {
-                            if let Some(range) = view_range {
-                                patch = patch.with_view_range_onebased(
-                                    path,
-                                    range[0] as isize,
-                                    range[1] as isize,
-                                );
-                            } else {
-                                patch = patch.with_view(path);
-                            }
-                        }
-                        tools::TextEditor::UndoEdit { path } => {
-                            patch = patch.with_undo(path);
-                        }
-                    },
-                    Err(e) => {
-                        return Err(TenxError::Internal(format!(
-                            "Failed to parse tool use: {e}"
-                        )));
-                    }
-                }
+                patch = editor::add_call(patch, &tool_use.name, tool_use.input.clone())?;
             }
         }
 
@@ -196,17 +129,9 @@ impl Chat for ClaudeEditorChat {
             serde_json::to_string_pretty(&self.request)?
         );
 
-        let resp = if self.streaming {
-            self.stream_response(self.anthropic_key.clone(), &self.request, sender.clone())
-                .await?
-        } else {
-            let anthropic = Anthropic::new(&self.anthropic_key);
-            let resp = anthropic.messages(&self.request).await?;
-            if let Some(text) = resp.format_content().into() {
-                send_event(&sender, Event::ModelResponse(text))?;
-            }
-            resp
-        };
+        let resp =
+            claude::send_messages(&self.api_base, &self.anthropic_key, &self.request, &sender)
+                .await?;
 
         trace!("Got response: {}", serde_json::to_string_pretty(&resp)?);
 
@@ -242,6 +167,8 @@ pub struct ClaudeEditor {
     pub api_model: String,
     /// The Anthropic API key
     pub anthropic_key: String,
+    /// The base URL of the API. Defaults to `ANTHROPIC_API_BASE` if empty.
+    pub api_base: String,
     /// Whether to stream responses
     pub streaming: bool,
 }
@@ -256,6 +183,20 @@ impl ModelProvider for ClaudeEditor {
         self.api_model.clone()
     }
 
+    fn system_prompt(&self) -> String {
+        // Anthropic adds its own definition of the built-in editor tool to the system prompt,
+        // which describes the same tool as ours
+        format!("{}\n{}", editor::TOOL_DESCRIPTION, editor::parameters())
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        tokens::estimate(text, tokens::CLAUDE_CHARS_PER_TOKEN)
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        Some(tokens::CLAUDE_WINDOW)
+    }
+
     fn chat(&self) -> Option<Box<dyn Chat>> {
         let mut request = misanthropy::MessagesRequest {
             model: self.api_model.clone(),
@@ -276,11 +217,126 @@ impl ModelProvider for ClaudeEditor {
         // Add text editor tool
         request = request.with_text_editor(misanthropy::TEXT_EDITOR_37);
 
+        let api_base = if self.api_base.is_empty() {
+            ANTHROPIC_API_BASE.to_string()
+        } else {
+            self.api_base.clone()
+        };
         Some(Box::new(ClaudeEditorChat {
             api_model: self.api_model.clone(),
             anthropic_key: self.anthropic_key.clone(),
+            api_base,
             streaming: self.streaming,
             request,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::mock_server::{MockResponse, MockServer};
+    use serde_json::{json, Value};
+
+    fn chat(server: &MockServer, streaming: bool) -> Box<dyn Chat> {
+        let model = ClaudeEditor {
+            name: "mock".into(),
+            api_model: "claude-mock".into(),
+            anthropic_key: "key".into(),
+            api_base: server.url(),
+            streaming,
+        };
+        let mut chat = model.chat().unwrap();
+        chat.add_user_message("Fix the typo").unwrap();
+        chat
+    }
+
+    fn tool_input() -> Value {
+        json!({"command": "str_replace", "path": "src/lib.rs", "old_str": "teh", "new_str": "the"})
+    }
+
+    fn message() -> Value {
+        json!({
+            "id": "msg_mock",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-mock",
+            "content": [
+                {"type": "text", "text": "Fixing it"},
+                {"type": "tool_use", "id": "tool_1", "name": editor::TOOL_NAME, "input": tool_input()}
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        })
+    }
+
+    /// Streams the same message as `message`, with the tool input split into pieces of JSON.
+    fn stream() -> MockResponse {
+        let mut start = message();
+        start["content"] = json!([]);
+        start["stop_reason"] = Value::Null;
+        let input = tool_input().to_string();
+        let (first, second) = input.split_at(input.len() / 2);
+        let events = [
+            json!({"type": "message_start", "message": start}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Fixing it"}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "tool_use", "id": "tool_1", "name": editor::TOOL_NAME, "input": {}}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": first}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": second}}),
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use", "stop_sequence": null}, "usage": {"output_tokens": 5}}),
+            json!({"type": "message_stop"}),
+        ];
+        MockResponse::Stream(events.iter().map(Value::to_string).collect())
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = MockServer::start(vec![MockResponse::json(200, message()), stream()])
+            .await
+            .unwrap();
+
+        for streaming in [false, true] {
+            let resp = chat(&server, streaming).send(None).await.unwrap();
+            assert_eq!(resp.comment.as_deref(), Some("Fixing it"));
+            assert_eq!(
+                resp.patch.unwrap(),
+                state::Patch::default().with_replace("src/lib.rs", "teh", "the")
+            );
+            assert_eq!(resp.usage.unwrap().totals(), (10, 5));
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("key"));
+        assert_eq!(
+            requests[0].json()["tools"][0]["type"],
+            misanthropy::TEXT_EDITOR_37
+        );
+        // Anthropic's built-in tool shares the name and commands of the editor tool
+        assert_eq!(requests[0].json()["tools"][0]["name"], editor::TOOL_NAME);
+        assert!(requests[1].json()["stream"].as_bool().unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn test_send_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::error(400, "Bad request"),
+            MockResponse::rate_limited(Some(7)),
+        ])
+        .await
+        .unwrap();
+
+        match chat(&server, false).send(None).await {
+            Err(TenxError::Model(msg)) => assert!(msg.contains("Bad request")),
+            other => panic!("expected a model error, got {other:?}"),
+        }
+        assert!(matches!(
+            chat(&server, true).send(None).await,
+            Err(TenxError::Throttle(Throttle::RetryAfter(7)))
+        ));
+    }
+}
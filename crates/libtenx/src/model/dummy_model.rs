@@ -66,6 +66,7 @@ impl DummyUsage {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DummyModel {
     model_response: Result<ModelResponse>,
+    context_window: Option<usize>,
 }
 
 impl DummyModel {
@@ -73,14 +74,22 @@ impl DummyModel {
     pub fn from_model_response(mr: ModelResponse) -> Self {
         Self {
             model_response: Ok(mr),
+            context_window: None,
         }
     }
+
+    /// Gives the model a context window, so requests are checked against it.
+    pub fn with_context_window(mut self, tokens: usize) -> Self {
+        self.context_window = Some(tokens);
+        self
+    }
 }
 
 impl Default for DummyModel {
     fn default() -> Self {
         Self {
             model_response: Ok(ModelResponse::default()),
+            context_window: None,
         }
     }
 }
@@ -100,4 +109,8 @@ impl ModelProvider for DummyModel {
             model_response: self.model_response.clone(),
         }))
     }
+
+    fn context_window(&self) -> Option<usize> {
+        self.context_window
+    }
 }
@@ -0,0 +1,248 @@
+//! A text editor tool for models that support function calling, modelled on Anthropic's text
+//! editor tool. Calls to the tool are mapped onto patch operations, so the editor models for
+//! different providers share one tool protocol. Claude is offered Anthropic's built-in tool,
+//! whose calls have the same name and arguments.
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::{Result, TenxError};
+
+/// The name of the editor tool, as offered to the model.
+pub const TOOL_NAME: &str = "str_replace_editor";
+
+/// A description of the editor tool, as offered to the model.
+pub const TOOL_DESCRIPTION: &str = "\
+A tool for viewing and editing files. Paths are relative to the project root.
+* `view` shows the content of a file, optionally limited to a one-based, inclusive `view_range`. An end of -1 means the end of the file.
+* `create` writes `file_text` to a file, replacing any existing content.
+* `str_replace` replaces `old_str` with `new_str`. `old_str` must match exactly one location in the file, including whitespace.
+* `insert` inserts `new_str` after line `insert_line`. A line of 0 inserts at the start of the file.
+* `replace_lines` replaces the one-based, inclusive `line_range` with `new_str`. An end of -1 means the end of the file. If `old_str` is given, it must match the current content of the lines, which catches stale line numbers.
+* `undo_edit` reverts the last edit made to a file.";
+
+/// The system prompt for editor models.
+pub const SYSTEM: &str = "\
+You are an expert software engineer, working on a project with the user. The user will give you \
+the files you may edit, context to help you, and a prompt. Make the changes the prompt asks for \
+by calling the str_replace_editor tool, once for each edit. Make minimal, focused changes that \
+match the style of the surrounding code. Explain what you did briefly, in plain text.";
+
+/// The system prompt and definition of the editor tool, as sent with every request by editor
+/// models.
+pub fn system_prompt() -> String {
+    format!(
+        "{SYSTEM}\n{TOOL_NAME}\n{TOOL_DESCRIPTION}\n{}",
+        parameters()
+    )
+}
+
+/// A JSON schema for the editor tool's parameters.
+pub fn parameters() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "command": {
+                "type": "string",
+                "enum": ["view", "create", "str_replace", "insert", "replace_lines", "undo_edit"],
+                "description": "The command to run."
+            },
+            "path": {
+                "type": "string",
+                "description": "The file to operate on."
+            },
+            "file_text": {
+                "type": "string",
+                "description": "The content of the file, for `create`."
+            },
+            "old_str": {
+                "type": "string",
+                "description": "The text to replace, for `str_replace`, or the current content of the lines, for `replace_lines`."
+            },
+            "new_str": {
+                "type": "string",
+                "description": "The new text, for `str_replace`, `insert` and `replace_lines`."
+            },
+            "insert_line": {
+                "type": "integer",
+                "description": "The line to insert after, for `insert`."
+            },
+            "view_range": {
+                "type": "array",
+                "items": {"type": "integer"},
+                "description": "The start and end lines to show, for `view`."
+            },
+            "line_range": {
+                "type": "array",
+                "items": {"type": "integer"},
+                "description": "The start and end lines to replace, for `replace_lines`."
+            }
+        },
+        "required": ["command", "path"]
+    })
+}
+
+/// A call to the editor tool.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    View {
+        path: String,
+        view_range: Option<Vec<i64>>,
+    },
+    Create {
+        path: String,
+        file_text: String,
+    },
+    StrReplace {
+        path: String,
+        old_str: String,
+        new_str: String,
+    },
+    Insert {
+        path: String,
+        insert_line: usize,
+        new_str: String,
+    },
+    ReplaceLines {
+        path: String,
+        line_range: Vec<i64>,
+        new_str: String,
+        old_str: Option<String>,
+    },
+    UndoEdit {
+        path: String,
+    },
+}
+
+/// Adds the operation for a tool call to a patch. Errors are returned to the model, so it can
+/// correct the call.
+pub fn add_call(patch: state::Patch, name: &str, arguments: Value) -> Result<state::Patch> {
+    if name != TOOL_NAME {
+        return Err(TenxError::ResponseParse {
+            user: format!("Model called an unknown tool: {name}"),
+            model: format!("There is no tool called {name}. Use the {TOOL_NAME} tool."),
+        });
+    }
+    let command: Command =
+        serde_json::from_value(arguments).map_err(|e| TenxError::ResponseParse {
+            user: "Failed to parse tool call".into(),
+            model: format!("Invalid arguments to the {TOOL_NAME} tool: {e}"),
+        })?;
+    Ok(match command {
+        Command::View {
+            path,
+            view_range: None,
+        } => patch.with_view(path),
+        Command::View {
+            path,
+            view_range: Some(range),
+        } => {
+            let [start, end] = range[..] else {
+                return Err(TenxError::ResponseParse {
+                    user: "Failed to parse tool call".into(),
+                    model: "view_range must be a start and an end line".into(),
+                });
+            };
+            patch.with_view_range_onebased(path, start as isize, end as isize)
+        }
+        Command::Create { path, file_text } => patch.with_write(path, file_text),
+        Command::StrReplace {
+            path,
+            old_str,
+            new_str,
+        } => patch.with_replace(path, old_str, new_str),
+        Command::Insert {
+            path,
+            insert_line,
+            new_str,
+        } => patch.with_insert(path, insert_line, new_str),
+        Command::ReplaceLines {
+            path,
+            line_range,
+            new_str,
+            old_str,
+        } => {
+            let [start, end] = line_range[..] else {
+                return Err(TenxError::ResponseParse {
+                    user: "Failed to parse tool call".into(),
+                    model: "line_range must be a start and an end line".into(),
+                });
+            };
+            if start < 1 || !(end == -1 || end >= start) {
+                return Err(TenxError::ResponseParse {
+                    user: "Failed to parse tool call".into(),
+                    model: format!(
+                        "Invalid line_range [{start}, {end}]: lines are numbered from 1, and the \
+                         end must be -1 or at least the start"
+                    ),
+                });
+            }
+            let end = (end != -1).then_some(end as usize);
+            patch.with_replace_lines(path, start as usize - 1, end, new_str, old_str)
+        }
+        Command::UndoEdit { path } => patch.with_undo(path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_add_call() {
+        let calls = [
+            json!({"command": "view", "path": "a.rs"}),
+            json!({"command": "view", "path": "a.rs", "view_range": [2, -1]}),
+            json!({"command": "create", "path": "b.rs", "file_text": "fn b() {}\n"}),
+            json!({"command": "str_replace", "path": "a.rs", "old_str": "x", "new_str": "y"}),
+            json!({"command": "insert", "path": "a.rs", "insert_line": 3, "new_str": "z"}),
+            json!({"command": "replace_lines", "path": "a.rs", "line_range": [2, 3], "new_str": "w", "old_str": "x\ny"}),
+            json!({"command": "replace_lines", "path": "a.rs", "line_range": [4, -1], "new_str": "v"}),
+            json!({"command": "undo_edit", "path": "a.rs"}),
+        ];
+        let mut patch = state::Patch::default();
+        for call in calls {
+            patch = add_call(patch, TOOL_NAME, call).unwrap();
+        }
+        assert_eq!(
+            patch,
+            state::Patch::default()
+                .with_view("a.rs")
+                .with_view_range_onebased("a.rs", 2, -1)
+                .with_write("b.rs", "fn b() {}\n")
+                .with_replace("a.rs", "x", "y")
+                .with_insert("a.rs", 3, "z")
+                .with_replace_lines("a.rs", 1, Some(3), "w", Some("x\ny"))
+                .with_replace_lines("a.rs", 3, None, "v", None)
+                .with_undo("a.rs")
+        );
+
+        assert!(add_call(
+            state::Patch::default(),
+            "other_tool",
+            json!({"command": "view", "path": "a.rs"})
+        )
+        .is_err());
+        assert!(add_call(
+            state::Patch::default(),
+            TOOL_NAME,
+            json!({"command": "delete", "path": "a.rs"})
+        )
+        .is_err());
+        assert!(add_call(
+            state::Patch::default(),
+            TOOL_NAME,
+            json!({"command": "view", "path": "a.rs", "view_range": [1]})
+        )
+        .is_err());
+        for line_range in [json!([0, 2]), json!([3, 2]), json!([2, -2])] {
+            assert!(add_call(
+                state::Patch::default(),
+                TOOL_NAME,
+                json!({"command": "replace_lines", "path": "a.rs", "line_range": line_range, "new_str": "x"})
+            )
+            .is_err());
+        }
+    }
+}
@@ -3,9 +3,12 @@ use std::collections::HashMap;
 
 use google_genai::datatypes::{Content, GenerateContentReq, GenerateContentResponse, Part};
 use serde::{Deserialize, Serialize};
-use tracing::{trace, warn};
+use tracing::trace;
 
-use super::Chat;
+use super::{
+    http::{self, JsonStream},
+    Chat, GOOGLE_API_BASE,
+};
 
 use crate::{
     checks::CheckResult,
@@ -13,34 +16,14 @@ use crate::{
     error::{Result, TenxError},
     events::*,
     model::tags,
+    model::tokens,
     model::ModelProvider,
     session::ModelResponse,
     throttle::Throttle,
 };
 
-fn map_error(e: google_genai::error::GenAiError) -> TenxError {
-    match e {
-        google_genai::error::GenAiError::Remote {
-            status,
-            message,
-            headers,
-        } => {
-            warn!("Google API error: {} ({})\n{:?}", message, status, headers);
-            if status == 429 {
-                // Look for retry-after header
-                if let Some(retry_after) = headers.get("retry-after") {
-                    if let Ok(secs) = retry_after.parse::<u64>() {
-                        return TenxError::Throttle(Throttle::RetryAfter(secs));
-                    }
-                }
-                TenxError::Throttle(Throttle::Backoff)
-            } else {
-                TenxError::Model(message)
-            }
-        }
-        google_genai::error::GenAiError::Internal(msg) => TenxError::Model(msg),
-    }
-}
+/// The name of the API in error messages.
+const API: &str = "Google API";
 
 /// A model that interacts with the Google Generative Language API. The general design of the model
 /// is to:
@@ -54,6 +37,8 @@ pub struct Google {
     pub name: String,
     pub api_model: String,
     pub api_key: String,
+    /// The base URL of the API. Defaults to `GOOGLE_API_BASE` if empty.
+    pub api_base: String,
     pub streaming: bool,
 }
 
@@ -94,6 +79,8 @@ pub struct GoogleChat {
     pub api_model: String,
     /// The Google API key
     pub api_key: String,
+    /// The base URL of the API
+    pub api_base: String,
     /// Whether to stream responses
     pub streaming: bool,
     /// The contents request being built
@@ -101,14 +88,15 @@ pub struct GoogleChat {
 }
 
 impl GoogleChat {
-    /// Creates a new GoogleChat configured for the given model, API key, and streaming setting.
-    pub fn new(api_model: String, api_key: String, streaming: bool) -> Self {
+    /// Creates a new GoogleChat configured for the given model, API key, and settings.
+    pub fn new(api_model: String, api_key: String, api_base: String, streaming: bool) -> Self {
         let request = GenerateContentReq::default()
             .model(&api_model)
             .system_instruction(Content::default().parts(vec![Part::default().text(tags::SYSTEM)]));
         Self {
             api_model,
             api_key,
+            api_base,
             streaming,
             request,
         }
@@ -168,20 +156,35 @@ impl GoogleChat {
         Ok(())
     }
 
+    /// Sends the request, returning the response if it succeeded.
+    async fn post(&self) -> Result<reqwest::Response> {
+        let method = if self.streaming {
+            "streamGenerateContent?alt=sse"
+        } else {
+            "generateContent"
+        };
+        let url = format!(
+            "{}/models/{}:{method}",
+            self.api_base.trim_end_matches('/'),
+            self.api_model
+        );
+        let req = reqwest::Client::new()
+            .post(url)
+            .header("x-goog-api-key", &self.api_key);
+        http::post(API, req, serde_json::to_string(&self.request)?).await
+    }
+
+    /// Streams a response as server-sent events, sending each piece of text as a snippet event.
     async fn stream_response(
         &self,
-        api_key: String,
-        req: &GenerateContentReq,
+        resp: reqwest::Response,
         sender: Option<EventSender>,
     ) -> Result<Vec<GenerateContentResponse>> {
-        use futures_util::StreamExt;
-        let mut stream = google_genai::generate_content_stream(&api_key, req.clone())
-            .await
-            .map_err(map_error)?;
-
+        let mut stream = JsonStream::new(API, resp);
         let mut responses = Vec::new();
-        while let Some(response) = stream.next().await {
-            let response = response.map_err(map_error)?;
+        while let Some(value) = stream.next().await? {
+            let response: GenerateContentResponse = serde_json::from_value(value)
+                .map_err(|e| TenxError::Model(format!("Invalid stream event from {API}: {e}")))?;
             self.emit_event(&sender, &response)?;
             responses.push(response);
         }
@@ -319,13 +322,16 @@ impl Chat for GoogleChat {
 
         trace!("Sending request: {:#?}", self.request);
 
+        let resp = self.post().await?;
         let responses = if self.streaming {
-            self.stream_response(self.api_key.clone(), &self.request, sender.clone())
-                .await?
+            self.stream_response(resp, sender.clone()).await?
         } else {
-            let resp = google_genai::generate_content(&self.api_key, self.request.clone())
+            let body = resp
+                .text()
                 .await
-                .map_err(map_error)?;
+                .map_err(|e| TenxError::Model(e.to_string()))?;
+            let resp: GenerateContentResponse = serde_json::from_str(&body)
+                .map_err(|e| TenxError::Model(format!("Invalid response from {API}: {e}")))?;
 
             self.emit_event(&sender, &resp)?;
             vec![resp]
@@ -352,11 +358,105 @@ impl ModelProvider for Google {
         self.api_model.clone()
     }
 
+    fn context_window(&self) -> Option<usize> {
+        Some(tokens::gemini_window(&self.api_model))
+    }
+
     fn chat(&self) -> Option<Box<dyn Chat>> {
+        let api_base = if self.api_base.is_empty() {
+            GOOGLE_API_BASE.to_string()
+        } else {
+            self.api_base.clone()
+        };
         Some(Box::new(GoogleChat::new(
             self.api_model.clone(),
             self.api_key.clone(),
+            api_base,
             self.streaming,
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::mock_server::{MockResponse, MockServer};
+    use serde_json::{json, Value};
+
+    fn response(text: &str, usage: bool) -> Value {
+        let mut resp = json!({
+            "candidates": [{"content": {"role": "model", "parts": [{"text": text}]}}]
+        });
+        if usage {
+            resp["usageMetadata"] =
+                json!({"promptTokenCount": 10, "candidatesTokenCount": 5, "totalTokenCount": 15});
+        }
+        resp
+    }
+
+    fn chat(server: &MockServer, streaming: bool) -> GoogleChat {
+        let mut chat = GoogleChat::new("gemini".into(), "key".into(), server.root_url(), streaming);
+        chat.add_user_prompt("Say hello").unwrap();
+        chat
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, response("<comment>\nHello\n</comment>", true)),
+            MockResponse::Stream(vec![
+                response("<comment>\nHel", false).to_string(),
+                response("lo\n</comment>", true).to_string(),
+            ]),
+        ])
+        .await
+        .unwrap();
+
+        for streaming in [false, true] {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+            let resp = chat(&server, streaming).send(Some(tx)).await.unwrap();
+            assert_eq!(resp.comment.as_deref(), Some("Hello"));
+            assert_eq!(resp.usage.unwrap().totals(), (10, 5));
+
+            let mut snippets = 0;
+            while let Ok(event) = rx.try_recv() {
+                if let Event::Snippet(_) = event {
+                    snippets += 1;
+                }
+            }
+            assert_eq!(snippets, if streaming { 2 } else { 1 });
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/models/gemini:generateContent");
+        assert_eq!(
+            requests[1].path,
+            "/models/gemini:streamGenerateContent?alt=sse"
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("key"));
+    }
+
+    #[tokio::test]
+    async fn test_send_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::error(400, "Bad request"),
+            MockResponse::rate_limited(Some(3)),
+            MockResponse::rate_limited(None),
+        ])
+        .await
+        .unwrap();
+
+        match chat(&server, false).send(None).await {
+            Err(TenxError::Model(msg)) => assert!(msg.contains("Bad request")),
+            other => panic!("expected a model error, got {other:?}"),
+        }
+        assert!(matches!(
+            chat(&server, true).send(None).await,
+            Err(TenxError::Throttle(Throttle::RetryAfter(3)))
+        ));
+        assert!(matches!(
+            chat(&server, false).send(None).await,
+            Err(TenxError::Throttle(Throttle::Backoff))
+        ));
+    }
+}
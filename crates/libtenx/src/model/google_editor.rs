@@ -0,0 +1,380 @@
+//! This module implements a Google model provider that edits files through function calls to the
+//! editor tool, rather than the tags dialect. It talks to the Generative Language REST API
+//! directly, since function calling needs parts of the API the tags-based model doesn't use.
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tracing::trace;
+
+use super::{
+    editor,
+    http::{self, JsonStream},
+    tokens, Chat, GoogleUsage, ModelProvider,
+};
+use crate::{
+    checks::CheckResult,
+    context::ContextItem,
+    error::{Result, TenxError},
+    events::{send_event, Event, EventSender},
+    model::tags,
+    session::ModelResponse,
+    throttle::Throttle,
+};
+
+/// The base URL of the Generative Language API.
+pub const GOOGLE_API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// The name of the API in error messages.
+const API: &str = "Google API";
+
+/// The parts of a response that make up a model response.
+#[derive(Debug, Default)]
+struct Reply {
+    text: String,
+    calls: Vec<(String, Value)>,
+    usage: GoogleUsage,
+}
+
+impl Reply {
+    /// Adds the content of a response, or of one chunk of a streamed response, returning the text
+    /// it contained.
+    fn absorb(&mut self, value: &Value) -> Result<String> {
+        if let Some(message) = http::error_message(value) {
+            return Err(TenxError::Model(message));
+        }
+        let mut text = String::new();
+        let parts = value["candidates"][0]["content"]["parts"].as_array();
+        for part in parts.into_iter().flatten() {
+            if let Some(t) = part["text"].as_str() {
+                text.push_str(t);
+            }
+            if let Some(name) = part["functionCall"]["name"].as_str() {
+                self.calls
+                    .push((name.to_string(), part["functionCall"]["args"].clone()));
+            }
+        }
+        let metadata = &value["usageMetadata"];
+        if !metadata.is_null() {
+            self.usage = GoogleUsage {
+                input_tokens: metadata["promptTokenCount"].as_u64().map(|n| n as u32),
+                output_tokens: metadata["candidatesTokenCount"].as_u64().map(|n| n as u32),
+                total_tokens: metadata["totalTokenCount"].as_u64().map(|n| n as u32),
+            };
+        }
+        self.text.push_str(&text);
+        Ok(text)
+    }
+}
+
+/// A chat with a Google model, using the editor tool.
+#[derive(Debug, Clone)]
+pub struct GoogleEditorChat {
+    /// Upstream model name to use
+    pub api_model: String,
+    /// The Google API key
+    pub api_key: String,
+    /// The base URL of the API
+    pub api_base: String,
+    /// Whether to stream responses
+    pub streaming: bool,
+    /// The system instruction
+    system: String,
+    /// The messages in the chat, as (role, text) pairs
+    messages: Vec<(String, String)>,
+}
+
+impl GoogleEditorChat {
+    /// Creates a new GoogleEditorChat configured for the given model, API key, and settings.
+    pub fn new(api_model: String, api_key: String, api_base: String, streaming: bool) -> Self {
+        Self {
+            api_model,
+            api_key,
+            api_base,
+            streaming,
+            system: editor::SYSTEM.to_string(),
+            messages: Vec::new(),
+        }
+    }
+
+    /// Helper to add or append a message with the given role.
+    fn add_message_with_role(&mut self, role: &str, text: &str) -> Result<()> {
+        match self.messages.last_mut() {
+            Some((last_role, last_text)) if last_role == role => {
+                last_text.push('\n');
+                last_text.push_str(text.trim());
+            }
+            _ => self.messages.push((role.into(), text.trim().into())),
+        }
+        Ok(())
+    }
+
+    /// The request body.
+    fn request(&self) -> Value {
+        let contents: Vec<Value> = self
+            .messages
+            .iter()
+            .map(|(role, text)| json!({"role": role, "parts": [{"text": text}]}))
+            .collect();
+        json!({
+            "systemInstruction": {"parts": [{"text": self.system}]},
+            "contents": contents,
+            "tools": [{
+                "functionDeclarations": [{
+                    "name": editor::TOOL_NAME,
+                    "description": editor::TOOL_DESCRIPTION,
+                    "parameters": editor::parameters(),
+                }]
+            }],
+        })
+    }
+
+    /// Sends the request, returning the response if it succeeded.
+    async fn post(&self) -> Result<reqwest::Response> {
+        let method = if self.streaming {
+            "streamGenerateContent?alt=sse"
+        } else {
+            "generateContent"
+        };
+        let url = format!(
+            "{}/models/{}:{method}",
+            self.api_base.trim_end_matches('/'),
+            self.api_model
+        );
+        let req = reqwest::Client::new()
+            .post(url)
+            .header("x-goog-api-key", &self.api_key);
+        http::post(API, req, self.request().to_string()).await
+    }
+
+    /// Streams a response as server-sent events, sending each piece of text as a snippet event.
+    async fn stream_response(
+        &self,
+        resp: reqwest::Response,
+        sender: &Option<EventSender>,
+    ) -> Result<Reply> {
+        let mut reply = Reply::default();
+        let mut stream = JsonStream::new(API, resp);
+        while let Some(value) = stream.next().await? {
+            let text = reply.absorb(&value)?;
+            if !text.is_empty() {
+                send_event(sender, Event::Snippet(text))?;
+            }
+        }
+        Ok(reply)
+    }
+
+    fn extract_changes(&self, reply: Reply) -> Result<ModelResponse> {
+        let comment = Some(reply.text.clone()).filter(|t| !t.trim().is_empty());
+        if comment.is_none() && reply.calls.is_empty() {
+            return Err(TenxError::Throttle(Throttle::Backoff));
+        }
+
+        let mut patch = state::Patch::default();
+        let mut raw = reply.text;
+        for (name, args) in reply.calls {
+            raw.push_str(&format!("\n{name}({args})"));
+            patch = editor::add_call(patch, &name, args)?;
+        }
+
+        Ok(ModelResponse {
+            patch: if !patch.is_empty() { Some(patch) } else { None },
+            comment,
+            usage: Some(super::Usage::Google(reply.usage)),
+            raw_response: Some(raw),
+        })
+    }
+}
+
+#[async_trait]
+impl Chat for GoogleEditorChat {
+    fn add_system_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.system = format!("{}\n{}", self.system, prompt);
+        Ok(())
+    }
+
+    fn add_user_message(&mut self, text: &str) -> Result<()> {
+        self.add_message_with_role("user", text)
+    }
+
+    fn add_agent_message(&mut self, text: &str) -> Result<()> {
+        self.add_message_with_role("model", text)
+    }
+
+    fn add_context(&mut self, ctx: &ContextItem) -> Result<()> {
+        self.add_user_message(&tags::render_context(ctx)?)
+    }
+
+    fn add_editable(&mut self, path: &str, data: &str) -> Result<()> {
+        self.add_user_message(&tags::render_editable(path, data)?)
+    }
+
+    fn add_agent_comment(&mut self, comment: &str) -> Result<()> {
+        self.add_agent_message(comment)
+    }
+
+    fn add_user_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.add_user_message(prompt)
+    }
+
+    fn add_user_check_results(&mut self, results: &[CheckResult]) -> Result<()> {
+        if !results.is_empty() {
+            let rendered = tags::render_check_results(results)?;
+            self.add_user_message(&rendered)?;
+        }
+        Ok(())
+    }
+
+    fn add_user_patch_failure(
+        &mut self,
+        patch_failures: &[crate::model::PatchFailure],
+    ) -> Result<()> {
+        if !patch_failures.is_empty() {
+            let rendered = tags::render_patch_failures(patch_failures)?;
+            self.add_user_message(&rendered)?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, sender: Option<EventSender>) -> Result<ModelResponse> {
+        if self.api_key.is_empty() {
+            return Err(TenxError::Model(
+                "No API key configured for Google model.".into(),
+            ));
+        }
+
+        trace!("Sending request: {}", self.request());
+
+        let resp = self.post().await?;
+        let reply = if self.streaming {
+            self.stream_response(resp, &sender).await?
+        } else {
+            let body = resp
+                .text()
+                .await
+                .map_err(|e| TenxError::Model(e.to_string()))?;
+            let value: Value = serde_json::from_str(&body)
+                .map_err(|e| TenxError::Model(format!("Invalid response from the API: {e}")))?;
+            let mut reply = Reply::default();
+            reply.absorb(&value)?;
+            send_event(&sender, Event::ModelResponse(reply.text.clone()))?;
+            reply
+        };
+
+        trace!("Got response: {:?}", reply);
+
+        self.extract_changes(reply)
+    }
+
+    fn render(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.request())?)
+    }
+}
+
+/// A Google model that edits files through function calls to the editor tool.
+#[derive(Default, Debug, Clone)]
+pub struct GoogleEditor {
+    pub name: String,
+    pub api_model: String,
+    pub api_key: String,
+    /// The base URL of the API. Defaults to `GOOGLE_API_BASE` if empty.
+    pub api_base: String,
+    pub streaming: bool,
+}
+
+#[async_trait]
+impl ModelProvider for GoogleEditor {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn api_model(&self) -> String {
+        self.api_model.clone()
+    }
+
+    fn system_prompt(&self) -> String {
+        editor::system_prompt()
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        Some(tokens::gemini_window(&self.api_model))
+    }
+
+    fn chat(&self) -> Option<Box<dyn Chat>> {
+        let api_base = if self.api_base.is_empty() {
+            GOOGLE_API_BASE.to_string()
+        } else {
+            self.api_base.clone()
+        };
+        Some(Box::new(GoogleEditorChat::new(
+            self.api_model.clone(),
+            self.api_key.clone(),
+            api_base,
+            self.streaming,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::mock_server::{MockResponse, MockServer};
+    use pretty_assertions::assert_eq;
+
+    fn response(parts: Value) -> Value {
+        json!({
+            "candidates": [{"content": {"role": "model", "parts": parts}}],
+            "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 5, "totalTokenCount": 15}
+        })
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let call = json!({"functionCall": {"name": "str_replace_editor", "args": {"command": "insert", "path": "a.rs", "insert_line": 1, "new_str": "fn b() {}"}}});
+        let server = MockServer::start(vec![
+            MockResponse::json(200, response(json!([{"text": "Adding b."}, call]))),
+            MockResponse::Stream(vec![
+                response(json!([{"text": "Adding "}])).to_string(),
+                response(json!([{"text": "b."}, call])).to_string(),
+            ]),
+            MockResponse::rate_limited(Some(3)),
+        ])
+        .await
+        .unwrap();
+
+        for streaming in [false, true] {
+            let mut chat =
+                GoogleEditorChat::new("gemini".into(), "key".into(), server.root_url(), streaming);
+            chat.add_editable("a.rs", "fn a() {}\n").unwrap();
+            chat.add_user_prompt("Add b").unwrap();
+            let resp = chat.send(None).await.unwrap();
+            assert_eq!(resp.comment.as_deref(), Some("Adding b."));
+            assert_eq!(
+                resp.patch,
+                Some(state::Patch::default().with_insert("a.rs", 1, "fn b() {}"))
+            );
+            assert_eq!(resp.usage.unwrap().totals(), (10, 5));
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/models/gemini:generateContent");
+        assert_eq!(
+            requests[1].path,
+            "/models/gemini:streamGenerateContent?alt=sse"
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("key"));
+        let body = requests[0].json();
+        assert_eq!(
+            body["tools"][0]["functionDeclarations"][0]["name"],
+            "str_replace_editor"
+        );
+        // The editable file and the prompt are merged into one user message
+        assert_eq!(body["contents"].as_array().unwrap().len(), 1);
+
+        let mut chat =
+            GoogleEditorChat::new("gemini".into(), "key".into(), server.root_url(), false);
+        chat.add_user_prompt("Add b").unwrap();
+        assert!(matches!(
+            chat.send(None).await,
+            Err(TenxError::Throttle(Throttle::RetryAfter(3)))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! Helpers shared by the providers that talk to model APIs over HTTP directly: posting requests,
+//! mapping error responses, and reading streamed responses.
+use serde_json::Value;
+
+use crate::{
+    error::{Result, TenxError},
+    throttle::Throttle,
+};
+
+/// Extracts the message from an error body. Most APIs report errors as an object with a message,
+/// and Ollama reports them as a string.
+pub(super) fn error_message(value: &Value) -> Option<String> {
+    match &value["error"] {
+        Value::String(message) => Some(message.clone()),
+        Value::Object(error) => error
+            .get("message")
+            .and_then(|m| m.as_str())
+            .map(String::from),
+        _ => None,
+    }
+}
+
+/// Maps an error response to an error. Rate limits and overloaded servers become throttle errors,
+/// honouring the `Retry-After` header if there is one. The `api` names the API in error messages.
+pub(super) fn map_status(
+    api: &str,
+    status: u16,
+    retry_after: Option<u64>,
+    body: &str,
+) -> TenxError {
+    if matches!(status, 429 | 503 | 529) {
+        return TenxError::Throttle(match retry_after {
+            Some(secs) => Throttle::RetryAfter(secs),
+            None => Throttle::Backoff,
+        });
+    }
+    let message = serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| error_message(&v))
+        .unwrap_or_else(|| body.to_string());
+    TenxError::Model(format!("{api} error ({status}): {message}"))
+}
+
+/// Returns the response if it succeeded, and otherwise maps it to an error.
+pub(super) async fn check(api: &str, resp: reqwest::Response) -> Result<reqwest::Response> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status().as_u16();
+    let retry_after = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let body = resp.text().await.unwrap_or_default();
+    Err(map_status(api, status, retry_after, &body))
+}
+
+/// Posts a JSON body, returning the response if it succeeded.
+pub(super) async fn post(
+    api: &str,
+    req: reqwest::RequestBuilder,
+    body: String,
+) -> Result<reqwest::Response> {
+    let resp = req
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| TenxError::Model(e.to_string()))?;
+    check(api, resp).await
+}
+
+/// Reads the JSON payloads of a streamed response one at a time. Both server-sent events, where
+/// each payload is on a `data:` line, and newline-delimited JSON, as Ollama streams, are handled.
+pub(super) struct JsonStream<'a> {
+    api: &'a str,
+    resp: reqwest::Response,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<'a> JsonStream<'a> {
+    pub(super) fn new(api: &'a str, resp: reqwest::Response) -> Self {
+        Self {
+            api,
+            resp,
+            buf: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Returns the next payload, or None at the end of the stream. Errors reported in the stream
+    /// are returned as errors.
+    pub(super) async fn next(&mut self) -> Result<Option<Value>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                if let Some(value) = self.parse(&line)? {
+                    return Ok(Some(value));
+                }
+                continue;
+            }
+            if self.done {
+                // The last line may not end with a newline
+                let line = std::mem::take(&mut self.buf);
+                return self.parse(&line);
+            }
+            match self
+                .resp
+                .chunk()
+                .await
+                .map_err(|e| TenxError::Model(e.to_string()))?
+            {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => self.done = true,
+            }
+        }
+    }
+
+    /// Parses a line of the stream. Blank lines, comments, other event fields and the `[DONE]`
+    /// marker have no payload.
+    fn parse(&self, line: &[u8]) -> Result<Option<Value>> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None if line.starts_with('{') => line,
+            None => return Ok(None),
+        };
+        if data.is_empty() || data == "[DONE]" {
+            return Ok(None);
+        }
+        let value: Value = serde_json::from_str(data).map_err(|e| {
+            TenxError::Model(format!("Invalid stream event from {}: {e}", self.api))
+        })?;
+        if let Some(message) = error_message(&value) {
+            return Err(TenxError::Model(message));
+        }
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_status() {
+        assert!(matches!(
+            map_status("Test API", 429, Some(7), ""),
+            TenxError::Throttle(Throttle::RetryAfter(7))
+        ));
+        for status in [429, 503, 529] {
+            assert!(matches!(
+                map_status("Test API", status, None, ""),
+                TenxError::Throttle(Throttle::Backoff)
+            ));
+        }
+        for (body, message) in [
+            (r#"{"error": {"message": "Bad model"}}"#, "Bad model"),
+            (r#"{"error": "model not found"}"#, "model not found"),
+            ("Internal error", "Internal error"),
+        ] {
+            match map_status("Test API", 400, None, body) {
+                TenxError::Model(msg) => {
+                    assert_eq!(msg, format!("Test API error (400): {message}"))
+                }
+                other => panic!("expected a model error, got {other:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! This module implements a provider for local inference servers: Ollama, through its native API,
+//! and the llama.cpp server, through its OpenAI-compatible endpoints. Neither needs an API key, so
+//! tenx can run on machines without network access.
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::trace;
+
+use crate::{
+    checks::CheckResult,
+    context::ContextItem,
+    error::{Result, TenxError},
+    events::{send_event, Event, EventSender},
+    model::http::{self, JsonStream},
+    model::tags,
+    model::tokens,
+    model::{Chat, ModelProvider},
+    session::ModelResponse,
+    throttle::Throttle,
+};
+
+/// The name of the API in error messages.
+const API: &str = "Local server";
+
+/// The kind of local inference server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LocalServer {
+    /// Ollama, using its native API at `/api`.
+    #[default]
+    Ollama,
+    /// The llama.cpp server, using its OpenAI-compatible API at `/v1`.
+    LlamaCpp,
+}
+
+/// A model served by a local inference server.
+#[derive(Default, Debug, Clone)]
+pub struct Local {
+    pub name: String,
+    /// The model to request. If empty, the first model the server lists is used.
+    pub api_model: String,
+    /// The root URL of the server, for example `http://localhost:11434`.
+    pub api_base: String,
+    pub server: LocalServer,
+    pub streaming: bool,
+    /// The size of the model's context window in tokens, if it's known. Ollama is asked to use a
+    /// window of this size, since its default is much smaller than most models support.
+    pub context_window: Option<usize>,
+}
+
+impl Local {
+    /// Lists the models available on the server.
+    pub async fn list_models(&self) -> Result<Vec<String>> {
+        list_models(&self.api_base, self.server).await
+    }
+}
+
+/// Usage statistics reported by a local inference server.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct LocalUsage {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+}
+
+impl LocalUsage {
+    pub fn values(&self) -> HashMap<String, u64> {
+        let mut map = HashMap::new();
+        if let Some(input_tokens) = self.input_tokens {
+            map.insert("input_tokens".to_string(), input_tokens as u64);
+        }
+        if let Some(output_tokens) = self.output_tokens {
+            map.insert("output_tokens".to_string(), output_tokens as u64);
+        }
+        map
+    }
+
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.input_tokens.unwrap_or(0) as u64,
+            self.output_tokens.unwrap_or(0) as u64,
+        )
+    }
+}
+
+/// Joins a path onto the server's root URL.
+fn endpoint(api_base: &str, path: &str) -> String {
+    format!("{}{path}", api_base.trim_end_matches('/'))
+}
+
+fn connect_error(api_base: &str, e: reqwest::Error) -> TenxError {
+    TenxError::Model(format!("Could not reach local server at {api_base}: {e}"))
+}
+
+async fn list_models(api_base: &str, server: LocalServer) -> Result<Vec<String>> {
+    let (path, list, key) = match server {
+        LocalServer::Ollama => ("/api/tags", "models", "name"),
+        LocalServer::LlamaCpp => ("/v1/models", "data", "id"),
+    };
+    let resp = reqwest::Client::new()
+        .get(endpoint(api_base, path))
+        .send()
+        .await
+        .map_err(|e| connect_error(api_base, e))?;
+    let body = http::check(API, resp)
+        .await?
+        .text()
+        .await
+        .map_err(|e| connect_error(api_base, e))?;
+    let value: Value = serde_json::from_str(&body)
+        .map_err(|e| TenxError::Model(format!("Invalid model list from local server: {e}")))?;
+    Ok(value[list]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|m| m[key].as_str().map(String::from))
+        .collect())
+}
+
+/// A single message in the chat.
+#[derive(Debug, Clone, Serialize)]
+struct Message {
+    role: String,
+    content: String,
+}
+
+/// A chat with a model served by a local inference server.
+#[derive(Debug, Clone)]
+pub struct LocalChat {
+    /// Upstream model name to use
+    pub api_model: String,
+    /// The root URL of the server
+    pub api_base: String,
+    /// The kind of server
+    pub server: LocalServer,
+    /// Whether to stream responses
+    pub streaming: bool,
+    /// The context window to ask Ollama for, if set
+    pub context_window: Option<usize>,
+    /// The messages in the chat, starting with the system prompt
+    messages: Vec<Message>,
+}
+
+impl LocalChat {
+    /// Creates a new LocalChat for the given model and server.
+    pub fn new(api_model: String, api_base: String, server: LocalServer, streaming: bool) -> Self {
+        Self {
+            api_model,
+            api_base,
+            server,
+            streaming,
+            context_window: None,
+            messages: vec![Message {
+                role: "system".into(),
+                content: tags::SYSTEM.into(),
+            }],
+        }
+    }
+
+    /// Helper to add or append a message with the given role.
+    fn add_message_with_role(&mut self, role: &str, text: &str) -> Result<()> {
+        match self.messages.last_mut() {
+            Some(last) if last.role == role => {
+                last.content = format!("{}\n{}", last.content, text.trim());
+            }
+            _ => self.messages.push(Message {
+                role: role.into(),
+                content: text.trim().into(),
+            }),
+        }
+        Ok(())
+    }
+
+    /// The request body, in the format the server expects.
+    fn request(&self, model: &str) -> Value {
+        let mut req = json!({
+            "model": model,
+            "messages": self.messages,
+            "stream": self.streaming,
+        });
+        if self.server == LocalServer::LlamaCpp && self.streaming {
+            req["stream_options"] = json!({"include_usage": true});
+        }
+        if let (LocalServer::Ollama, Some(window)) = (self.server, self.context_window) {
+            req["options"] = json!({"num_ctx": window});
+        }
+        req
+    }
+
+    /// Adds the content and usage in a response, or in one chunk of a streamed response.
+    fn absorb(&self, value: &Value, text: &mut String, usage: &mut LocalUsage) -> Result<String> {
+        if let Some(message) = http::error_message(value) {
+            return Err(TenxError::Model(message));
+        }
+        let mut content = String::new();
+        match self.server {
+            LocalServer::Ollama => {
+                if let Some(c) = value["message"]["content"].as_str() {
+                    content.push_str(c);
+                }
+                if let Some(n) = value["prompt_eval_count"].as_u64() {
+                    usage.input_tokens = Some(n as u32);
+                }
+                if let Some(n) = value["eval_count"].as_u64() {
+                    usage.output_tokens = Some(n as u32);
+                }
+            }
+            LocalServer::LlamaCpp => {
+                let field = if self.streaming { "delta" } else { "message" };
+                for choice in value["choices"].as_array().into_iter().flatten() {
+                    if let Some(c) = choice[field]["content"].as_str() {
+                        content.push_str(c);
+                    }
+                }
+                if let Some(n) = value["usage"]["prompt_tokens"].as_u64() {
+                    usage.input_tokens = Some(n as u32);
+                }
+                if let Some(n) = value["usage"]["completion_tokens"].as_u64() {
+                    usage.output_tokens = Some(n as u32);
+                }
+            }
+        }
+        text.push_str(&content);
+        Ok(content)
+    }
+}
+
+#[async_trait]
+impl Chat for LocalChat {
+    fn add_system_prompt(&mut self, prompt: &str) -> Result<()> {
+        if let Some(system) = self.messages.first_mut().filter(|m| m.role == "system") {
+            system.content = format!("{}\n{}", system.content, prompt);
+        }
+        Ok(())
+    }
+
+    fn add_user_message(&mut self, text: &str) -> Result<()> {
+        self.add_message_with_role("user", text)
+    }
+
+    fn add_agent_message(&mut self, text: &str) -> Result<()> {
+        self.add_message_with_role("assistant", text)
+    }
+
+    fn add_context(&mut self, ctx: &ContextItem) -> Result<()> {
+        self.add_user_message(&tags::render_context(ctx)?)
+    }
+
+    fn add_editable(&mut self, path: &str, data: &str) -> Result<()> {
+        self.add_user_message(&tags::render_editable(path, data)?)
+    }
+
+    fn add_agent_patch(&mut self, patch: &crate::model::Patch) -> Result<()> {
+        self.add_agent_message(&tags::render_patch(patch)?)
+    }
+
+    fn add_agent_comment(&mut self, comment: &str) -> Result<()> {
+        self.add_agent_message(&tags::render_comment(comment)?)
+    }
+
+    fn add_user_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.add_user_message(&tags::render_prompt(prompt)?)
+    }
+
+    fn add_user_check_results(&mut self, results: &[CheckResult]) -> Result<()> {
+        if !results.is_empty() {
+            let rendered = tags::render_check_results(results)?;
+            self.add_user_message(&rendered)?;
+        }
+        Ok(())
+    }
+
+    fn add_user_patch_failure(
+        &mut self,
+        patch_failures: &[crate::model::PatchFailure],
+    ) -> Result<()> {
+        if !patch_failures.is_empty() {
+            let rendered = tags::render_patch_failures(patch_failures)?;
+            self.add_user_message(&rendered)?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, sender: Option<EventSender>) -> Result<ModelResponse> {
+        let model = if self.api_model.is_empty() {
+            list_models(&self.api_base, self.server)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    TenxError::Model(format!(
+                        "No models available on local server at {}",
+                        self.api_base
+                    ))
+                })?
+        } else {
+            self.api_model.clone()
+        };
+
+        let path = match self.server {
+            LocalServer::Ollama => "/api/chat",
+            LocalServer::LlamaCpp => "/v1/chat/completions",
+        };
+        let req = self.request(&model);
+        trace!("Sending request: {}", req);
+
+        let resp = reqwest::Client::new()
+            .post(endpoint(&self.api_base, path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(req.to_string())
+            .send()
+            .await
+            .map_err(|e| connect_error(&self.api_base, e))?;
+        let resp = http::check(API, resp).await?;
+
+        let mut text = String::new();
+        let mut usage = LocalUsage::default();
+        if self.streaming {
+            // Ollama streams JSON objects one per line, and llama.cpp streams server-sent events
+            let mut stream = JsonStream::new(API, resp);
+            while let Some(value) = stream.next().await? {
+                let content = self.absorb(&value, &mut text, &mut usage)?;
+                if !content.is_empty() {
+                    send_event(&sender, Event::Snippet(content))?;
+                }
+            }
+        } else {
+            let body = resp
+                .text()
+                .await
+                .map_err(|e| connect_error(&self.api_base, e))?;
+            let value: Value = serde_json::from_str(&body).map_err(|e| {
+                TenxError::Model(format!("Invalid response from local server: {e}"))
+            })?;
+            self.absorb(&value, &mut text, &mut usage)?;
+            send_event(&sender, Event::ModelResponse(text.clone()))?;
+        }
+        trace!("Got response: {}", text);
+
+        if text.is_empty() {
+            return Err(TenxError::Throttle(Throttle::Backoff));
+        }
+        let mut modresp = tags::parse(&text)?;
+        modresp.usage = Some(super::Usage::Local(usage));
+        Ok(modresp)
+    }
+
+    fn render(&self) -> Result<String> {
+        serde_json::to_string_pretty(&self.request(&self.api_model))
+            .map_err(|e| TenxError::Internal(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl ModelProvider for Local {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn api_model(&self) -> String {
+        self.api_model.clone()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        tokens::estimate(text, tokens::LOCAL_CHARS_PER_TOKEN)
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        self.context_window
+    }
+
+    fn chat(&self) -> Option<Box<dyn Chat>> {
+        let mut chat = LocalChat::new(
+            self.api_model.clone(),
+            self.api_base.clone(),
+            self.server,
+            self.streaming,
+        );
+        chat.context_window = self.context_window;
+        Some(Box::new(chat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::Usage,
+        testutils::mock_server::{MockResponse, MockServer},
+    };
+
+    fn ollama_chunk(content: &str, done: bool) -> String {
+        let mut chunk = json!({
+            "model": "qwen",
+            "message": {"role": "assistant", "content": content},
+            "done": done,
+        });
+        if done {
+            chunk["prompt_eval_count"] = json!(12);
+            chunk["eval_count"] = json!(4);
+        }
+        chunk.to_string()
+    }
+
+    async fn send(
+        server: &MockServer,
+        kind: LocalServer,
+        api_model: &str,
+        streaming: bool,
+    ) -> Result<ModelResponse> {
+        let mut chat = LocalChat::new(api_model.into(), server.root_url(), kind, streaming);
+        chat.add_user_prompt("Say hello")?;
+        chat.send(None).await
+    }
+
+    #[tokio::test]
+    async fn test_ollama() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({"models": [{"name": "qwen:7b"}, {"name": "llama3"}]})),
+            MockResponse::Lines(vec![
+                ollama_chunk("<comment>\nHel", false),
+                ollama_chunk("lo\n</comment>", false),
+                ollama_chunk("", true),
+            ]),
+            MockResponse::json(200, json!({"message": {"role": "assistant", "content": "<comment>\nHi\n</comment>"}, "done": true, "prompt_eval_count": 3, "eval_count": 2})),
+        ])
+        .await
+        .unwrap();
+
+        let local = Local {
+            api_base: server.root_url(),
+            ..Default::default()
+        };
+        assert_eq!(
+            local.list_models().await.unwrap(),
+            vec!["qwen:7b", "llama3"]
+        );
+
+        let resp = send(&server, LocalServer::Ollama, "qwen:7b", true)
+            .await
+            .unwrap();
+        assert_eq!(resp.comment.as_deref(), Some("Hello"));
+        assert_eq!(
+            resp.usage,
+            Some(Usage::Local(LocalUsage {
+                input_tokens: Some(12),
+                output_tokens: Some(4),
+            }))
+        );
+
+        let resp = send(&server, LocalServer::Ollama, "qwen:7b", false)
+            .await
+            .unwrap();
+        assert_eq!(resp.comment.as_deref(), Some("Hi"));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/tags");
+        assert_eq!(requests[1].path, "/api/chat");
+        let body = requests[1].json();
+        assert_eq!(body["model"], "qwen:7b");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["role"], "user");
+        assert_eq!(requests[2].json()["stream"], false);
+    }
+
+    #[tokio::test]
+    async fn test_llamacpp() {
+        // With no model configured, the first model the server lists is used
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"object": "list", "data": [{"id": "model.gguf"}]}),
+            ),
+            MockResponse::chat_stream(&["<comment>\nHello\n</comment>"]),
+        ])
+        .await
+        .unwrap();
+
+        let resp = send(&server, LocalServer::LlamaCpp, "", true)
+            .await
+            .unwrap();
+        assert_eq!(resp.comment.as_deref(), Some("Hello"));
+        assert_eq!(resp.usage.unwrap().totals(), (10, 5));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/models");
+        assert_eq!(requests[1].path, "/v1/chat/completions");
+        assert_eq!(requests[1].json()["model"], "model.gguf");
+    }
+
+    #[test]
+    fn test_context_window() {
+        let mut local = Local {
+            api_model: "qwen".into(),
+            ..Default::default()
+        };
+        assert_eq!(local.context_window(), None);
+        let chat = LocalChat::new("qwen".into(), String::new(), LocalServer::Ollama, false);
+        assert!(chat.request("qwen")["options"].is_null());
+
+        // Ollama is asked for the configured window, and llama.cpp sets its window at startup
+        local.context_window = Some(32_768);
+        assert_eq!(local.context_window(), Some(32_768));
+        let mut chat = LocalChat::new("qwen".into(), String::new(), LocalServer::Ollama, false);
+        chat.context_window = local.context_window;
+        assert_eq!(chat.request("qwen")["options"]["num_ctx"], 32_768);
+        chat.server = LocalServer::LlamaCpp;
+        assert!(chat.request("qwen")["options"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                404,
+                json!({"error": "model \"missing\" not found, try pulling it first"}),
+            ),
+            MockResponse::error(503, "Loading model"),
+            MockResponse::json(200, json!({"models": []})),
+        ])
+        .await
+        .unwrap();
+
+        match send(&server, LocalServer::Ollama, "missing", false).await {
+            Err(TenxError::Model(msg)) => assert!(msg.contains("not found")),
+            other => panic!("expected a model error, got {other:?}"),
+        }
+        assert!(matches!(
+            send(&server, LocalServer::LlamaCpp, "model", false).await,
+            Err(TenxError::Throttle(Throttle::Backoff))
+        ));
+        match send(&server, LocalServer::Ollama, "", false).await {
+            Err(TenxError::Model(msg)) => assert!(msg.contains("No models available")),
+            other => panic!("expected a model error, got {other:?}"),
+        }
+    }
+}
@@ -6,9 +6,15 @@
 mod claude;
 mod claude_editor;
 mod dummy_model;
+mod editor;
 mod google;
+mod google_editor;
+mod http;
+mod local;
 mod openai;
+mod openai_editor;
 mod tags;
+pub mod tokens;
 
 use std::collections::HashMap;
 
@@ -23,11 +29,14 @@ use crate::{
     session::ModelResponse,
 };
 
-pub use claude::{Claude, ClaudeChat, ClaudeUsage};
+pub use claude::{Claude, ClaudeChat, ClaudeUsage, ANTHROPIC_API_BASE};
 pub use claude_editor::ClaudeEditor;
 pub use dummy_model::{DummyModel, DummyUsage};
 pub use google::{Google, GoogleChat, GoogleUsage};
+pub use google_editor::{GoogleEditor, GoogleEditorChat, GOOGLE_API_BASE};
+pub use local::{Local, LocalChat, LocalServer, LocalUsage};
 pub use openai::{OpenAi, OpenAiChat, OpenAiUsage, ReasoningEffort};
+pub use openai_editor::{OpenAiEditor, OpenAiEditorChat};
 
 /// A trait used to prepare a chat interaction to be sent to the model for
 /// completion.
@@ -96,6 +105,7 @@ pub enum Usage {
     OpenAi(OpenAiUsage),
     Dummy(DummyUsage),
     Google(google::GoogleUsage),
+    Local(LocalUsage),
 }
 
 impl Usage {
@@ -106,6 +116,7 @@ impl Usage {
             Usage::OpenAi(usage) => usage.values(),
             Usage::Dummy(usage) => usage.values(),
             Usage::Google(usage) => usage.values(),
+            Usage::Local(usage) => usage.values(),
         }
     }
 
@@ -116,6 +127,7 @@ impl Usage {
             Usage::OpenAi(usage) => usage.totals(),
             Usage::Dummy(usage) => usage.totals(),
             Usage::Google(usage) => usage.totals(),
+            Usage::Local(usage) => usage.totals(),
         }
     }
 }
@@ -135,6 +147,24 @@ pub trait ModelProvider {
     fn chat(&self) -> Option<Box<dyn Chat>> {
         None
     }
+
+    /// Returns the text the model sends with every request, ahead of the conversation: its system
+    /// prompt, and the definitions of any tools it offers. This is counted when estimating the
+    /// size of a request.
+    fn system_prompt(&self) -> String {
+        tags::SYSTEM.to_string()
+    }
+
+    /// Returns an estimate of the number of tokens the model will use for a piece of text.
+    fn count_tokens(&self, text: &str) -> usize {
+        tokens::estimate(text, tokens::CHARS_PER_TOKEN)
+    }
+
+    /// Returns the size of the model's context window in tokens, or `None` if it isn't known.
+    /// Requests are only checked against the window if it is known.
+    fn context_window(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// Available model implementations that can be used for AI interactions.
@@ -144,6 +174,9 @@ pub enum Model {
     Claude(Claude),
     ClaudeEditor(ClaudeEditor),
     OpenAi(OpenAi),
+    OpenAiEditor(OpenAiEditor),
     Google(google::Google),
+    GoogleEditor(GoogleEditor),
+    Local(Local),
     Dummy(DummyModel),
 }
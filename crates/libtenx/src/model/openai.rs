@@ -1,16 +1,12 @@
-use async_openai::{
-    config::OpenAIConfig,
-    types::{
-        ChatChoice, ChatCompletionRequestAssistantMessageArgs,
-        ChatCompletionRequestDeveloperMessageArgs, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageArgs, ChatCompletionResponseMessage,
-        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
-        FinishReason,
-    },
-    Client,
+use async_openai::types::{
+    ChatChoice, ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestDeveloperMessageArgs, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionResponseMessage,
+    ChatCompletionStreamOptions, ChatCompletionTool, ChatCompletionToolType,
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    FinishReason, FunctionCall,
 };
 use async_trait::async_trait;
-use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use tracing::trace;
 
@@ -19,7 +15,9 @@ use crate::{
     context::ContextItem,
     error::{Result, TenxError},
     events::{send_event, Event, EventSender},
+    model::http::{self, JsonStream},
     model::tags,
+    model::tokens,
     model::{Chat, ModelProvider},
     session::ModelResponse,
     throttle::Throttle,
@@ -27,6 +25,9 @@ use crate::{
 
 use std::collections::HashMap;
 
+/// The name of the API in error messages.
+const API: &str = "OpenAI API";
+
 #[derive(Debug, Clone)]
 pub enum ReasoningEffort {
     Low,
@@ -60,7 +61,7 @@ impl From<async_openai::error::OpenAIError> for TenxError {
         if let async_openai::error::OpenAIError::Reqwest(ref e) = e {
             if let Some(status) = e.status() {
                 if status == 429 || status == 529 {
-                    return TenxError::Throttle(crate::throttle::Throttle::Backoff);
+                    return TenxError::Throttle(Throttle::Backoff);
                 }
             }
         }
@@ -68,6 +69,17 @@ impl From<async_openai::error::OpenAIError> for TenxError {
     }
 }
 
+/// Converts the usage reported in a response.
+pub(super) fn usage(resp: &CreateChatCompletionResponse) -> Option<super::Usage> {
+    resp.usage.as_ref().map(|usage| {
+        super::Usage::OpenAi(OpenAiUsage {
+            prompt_tokens: Some(usage.prompt_tokens),
+            completion_tokens: Some(usage.completion_tokens),
+            total_tokens: Some(usage.total_tokens),
+        })
+    })
+}
+
 impl OpenAiUsage {
     pub fn values(&self) -> HashMap<String, u64> {
         let mut map = HashMap::new();
@@ -191,38 +203,98 @@ impl OpenAiChat {
         Ok(())
     }
 
-    async fn stream_response(
+    /// Posts a request to the chat completions endpoint, returning the response if it succeeded.
+    async fn post(&self, req: &CreateChatCompletionRequest) -> Result<reqwest::Response> {
+        let url = format!("{}/chat/completions", self.api_base.trim_end_matches('/'));
+        let body = serde_json::to_string(req).map_err(|e| TenxError::Internal(e.to_string()))?;
+        let req = reqwest::Client::new()
+            .post(url)
+            .bearer_auth(&self.openai_key);
+        http::post(API, req, body).await
+    }
+
+    async fn complete_response(
         &self,
         sender: Option<EventSender>,
     ) -> Result<CreateChatCompletionResponse> {
-        let openai_config = OpenAIConfig::new()
-            .with_api_key(self.openai_key.clone())
-            .with_api_base(&self.api_base);
-        let client = Client::with_config(openai_config);
+        let text = self
+            .post(&self.request)
+            .await?
+            .text()
+            .await
+            .map_err(|e| TenxError::Model(e.to_string()))?;
+        let resp: CreateChatCompletionResponse = serde_json::from_str(&text)
+            .map_err(|e| TenxError::Model(format!("Invalid response from the API: {e}")))?;
+        if let Some(content) = resp
+            .choices
+            .first()
+            .and_then(|c| c.message.content.as_ref())
+        {
+            send_event(&sender, Event::ModelResponse(content.to_string()))?;
+        }
+        Ok(resp)
+    }
 
+    /// Streams a response as server-sent events, sending each piece of content as a snippet
+    /// event, and assembles the full response.
+    // The response message has a deprecated field that must still be set
+    #[allow(deprecated)]
+    async fn stream_response(
+        &self,
+        sender: Option<EventSender>,
+    ) -> Result<CreateChatCompletionResponse> {
         let mut req = self.request.clone();
         req.stream = Some(true);
+        req.stream_options = Some(ChatCompletionStreamOptions {
+            include_usage: true,
+        });
 
-        let mut stream = client.chat().create_stream(req).await?;
+        let mut stream = JsonStream::new(API, self.post(&req).await?);
         let mut full_response = String::new();
-
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(response) => {
-                    for choice in response.choices {
-                        if let Some(content) = &choice.delta.content {
-                            full_response.push_str(content);
-                            send_event(&sender, Event::Snippet(content.to_string()))?;
-                        }
-                    }
+        let mut usage = None;
+        // Tool calls arrive in pieces, keyed by their index
+        let mut tool_calls: Vec<ChatCompletionMessageToolCall> = Vec::new();
+
+        while let Some(value) = stream.next().await? {
+            for choice in value["choices"].as_array().into_iter().flatten() {
+                if let Some(content) = choice["delta"]["content"].as_str() {
+                    full_response.push_str(content);
+                    send_event(&sender, Event::Snippet(content.to_string()))?;
                 }
-                Err(err) => {
-                    return Err(err.into());
+                for delta in choice["delta"]["tool_calls"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                {
+                    let index = delta["index"].as_u64().unwrap_or(0) as usize;
+                    if tool_calls.len() <= index {
+                        tool_calls.resize_with(index + 1, || ChatCompletionMessageToolCall {
+                            id: String::new(),
+                            r#type: ChatCompletionToolType::Function,
+                            function: FunctionCall {
+                                name: String::new(),
+                                arguments: String::new(),
+                            },
+                        });
+                    }
+                    let call = &mut tool_calls[index];
+                    if let Some(id) = delta["id"].as_str() {
+                        call.id.push_str(id);
+                    }
+                    if let Some(name) = delta["function"]["name"].as_str() {
+                        call.function.name.push_str(name);
+                    }
+                    if let Some(arguments) = delta["function"]["arguments"].as_str() {
+                        call.function.arguments.push_str(arguments);
+                    }
                 }
             }
+            if !value["usage"].is_null() {
+                usage = serde_json::from_value(value["usage"].clone()).ok();
+            }
         }
 
-        #[allow(deprecated)]
+        let has_tool_calls = !tool_calls.is_empty();
         Ok(CreateChatCompletionResponse {
             id: "stream".to_string(),
             object: "chat.completion".to_string(),
@@ -235,18 +307,75 @@ impl OpenAiChat {
                 message: ChatCompletionResponseMessage {
                     role: async_openai::types::Role::Assistant,
                     content: Some(full_response),
-                    tool_calls: None,
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
                     refusal: None,
                     function_call: None,
                     audio: None,
                 },
-                finish_reason: Some(FinishReason::Stop),
+                finish_reason: Some(if has_tool_calls {
+                    FinishReason::ToolCalls
+                } else {
+                    FinishReason::Stop
+                }),
                 logprobs: None,
             }],
-            usage: None,
+            usage,
         })
     }
 
+    /// Sends the request, storing and returning the response.
+    pub(super) async fn fetch(
+        &mut self,
+        sender: Option<EventSender>,
+    ) -> Result<CreateChatCompletionResponse> {
+        if self.openai_key.is_empty() {
+            return Err(TenxError::Model("No OpenAI key configured.".into()));
+        }
+        if self.api_model.is_empty() {
+            return Err(TenxError::Model("Empty API model name".into()));
+        }
+
+        self.request.model = self.api_model.clone();
+        if let Some(ref re) = self.reasoning_effort {
+            self.request.reasoning_effort = Some(match re {
+                ReasoningEffort::Low => async_openai::types::ReasoningEffort::Low,
+                ReasoningEffort::Medium => async_openai::types::ReasoningEffort::Medium,
+                ReasoningEffort::High => async_openai::types::ReasoningEffort::High,
+            });
+        }
+
+        trace!("Sending request: {:?}", self.request);
+
+        let resp = if self.streaming {
+            self.stream_response(sender.clone()).await?
+        } else {
+            self.complete_response(sender.clone()).await?
+        };
+
+        trace!("Got response: {:?}", resp);
+
+        // Store response for future reference
+        if let Some(choice) = resp.choices.first() {
+            self.response = Some(choice.message.clone());
+        }
+
+        Ok(resp)
+    }
+
+    /// Replaces the system prompt, and offers the model the given tools. Used by editor models,
+    /// which make changes through tool calls rather than the tags dialect.
+    pub(super) fn use_tools(&mut self, system: &str, tools: Vec<ChatCompletionTool>) -> Result<()> {
+        self.request.messages.clear();
+        self.add_system_prompt(system)?;
+        self.request.tools = Some(tools);
+        Ok(())
+    }
+
+    /// The last response from the model.
+    pub(super) fn response(&self) -> Option<&ChatCompletionResponseMessage> {
+        self.response.as_ref()
+    }
+
     fn extract_changes(&self) -> Result<ModelResponse> {
         if let Some(response) = &self.response {
             if let Some(content) = &response.content {
@@ -331,55 +460,9 @@ impl Chat for OpenAiChat {
     }
 
     async fn send(&mut self, sender: Option<EventSender>) -> Result<ModelResponse> {
-        if self.openai_key.is_empty() {
-            return Err(TenxError::Model("No OpenAI key configured.".into()));
-        }
-        if self.api_model.is_empty() {
-            return Err(TenxError::Model("Empty API model name".into()));
-        }
-
-        self.request.model = self.api_model.clone();
-        if let Some(ref re) = self.reasoning_effort {
-            self.request.reasoning_effort = Some(match re {
-                ReasoningEffort::Low => async_openai::types::ReasoningEffort::Low,
-                ReasoningEffort::Medium => async_openai::types::ReasoningEffort::Medium,
-                ReasoningEffort::High => async_openai::types::ReasoningEffort::High,
-            });
-        }
-
-        trace!("Sending request: {:?}", self.request);
-
-        let resp = if self.streaming {
-            self.stream_response(sender.clone()).await?
-        } else {
-            let openai_config = OpenAIConfig::new()
-                .with_api_key(self.openai_key.clone())
-                .with_api_base(&self.api_base);
-            let client = Client::with_config(openai_config);
-
-            let resp = client.chat().create(self.request.clone()).await?;
-            if let Some(content) = resp.choices[0].message.content.as_ref() {
-                send_event(&sender, Event::ModelResponse(content.to_string()))?;
-            }
-            resp
-        };
-
-        trace!("Got response: {:?}", resp);
-
-        // Store response for future reference
-        if let Some(choice) = resp.choices.first() {
-            self.response = Some(choice.message.clone());
-        }
-
+        let resp = self.fetch(sender).await?;
         let mut modresp = self.extract_changes()?;
-
-        if let Some(usage) = resp.usage {
-            modresp.usage = Some(super::Usage::OpenAi(OpenAiUsage {
-                prompt_tokens: Some(usage.prompt_tokens),
-                completion_tokens: Some(usage.completion_tokens),
-                total_tokens: Some(usage.total_tokens),
-            }));
-        }
+        modresp.usage = usage(&resp);
 
         Ok(modresp)
     }
@@ -399,6 +482,10 @@ impl ModelProvider for OpenAi {
         self.api_model.clone()
     }
 
+    fn context_window(&self) -> Option<usize> {
+        Some(tokens::openai_window(&self.api_model))
+    }
+
     fn chat(&self) -> Option<Box<dyn Chat>> {
         match OpenAiChat::new(
             self.api_model.clone(),
@@ -413,3 +500,88 @@ impl ModelProvider for OpenAi {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::mock_server::{MockResponse, MockServer};
+
+    fn chat(server: &MockServer, streaming: bool) -> OpenAiChat {
+        let mut chat = OpenAiChat::new(
+            "mock-model".to_string(),
+            "key".to_string(),
+            server.url(),
+            streaming,
+            false,
+            None,
+        )
+        .unwrap();
+        chat.add_user_prompt("Say hello").unwrap();
+        chat
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        for streaming in [false, true] {
+            let response = if streaming {
+                MockResponse::chat_stream(&["<comment>\nHel", "lo\n</comment>"])
+            } else {
+                MockResponse::chat("<comment>\nHello\n</comment>")
+            };
+            let server = MockServer::start(vec![response]).await.unwrap();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+
+            let resp = chat(&server, streaming).send(Some(tx)).await.unwrap();
+            assert_eq!(resp.comment.as_deref(), Some("Hello"));
+            let Some(crate::model::Usage::OpenAi(usage)) = resp.usage else {
+                panic!("expected OpenAI usage");
+            };
+            assert_eq!(usage.prompt_tokens, Some(10));
+            assert_eq!(usage.completion_tokens, Some(5));
+
+            let mut snippets = 0;
+            while let Ok(event) = rx.try_recv() {
+                if let Event::Snippet(_) = event {
+                    snippets += 1;
+                }
+            }
+            assert_eq!(snippets, if streaming { 2 } else { 0 });
+
+            let requests = server.requests();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].method, "POST");
+            assert_eq!(requests[0].path, "/v1/chat/completions");
+            assert_eq!(requests[0].header("authorization"), Some("Bearer key"));
+            let body = requests[0].json();
+            assert_eq!(body["model"], "mock-model");
+            assert_eq!(body["stream"].as_bool().unwrap_or(false), streaming);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::error(400, "Bad model"),
+            MockResponse::rate_limited(Some(7)),
+            MockResponse::rate_limited(None),
+        ])
+        .await
+        .unwrap();
+
+        match chat(&server, false).send(None).await {
+            Err(TenxError::Model(msg)) => {
+                assert!(msg.contains("400"));
+                assert!(msg.contains("Bad model"));
+            }
+            other => panic!("expected a model error, got {other:?}"),
+        }
+        assert!(matches!(
+            chat(&server, true).send(None).await,
+            Err(TenxError::Throttle(Throttle::RetryAfter(7)))
+        ));
+        assert!(matches!(
+            chat(&server, false).send(None).await,
+            Err(TenxError::Throttle(Throttle::Backoff))
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+//! This module implements an OpenAI model provider that edits files through function calls to the
+//! editor tool, rather than the tags dialect.
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
+use async_trait::async_trait;
+
+use super::{editor, openai::OpenAiChat, tokens, Chat, ModelProvider, ReasoningEffort};
+use crate::{
+    checks::CheckResult,
+    context::ContextItem,
+    error::{Result, TenxError},
+    events::EventSender,
+    session::ModelResponse,
+    throttle::Throttle,
+};
+
+/// A chat implementation for OpenAI models with the editor tool. Requests are sent through an
+/// `OpenAiChat`, so both share the same transport.
+#[derive(Debug, Clone)]
+pub struct OpenAiEditorChat {
+    chat: OpenAiChat,
+}
+
+impl OpenAiEditorChat {
+    /// Creates a new OpenAiEditorChat configured for the given model, API key, and settings.
+    pub fn new(
+        api_model: String,
+        openai_key: String,
+        api_base: String,
+        streaming: bool,
+        no_system_prompt: bool,
+        reasoning_effort: Option<ReasoningEffort>,
+    ) -> Result<Self> {
+        let mut chat = OpenAiChat::new(
+            api_model,
+            openai_key,
+            api_base,
+            streaming,
+            no_system_prompt,
+            reasoning_effort,
+        )?;
+        chat.use_tools(
+            editor::SYSTEM,
+            vec![ChatCompletionTool {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionObject {
+                    name: editor::TOOL_NAME.into(),
+                    description: Some(editor::TOOL_DESCRIPTION.into()),
+                    parameters: Some(editor::parameters()),
+                    strict: None,
+                },
+            }],
+        )?;
+        Ok(Self { chat })
+    }
+
+    fn extract_changes(&self) -> Result<ModelResponse> {
+        let Some(message) = self.chat.response() else {
+            return Err(TenxError::Internal("No response to parse.".into()));
+        };
+        let comment = message.content.clone().filter(|c| !c.trim().is_empty());
+        let tool_calls = message.tool_calls.clone().unwrap_or_default();
+        if comment.is_none() && tool_calls.is_empty() {
+            return Err(TenxError::Throttle(Throttle::Backoff));
+        }
+
+        let mut patch = state::Patch::default();
+        let mut raw = comment.clone().unwrap_or_default();
+        for call in tool_calls {
+            let arguments = serde_json::from_str(&call.function.arguments).map_err(|e| {
+                TenxError::ResponseParse {
+                    user: "Failed to parse tool call".into(),
+                    model: format!("Tool call arguments are not valid JSON: {e}"),
+                }
+            })?;
+            patch = editor::add_call(patch, &call.function.name, arguments)?;
+            raw.push_str(&format!(
+                "\n{}({})",
+                call.function.name, call.function.arguments
+            ));
+        }
+
+        Ok(ModelResponse {
+            patch: if !patch.is_empty() { Some(patch) } else { None },
+            comment,
+            usage: None,
+            raw_response: Some(raw),
+        })
+    }
+}
+
+#[async_trait]
+impl Chat for OpenAiEditorChat {
+    fn add_system_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.chat.add_system_prompt(prompt)
+    }
+
+    fn add_user_message(&mut self, text: &str) -> Result<()> {
+        self.chat.add_user_message(text)
+    }
+
+    fn add_agent_message(&mut self, text: &str) -> Result<()> {
+        self.chat.add_agent_message(text)
+    }
+
+    fn add_context(&mut self, ctx: &ContextItem) -> Result<()> {
+        self.chat.add_context(ctx)
+    }
+
+    fn add_editable(&mut self, path: &str, data: &str) -> Result<()> {
+        self.chat.add_editable(path, data)
+    }
+
+    fn add_agent_comment(&mut self, comment: &str) -> Result<()> {
+        self.add_agent_message(comment)
+    }
+
+    fn add_user_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.add_user_message(prompt)
+    }
+
+    fn add_user_check_results(&mut self, results: &[CheckResult]) -> Result<()> {
+        self.chat.add_user_check_results(results)
+    }
+
+    fn add_user_patch_failure(
+        &mut self,
+        patch_failures: &[crate::model::PatchFailure],
+    ) -> Result<()> {
+        self.chat.add_user_patch_failure(patch_failures)
+    }
+
+    async fn send(&mut self, sender: Option<EventSender>) -> Result<ModelResponse> {
+        let resp = self.chat.fetch(sender).await?;
+        let mut modresp = self.extract_changes()?;
+        modresp.usage = super::openai::usage(&resp);
+        Ok(modresp)
+    }
+
+    fn render(&self) -> Result<String> {
+        self.chat.render()
+    }
+}
+
+/// An OpenAI model that edits files through function calls to the editor tool.
+#[derive(Default, Debug, Clone)]
+pub struct OpenAiEditor {
+    pub name: String,
+    pub api_model: String,
+    pub openai_key: String,
+    pub api_base: String,
+    pub streaming: bool,
+    pub no_system_prompt: bool,
+    /// For OpenAI o1 and o3 models only.
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[async_trait]
+impl ModelProvider for OpenAiEditor {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn api_model(&self) -> String {
+        self.api_model.clone()
+    }
+
+    fn system_prompt(&self) -> String {
+        editor::system_prompt()
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        Some(tokens::openai_window(&self.api_model))
+    }
+
+    fn chat(&self) -> Option<Box<dyn Chat>> {
+        match OpenAiEditorChat::new(
+            self.api_model.clone(),
+            self.openai_key.clone(),
+            self.api_base.clone(),
+            self.streaming,
+            self.no_system_prompt,
+            self.reasoning_effort.clone(),
+        ) {
+            Ok(chat) => Some(Box::new(chat)),
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::mock_server::{MockResponse, MockServer};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn chat(server: &MockServer, streaming: bool) -> OpenAiEditorChat {
+        let mut chat = OpenAiEditorChat::new(
+            "mock-model".to_string(),
+            "key".to_string(),
+            server.url(),
+            streaming,
+            false,
+            None,
+        )
+        .unwrap();
+        chat.add_editable("a.rs", "fn a() {}\n").unwrap();
+        chat.add_user_prompt("Rename a to b").unwrap();
+        chat
+    }
+
+    fn expected() -> state::Patch {
+        state::Patch::default()
+            .with_replace("a.rs", "fn a", "fn b")
+            .with_view("a.rs")
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let arguments = [
+            json!({"command": "str_replace", "path": "a.rs", "old_str": "fn a", "new_str": "fn b"})
+                .to_string(),
+            json!({"command": "view", "path": "a.rs"}).to_string(),
+        ];
+        let tool_calls: Vec<_> = arguments
+            .iter()
+            .enumerate()
+            .map(|(i, args)| {
+                json!({"id": format!("call_{i}"), "type": "function", "function": {"name": "str_replace_editor", "arguments": args}})
+            })
+            .collect();
+        let complete = MockResponse::json(
+            200,
+            json!({
+                "id": "mock",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "mock",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Renaming.", "tool_calls": tool_calls},
+                    "finish_reason": "tool_calls",
+                    "logprobs": null
+                }],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+            }),
+        );
+
+        // Streamed tool calls arrive in pieces, which have to be reassembled
+        let chunk = |delta: serde_json::Value| {
+            json!({
+                "id": "mock",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "mock",
+                "choices": [{"index": 0, "delta": delta, "finish_reason": null}]
+            })
+            .to_string()
+        };
+        let (first, rest) = arguments[0].split_at(10);
+        let streamed = MockResponse::Stream(vec![
+            chunk(json!({"content": "Renaming."})),
+            chunk(
+                json!({"tool_calls": [{"index": 0, "id": "call_0", "type": "function", "function": {"name": "str_replace_editor", "arguments": first}}]}),
+            ),
+            chunk(json!({"tool_calls": [{"index": 0, "function": {"arguments": rest}}]})),
+            chunk(
+                json!({"tool_calls": [{"index": 1, "id": "call_1", "type": "function", "function": {"name": "str_replace_editor", "arguments": arguments[1]}}]}),
+            ),
+        ]);
+
+        let server = MockServer::start(vec![complete, streamed]).await.unwrap();
+        for streaming in [false, true] {
+            let resp = chat(&server, streaming).send(None).await.unwrap();
+            assert_eq!(resp.comment.as_deref(), Some("Renaming."));
+            assert_eq!(resp.patch, Some(expected()));
+        }
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["tools"][0]["function"]["name"], "str_replace_editor");
+        assert_eq!(body["messages"][0]["content"], editor::SYSTEM);
+    }
+}
@@ -9,7 +9,10 @@ use crate::{
     session::ModelResponse,
 };
 
-use state::{Operation, Patch, PatchFailure, ReplaceFuzzy, WriteFile};
+use state::{
+    Append, Operation, Patch, PatchFailure, Prepend, Replace, ReplaceAll, ReplaceFuzzy,
+    ReplaceLines, RustItem, UnifiedDiff, WriteFile,
+};
 
 pub const SYSTEM: &str = include_str!("./tags-system.txt");
 
@@ -24,6 +27,10 @@ pub const SYSTEM: &str = include_str!("./tags-system.txt");
 /// </write_file>
 /// ```
 ///
+/// `<create>`, `<append>` and `<prepend>` tags, which take a path and content like
+/// `<write_file>`. A create fails if the file exists, and append and prepend add content to the
+/// end or start of an existing file.
+///
 /// `<replace>` tag for file replace:
 /// ```xml
 /// <replace path="/path/to/file.txt">
@@ -32,6 +39,52 @@ pub const SYSTEM: &str = include_str!("./tags-system.txt");
 /// </replace>
 /// ```
 ///
+/// `<replace_all>` tag, which replaces every occurrence of the old text. With `regex="true"` the
+/// old text is a regular expression, and with `count="N"` the replace fails unless there are
+/// exactly N matches:
+/// ```xml
+/// <replace_all path="/path/to/file.txt" count="2">
+///     <old>Old content goes here</old>
+///     <new>New content goes here</new>
+/// </replace_all>
+/// ```
+///
+/// `<replace_lines>` tag, which replaces a one-based, inclusive range of lines. Without an `end`,
+/// the range runs to the end of the file. An optional `<old>` block guards against stale line
+/// numbers:
+/// ```xml
+/// <replace_lines path="/path/to/file.txt" start="3" end="4">
+///     <old>Current content of lines 3 and 4</old>
+///     <new>New content goes here</new>
+/// </replace_lines>
+/// ```
+///
+/// `<rust_item>` tag, which replaces or inserts a Rust item by its path:
+/// ```xml
+/// <rust_item path="src/lib.rs" item="Config::new">
+///     pub fn new() -> Self { Self {} }
+/// </rust_item>
+/// ```
+///
+/// `<udiff>` tag, which applies a unified diff to a file:
+/// ```xml
+/// <udiff path="/path/to/file.txt">
+/// @@ -1,1 +1,1 @@
+/// -old line
+/// +new line
+/// </udiff>
+/// ```
+///
+/// `<delete>` tag, which deletes the files listed one per line, and `<rename>` tag, which moves a
+/// file to a path that doesn't exist yet:
+/// ```xml
+/// <delete>
+/// src/old.rs
+/// </delete>
+/// <rename path="src/a.rs" to="src/b.rs">
+/// </rename>
+/// ```
+///
 /// The function parses these tags and populates an `Patch` struct with
 /// `WriteFile` entries for `<write_file>` tags and `Replace` entries for `<replace>` tags.
 /// Whitespace is trimmed from the content of all tags. Any text outside of recognized tags is
@@ -61,6 +114,23 @@ pub fn parse(response: &str) -> Result<ModelResponse> {
                         content: content.join("\n"),
                     }));
                 }
+                name @ ("create" | "append" | "prepend") => {
+                    let path = tag
+                        .attributes
+                        .get("path")
+                        .ok_or_else(|| TenxError::ResponseParse {
+                            user: "Failed to parse model response".into(),
+                            model: format!("Missing path attribute in {name} tag. Line: '{line}'",),
+                        })?
+                        .into();
+                    let (_, content) = xmlish::parse_block(name, &mut lines)?;
+                    let content = content.join("\n");
+                    patch.ops.push(match name {
+                        "create" => Operation::Create(WriteFile { path, content }),
+                        "append" => Operation::Append(Append { path, content }),
+                        _ => Operation::Prepend(Prepend { path, content }),
+                    });
+                }
                 "replace" => {
                     let path =
                         tag.attributes
@@ -82,6 +152,148 @@ pub fn parse(response: &str) -> Result<ModelResponse> {
                         new: new.join("\n"),
                     }));
                 }
+                "replace_all" => {
+                    let parse_err = |msg: String| TenxError::ResponseParse {
+                        user: "Failed to parse model response".into(),
+                        model: format!("{msg}. Line: '{line}'"),
+                    };
+                    let path = tag
+                        .attributes
+                        .get("path")
+                        .ok_or_else(|| {
+                            parse_err("Missing path attribute in replace_all tag".into())
+                        })?
+                        .into();
+                    let regex = tag.attributes.get("regex").is_some_and(|v| v == "true");
+                    let count = tag
+                        .attributes
+                        .get("count")
+                        .map(|c| {
+                            c.parse::<usize>().map_err(|_| {
+                                parse_err(format!(
+                                    "Invalid count attribute in replace_all tag: {c}"
+                                ))
+                            })
+                        })
+                        .transpose()?;
+                    let (_, replace_content) = xmlish::parse_block("replace_all", &mut lines)?;
+                    let mut replace_lines = replace_content.into_iter().peekable();
+                    let (_, old) = xmlish::parse_block("old", &mut replace_lines)?;
+                    let (_, new) = xmlish::parse_block("new", &mut replace_lines)?;
+                    patch.ops.push(Operation::ReplaceAll(ReplaceAll {
+                        path,
+                        pattern: old.join("\n"),
+                        new: new.join("\n"),
+                        regex,
+                        count,
+                    }));
+                }
+                "replace_lines" => {
+                    let parse_err = |msg: String| TenxError::ResponseParse {
+                        user: "Failed to parse model response".into(),
+                        model: format!("{msg}. Line: '{line}'"),
+                    };
+                    let attr = |name: &str| {
+                        tag.attributes.get(name).ok_or_else(|| {
+                            parse_err(format!("Missing {name} attribute in replace_lines tag"))
+                        })
+                    };
+                    let number = |name: &str| -> Result<usize> {
+                        let v = attr(name)?;
+                        v.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| {
+                            parse_err(format!(
+                                "Invalid {name} attribute in replace_lines tag: {v}"
+                            ))
+                        })
+                    };
+                    let path = attr("path")?.into();
+                    let start = number("start")?;
+                    let end = match tag.attributes.get("end") {
+                        Some(_) => Some(number("end")?),
+                        None => None,
+                    };
+                    let (_, content) = xmlish::parse_block("replace_lines", &mut lines)?;
+                    let mut content = content.into_iter().peekable();
+                    let expected = match content.peek().and_then(|l| xmlish::parse_open(l)) {
+                        Some(t) if t.name == "old" => {
+                            Some(xmlish::parse_block("old", &mut content)?.1.join("\n"))
+                        }
+                        _ => None,
+                    };
+                    let (_, new) = xmlish::parse_block("new", &mut content)?;
+                    patch.ops.push(Operation::ReplaceLines(ReplaceLines {
+                        path,
+                        start: start - 1,
+                        end,
+                        new: new.join("\n"),
+                        expected,
+                    }));
+                }
+                "rust_item" => {
+                    let parse_err = |attr: &str| TenxError::ResponseParse {
+                        user: "Failed to parse model response".into(),
+                        model: format!("Missing {attr} attribute in rust_item tag. Line: '{line}'"),
+                    };
+                    let path = tag
+                        .attributes
+                        .get("path")
+                        .ok_or_else(|| parse_err("path"))?
+                        .into();
+                    let item = tag
+                        .attributes
+                        .get("item")
+                        .ok_or_else(|| parse_err("item"))?
+                        .clone();
+                    let (_, content) = xmlish::parse_block("rust_item", &mut lines)?;
+                    patch.ops.push(Operation::RustItem(RustItem {
+                        path,
+                        item,
+                        new: content.join("\n"),
+                    }));
+                }
+                "udiff" => {
+                    let path = tag
+                        .attributes
+                        .get("path")
+                        .ok_or_else(|| TenxError::ResponseParse {
+                            user: "Failed to parse model response".into(),
+                            model: format!("Missing path attribute in udiff tag. Line: '{line}'"),
+                        })?
+                        .into();
+                    let (_, content) = xmlish::parse_block("udiff", &mut lines)?;
+                    let mut diff = content.join("\n");
+                    diff.push('\n');
+                    patch
+                        .ops
+                        .push(Operation::UnifiedDiff(UnifiedDiff { path, diff }));
+                }
+                "delete" => {
+                    let (_, content) = xmlish::parse_block("delete", &mut lines)?;
+                    for line in content {
+                        let path = line.trim();
+                        if !path.is_empty() {
+                            patch.ops.push(Operation::Delete(path.into()));
+                        }
+                    }
+                }
+                "rename" => {
+                    let parse_err = |attr: &str| TenxError::ResponseParse {
+                        user: "Failed to parse model response".into(),
+                        model: format!("Missing {attr} attribute in rename tag. Line: '{line}'"),
+                    };
+                    let from = tag
+                        .attributes
+                        .get("path")
+                        .ok_or_else(|| parse_err("path"))?
+                        .into();
+                    let to = tag
+                        .attributes
+                        .get("to")
+                        .ok_or_else(|| parse_err("to"))?
+                        .into();
+                    xmlish::parse_block("rename", &mut lines)?;
+                    patch.ops.push(Operation::Rename(from, to));
+                }
                 "comment" => {
                     let (_, content) = xmlish::parse_block("comment", &mut lines)?;
                     comment = Some(content.join("\n"));
@@ -132,6 +344,9 @@ pub fn render_context(ctx: &ContextItem) -> Result<String> {
     ))
 }
 
+/// Renders a patch as the tags a model would have produced for it. Exact replaces are rendered
+/// as fuzzy replaces, and range views as whole-file edits. Operations with no tag, like inserts
+/// and undos, are an error.
 pub fn render_patch(patch: &Patch) -> Result<String> {
     let mut rendered = String::new();
     for change in &patch.ops {
@@ -144,21 +359,108 @@ pub fn render_patch(patch: &Patch) -> Result<String> {
                     &write_file.content,
                 ));
             }
-            Operation::ReplaceFuzzy(replace) => {
-                let path_str = replace.path.display().to_string();
+            Operation::Create(write_file) => {
+                let path_str = write_file.path.display().to_string();
+                rendered.push_str(&tag(
+                    "create",
+                    [("path", path_str.as_str())],
+                    &write_file.content,
+                ));
+            }
+            Operation::Append(append) => {
+                let path_str = append.path.display().to_string();
+                rendered.push_str(&tag(
+                    "append",
+                    [("path", path_str.as_str())],
+                    &append.content,
+                ));
+            }
+            Operation::Prepend(prepend) => {
+                let path_str = prepend.path.display().to_string();
+                rendered.push_str(&tag(
+                    "prepend",
+                    [("path", path_str.as_str())],
+                    &prepend.content,
+                ));
+            }
+            Operation::ReplaceFuzzy(ReplaceFuzzy { path, old, new })
+            | Operation::Replace(Replace { path, old, new }) => {
+                let path_str = path.display().to_string();
 
-                let old_tag = tag("old", [], &replace.old);
-                let new_tag = tag("new", [], &replace.new);
+                let old_tag = tag("old", [], old);
+                let new_tag = tag("new", [], new);
                 let body = format!("{}{}", old_tag.trim_end(), new_tag);
 
                 rendered.push_str(&tag("replace", [("path", path_str.as_str())], &body));
             }
-            Operation::View(v) => {
+            Operation::ReplaceAll(replace) => {
+                let path_str = replace.path.display().to_string();
+                let count = replace.count.map(|c| c.to_string());
+
+                let mut attrs = vec![("path", path_str.as_str())];
+                if replace.regex {
+                    attrs.push(("regex", "true"));
+                }
+                if let Some(count) = &count {
+                    attrs.push(("count", count.as_str()));
+                }
+
+                let old_tag = tag("old", [], &replace.pattern);
+                let new_tag = tag("new", [], &replace.new);
+                let body = format!("{}\n{}", old_tag.trim_end(), new_tag);
+
+                rendered.push_str(&tag("replace_all", attrs, &body));
+            }
+            Operation::ReplaceLines(replace) => {
+                let path_str = replace.path.display().to_string();
+                let start = (replace.start + 1).to_string();
+                let end = replace.end.map(|e| e.to_string());
+                let mut attrs = vec![("path", path_str.as_str()), ("start", start.as_str())];
+                if let Some(end) = &end {
+                    attrs.push(("end", end.as_str()));
+                }
+                let mut body = String::new();
+                if let Some(expected) = &replace.expected {
+                    body.push_str(tag("old", [], expected).trim_end());
+                    body.push('\n');
+                }
+                body.push_str(&tag("new", [], &replace.new));
+                rendered.push_str(&tag("replace_lines", attrs, &body));
+            }
+            Operation::RustItem(item) => {
+                let path_str = item.path.display().to_string();
+                rendered.push_str(&tag(
+                    "rust_item",
+                    [("path", path_str.as_str()), ("item", item.item.as_str())],
+                    &item.new,
+                ));
+            }
+            Operation::UnifiedDiff(diff) => {
+                let path_str = diff.path.display().to_string();
+                rendered.push_str(&tag("udiff", [("path", path_str.as_str())], &diff.diff));
+            }
+            Operation::View(v) | Operation::ViewRange(v, _, _) => {
                 let path_str = v.display().to_string();
                 rendered.push_str(&tag("edit", [], &path_str));
             }
-            _ => {
-                panic!("unsupported change type: {change:?}");
+            Operation::Delete(path) => {
+                let path_str = path.display().to_string();
+                rendered.push_str(&tag("delete", [], &path_str));
+            }
+            Operation::Rename(from, to) => {
+                let from_str = from.display().to_string();
+                let to_str = to.display().to_string();
+                rendered.push_str(&tag(
+                    "rename",
+                    [("path", from_str.as_str()), ("to", to_str.as_str())],
+                    "",
+                ));
+            }
+            Operation::Insert(_) | Operation::Undo(_) => {
+                return Err(TenxError::Internal(format!(
+                    "The tags dialect can't render a {} operation",
+                    change.name()
+                )));
             }
         }
     }
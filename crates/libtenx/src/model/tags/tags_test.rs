@@ -3,7 +3,10 @@ use std::path::PathBuf;
 use indoc::indoc;
 use pretty_assertions::assert_eq;
 
-use state::{Operation, Patch, ReplaceFuzzy, WriteFile};
+use state::{
+    Append, Operation, Patch, Prepend, ReplaceAll, ReplaceFuzzy, ReplaceLines, RustItem,
+    UnifiedDiff, WriteFile,
+};
 
 use crate::{
     error::Result,
@@ -56,6 +59,223 @@ fn test_parse_response_basic() {
     assert_eq!(result, expected);
 }
 
+#[test]
+fn test_create_append_prepend() {
+    let input = indoc! {r#"
+            <create path="src/new.rs">
+            fn new() {}
+            </create>
+            <append path="src/lib.rs">
+            mod new;
+            </append>
+            <prepend path="src/lib.rs">
+            //! A library.
+            </prepend>
+        "#};
+
+    let ops = vec![
+        Operation::Create(WriteFile {
+            path: PathBuf::from("src/new.rs"),
+            content: "fn new() {}".to_string(),
+        }),
+        Operation::Append(Append {
+            path: PathBuf::from("src/lib.rs"),
+            content: "mod new;".to_string(),
+        }),
+        Operation::Prepend(Prepend {
+            path: PathBuf::from("src/lib.rs"),
+            content: "//! A library.".to_string(),
+        }),
+    ];
+    let patch = parse(input).unwrap().patch.unwrap();
+    assert_eq!(patch.ops, ops);
+
+    // Rendered patches parse back to the same operations
+    let rendered = render_patch(&patch).unwrap();
+    assert_eq!(parse(&rendered).unwrap().patch.unwrap().ops, ops);
+}
+
+#[test]
+fn test_replace_all() {
+    let input = indoc! {r#"
+            <replace_all path="src/lib.rs" count="2">
+            <old>
+            foo
+            </old>
+            <new>
+            bar
+            </new>
+            </replace_all>
+            <replace_all path="src/lib.rs" regex="true">
+            <old>
+            get_(\w+)
+            </old>
+            <new>
+            ${1}_value
+            </new>
+            </replace_all>
+        "#};
+
+    let ops = vec![
+        Operation::ReplaceAll(ReplaceAll {
+            path: PathBuf::from("src/lib.rs"),
+            pattern: "foo".to_string(),
+            new: "bar".to_string(),
+            regex: false,
+            count: Some(2),
+        }),
+        Operation::ReplaceAll(ReplaceAll {
+            path: PathBuf::from("src/lib.rs"),
+            pattern: r"get_(\w+)".to_string(),
+            new: "${1}_value".to_string(),
+            regex: true,
+            count: None,
+        }),
+    ];
+    let patch = parse(input).unwrap().patch.unwrap();
+    assert_eq!(patch.ops, ops);
+
+    let rendered = render_patch(&patch).unwrap();
+    assert_eq!(parse(&rendered).unwrap().patch.unwrap().ops, ops);
+
+    let invalid = indoc! {r#"
+            <replace_all path="src/lib.rs" count="two">
+            <old>foo</old>
+            <new>bar</new>
+            </replace_all>
+        "#};
+    assert!(parse(invalid).is_err());
+}
+
+#[test]
+fn test_replace_lines() {
+    let input = indoc! {r#"
+            <replace_lines path="src/lib.rs" start="2" end="3">
+            <old>
+            let a = 1;
+            let b = 2;
+            </old>
+            <new>
+            let (a, b) = (1, 2);
+            </new>
+            </replace_lines>
+            <replace_lines path="src/lib.rs" start="5">
+            <new>
+            }
+            </new>
+            </replace_lines>
+        "#};
+
+    let ops = vec![
+        Operation::ReplaceLines(ReplaceLines {
+            path: PathBuf::from("src/lib.rs"),
+            start: 1,
+            end: Some(3),
+            new: "let (a, b) = (1, 2);".to_string(),
+            expected: Some("let a = 1;\nlet b = 2;".to_string()),
+        }),
+        Operation::ReplaceLines(ReplaceLines {
+            path: PathBuf::from("src/lib.rs"),
+            start: 4,
+            end: None,
+            new: "}".to_string(),
+            expected: None,
+        }),
+    ];
+    let patch = parse(input).unwrap().patch.unwrap();
+    assert_eq!(patch.ops, ops);
+
+    let rendered = render_patch(&patch).unwrap();
+    assert_eq!(parse(&rendered).unwrap().patch.unwrap().ops, ops);
+
+    let invalid = indoc! {r#"
+            <replace_lines path="src/lib.rs" start="0" end="1">
+            <new>x</new>
+            </replace_lines>
+        "#};
+    assert!(parse(invalid).is_err());
+}
+
+#[test]
+fn test_rust_item() {
+    let input = indoc! {r#"
+            <rust_item path="src/lib.rs" item="Config::new">
+            fn new() -> Self {
+                Self {}
+            }
+            </rust_item>
+        "#};
+
+    let ops = vec![Operation::RustItem(RustItem {
+        path: PathBuf::from("src/lib.rs"),
+        item: "Config::new".to_string(),
+        new: "fn new() -> Self {\n    Self {}\n}".to_string(),
+    })];
+    let patch = parse(input).unwrap().patch.unwrap();
+    assert_eq!(patch.ops, ops);
+
+    let rendered = render_patch(&patch).unwrap();
+    assert_eq!(parse(&rendered).unwrap().patch.unwrap().ops, ops);
+
+    let invalid = indoc! {r#"
+            <rust_item path="src/lib.rs">
+            fn new() -> Self {}
+            </rust_item>
+        "#};
+    assert!(parse(invalid).is_err());
+}
+
+#[test]
+fn test_udiff() {
+    let input = indoc! {r#"
+            <udiff path="src/main.rs">
+            @@ -1,2 +1,2 @@
+             fn main() {
+            -    old();
+            +    new();
+            </udiff>
+        "#};
+
+    let ops = vec![Operation::UnifiedDiff(UnifiedDiff {
+        path: PathBuf::from("src/main.rs"),
+        diff: "@@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();\n".to_string(),
+    })];
+    let patch = parse(input).unwrap().patch.unwrap();
+    assert_eq!(patch.ops, ops);
+
+    let rendered = render_patch(&patch).unwrap();
+    assert_eq!(parse(&rendered).unwrap().patch.unwrap().ops, ops);
+}
+
+#[test]
+fn test_delete_rename() {
+    let input = indoc! {r#"
+            <delete>
+            src/old.rs
+            src/older.rs
+            </delete>
+            <rename path="src/a.rs" to="src/b.rs">
+            </rename>
+        "#};
+
+    let ops = vec![
+        Operation::Delete(PathBuf::from("src/old.rs")),
+        Operation::Delete(PathBuf::from("src/older.rs")),
+        Operation::Rename(PathBuf::from("src/a.rs"), PathBuf::from("src/b.rs")),
+    ];
+    let patch = parse(input).unwrap().patch.unwrap();
+    assert_eq!(patch.ops, ops);
+
+    let rendered = render_patch(&patch).unwrap();
+    assert_eq!(parse(&rendered).unwrap().patch.unwrap().ops, ops);
+
+    // Operations without a tag can't be rendered
+    let undo = Patch {
+        ops: vec![Operation::Undo(PathBuf::from("src/a.rs"))],
+    };
+    assert!(render_patch(&undo).is_err());
+}
+
 #[test]
 fn test_parse_edit() {
     let input = indoc! {r#"
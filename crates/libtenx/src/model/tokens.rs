@@ -0,0 +1,321 @@
+//! Token count estimates, used to keep requests within a model's context window. We don't ship
+//! tokenizers for the providers, so counts are estimated from the length of the text. Estimates
+//! err on the high side, so a request that fits by our count should fit in practice.
+use async_trait::async_trait;
+use state::{Patch, PatchFailure};
+
+use super::{tags, Chat, Model, ModelProvider};
+use crate::{
+    checks::CheckResult,
+    context::ContextItem,
+    error::{Result, TenxError},
+    events::EventSender,
+    session::ModelResponse,
+};
+
+/// The average number of characters per token for English text and code, across most tokenizers.
+pub const CHARS_PER_TOKEN: f64 = 4.0;
+
+/// Anthropic's tokenizer produces more tokens for the same text, so Claude models use a lower
+/// ratio.
+pub const CLAUDE_CHARS_PER_TOKEN: f64 = 3.5;
+
+/// Open models served locally often have smaller vocabularies than the hosted ones, so they also
+/// produce more tokens for the same text, and use the same lower ratio as Claude.
+pub const LOCAL_CHARS_PER_TOKEN: f64 = 3.5;
+
+/// Tokens left free in the context window for the model's response. Small windows reserve at
+/// most a quarter of the window instead, so that there's still room for the request.
+pub const OUTPUT_RESERVE: usize = 8192;
+
+/// The context window of Claude models.
+pub const CLAUDE_WINDOW: usize = 200_000;
+
+/// The context window of most Gemini models.
+pub const GEMINI_WINDOW: usize = 1_048_576;
+
+/// Context windows of OpenAI-compatible models, by prefix of the API model name. The first
+/// matching prefix wins, so more specific prefixes come first.
+const OPENAI_WINDOWS: &[(&str, usize)] = &[
+    ("o1-mini", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4.5", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5", 16_385),
+    ("deepseek", 64_000),
+];
+
+/// Estimates the number of tokens in a piece of text, given the average number of characters per
+/// token.
+pub fn estimate(text: &str, chars_per_token: f64) -> usize {
+    (text.chars().count() as f64 / chars_per_token).ceil() as usize
+}
+
+/// Returns the number of tokens a request may use in a context window, leaving room for the
+/// response.
+pub fn budget(window: usize) -> usize {
+    window - OUTPUT_RESERVE.min(window / 4)
+}
+
+/// Returns the context window for an OpenAI-compatible model, based on its API model name.
+/// Unknown models are assumed to have the same window as gpt-4o.
+pub fn openai_window(api_model: &str) -> usize {
+    let m = api_model.to_lowercase();
+    OPENAI_WINDOWS
+        .iter()
+        .find(|(prefix, _)| m.starts_with(prefix))
+        .map_or(128_000, |(_, window)| *window)
+}
+
+/// Returns the context window for a Gemini model, based on its API model name.
+pub fn gemini_window(api_model: &str) -> usize {
+    if api_model.to_lowercase().contains("flash-thinking") {
+        32_768
+    } else {
+        GEMINI_WINDOW
+    }
+}
+
+/// The estimated size of one part of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    /// A description of the part, like "file src/lib.rs" or "editable src/main.rs".
+    pub label: String,
+    /// The estimated number of tokens.
+    pub tokens: usize,
+}
+
+/// A chat that measures a request instead of sending it. Context items and editables are counted
+/// individually, while prompts, responses and other messages are counted together as the
+/// conversation. Messages are rendered as the tags models render them, which is close enough for
+/// estimates across providers.
+pub struct Tally {
+    model: Model,
+    system: usize,
+    parts: Vec<Contribution>,
+    conversation: usize,
+}
+
+impl Tally {
+    /// Creates a tally for a model, including the system prompt the model sends with every
+    /// request.
+    pub fn new(model: &Model) -> Self {
+        Self {
+            system: model.count_tokens(&model.system_prompt()),
+            model: model.clone(),
+            parts: vec![],
+            conversation: 0,
+        }
+    }
+
+    /// Returns the estimated size of each part of the request, in the order they were added. The
+    /// system prompt comes first and the conversation last.
+    pub fn contributions(&self) -> Vec<Contribution> {
+        let mut v = vec![Contribution {
+            label: "system prompt".into(),
+            tokens: self.system,
+        }];
+        v.extend(self.parts.iter().cloned());
+        v.push(Contribution {
+            label: "conversation".into(),
+            tokens: self.conversation,
+        });
+        v
+    }
+
+    /// Returns the estimated size of the whole request.
+    pub fn total(&self) -> usize {
+        self.system + self.conversation + self.parts.iter().map(|p| p.tokens).sum::<usize>()
+    }
+
+    /// Returns the `n` largest parts of the request, largest first.
+    pub fn largest(&self, n: usize) -> Vec<Contribution> {
+        let mut v = self.contributions();
+        v.sort_by(|a, b| b.tokens.cmp(&a.tokens));
+        v.truncate(n);
+        v
+    }
+
+    fn count(&mut self, text: &str) {
+        self.conversation += self.model.count_tokens(text);
+    }
+}
+
+#[async_trait]
+impl Chat for Tally {
+    fn add_system_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.system += self.model.count_tokens(prompt);
+        Ok(())
+    }
+
+    fn add_user_message(&mut self, text: &str) -> Result<()> {
+        self.count(text);
+        Ok(())
+    }
+
+    fn add_agent_message(&mut self, text: &str) -> Result<()> {
+        self.count(text);
+        Ok(())
+    }
+
+    fn add_context(&mut self, ctx: &ContextItem) -> Result<()> {
+        self.parts.push(Contribution {
+            label: format!("{} {}", ctx.ty, ctx.source),
+            tokens: self.model.count_tokens(&tags::render_context(ctx)?),
+        });
+        Ok(())
+    }
+
+    fn add_editable(&mut self, path: &str, data: &str) -> Result<()> {
+        self.parts.push(Contribution {
+            label: format!("editable {path}"),
+            tokens: self.model.count_tokens(&tags::render_editable(path, data)?),
+        });
+        Ok(())
+    }
+
+    fn add_agent_patch(&mut self, patch: &Patch) -> Result<()> {
+        // Editor models produce operations the tags renderer can't show, so patches are measured
+        // as JSON
+        let json = serde_json::to_string(patch).map_err(|e| TenxError::Internal(e.to_string()))?;
+        self.count(&json);
+        Ok(())
+    }
+
+    fn add_agent_comment(&mut self, comment: &str) -> Result<()> {
+        self.count(&tags::render_comment(comment)?);
+        Ok(())
+    }
+
+    fn add_user_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.count(&tags::render_prompt(prompt)?);
+        Ok(())
+    }
+
+    fn add_user_patch_failure(&mut self, failures: &[PatchFailure]) -> Result<()> {
+        self.count(&tags::render_patch_failures(failures)?);
+        Ok(())
+    }
+
+    fn add_user_check_results(&mut self, results: &[CheckResult]) -> Result<()> {
+        self.count(&tags::render_check_results(results)?);
+        Ok(())
+    }
+
+    async fn send(&mut self, _sender: Option<EventSender>) -> Result<ModelResponse> {
+        Err(TenxError::Internal(
+            "A tally can't be sent to a model".into(),
+        ))
+    }
+
+    fn render(&self) -> Result<String> {
+        Ok(self
+            .contributions()
+            .iter()
+            .map(|c| format!("{}: ~{} tokens\n", c.label, c.tokens))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{editor, DummyModel, GoogleEditor};
+
+    #[test]
+    fn test_estimate() {
+        assert_eq!(estimate("", CHARS_PER_TOKEN), 0);
+        assert_eq!(estimate("abcd", CHARS_PER_TOKEN), 1);
+        assert_eq!(estimate("abcde", CHARS_PER_TOKEN), 2);
+        assert_eq!(estimate("ééé", 1.0), 3);
+        assert_eq!(estimate("abcdefg", CLAUDE_CHARS_PER_TOKEN), 2);
+    }
+
+    #[test]
+    fn test_openai_window() {
+        assert_eq!(openai_window("o1-mini"), 128_000);
+        assert_eq!(openai_window("o3-mini"), 200_000);
+        assert_eq!(openai_window("gpt-4o-2024-08-06"), 128_000);
+        assert_eq!(openai_window("gpt-4"), 8_192);
+        assert_eq!(openai_window("gpt-4-0613"), 8_192);
+        assert_eq!(openai_window("gpt-4-32k"), 32_768);
+        assert_eq!(openai_window("gpt-4.5-preview"), 128_000);
+        assert_eq!(openai_window("gpt-4.1-mini"), 1_047_576);
+        assert_eq!(openai_window("GPT-4-Turbo"), 128_000);
+        assert_eq!(openai_window("deepseek-chat"), 64_000);
+        assert_eq!(openai_window("some-other-model"), 128_000);
+    }
+
+    #[test]
+    fn test_gemini_window() {
+        assert_eq!(gemini_window("gemini-2.0-flash"), GEMINI_WINDOW);
+        assert_eq!(gemini_window("gemini-2.0-flash-thinking-exp-01-21"), 32_768);
+    }
+
+    #[test]
+    fn test_budget() {
+        assert_eq!(budget(200_000), 200_000 - OUTPUT_RESERVE);
+        assert_eq!(budget(32_768), 32_768 - OUTPUT_RESERVE);
+        // Small windows reserve a quarter of the window
+        assert_eq!(budget(8_192), 6_144);
+        assert_eq!(budget(openai_window("gpt-4")), 6_144);
+        assert_eq!(budget(0), 0);
+    }
+
+    #[test]
+    fn test_tally() {
+        let model = Model::Dummy(DummyModel::default());
+        let mut tally = Tally::new(&model);
+        let system = tally.total();
+        tally
+            .add_context(&ContextItem {
+                ty: "file".into(),
+                source: "src/lib.rs".into(),
+                body: "x".repeat(400),
+            })
+            .unwrap();
+        tally.add_editable("src/main.rs", &"y".repeat(40)).unwrap();
+        tally.add_user_prompt("do the thing").unwrap();
+
+        let parts = tally.contributions();
+        assert_eq!(
+            parts.iter().map(|c| c.label.as_str()).collect::<Vec<_>>(),
+            vec![
+                "system prompt",
+                "file src/lib.rs",
+                "editable src/main.rs",
+                "conversation"
+            ]
+        );
+        assert!(parts[1].tokens > parts[2].tokens);
+        assert!(parts[1].tokens >= 100);
+        assert_eq!(
+            tally.total(),
+            system + parts[1..].iter().map(|c| c.tokens).sum::<usize>()
+        );
+        assert_eq!(tally.largest(1)[0].tokens, system.max(parts[1].tokens));
+    }
+
+    #[test]
+    fn test_tally_system_prompt() {
+        // Tags models send the tags system prompt, and editor models their own prompt and tool
+        let tags_model = Model::Dummy(DummyModel::default());
+        let editor_model = Model::GoogleEditor(GoogleEditor::default());
+        assert_eq!(
+            Tally::new(&tags_model).total(),
+            tags_model.count_tokens(tags::SYSTEM)
+        );
+        assert_eq!(
+            Tally::new(&editor_model).total(),
+            editor_model.count_tokens(&editor::system_prompt())
+        );
+    }
+}
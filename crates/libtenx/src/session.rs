@@ -125,6 +125,26 @@ impl Action {
         self.steps.last()
     }
 
+    /// Returns the changes made by the whole action since its first step, or by a single step if
+    /// `step_idx` is given. An action with no steps has an empty diff.
+    pub fn diff(&self, step_idx: Option<usize>) -> Result<state::diff::Diff> {
+        let Some(step_idx) = step_idx else {
+            return match self.steps.first() {
+                Some(first) => Ok(self.state.diff(first.rollback_id, None)?),
+                None => Ok(state::diff::Diff::default()),
+            };
+        };
+        let step = self.steps.get(step_idx).ok_or_else(|| {
+            TenxError::Internal(format!(
+                "Invalid step index {} for action, which has {} steps",
+                step_idx,
+                self.steps.len()
+            ))
+        })?;
+        let to = self.steps.get(step_idx + 1).map(|next| next.rollback_id);
+        Ok(self.state.diff(step.rollback_id, to)?)
+    }
+
     /// Adds a new step to the action.
     ///
     /// Returns an error if the last step doesn't have either a model response or an error.
@@ -153,15 +173,22 @@ impl Action {
     ) -> Result<()> {
         renderer.push(&format!("{}: {}", action_offset, self.strategy.name()));
 
-        // Add list of touched files if there are any
+        // Add list of touched files if there are any, with line counts for those the action changed
         if let Ok(touched_files) = self.state.changed() {
             if !touched_files.is_empty() {
+                let diff = self.diff(None).unwrap_or_default();
                 renderer.push("files");
                 let file_strings: Vec<String> = touched_files
                     .iter()
-                    .map(|path| path.to_string_lossy().to_string())
+                    .map(|path| match diff.files.iter().find(|f| &f.path == path) {
+                        Some(f) => format!("{} (+{} -{})", path.display(), f.added, f.removed),
+                        None => path.to_string_lossy().to_string(),
+                    })
                     .collect();
                 renderer.bullets(file_strings);
+                if detail >= Detail::Full && !diff.is_empty() {
+                    renderer.para(&diff.unified());
+                }
                 renderer.pop();
             }
         }
@@ -306,6 +333,41 @@ impl Session {
         Ok(())
     }
 
+    /// Undo the changes made by a single step, keeping the changes made by later steps. The steps
+    /// themselves are kept in the session history. Changes are undone with a three-way merge, and
+    /// if any file can't be merged cleanly, nothing is changed and the conflicts are returned.
+    ///
+    /// * `action_idx` - The 0-based index of the action containing the step
+    /// * `step_idx` - The 0-based index of the step within the action to undo
+    pub fn revert_step(
+        &mut self,
+        action_idx: usize,
+        step_idx: usize,
+    ) -> Result<Vec<state::MergeConflict>> {
+        let action = self
+            .actions
+            .get_mut(action_idx)
+            .ok_or_else(|| TenxError::Internal(format!("Invalid action index: {action_idx}")))?;
+        let first = action
+            .steps
+            .get(step_idx)
+            .ok_or_else(|| {
+                TenxError::Internal(format!(
+                    "Invalid step index {} for action {}, which has {} steps",
+                    step_idx,
+                    action_idx,
+                    action.steps.len()
+                ))
+            })?
+            .rollback_id;
+        // A step's snapshots run up to the marker of the next step
+        let last = match action.steps.get(step_idx + 1) {
+            Some(next) => next.rollback_id.saturating_sub(1),
+            None => u64::MAX,
+        };
+        Ok(action.state.revert_merge(first, last)?)
+    }
+
     /// Reset the session to a specific action and step and prepare it for retry.
     /// This method first resets the session to the specified step, then clears the step's
     /// response data, and reverts to the step's rollback_id to reset the state.
@@ -341,7 +403,7 @@ impl Session {
 
     /// Apply the last step in the session, applying the patch and operations. The step must
     /// already have a model response.
-    pub fn apply_last_step(&mut self, _config: &config::Config) -> Result<()> {
+    pub fn apply_last_step(&mut self, config: &config::Config) -> Result<()> {
         let resp = self
             .last_step()
             .ok_or_else(|| TenxError::Internal("No steps in session".into()))?
@@ -349,7 +411,9 @@ impl Session {
             .clone()
             .ok_or_else(|| TenxError::Internal("No response in the last step".into()))?;
         if let Some(patch) = &resp.patch {
-            let patch_info = self.actions.last_mut().unwrap().state.patch(patch)?;
+            let action = self.actions.last_mut().unwrap();
+            let mode = action.strategy.patch_mode(config);
+            let patch_info = action.state.patch_with_mode(patch, mode)?;
             let step = self
                 .last_step_mut()
                 .ok_or_else(|| TenxError::Internal("No steps in session".into()))?;
@@ -506,4 +570,97 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_revert_step_keeps_later_steps() -> Result<()> {
+        let tp = testutils::test_project();
+        tp.create_file_tree(&["test.txt"]);
+        tp.write("test.txt", "1\n2\n3\n4\n5\n");
+        let strategy = Strategy::Code(strategy::Code::default());
+        let mut action = Action::new(&tp.config, strategy)?;
+        assert!(action.diff(None)?.is_empty());
+
+        for (prompt, old, new) in [("bad", "2\n", "two\n"), ("good", "4\n", "four\n")] {
+            let mut step = Step::new(
+                "model1".into(),
+                strategy::StrategyState::Code(strategy::CodeState::default()),
+            )
+            .with_prompt(prompt);
+            step.model_response = Some(ModelResponse {
+                comment: None,
+                patch: None,
+                usage: None,
+                raw_response: None,
+            });
+            action.add_step(step)?;
+            action
+                .state
+                .patch(&Patch::default().with_replace("test.txt", old, new))?;
+        }
+
+        let mut session = Session {
+            actions: vec![action],
+            contexts: context::ContextManager::new(),
+        };
+        let diff = session.actions[0].diff(Some(1))?;
+        assert_eq!((diff.added(), diff.removed()), (1, 1));
+        assert!(diff.unified().contains("-4\n+four\n"));
+        assert_eq!(session.actions[0].diff(None)?.added(), 2);
+
+        let mut md = unirend::Markdown::new();
+        session.render(&tp.config, &mut md, Detail::Default)?;
+        assert!(md.render().contains("test.txt (+2 -2)"));
+        assert!(!md.render().contains("+four"));
+        let mut md = unirend::Markdown::new();
+        session.render(&tp.config, &mut md, Detail::Full)?;
+        assert!(md.render().contains("-4\n+four\n"));
+
+        let conflicts = session.revert_step(0, 0)?;
+        assert!(conflicts.is_empty());
+        assert_eq!(tp.read("test.txt"), "1\n2\n3\nfour\n5\n");
+        assert_eq!(session.actions[0].steps.len(), 2);
+        assert!(session.revert_step(0, 2).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revert_step_keeps_external_edits() -> Result<()> {
+        let tp = testutils::test_project();
+        tp.create_file_tree(&["test.txt"]);
+        tp.write("test.txt", "1\n2\n3\n4\n5\n");
+        let strategy = Strategy::Code(strategy::Code::default());
+        let mut action = Action::new(&tp.config, strategy)?;
+
+        for (prompt, old, new) in [("first", "1\n", "one\n"), ("second", "5\n", "five\n")] {
+            let mut step = Step::new(
+                "model1".into(),
+                strategy::StrategyState::Code(strategy::CodeState::default()),
+            )
+            .with_prompt(prompt);
+            step.model_response = Some(ModelResponse::default());
+            action.add_step(step)?;
+            if prompt == "second" {
+                // The user edits the file in their editor between steps
+                tp.write("test.txt", "one\n2\nuser\n4\n5\n");
+            }
+            action
+                .state
+                .patch(&Patch::default().with_replace("test.txt", old, new))?;
+        }
+
+        let mut session = Session {
+            actions: vec![action],
+            contexts: context::ContextManager::new(),
+        };
+        let diff = session.actions[0].diff(Some(1))?;
+        assert_eq!((diff.added(), diff.removed()), (1, 1));
+        assert!(diff.unified().contains("-5\n+five\n"));
+
+        let conflicts = session.revert_step(0, 1)?;
+        assert!(conflicts.is_empty());
+        assert_eq!(tp.read("test.txt"), "one\n2\nuser\n4\n5\n");
+
+        Ok(())
+    }
 }
@@ -6,7 +6,15 @@ use crate::{
     session::Session,
 };
 use fs_err as fs;
-use std::path::{Path, PathBuf};
+use state::journal::Journal;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// The directory under the store where snapshot journals are kept. Each session has its own
+/// journal, named after the session file, which holds every blob the session references.
+const JOURNAL_DIR: &str = "blobs";
 
 /// Normalizes a path for use as a filename by replacing problematic characters.
 pub fn path_to_filename(path: &Path) -> String {
@@ -15,7 +23,14 @@ pub fn path_to_filename(path: &Path) -> String {
         .replace([':', '<', '>', '"', '|', '?', '*'], "")
 }
 
-/// Loads a session from a file located at a specific path.
+/// Returns the snapshot journal directory for the named session in a store.
+pub fn journal_dir(base_dir: &Path, name: &str) -> PathBuf {
+    base_dir.join(JOURNAL_DIR).join(name)
+}
+
+/// Loads a session from a file located at a specific path. If the session has a snapshot
+/// journal next to it, the journal is attached to the state of every action. A session file
+/// moved to another path must be moved along with its journal.
 pub fn load_session<P: AsRef<Path>>(path: P) -> Result<Session> {
     let path = path.as_ref();
     if !path.exists() {
@@ -26,8 +41,17 @@ pub fn load_session<P: AsRef<Path>>(path: P) -> Result<Session> {
     }
     let serialized = fs::read_to_string(path)
         .map_err(|e| TenxError::SessionStore(format!("Failed to read session: {e}")))?;
-    serde_json::from_str(&serialized)
-        .map_err(|e| TenxError::SessionStore(format!("Failed to parse session: {e}")))
+    let mut session: Session = serde_json::from_str(&serialized)
+        .map_err(|e| TenxError::SessionStore(format!("Failed to parse session: {e}")))?;
+    if let (Some(base_dir), Some(name)) = (path.parent(), path.file_name()) {
+        let dir = journal_dir(base_dir, &name.to_string_lossy());
+        if dir.exists() {
+            for action in session.actions.iter_mut() {
+                action.state.set_journal(&dir)?;
+            }
+        }
+    }
+    Ok(session)
 }
 
 /// Manages persistent storage and retrieval of Session objects.
@@ -45,12 +69,25 @@ impl SessionStore {
         Ok(Self { base_dir })
     }
 
-    /// Saves a session to the store with the specified name.
+    /// Saves a session to the store with the specified name. Blobs the session references are
+    /// copied into its journal if they are kept anywhere else, such as the journal of a session
+    /// loaded from another path. Journal blobs that are no longer referenced by any action in the
+    /// session are garbage collected.
     pub fn save(&self, name: &str, state: &Session) -> Result<()> {
+        let journal = journal_dir(&self.base_dir, name);
+        for action in &state.actions {
+            action.state.copy_journal(&journal)?;
+        }
         let file_path = self.base_dir.join(name);
         let serialized = serde_json::to_string(state)
             .map_err(|e| TenxError::SessionStore(format!("serialization failed: {e}")))?;
         fs::write(&file_path, serialized)?;
+        let referenced: HashSet<_> = state
+            .actions
+            .iter()
+            .flat_map(|a| a.state.referenced_blobs())
+            .collect();
+        Journal::gc(&journal, &referenced)?;
         Ok(())
     }
 
@@ -91,7 +128,7 @@ impl SessionStore {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::Project;
+    use crate::{config::Project, session::Action, strategy};
     use tempfile::TempDir;
 
     #[test]
@@ -131,4 +168,61 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_journal() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let project = temp_dir.path().join("project");
+        fs::create_dir_all(&project)?;
+        fs::write(project.join("a.txt"), "original")?;
+        let config = Config {
+            project: Project {
+                root: project.clone(),
+                ..Default::default()
+            },
+            session_store_dir: temp_dir.path().join("sess"),
+            ..Default::default()
+        };
+        let store = SessionStore::open(config.session_store_dir.clone())?;
+        let name = path_to_filename(&config.project_root());
+        let journal = journal_dir(&config.session_store_dir, &name);
+
+        let mut session = Session::new(&config)?;
+        let mut action = Action::new(&config, strategy::Strategy::Code(strategy::Code::default()))?;
+        let info = action
+            .state
+            .patch(&state::Patch::default().with_write("a.txt", "modified"))?;
+        session.add_action(action)?;
+        store.save_current(&config, &session)?;
+        assert!(journal.exists());
+
+        // Snapshot contents live in the journal, not the session file
+        let serialized = fs::read_to_string(config.session_store_dir.join(&name))?;
+        assert!(!serialized.contains("original"));
+
+        let mut loaded = store.load(&name)?;
+        loaded.actions[0].state.revert(info.rollback_id)?;
+        assert_eq!(fs::read_to_string(project.join("a.txt"))?, "original");
+
+        // A session loaded from another path and saved under a new name gets its own journal
+        let other_dir = temp_dir.path().join("other");
+        let other = SessionStore::open(other_dir.clone())?;
+        other.save(
+            "moved",
+            &load_session(config.session_store_dir.join(&name))?,
+        )?;
+
+        // Clearing the actions lets the journal be collected on save
+        loaded.clear();
+        store.save(&name, &loaded)?;
+        assert!(fs::read_dir(&journal)?.next().is_none());
+
+        // The moved session still reverts once the original journal is gone
+        fs::write(project.join("a.txt"), "modified")?;
+        let mut moved = load_session(other_dir.join("moved"))?;
+        moved.actions[0].state.revert(info.rollback_id)?;
+        assert_eq!(fs::read_to_string(project.join("a.txt"))?, "original");
+
+        Ok(())
+    }
 }
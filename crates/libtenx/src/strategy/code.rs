@@ -1,15 +1,16 @@
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     checks::CheckResult,
     checks::{check_all, check_paths},
     config::Config,
-    context::ContextProvider,
+    context::{ContextItem, ContextProvider},
     error::Result,
     error::TenxError,
     events::EventSender,
-    model::Chat,
+    model::tokens::{self, Tally},
+    model::{Chat, Model},
     session::{Action, Step},
 };
 use state::Operation;
@@ -19,17 +20,22 @@ use super::*;
 
 pub const ACK: &str = "Got it.";
 
+/// The number of trailing lines kept when command output is truncated to fit a request into the
+/// context window. The end of the output is where errors usually are.
+const CMD_TAIL_LINES: usize = 100;
+
+/// The number of parts listed when a request doesn't fit the context window.
+const LARGEST_PARTS: usize = 5;
+
 fn build_chat(
-    config: &Config,
+    context: &[ContextItem],
     session: &Session,
     action_offset: usize,
-    chat: &mut Box<dyn Chat>,
+    chat: &mut dyn Chat,
 ) -> Result<()> {
-    if !session.contexts.is_empty() {
-        for cspec in &session.contexts {
-            for ctx in cspec.context_items(config, session)? {
-                chat.add_context(&ctx)?;
-            }
+    if !context.is_empty() {
+        for ctx in context {
+            chat.add_context(ctx)?;
         }
         chat.add_agent_message(ACK)?;
     }
@@ -88,6 +94,100 @@ fn build_chat(
     Ok(())
 }
 
+/// Estimates the size of a request, as it would be built from the given context.
+fn measure(
+    context: &[ContextItem],
+    session: &Session,
+    action_offset: usize,
+    model: &Model,
+) -> Result<Tally> {
+    let mut tally = Tally::new(model);
+    build_chat(context, session, action_offset, &mut tally)?;
+    Ok(tally)
+}
+
+/// Keeps the last `CMD_TAIL_LINES` lines of command output. Returns None if the output is already
+/// short enough.
+fn truncate_cmd(body: &str) -> Option<String> {
+    let lines: Vec<&str> = body.lines().collect();
+    let dropped = lines.len().checked_sub(CMD_TAIL_LINES).filter(|n| *n > 0)?;
+    Some(format!(
+        "[{dropped} lines truncated]\n{}",
+        lines[dropped..].join("\n")
+    ))
+}
+
+/// Resolves the session's context, and checks that the request fits in the model's context
+/// window. The estimated size of each part of the request is logged. If the request is too large,
+/// context is trimmed: first the project map is dropped, then command output is truncated. If it
+/// still doesn't fit, we fail with a list of the largest parts of the request.
+fn fit_context(
+    config: &Config,
+    session: &Session,
+    action_offset: usize,
+    model: &Model,
+) -> Result<Vec<ContextItem>> {
+    let mut context = vec![];
+    for cspec in &session.contexts {
+        context.extend(cspec.context_items(config, session)?);
+    }
+
+    let mut tally = measure(&context, session, action_offset, model)?;
+    for c in tally.contributions() {
+        debug!("request size: {}: ~{} tokens", c.label, c.tokens);
+    }
+    debug!("request size: total: ~{} tokens", tally.total());
+
+    let Some(window) = model.context_window() else {
+        return Ok(context);
+    };
+    let budget = tokens::budget(window);
+    if tally.total() <= budget {
+        return Ok(context);
+    }
+
+    if context.iter().any(|c| c.ty == "project_map") {
+        warn!(
+            "request is ~{} tokens, over the budget of {budget}: dropping the project map",
+            tally.total()
+        );
+        context.retain(|c| c.ty != "project_map");
+        tally = measure(&context, session, action_offset, model)?;
+        if tally.total() <= budget {
+            return Ok(context);
+        }
+    }
+
+    let mut truncated = false;
+    for c in context.iter_mut().filter(|c| c.ty == "cmd") {
+        if let Some(body) = truncate_cmd(&c.body) {
+            c.body = body;
+            truncated = true;
+        }
+    }
+    if truncated {
+        warn!(
+            "request is ~{} tokens, over the budget of {budget}: truncating command output",
+            tally.total()
+        );
+        tally = measure(&context, session, action_offset, model)?;
+        if tally.total() <= budget {
+            return Ok(context);
+        }
+    }
+
+    Err(TenxError::ContextBudget {
+        model: model.name(),
+        tokens: tally.total(),
+        budget,
+        largest: tally
+            .largest(LARGEST_PARTS)
+            .iter()
+            .map(|c| format!("  {}: ~{} tokens\n", c.label, c.tokens))
+            .collect(),
+    })
+}
+
 /// Shared step data for Code and Fix strategies.
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct CodeState {}
@@ -390,7 +490,8 @@ impl ActionStrategy for Code {
         let mut chat = model
             .chat()
             .ok_or(TenxError::Internal("Chat not supported".into()))?;
-        build_chat(config, session, action_offset, &mut chat)?;
+        let context = fit_context(config, session, action_offset, &model)?;
+        build_chat(&context, session, action_offset, chat.as_mut())?;
         chat.send(sender).await
     }
 }
@@ -501,7 +602,8 @@ impl ActionStrategy for Fix {
         let mut chat = model
             .chat()
             .ok_or(TenxError::Internal("Chat not supported".into()))?;
-        build_chat(config, session, action_offset, &mut chat)?;
+        let context = fit_context(config, session, action_offset, &model)?;
+        build_chat(&context, session, action_offset, chat.as_mut())?;
         chat.send(sender).await
     }
 }
@@ -509,10 +611,89 @@ impl ActionStrategy for Fix {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{strategy::Strategy, testutils::test_project};
+    use crate::{
+        context::{Cmd, Context, ProjectMap, Text},
+        model::DummyModel,
+        strategy::Strategy,
+        testutils::test_project,
+    };
     use state;
     use std::path::PathBuf;
 
+    #[test]
+    fn test_fit_context() -> Result<()> {
+        let test_project = test_project();
+        test_project.create_file_tree(&["src/lib.rs", "src/main.rs", "README.md"]);
+        let config = &test_project.config;
+        let mut session = Session::new(config)?;
+        session.add_action(Action::new(config, Strategy::Code(Code::default()))?)?;
+        session.last_action_mut()?.add_step(
+            Step::new(
+                config.models.default.clone(),
+                StrategyState::Code(CodeState::default()),
+            )
+            .with_prompt("Test"),
+        )?;
+        session.add_context(Context::Text(Text::new(
+            "notes".into(),
+            "note ".repeat(200),
+        )));
+        session.add_context(Context::ProjectMap(ProjectMap::new()));
+        let output: Vec<String> = (0..500).map(|i| format!("line {i}")).collect();
+        session.add_context(Context::Cmd(Cmd {
+            command: "cargo test".into(),
+            content: output.join("\n"),
+        }));
+
+        let dummy = Model::Dummy(DummyModel::default());
+        let all = fit_context(config, &session, 0, &dummy)?;
+        assert_eq!(all.len(), 3);
+        let without_map: Vec<ContextItem> = all
+            .iter()
+            .filter(|c| c.ty != "project_map")
+            .cloned()
+            .collect();
+        let size = |context: &[ContextItem]| measure(context, &session, 0, &dummy).unwrap().total();
+        // The smallest window with a budget of n tokens
+        let window = |n: usize| {
+            let w = (n..).find(|w| tokens::budget(*w) == n).unwrap();
+            Model::Dummy(DummyModel::default().with_context_window(w))
+        };
+
+        // Within the window, nothing changes
+        let fitted = fit_context(config, &session, 0, &window(size(&all)))?;
+        assert_eq!(fitted, all);
+
+        // The project map is dropped first
+        let fitted = fit_context(config, &session, 0, &window(size(&without_map)))?;
+        assert_eq!(fitted, without_map);
+
+        // Then command output is truncated
+        let fitted = fit_context(config, &session, 0, &window(size(&without_map) - 1))?;
+        assert_eq!(fitted.len(), 2);
+        let cmd = &fitted.iter().find(|c| c.ty == "cmd").unwrap().body;
+        assert!(cmd.starts_with("[400 lines truncated]\nline 400\n"));
+        assert!(cmd.ends_with("line 499"));
+
+        // If nothing else can be trimmed, we fail with the largest parts of the request
+        match fit_context(config, &session, 0, &window(10)) {
+            Err(TenxError::ContextBudget {
+                tokens,
+                budget,
+                largest,
+                ..
+            }) => {
+                assert_eq!(budget, 10);
+                assert_eq!(tokens, size(&fitted));
+                assert!(largest.contains("text notes"));
+                assert!(largest.contains("cmd cargo test"));
+            }
+            other => panic!("expected a budget error, got {other:?}"),
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_code_next_step() -> Result<()> {
         let test_project = test_project();
@@ -564,6 +745,7 @@ mod test {
                 }),
             }],
             should_continue: false,
+            rolled_back: false,
         });
 
         let state = code.next_step(&test_project.config, &mut session, action_idx, None, None)?;
@@ -604,6 +786,18 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_patch_mode() -> Result<()> {
+        let mut config = test_project().config;
+        config.patches.atomic = vec!["fix".to_string()];
+        assert_eq!(
+            Code::default().patch_mode(&config),
+            state::PatchMode::Partial
+        );
+        assert_eq!(Fix::default().patch_mode(&config), state::PatchMode::Atomic);
+        Ok(())
+    }
+
     #[test]
     fn test_fix_next_step() -> Result<()> {
         let test_project = test_project().with_check_result(Some(Ok(vec![CheckResult {
@@ -652,6 +846,7 @@ mod test {
                 }),
             }],
             should_continue: false,
+            rolled_back: false,
         });
 
         let state = fix.next_step(&test_project.config, &mut session, action_idx, None, None)?;
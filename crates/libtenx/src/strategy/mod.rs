@@ -95,6 +95,16 @@ pub trait ActionStrategy {
         }
     }
 
+    /// How patches from the model are applied. Strategies named in the `patches.atomic` config
+    /// are all-or-nothing, so their checks never run against a partially applied patch.
+    fn patch_mode(&self, config: &Config) -> state::PatchMode {
+        if config.patches.atomic.iter().any(|s| s == self.name()) {
+            state::PatchMode::Atomic
+        } else {
+            state::PatchMode::Partial
+        }
+    }
+
     /// Run the checks for this strategy.
     fn check(
         &self,
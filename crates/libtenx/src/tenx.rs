@@ -7,10 +7,10 @@ use crate::{
     context::{Context, ContextProvider},
     error::{Result, TenxError},
     events::{send_event, Event, EventBlock, EventSender},
-    session::{Action, Session},
+    session::{Action, ModelResponse, Session, Step},
     session_store::{path_to_filename, SessionStore},
     strategy,
-    strategy::{ActionStrategy, Completion},
+    strategy::{ActionStrategy, CodeState, Completion, StrategyState},
 };
 
 /// Tenx is an AI-driven coding assistant.
@@ -142,6 +142,35 @@ impl Tenx {
         Ok(())
     }
 
+    /// Applies a patch to the session as if the model had produced it, in a new code action of
+    /// its own, and saves the session. The imported patch is rendered back to the model like any
+    /// other response if the action continues.
+    pub fn import_patch(
+        &self,
+        session: &mut Session,
+        patch: state::Patch,
+    ) -> Result<state::PatchInfo> {
+        self.code(session)?;
+        let step = Step::new(
+            self.config.models.default.clone(),
+            StrategyState::Code(CodeState::default()),
+        )
+        .with_prompt("Apply an imported patch");
+        session.last_action_mut()?.add_step(step)?;
+        if let Some(step) = session.last_step_mut() {
+            step.model_response = Some(ModelResponse {
+                patch: Some(patch),
+                ..Default::default()
+            });
+        }
+        session.apply_last_step(&self.config)?;
+        self.save_session(session)?;
+        session
+            .last_step()
+            .and_then(|step| step.patch_info.clone())
+            .ok_or_else(|| TenxError::Internal("Imported patch was not applied".into()))
+    }
+
     /// Saves a session to the store.
     pub fn save_session(&self, session: &Session) -> Result<()> {
         if self.config.session_store_dir.as_os_str().is_empty() {
@@ -210,6 +239,24 @@ impl Tenx {
         self.save_session(session)
     }
 
+    /// Undoes the changes made by a single step, keeping later steps. The session is only saved if
+    /// the step was reverted without conflicts.
+    ///
+    /// * `action_idx` - The 0-based index of the action
+    /// * `step_idx` - The 0-based index of the step within the action
+    pub fn revert_step(
+        &self,
+        session: &mut Session,
+        action_idx: usize,
+        step_idx: usize,
+    ) -> Result<Vec<state::MergeConflict>> {
+        let conflicts = session.revert_step(action_idx, step_idx)?;
+        if conflicts.is_empty() {
+            self.save_session(session)?;
+        }
+        Ok(conflicts)
+    }
+
     /// Resets all steps in the session.
     pub fn reset_all(&self, session: &mut Session) -> Result<()> {
         session.reset_all()?;
@@ -577,4 +624,130 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_openai_mock_server() -> Result<()> {
+        use crate::testutils::mock_server::{MockResponse, MockServer};
+
+        // The first request is rate limited, and retried after the delay the server gives
+        let server = MockServer::start(vec![
+            MockResponse::rate_limited(Some(0)),
+            MockResponse::chat("<write_file path=\"test.txt\">\nUpdated content\n</write_file>"),
+        ])
+        .await
+        .unwrap();
+
+        let temp_dir = tempdir().unwrap();
+        let mut config = Config::default().with_root(temp_dir.path());
+        config.models.custom.push(crate::config::Model::OpenAi {
+            name: "mock".to_string(),
+            api_model: "mock-model".to_string(),
+            key: "key".to_string(),
+            key_env: String::new(),
+            api_base: server.url(),
+            can_stream: false,
+            no_system_prompt: false,
+            reasoning_effort: None,
+            editor: false,
+        });
+        config.models.default = "mock".to_string();
+        config.session_store_dir = temp_dir.path().join("sess");
+        config.step_limit = 1;
+        config.project.include.push("**".to_string());
+
+        let tenx = Tenx::new(config.clone());
+        let test_file_path = temp_dir.path().join("test.txt");
+        fs::write(&test_file_path, "Initial content").unwrap();
+
+        let mut session = Session::new(&config).unwrap();
+        tenx.code(&mut session)?;
+        session
+            .last_action_mut()
+            .unwrap()
+            .state
+            .view(temp_dir.path().to_path_buf(), vec!["**".to_string()])
+            .unwrap();
+
+        tenx.continue_steps(&mut session, Some("test".into()), None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&test_file_path).unwrap(),
+            "Updated content"
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].json()["model"], "mock-model");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_patch() -> Result<()> {
+        use crate::testutils::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![MockResponse::chat("<comment>\nOk\n</comment>")])
+            .await
+            .unwrap();
+
+        let temp_dir = tempdir().unwrap();
+        let mut config = Config::default().with_root(temp_dir.path());
+        config.models.custom.push(crate::config::Model::OpenAi {
+            name: "mock".to_string(),
+            api_model: "mock-model".to_string(),
+            key: "key".to_string(),
+            key_env: String::new(),
+            api_base: server.url(),
+            can_stream: false,
+            no_system_prompt: false,
+            reasoning_effort: None,
+            editor: false,
+        });
+        config.models.default = "mock".to_string();
+        config.session_store_dir = temp_dir.path().join("sess");
+        config.step_limit = 1;
+        config.project.include.push("**".to_string());
+        fs::write(temp_dir.path().join("test.txt"), "one\ntwo\n").unwrap();
+        fs::write(temp_dir.path().join("old.txt"), "old\n").unwrap();
+
+        let tenx = Tenx::new(config.clone());
+        let mut session = Session::new(&config).unwrap();
+        tenx.code(&mut session)?;
+        let patch = Patch::from_diff(
+            "--- a/test.txt\n+++ b/test.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n",
+        )?;
+        let info = tenx.import_patch(&mut session, patch)?;
+
+        // The import gets an action of its own, rather than joining the user's action
+        assert!(info.failures.is_empty());
+        assert_eq!(session.actions.len(), 2);
+        assert!(session.actions[0].steps.is_empty());
+        assert_eq!(session.last_action()?.steps.len(), 1);
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("test.txt")).unwrap(),
+            "one\nthree\n"
+        );
+
+        // A delete that applies and a hunk that doesn't. The failure makes the action continue,
+        // so the imported patch is rendered into the next request.
+        let patch = Patch::from_diff(concat!(
+            "diff --git a/old.txt b/old.txt\ndeleted file mode 100644\n",
+            "--- a/old.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-old\n",
+            "--- a/test.txt\n+++ b/test.txt\n@@ -1,2 +1,2 @@\n one\n-missing\n+four\n",
+        ))?;
+        let info = tenx.import_patch(&mut session, patch)?;
+        assert_eq!((info.succeeded, info.failures.len()), (1, 1));
+        assert_eq!(session.actions.len(), 3);
+        assert!(!temp_dir.path().join("old.txt").exists());
+
+        tenx.continue_steps(&mut session, None, None, None).await?;
+        assert_eq!(session.last_action()?.steps.len(), 2);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .json()
+            .to_string()
+            .contains("<delete>\\nold.txt\\n</delete>"));
+        Ok(())
+    }
 }
@@ -1,3 +1,5 @@
+pub mod mock_server;
+
 use crate::{checks::CheckResult, config, error, session::Session};
 use fs_err as fs;
 use std::path::Path;
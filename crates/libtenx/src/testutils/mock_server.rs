@@ -0,0 +1,311 @@
+//! A local stand-in for model provider APIs, used to test model providers end to end without
+//! network access. Responses are scripted up front and served in order, one per request.
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// A request received by the mock server.
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockRequest {
+    /// Returns the value of a header, matching the name case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parses the body as JSON, returning `Null` if it isn't valid.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap_or_default()
+    }
+}
+
+/// A scripted response.
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    /// A complete JSON response, with extra headers.
+    Body {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    },
+    /// A successful response streamed as server-sent events, one per data payload, followed by
+    /// `[DONE]`.
+    Stream(Vec<String>),
+    /// A successful response streamed as newline-delimited JSON, one object per line, as Ollama
+    /// does.
+    Lines(Vec<String>),
+}
+
+impl MockResponse {
+    /// A JSON response with the given status.
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        MockResponse::Body {
+            status,
+            headers: vec![],
+            body: body.to_string(),
+        }
+    }
+
+    /// Adds a header to a complete response. Streamed responses are unchanged.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        if let MockResponse::Body {
+            ref mut headers, ..
+        } = self
+        {
+            headers.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// A chat completion with the given message content, reporting 10 prompt tokens and 5
+    /// completion tokens.
+    pub fn chat(content: &str) -> Self {
+        Self::json(
+            200,
+            json!({
+                "id": "mock",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "mock",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": "stop",
+                    "logprobs": null
+                }],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+            }),
+        )
+    }
+
+    /// A streamed chat completion, sending each chunk of content as a separate event, followed by
+    /// a usage event reporting 10 prompt tokens and 5 completion tokens.
+    pub fn chat_stream(chunks: &[&str]) -> Self {
+        let mut events: Vec<String> = chunks
+            .iter()
+            .map(|chunk| {
+                json!({
+                    "id": "mock",
+                    "object": "chat.completion.chunk",
+                    "created": 0,
+                    "model": "mock",
+                    "choices": [{"index": 0, "delta": {"content": chunk}, "finish_reason": null}]
+                })
+                .to_string()
+            })
+            .collect();
+        events.push(
+            json!({
+                "id": "mock",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "mock",
+                "choices": [],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+            })
+            .to_string(),
+        );
+        MockResponse::Stream(events)
+    }
+
+    /// An error response in the OpenAI format.
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(
+            status,
+            json!({"error": {"message": message, "type": "mock_error", "param": null, "code": null}}),
+        )
+    }
+
+    /// A 429 rate limit response, with a `Retry-After` header if `retry_after` is set.
+    pub fn rate_limited(retry_after: Option<u64>) -> Self {
+        let resp = Self::error(429, "Rate limit reached");
+        match retry_after {
+            Some(secs) => resp.with_header("Retry-After", &secs.to_string()),
+            None => resp,
+        }
+    }
+}
+
+/// A local HTTP server that answers each request with the next scripted response. The server
+/// stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a server on a free local port. Once the scripted responses run out, requests get a
+    /// 500 error.
+    pub async fn start(responses: Vec<MockResponse>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requests.clone();
+        let task = tokio::spawn(async move {
+            let mut responses = responses.into_iter();
+            // Connections are served one at a time, so responses go out in order
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let Ok(request) = read_request(&mut stream).await else {
+                    continue;
+                };
+                log.lock().unwrap().push(request);
+                let response = responses
+                    .next()
+                    .unwrap_or_else(|| MockResponse::error(500, "No more scripted responses"));
+                let _ = write_response(&mut stream, &response).await;
+            }
+        });
+
+        Ok(Self {
+            addr,
+            requests,
+            task,
+        })
+    }
+
+    /// The base URL of the API, to use as a provider's `api_base`.
+    pub fn url(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    /// The root URL of the server, for providers that add the API path themselves.
+    pub fn root_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Reads a single HTTP/1.1 request, with a body delimited by `Content-Length`.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<MockRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let mut body = buf[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(MockRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+/// Writes a response and closes the connection.
+async fn write_response(stream: &mut TcpStream, response: &MockResponse) -> std::io::Result<()> {
+    match response {
+        MockResponse::Body {
+            status,
+            headers,
+            body,
+        } => {
+            let mut out = format!(
+                "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+                reason(*status),
+                body.len()
+            );
+            for (name, value) in headers {
+                out.push_str(&format!("{name}: {value}\r\n"));
+            }
+            out.push_str("\r\n");
+            out.push_str(body);
+            stream.write_all(out.as_bytes()).await?;
+        }
+        MockResponse::Stream(events) => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            for event in events.iter().map(String::as_str).chain(["[DONE]"]) {
+                stream
+                    .write_all(format!("data: {event}\n\n").as_bytes())
+                    .await?;
+                stream.flush().await?;
+            }
+        }
+        MockResponse::Lines(lines) => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            for line in lines {
+                stream.write_all(format!("{line}\n").as_bytes()).await?;
+                stream.flush().await?;
+            }
+        }
+    }
+    stream.shutdown().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
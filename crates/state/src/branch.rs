@@ -0,0 +1,334 @@
+//! Named checkpoints, and alternate branches of a state's history.
+//!
+//! A state's history is the list of snapshots taken by its patches. Forking starts a new branch
+//! from any point in that history, setting the current branch aside along with the content of
+//! the files it touched. Switching branches sets the current branch aside in the same way, undoes
+//! its snapshots back to the point where the two branches diverge, and restores the files of the
+//! branch being checked out. Snapshot IDs are unique across branches, so rollback IDs and
+//! checkpoints keep their meaning whichever branch is checked out.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    journal::BlobId,
+    Snapshot, State,
+};
+
+/// The name of the branch a state starts out on.
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// A branch that isn't checked out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Branch {
+    /// The branch's history, including snapshots shared with other branches
+    pub snapshots: Vec<(u64, Snapshot)>,
+    /// The content of every file the branch's snapshots touched, as of when the branch was set
+    /// aside. None if the file didn't exist.
+    pub tip: BTreeMap<PathBuf, Option<BlobId>>,
+}
+
+impl Branch {
+    /// Returns the IDs of all journal blobs the branch references.
+    pub fn blobs(&self) -> impl Iterator<Item = &BlobId> {
+        self.snapshots
+            .iter()
+            .flat_map(|(_, snap)| snap.content.values())
+            .chain(self.tip.values().flatten())
+    }
+}
+
+impl State {
+    /// The name of the checked out branch.
+    pub fn branch(&self) -> &str {
+        self.current_branch.as_deref().unwrap_or(DEFAULT_BRANCH)
+    }
+
+    /// The names of all branches, including the checked out one, sorted.
+    pub fn branches(&self) -> Vec<String> {
+        let mut names: BTreeSet<String> = self.branches.keys().cloned().collect();
+        names.insert(self.branch().to_string());
+        names.into_iter().collect()
+    }
+
+    /// Records a named checkpoint at the current point in history, and returns its snapshot ID.
+    /// The ID can be passed to `fork` to start a branch from the checkpoint. An existing
+    /// checkpoint with the same name is moved.
+    pub fn checkpoint(&mut self, name: &str) -> Result<u64> {
+        let id = self.mark()?;
+        self.checkpoints.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    /// Named checkpoints on all branches, mapped to their snapshot IDs.
+    pub fn checkpoints(&self) -> &BTreeMap<String, u64> {
+        &self.checkpoints
+    }
+
+    /// Returns the snapshot ID of a named checkpoint.
+    pub fn checkpoint_id(&self, name: &str) -> Result<u64> {
+        self.checkpoints
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::NotFound {
+                msg: "Checkpoint not found".to_string(),
+                path: name.to_string(),
+            })
+    }
+
+    /// Starts a new branch from the point just before snapshot `from` was taken, and checks it
+    /// out. The current branch, including the snapshots from `from` on, is set aside and can be
+    /// switched back to.
+    pub fn fork(&mut self, name: &str, from: u64) -> Result<()> {
+        self.check_branching()?;
+        if self.branches().iter().any(|b| b == name) {
+            return Err(Error::Branch(format!("branch {name} already exists")));
+        }
+        if !self.snapshots.iter().any(|(id, _)| *id == from) {
+            return Err(Error::NotFound {
+                msg: "Snapshot not found on the current branch".to_string(),
+                path: from.to_string(),
+            });
+        }
+
+        let aside = self.set_aside()?;
+        self.branches.insert(self.branch().to_string(), aside);
+        self.rewind(from)?;
+        self.current_branch = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Checks out another branch, setting the current one aside.
+    pub fn switch(&mut self, name: &str) -> Result<()> {
+        self.check_branching()?;
+        if name == self.branch() {
+            return Ok(());
+        }
+        let target = self.branches.remove(name).ok_or_else(|| Error::NotFound {
+            msg: "Branch not found".to_string(),
+            path: name.to_string(),
+        })?;
+
+        let aside = match self.set_aside() {
+            Ok(aside) => aside,
+            Err(e) => {
+                self.branches.insert(name.to_string(), target);
+                return Err(e);
+            }
+        };
+        self.branches.insert(self.branch().to_string(), aside);
+
+        // Undo the current branch back to where it diverges from the target
+        let diverged = self
+            .snapshots
+            .iter()
+            .zip(
+                target
+                    .snapshots
+                    .iter()
+                    .map(Some)
+                    .chain(std::iter::repeat(None)),
+            )
+            .find(|((id, _), other)| other.is_none_or(|(other_id, _)| other_id != id))
+            .map(|((id, _), _)| *id);
+        if let Some(id) = diverged {
+            self.rewind(id)?;
+        }
+
+        for (path, blob) in &target.tip {
+            match blob {
+                Some(blob) => {
+                    let content = self.blob(blob)?;
+                    self.write(path, &content)?;
+                }
+                None => match self.remove(path) {
+                    Ok(()) | Err(Error::NotFound { .. }) => {}
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        let paths: Vec<PathBuf> = target.tip.keys().cloned().collect();
+        self.snapshots = target.snapshots;
+        self.current_branch = (name != DEFAULT_BRANCH).then(|| name.to_string());
+        self.observe(&paths)
+    }
+
+    /// Deletes a branch that isn't checked out, along with checkpoints that were only on it.
+    pub fn delete_branch(&mut self, name: &str) -> Result<()> {
+        if name == self.branch() {
+            return Err(Error::Branch(format!(
+                "can't delete the checked out branch {name}"
+            )));
+        }
+        self.branches.remove(name).ok_or_else(|| Error::NotFound {
+            msg: "Branch not found".to_string(),
+            path: name.to_string(),
+        })?;
+        self.prune_checkpoints();
+        self.journal.retain(&self.referenced_blobs());
+        Ok(())
+    }
+
+    /// Drops checkpoints whose snapshots are no longer in any branch's history.
+    pub(crate) fn prune_checkpoints(&mut self) {
+        let live: HashSet<u64> = self
+            .snapshots
+            .iter()
+            .chain(self.branches.values().flat_map(|b| b.snapshots.iter()))
+            .map(|(id, _)| *id)
+            .collect();
+        self.checkpoints.retain(|_, id| live.contains(id));
+    }
+
+    /// Branching rewrites files without going through patches, which a git store can't track.
+    fn check_branching(&self) -> Result<()> {
+        if self.git.is_some() {
+            return Err(Error::Branch(
+                "branches are not supported for git-backed state, use git branches instead"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Captures the current branch's history and the content of every file it touched.
+    fn set_aside(&mut self) -> Result<Branch> {
+        let paths: BTreeSet<PathBuf> = self
+            .snapshots
+            .iter()
+            .flat_map(|(_, snap)| snap.affected())
+            .collect();
+        let mut tip = BTreeMap::new();
+        for path in paths {
+            let blob = match self.read_opt(&path)? {
+                Some(content) => Some(self.journal.put(&content)?),
+                None => None,
+            };
+            tip.insert(path, blob);
+        }
+        Ok(Branch {
+            snapshots: self.snapshots.clone(),
+            tip,
+        })
+    }
+
+    /// Undoes snapshots from `from` on, newest first, and drops them from the history.
+    fn rewind(&mut self, from: u64) -> Result<()> {
+        let undone: Vec<(u64, Snapshot)> = self
+            .snapshots
+            .iter()
+            .filter(|(id, _)| *id >= from)
+            .cloned()
+            .collect();
+        let paths: Vec<PathBuf> = undone.iter().flat_map(|(_, s)| s.affected()).collect();
+        for (_, snap) in undone.into_iter().rev() {
+            self.revert_snapshot(snap)?;
+        }
+        self.snapshots.retain(|(id, _)| *id < from);
+        self.observe(&paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{abspath::AbsPath, Patch};
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn read(state: &State, path: &str) -> Option<String> {
+        state.read_opt(Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn test_branches() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        std::fs::write(temp_dir.path().join("a.txt"), "base\n").unwrap();
+        let mut state = State::default().with_directory(root, vec![]).unwrap();
+        assert_eq!(state.branch(), DEFAULT_BRANCH);
+
+        let setup = state
+            .patch(&Patch::default().with_write("::setup", "setup"))
+            .unwrap()
+            .rollback_id;
+        let start = state.checkpoint("start").unwrap();
+        state
+            .patch(
+                &Patch::default()
+                    .with_replace("a.txt", "base", "first")
+                    .with_write("first.txt", "1")
+                    .with_write("::mem", "first"),
+            )
+            .unwrap();
+
+        // Forking from the checkpoint undoes the first approach
+        state
+            .fork("second", state.checkpoint_id("start").unwrap())
+            .unwrap();
+        assert_eq!(state.branch(), "second");
+        assert_eq!(state.branches(), vec!["main", "second"]);
+        assert_eq!(read(&state, "a.txt").unwrap(), "base\n");
+        assert_eq!(read(&state, "first.txt"), None);
+        assert_eq!(read(&state, "::mem"), None);
+        assert!(!state.was_modified_since(setup));
+
+        state
+            .patch(&Patch::default().with_replace("a.txt", "base", "second"))
+            .unwrap();
+        assert!(state.was_modified_since(setup));
+
+        // Switching restores each branch's files
+        state.switch("main").unwrap();
+        assert_eq!(read(&state, "a.txt").unwrap(), "first\n");
+        assert_eq!(read(&state, "first.txt").unwrap(), "1");
+        assert_eq!(read(&state, "::mem").unwrap(), "first");
+        state.switch("second").unwrap();
+        assert_eq!(read(&state, "a.txt").unwrap(), "second\n");
+        assert_eq!(read(&state, "first.txt"), None);
+
+        // Rollback IDs from before the fork still work on the new branch
+        state.revert(setup).unwrap();
+        assert_eq!(read(&state, "::setup"), None);
+        assert_eq!(read(&state, "a.txt").unwrap(), "second\n");
+        assert_eq!(state.checkpoint_id("start").unwrap(), start);
+
+        assert!(state.fork("main", 0).is_err());
+        assert!(state.switch("missing").is_err());
+        assert!(state.delete_branch("second").is_err());
+        state.switch("main").unwrap();
+        assert_eq!(read(&state, "::setup").unwrap(), "setup");
+        state.delete_branch("second").unwrap();
+        assert_eq!(state.branches(), vec!["main"]);
+        assert_eq!(read(&state, "a.txt").unwrap(), "first\n");
+    }
+
+    #[test]
+    fn test_checkpoints_pruned() {
+        let mut state = State::default();
+        state
+            .patch(&Patch::default().with_write("::a", "a"))
+            .unwrap();
+        state.checkpoint("first").unwrap();
+        let fork = state
+            .patch(&Patch::default().with_write("::a", "b"))
+            .unwrap()
+            .rollback_id;
+        state.checkpoint("second").unwrap();
+        state.fork("other", fork).unwrap();
+        assert_eq!(state.checkpoints().len(), 2);
+
+        // Checkpoints only on a deleted branch go with it
+        state.delete_branch("main").unwrap();
+        assert_eq!(
+            state.checkpoints().keys().collect::<Vec<_>>(),
+            vec!["first"]
+        );
+        assert!(state.checkpoint_id("second").is_err());
+        assert_eq!(read(&state, "::a").unwrap(), "a");
+    }
+}
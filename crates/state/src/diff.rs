@@ -0,0 +1,337 @@
+//! Structured diffs between two points in a state's history.
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MEM_PREFIX;
+
+/// A single line in a diff hunk, without its trailing newline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffLine {
+    Context(String),
+    Added(String),
+    Removed(String),
+}
+
+/// A contiguous block of changes. Line numbers are 1-based, as in unified diff headers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffHunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+/// How a file changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileStatus {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// The changes to a single file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    pub status: FileStatus,
+    pub hunks: Vec<DiffHunk>,
+    /// Number of lines added
+    pub added: usize,
+    /// Number of lines removed
+    pub removed: usize,
+    /// The changes in unified diff format
+    pub unified: String,
+    /// The git mode of the file after the change, like `0o100755` for an executable, if it's
+    /// known. Only files that exist on disk have a mode.
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+impl FileDiff {
+    /// Compares two versions of a file, where None means the file doesn't exist. Returns None if
+    /// the versions are identical.
+    pub(crate) fn new(path: &Path, old: Option<&str>, new: Option<&str>) -> Option<Self> {
+        let status = match (old, new) {
+            (None, None) => return None,
+            (None, Some(_)) => FileStatus::Added,
+            (Some(_), None) => FileStatus::Removed,
+            (Some(old), Some(new)) if old == new => return None,
+            (Some(_), Some(_)) => FileStatus::Modified,
+        };
+
+        let name = path.display().to_string();
+        let old_name = match status {
+            FileStatus::Added => "/dev/null".to_string(),
+            _ => header_path(&format!("a/{name}")),
+        };
+        let new_name = match status {
+            FileStatus::Removed => "/dev/null".to_string(),
+            _ => header_path(&format!("b/{name}")),
+        };
+        let patch = diffy::create_patch(old.unwrap_or_default(), new.unwrap_or_default());
+        // diffy escapes file names differently from git, so the file headers are written here
+        let rendered = patch.to_string();
+        let hunks_text = rendered.splitn(3, '\n').nth(2).unwrap_or_default();
+        let unified = format!("--- {old_name}\n+++ {new_name}\n{hunks_text}");
+
+        let mut added = 0;
+        let mut removed = 0;
+        let hunks = patch
+            .hunks()
+            .iter()
+            .map(|hunk| {
+                let lines = hunk
+                    .lines()
+                    .iter()
+                    .map(|line| match line {
+                        diffy::Line::Context(text) => DiffLine::Context(strip(text)),
+                        diffy::Line::Insert(text) => {
+                            added += 1;
+                            DiffLine::Added(strip(text))
+                        }
+                        diffy::Line::Delete(text) => {
+                            removed += 1;
+                            DiffLine::Removed(strip(text))
+                        }
+                    })
+                    .collect();
+                DiffHunk {
+                    old_start: hunk.old_range().start(),
+                    old_len: hunk.old_range().len(),
+                    new_start: hunk.new_range().start(),
+                    new_len: hunk.new_range().len(),
+                    lines,
+                }
+            })
+            .collect();
+
+        Some(FileDiff {
+            path: path.to_path_buf(),
+            status,
+            hunks,
+            added,
+            removed,
+            unified,
+            mode: None,
+        })
+    }
+
+    /// The changes in git's patch format, which adds a `diff --git` header so that `git apply`
+    /// can create and delete files. Created files get a mode line if their mode is known, which
+    /// git needs to create empty files.
+    pub fn git(&self) -> String {
+        let name = self.path.display().to_string();
+        let mut out = format!(
+            "diff --git {} {}\n",
+            quote_path(&format!("a/{name}")),
+            quote_path(&format!("b/{name}"))
+        );
+        if let (FileStatus::Added, Some(mode)) = (self.status, self.mode) {
+            out.push_str(&format!("new file mode {mode:o}\n"));
+        }
+        // An empty file has no hunks, and git expects no file headers either
+        if !self.hunks.is_empty() {
+            out.push_str(&self.unified);
+        }
+        out
+    }
+}
+
+/// Quotes a path the way git does in patch headers: paths with control characters, quotes,
+/// backslashes or non-ASCII characters are put in double quotes, with C-style escapes.
+pub(crate) fn quote_path(path: &str) -> String {
+    let needs_quotes = |b: &u8| *b < 0x20 || *b >= 0x7f || *b == b'"' || *b == b'\\';
+    if !path.bytes().any(|b| needs_quotes(&b)) {
+        return path.to_string();
+    }
+    let mut out = String::from("\"");
+    for b in path.bytes() {
+        match b {
+            b'\x07' => out.push_str("\\a"),
+            b'\x08' => out.push_str("\\b"),
+            b'\t' => out.push_str("\\t"),
+            b'\n' => out.push_str("\\n"),
+            b'\x0b' => out.push_str("\\v"),
+            b'\x0c' => out.push_str("\\f"),
+            b'\r' => out.push_str("\\r"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b if needs_quotes(&b) => out.push_str(&format!("\\{b:03o}")),
+            b => out.push(b as char),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Reverses `quote_path`. Paths that aren't quoted are returned unchanged.
+pub(crate) fn unquote_path(path: &str) -> String {
+    let Some(inner) = path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) else {
+        return path.to_string();
+    };
+    let mut bytes = Vec::new();
+    let mut chars = inner.bytes().peekable();
+    while let Some(b) = chars.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        match chars.next() {
+            Some(b'a') => bytes.push(0x07),
+            Some(b'b') => bytes.push(0x08),
+            Some(b't') => bytes.push(b'\t'),
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b'v') => bytes.push(0x0b),
+            Some(b'f') => bytes.push(0x0c),
+            Some(b'r') => bytes.push(b'\r'),
+            Some(d @ b'0'..=b'7') => {
+                let mut value = u32::from(d - b'0');
+                for _ in 0..2 {
+                    if let Some(d) = chars.next_if(|d| (b'0'..=b'7').contains(d)) {
+                        value = value * 8 + u32::from(d - b'0');
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            Some(other) => bytes.push(other),
+            None => bytes.push(b'\\'),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Formats a path for a `---` or `+++` header as git does, quoted if needed, and followed by a
+/// tab if it contains a space so that the end of the name is unambiguous.
+fn header_path(path: &str) -> String {
+    let quoted = quote_path(path);
+    if path.contains(' ') {
+        format!("{quoted}\t")
+    } else {
+        quoted
+    }
+}
+
+fn strip(text: &str) -> String {
+    text.strip_suffix('\n').unwrap_or(text).to_string()
+}
+
+/// The changes to a set of files, as returned by `State::diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diff {
+    pub files: Vec<FileDiff>,
+}
+
+impl Diff {
+    /// Returns true if no files changed.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Total number of lines added across all files.
+    pub fn added(&self) -> usize {
+        self.files.iter().map(|f| f.added).sum()
+    }
+
+    /// Total number of lines removed across all files.
+    pub fn removed(&self) -> usize {
+        self.files.iter().map(|f| f.removed).sum()
+    }
+
+    /// All changes in unified diff format.
+    pub fn unified(&self) -> String {
+        self.files.iter().map(|f| f.unified.as_str()).collect()
+    }
+
+    /// All changes as a patch file that `git apply` accepts, and that `Patch::from_diff` can
+    /// import. In-memory files are left out, since they don't exist on disk.
+    pub fn git(&self) -> String {
+        self.files
+            .iter()
+            .filter(|f| !f.path.to_string_lossy().starts_with(MEM_PREFIX))
+            .map(|f| f.git())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_file_diff() {
+        let path = Path::new("src/lib.rs");
+        assert_eq!(FileDiff::new(path, Some("a\n"), Some("a\n")), None);
+        assert_eq!(FileDiff::new(path, None, None), None);
+
+        let diff = FileDiff::new(path, Some("a\nb\nc\n"), Some("a\nB\nc\nd\n")).unwrap();
+        assert_eq!(diff.status, FileStatus::Modified);
+        assert_eq!((diff.added, diff.removed), (2, 1));
+        assert_eq!(
+            diff.hunks,
+            vec![DiffHunk {
+                old_start: 1,
+                old_len: 3,
+                new_start: 1,
+                new_len: 4,
+                lines: vec![
+                    DiffLine::Context("a".into()),
+                    DiffLine::Removed("b".into()),
+                    DiffLine::Added("B".into()),
+                    DiffLine::Context("c".into()),
+                    DiffLine::Added("d".into()),
+                ],
+            }]
+        );
+        assert_eq!(
+            diff.unified,
+            "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,3 +1,4 @@\n a\n-b\n+B\n c\n+d\n"
+        );
+
+        let diff = FileDiff::new(path, None, Some("new")).unwrap();
+        assert_eq!(diff.status, FileStatus::Added);
+        assert!(diff
+            .unified
+            .starts_with("--- /dev/null\n+++ b/src/lib.rs\n"));
+        assert!(diff.unified.contains("\\ No newline at end of file"));
+
+        let diff = FileDiff::new(path, Some("old\n"), None).unwrap();
+        assert_eq!(diff.status, FileStatus::Removed);
+        assert_eq!((diff.added, diff.removed), (0, 1));
+        assert!(diff.unified.contains("+++ /dev/null"));
+        assert!(!diff.git().contains("mode"));
+
+        let mut diff = FileDiff::new(Path::new("bin/run me"), None, Some("")).unwrap();
+        assert_eq!(diff.git(), "diff --git a/bin/run me b/bin/run me\n");
+        diff.mode = Some(0o100755);
+        assert_eq!(
+            diff.git(),
+            "diff --git a/bin/run me b/bin/run me\nnew file mode 100755\n"
+        );
+        let diff = FileDiff::new(Path::new("run me"), Some("a\n"), Some("b\n")).unwrap();
+        assert!(diff
+            .unified
+            .starts_with("--- a/run me\t\n+++ b/run me\t\n@@"));
+    }
+
+    #[test]
+    fn test_quote_path() {
+        for (path, quoted) in [
+            ("a/src/lib.rs", "a/src/lib.rs"),
+            ("a/with space", "a/with space"),
+            ("a/tab\there", "\"a/tab\\there\""),
+            ("a/\"quoted\"", "\"a/\\\"quoted\\\"\""),
+            ("a/back\\slash", "\"a/back\\\\slash\""),
+            ("a/café", "\"a/caf\\303\\251\""),
+        ] {
+            assert_eq!(quote_path(path), quoted);
+            assert_eq!(unquote_path(quoted), path);
+        }
+        let diff = FileDiff::new(Path::new("tab\there"), Some("a\n"), Some("b\n")).unwrap();
+        assert!(diff
+            .git()
+            .starts_with("diff --git \"a/tab\\there\" \"b/tab\\there\"\n--- \"a/tab\\there\"\n"));
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeSet,
     fs,
     path::{absolute, Path, PathBuf},
 };
@@ -13,27 +14,89 @@ use crate::{
 use super::files;
 use super::SubStore;
 
-/// A file system directory
+/// The number of leading bytes checked for NUL bytes when deciding whether a file is binary.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// The line endings of a file's content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineEndings {
+    Lf,
+    Crlf,
+    /// Both LF and CRLF line endings, which are kept byte-for-byte
+    Mixed,
+}
+
+impl LineEndings {
+    fn detect(bytes: &[u8]) -> Self {
+        let lf = bytes.iter().filter(|b| **b == b'\n').count();
+        let crlf = bytes.windows(2).filter(|w| *w == b"\r\n").count();
+        if crlf == 0 {
+            LineEndings::Lf
+        } else if crlf == lf {
+            LineEndings::Crlf
+        } else {
+            LineEndings::Mixed
+        }
+    }
+}
+
+/// Decodes file content as text. Files with NUL bytes near the start are taken to be binary.
+/// CRLF line endings are normalized to LF, unless the file mixes line endings, in which case the
+/// text is returned unchanged so that it's written back byte-for-byte.
+fn decode(bytes: Vec<u8>, path: &Path) -> Result<String> {
+    let not_text = || Error::NotText {
+        path: path.display().to_string(),
+    };
+    if bytes[..bytes.len().min(BINARY_CHECK_LEN)].contains(&0) {
+        return Err(not_text());
+    }
+    let endings = LineEndings::detect(&bytes);
+    let text = String::from_utf8(bytes).map_err(|_| not_text())?;
+    Ok(if endings == LineEndings::Crlf {
+        text.replace("\r\n", "\n")
+    } else {
+        text
+    })
+}
+
+/// A file system directory. Files are read as UTF-8 text with LF line endings, and files with CRLF
+/// line endings keep them when they are overwritten. Files with mixed line endings are read and
+/// written unchanged.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Directory {
     pub root: AbsPath,
     globs: Vec<String>,
+    /// Files with CRLF line endings that were removed, relative to the root, so they are restored
+    /// with CRLF line endings if they are written again, for instance when a deletion is reverted
+    #[serde(default)]
+    crlf: BTreeSet<PathBuf>,
 }
 
 impl Directory {
     pub fn new(root: AbsPath, globs: Vec<String>) -> Result<Self> {
-        Ok(Self { root, globs })
+        Ok(Self {
+            root,
+            globs,
+            crlf: BTreeSet::new(),
+        })
     }
 
-    /// Converts a path relative to the root directory to an absolute path
-    fn abspath(&self, path: &Path) -> Result<PathBuf> {
-        // First normalize the path to ensure it doesn't escape the root
-        let normalized = files::normalize_path(
+    /// Normalizes a path to the path relative to the root directory that it refers to. Absolute
+    /// paths inside the root and Windows-style separators are accepted, and paths that escape the
+    /// root are an error.
+    pub(crate) fn relpath(&self, path: &Path) -> Result<PathBuf> {
+        files::normalize_path(
             self.root.clone(),
             self.root.clone(),
             path.to_str()
                 .ok_or_else(|| Error::Path("Invalid path encoding".to_string()))?,
-        )?;
+        )
+    }
+
+    /// Converts a path relative to the root directory to an absolute path
+    fn abspath(&self, path: &Path) -> Result<PathBuf> {
+        // First normalize the path to ensure it doesn't escape the root
+        let normalized = self.relpath(path)?;
 
         let p = PathBuf::from(&*self.root).join(normalized);
         absolute(p.clone())
@@ -51,6 +114,29 @@ impl Directory {
         files::list_files(self.root.clone(), self.globs.clone())
     }
 
+    /// Returns true if `list` would include the path, which may be a file or a directory. This
+    /// only reads the directories leading to the path.
+    pub fn is_listed(&self, path: &Path) -> Result<bool> {
+        files::is_listed(self.root.clone(), self.globs.clone(), path)
+    }
+
+    /// Returns a filter for paths relative to the root that keeps the paths the directory could
+    /// contain: listed paths, and removed paths whose parent directory is listed.
+    pub(crate) fn filter(&self) -> impl Fn(&Path) -> bool + Send + 'static {
+        let dir = self.clone();
+        move |path| {
+            if dir.root.join(path).exists() {
+                return dir.is_listed(path).unwrap_or(false);
+            }
+            match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => {
+                    dir.is_listed(parent).unwrap_or(false)
+                }
+                _ => true,
+            }
+        }
+    }
+
     /// Gets the content of a file by converting the input path to an absolute path and reading it.
     pub fn read(&self, path: &Path) -> Result<String> {
         let abs_path = self.abspath(path)?;
@@ -60,14 +146,42 @@ impl Directory {
                 path: path.display().to_string(),
             });
         }
-        fs::read_to_string(&abs_path).map_err(|e| {
+        let bytes = fs::read(&abs_path).map_err(|e| {
             Error::Internal(format!("Could not read file {}: {}", abs_path.display(), e))
-        })
+        })?;
+        decode(bytes, path)
     }
 
     /// Writes content to a file, creating it if it doesn't exist or overwriting if it does.
+    ///
+    /// When overwriting, a file with CRLF line endings keeps them, and a file that ended with a
+    /// newline still does. Binary and non-UTF-8 files are never overwritten.
     pub fn write(&mut self, path: &Path, content: &str) -> Result<()> {
-        let abs_path = self.abspath(path)?;
+        let relpath = self.relpath(path)?;
+        let abs_path = self.abspath(&relpath)?;
+        let mut content = content.to_string();
+        let crlf = if abs_path.exists() {
+            let existing = fs::read(&abs_path).map_err(|e| {
+                Error::Internal(format!("Could not read file {}: {}", abs_path.display(), e))
+            })?;
+            let endings = LineEndings::detect(&existing);
+            if existing.ends_with(b"\n") && !content.is_empty() && !content.ends_with('\n') {
+                // A file with mixed line endings is written unchanged, so it needs the original
+                // line ending
+                if endings == LineEndings::Mixed && existing.ends_with(b"\r\n") {
+                    content.push('\r');
+                }
+                content.push('\n');
+            }
+            decode(existing, path)?;
+            endings == LineEndings::Crlf
+        } else {
+            self.crlf.contains(&relpath)
+        };
+        if crlf {
+            content = content.replace("\r\n", "\n").replace('\n', "\r\n");
+        }
+        self.crlf.remove(&relpath);
         if let Some(parent) = abs_path.parent() {
             fs::create_dir_all(parent).map_err(|e| {
                 Error::Internal(format!(
@@ -81,15 +195,51 @@ impl Directory {
             .map_err(|e| Error::Internal(format!("Could not write file {}: {}", path.display(), e)))
     }
 
-    /// Removes a file by joining the given path with the root directory.
+    /// Returns the git mode of a file: `0o100755` if it's executable, and `0o100644` otherwise.
+    /// Returns None if the file doesn't exist.
+    pub fn mode(&self, path: &Path) -> Option<u32> {
+        let meta = fs::metadata(self.abspath(path).ok()?).ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if meta.permissions().mode() & 0o111 != 0 {
+                return Some(0o100755);
+            }
+        }
+        Some(0o100644)
+    }
+
+    /// Checks that `write` would accept the path: it must stay inside the root, and an existing
+    /// file there must be text.
+    pub fn check_write(&self, path: &Path) -> Result<()> {
+        let abs_path = self.abspath(path)?;
+        if abs_path.exists() {
+            let existing = fs::read(&abs_path).map_err(|e| {
+                Error::Internal(format!("Could not read file {}: {}", abs_path.display(), e))
+            })?;
+            decode(existing, path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a file by converting the input path to an absolute path and deleting it.
     pub fn remove(&mut self, path: &Path) -> Result<()> {
-        let abs_path = self.root.join(path);
+        let relpath = self.relpath(path)?;
+        let abs_path = self.abspath(&relpath)?;
         if !abs_path.exists() {
             return Err(Error::NotFound {
                 msg: "File not found".to_string(),
                 path: path.display().to_string(),
             });
         }
+        if let Ok(existing) = fs::read(&abs_path) {
+            if LineEndings::detect(&existing) == LineEndings::Crlf {
+                self.crlf.insert(relpath);
+            }
+        }
         fs::remove_file(&abs_path).map_err(|e| {
             Error::Internal(format!(
                 "Could not remove file {}: {}",
@@ -116,6 +266,10 @@ impl SubStore for Directory {
     fn remove(&mut self, path: &Path) -> Result<()> {
         self.remove(path)
     }
+
+    fn check_write(&self, path: &Path) -> Result<()> {
+        self.check_write(path)
+    }
 }
 
 #[cfg(test)]
@@ -208,12 +362,102 @@ mod tests {
         if dir.read(Path::new("link")).is_ok() {
             println!("Warning: Symlinks can escape root directory");
         }
-        if dir.write(Path::new("link"), "modified").is_ok() {
-            if std::fs::read_to_string(&outside_file).unwrap() == "modified" {
-                println!("Warning: Can write through symlinks");
-            }
+        if dir.write(Path::new("link"), "modified").is_ok()
+            && std::fs::read_to_string(&outside_file).unwrap() == "modified"
+        {
+            println!("Warning: Can write through symlinks");
         }
 
         Ok(())
     }
+
+    #[test]
+    fn test_directory_text_handling() -> Result<()> {
+        let temp_dir = TempDir::new().expect("failed to create temp dir");
+        let root = AbsPath::new(temp_dir.path().to_path_buf())?;
+        let mut dir = Directory::new(root, vec![])?;
+        let disk = |name: &str| std::fs::read(temp_dir.path().join(name)).unwrap();
+
+        // CRLF files are read with LF line endings, and keep CRLF when written
+        std::fs::write(temp_dir.path().join("crlf.txt"), "a\r\nb\r\n")?;
+        assert_eq!(dir.read(Path::new("crlf.txt"))?, "a\nb\n");
+        dir.write(Path::new("crlf.txt"), "a\nb\nc\n")?;
+        assert_eq!(disk("crlf.txt"), b"a\r\nb\r\nc\r\n");
+
+        // A removed CRLF file is restored with CRLF line endings, however its path is spelled
+        dir.remove(Path::new("./crlf.txt"))?;
+        dir.write(Path::new("crlf.txt"), "a\n")?;
+        assert_eq!(disk("crlf.txt"), b"a\r\n");
+        dir.write(Path::new("crlf.txt"), "a\nb")?;
+        assert_eq!(disk("crlf.txt"), b"a\r\nb\r\n");
+
+        // Files with mixed line endings round-trip byte-for-byte, and aren't converted to CRLF
+        let mixed = b"a\r\nb\nc\r\n";
+        std::fs::write(temp_dir.path().join("mixed.txt"), mixed)?;
+        let text = dir.read(Path::new("mixed.txt"))?;
+        assert_eq!(text.as_bytes(), mixed);
+        dir.write(Path::new("mixed.txt"), &text)?;
+        assert_eq!(disk("mixed.txt"), mixed);
+        dir.write(Path::new("mixed.txt"), &text.replace('b', "x"))?;
+        assert_eq!(disk("mixed.txt"), b"a\r\nx\nc\r\n");
+        dir.write(Path::new("mixed.txt"), "a\r\nb\nc")?;
+        assert_eq!(disk("mixed.txt"), mixed);
+        dir.remove(Path::new("mixed.txt"))?;
+        dir.write(Path::new("mixed.txt"), &text)?;
+        assert_eq!(disk("mixed.txt"), mixed);
+
+        // A file that ended with a newline keeps it
+        std::fs::write(temp_dir.path().join("nl.txt"), "a\n")?;
+        dir.write(Path::new("nl.txt"), "b")?;
+        assert_eq!(disk("nl.txt"), b"b\n");
+        dir.write(Path::new("nl.txt"), "")?;
+        assert_eq!(disk("nl.txt"), b"");
+        std::fs::write(temp_dir.path().join("no_nl.txt"), "a")?;
+        dir.write(Path::new("no_nl.txt"), "b\n\n")?;
+        assert_eq!(disk("no_nl.txt"), b"b\n\n");
+        dir.write(Path::new("new.txt"), "new\n")?;
+        assert_eq!(disk("new.txt"), b"new\n");
+
+        // Binary and non-UTF-8 files can't be read or overwritten
+        std::fs::write(temp_dir.path().join("bin"), [0x7f, b'E', b'L', b'F', 0, 1])?;
+        std::fs::write(temp_dir.path().join("latin1.txt"), [b'c', b'a', b'f', 0xe9])?;
+        for name in ["bin", "latin1.txt"] {
+            assert!(matches!(
+                dir.read(Path::new(name)),
+                Err(Error::NotText { .. })
+            ));
+            assert!(matches!(
+                dir.write(Path::new(name), "text"),
+                Err(Error::NotText { .. })
+            ));
+        }
+        assert_eq!(disk("bin"), [0x7f, b'E', b'L', b'F', 0, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_filter() -> Result<()> {
+        let temp_dir = TempDir::new().expect("failed to create temp dir");
+        let root = AbsPath::new(temp_dir.path().to_path_buf())?;
+        std::fs::create_dir_all(temp_dir.path().join("src"))?;
+        std::fs::create_dir_all(temp_dir.path().join("target/debug"))?;
+        std::fs::write(temp_dir.path().join("src/lib.rs"), "")?;
+        std::fs::write(temp_dir.path().join("target/debug/out"), "")?;
+        let dir = Directory::new(root, vec!["!target".to_string()])?;
+
+        let filter = dir.filter();
+        for (path, keep) in [
+            ("src/lib.rs", true),
+            ("src/removed.rs", true),
+            ("removed.rs", true),
+            ("target/debug/out", false),
+            ("target/debug/removed", false),
+            ("target/gone/removed", false),
+            (".git/index", false),
+        ] {
+            assert_eq!(filter(Path::new(path)), keep, "{path}");
+        }
+        Ok(())
+    }
 }
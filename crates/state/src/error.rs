@@ -18,6 +18,28 @@ pub enum Error {
 
     #[error("Internal error: {0}")]
     Internal(String),
+
+    #[error("Git error: {0}")]
+    Git(String),
+
+    #[error("Branch error: {0}")]
+    Branch(String),
+
+    /// A patch file could not be parsed.
+    #[error("Invalid diff: {0}")]
+    Diff(String),
+
+    /// A file is binary, or not valid UTF-8, so it can't be read or edited as text.
+    #[error("Not a UTF-8 text file: {path}")]
+    NotText { path: String },
+
+    /// A merge revert could not write the reverted files, so nothing was changed.
+    #[error("Could not revert: {0}")]
+    Revert(String),
+
+    /// A file was changed outside of the state since it was last patched.
+    #[error("File changed since it was last patched: {path}\n{diff}")]
+    Conflict { path: String, diff: String },
 }
 
 impl From<std::io::Error> for Error {
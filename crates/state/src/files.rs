@@ -1,5 +1,5 @@
 //! File and path manipulation for filesystem state.
-use std::path::{Component, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use ignore::{overrides::OverrideBuilder, WalkBuilder};
 use path_clean;
@@ -83,23 +83,13 @@ where
     Ok(rel_path)
 }
 
-/// Walk project directory using ignore rules, returning all included files relative to project
-/// root.
-///
-/// Glob patterns can be positive (equivalent to --include) or negative (prefixed with `!`,
-/// equivalent to --exclude). If no glob patterns are provided, all files are included.
-///
-/// Files are sorted by path.
-pub fn list_files<R>(root: R, globs: Vec<String>) -> Result<Vec<PathBuf>>
-where
-    R: IntoAbsPath,
-{
-    let root = root.into_abs_path()?;
+/// Builds a walker over the root directory that applies the glob patterns and ignore rules.
+fn walker(root: &Path, globs: &[String]) -> Result<WalkBuilder> {
     // Build override rules from project config
-    let mut builder = OverrideBuilder::new(&root);
+    let mut builder = OverrideBuilder::new(root);
 
     // Add glob patterns directly - they're already in the correct format
-    for pattern in &globs {
+    for pattern in globs {
         builder
             .add(pattern)
             .map_err(|e| Error::Path(format!("Invalid glob pattern: {e}")))?;
@@ -113,7 +103,7 @@ where
         .map_err(|e| Error::Path(format!("Failed to build override rules: {e}")))?;
 
     // Build and configure the walker
-    let mut walker = WalkBuilder::new(&root);
+    let mut walker = WalkBuilder::new(root);
     walker
         .hidden(false) // Don't skip hidden files
         .git_ignore(true) // Respect .gitignore
@@ -121,6 +111,43 @@ where
         .git_exclude(true) // Respect .git/info/exclude
         .overrides(overrides)
         .sort_by_file_path(|a, b| a.cmp(b)); // Sort files by path
+    Ok(walker)
+}
+
+/// Returns true if the walk of `list_files` reaches `path`, relative to the root, which is a file
+/// or directory. Only the directories leading to the path are walked, so a single path is checked
+/// against the same ignore rules without listing the whole tree.
+pub fn is_listed<R>(root: R, globs: Vec<String>, path: &Path) -> Result<bool>
+where
+    R: IntoAbsPath,
+{
+    let root = root.into_abs_path()?;
+    let target = root.join(path);
+    let mut walker = walker(&root, &globs)?;
+    let filter_target = target.clone();
+    walker.filter_entry(move |e| filter_target.starts_with(e.path()));
+    for result in walker.build() {
+        let entry = result.map_err(|e| Error::Path(format!("Walk error: {e}")))?;
+        if entry.path() == target {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Walk project directory using ignore rules, returning all included files relative to project
+/// root.
+///
+/// Glob patterns can be positive (equivalent to --include) or negative (prefixed with `!`,
+/// equivalent to --exclude). If no glob patterns are provided, all files are included.
+///
+/// Files are sorted by path.
+pub fn list_files<R>(root: R, globs: Vec<String>) -> Result<Vec<PathBuf>>
+where
+    R: IntoAbsPath,
+{
+    let root = root.into_abs_path()?;
+    let walker = walker(&root, &globs)?;
 
     // Collect all files, converting to relative paths
     let mut files = Vec::new();
@@ -279,6 +306,23 @@ mod tests {
 
         assert_eq!(files, expected, "Files don't match expected list");
 
+        let globs = vec!["*.rs".to_string(), "!*.tmp".to_string()];
+        for (path, listed) in [
+            ("src/main.rs", true),
+            ("src", true),
+            ("target/debug/build.rs", false),
+            ("target", false),
+            (".git/HEAD", false),
+            ("src/missing.rs", false),
+            ("src/x.tmp", false),
+        ] {
+            assert_eq!(
+                is_listed(root.clone(), globs.clone(), Path::new(path))?,
+                listed,
+                "{path}"
+            );
+        }
+
         Ok(())
     }
 }
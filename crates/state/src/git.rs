@@ -0,0 +1,209 @@
+//! A filesystem store backed by a git worktree, where every patch becomes a commit on a branch.
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    directory::Directory,
+    error::{Error, Result},
+};
+
+use super::SubStore;
+
+/// Identity used for commits if the repository has no user configured.
+const FALLBACK_NAME: &str = "tenx";
+const FALLBACK_EMAIL: &str = "tenx@localhost";
+
+/// A git worktree. File operations are delegated to a `Directory` at the worktree root, and each
+/// patch that modifies files is committed to `branch`. Reverting the state resets the branch to
+/// the commit that preceded the reverted patches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Git {
+    pub(crate) dir: Directory,
+    /// The branch patches are committed to.
+    pub branch: String,
+    /// For each snapshot ID, the commit the branch pointed to before the patch was applied.
+    commits: Vec<(u64, String)>,
+    /// True once the worktree is known to be on `branch`.
+    #[serde(skip)]
+    on_branch: bool,
+}
+
+impl Git {
+    /// Creates a git store for the worktree at the directory's root. The worktree is left on its
+    /// current branch until `switch` is called.
+    pub fn new(dir: Directory, branch: &str) -> Result<Self> {
+        let git = Self {
+            dir,
+            branch: branch.to_string(),
+            commits: vec![],
+            on_branch: false,
+        };
+        git.run(&["rev-parse", "--is-inside-work-tree"])?;
+        Ok(git)
+    }
+
+    /// Switches the worktree to `branch`, creating it from the current HEAD if it doesn't exist.
+    /// Refuses to switch if tracked files have uncommitted changes, so the user's work is never
+    /// carried onto the branch. Does nothing once the worktree is on the branch.
+    pub fn switch(&mut self) -> Result<()> {
+        if self.on_branch {
+            return Ok(());
+        }
+        let current = self
+            .run(&["symbolic-ref", "--quiet", "--short", "HEAD"])
+            .ok();
+        if current.as_deref() == Some(self.branch.as_str()) {
+            self.on_branch = true;
+            return Ok(());
+        }
+        if !self
+            .run(&["status", "--porcelain", "--untracked-files=no"])?
+            .is_empty()
+        {
+            return Err(Error::Git(format!(
+                "the worktree has uncommitted changes - commit or stash them before switching to branch {}",
+                self.branch
+            )));
+        }
+        let exists = self
+            .run(&[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                &format!("refs/heads/{}", self.branch),
+            ])
+            .is_ok();
+        if exists {
+            self.run(&["switch", "--quiet", &self.branch])?;
+        } else {
+            self.run(&["switch", "--quiet", "-c", &self.branch])?;
+        }
+        self.on_branch = true;
+        Ok(())
+    }
+
+    /// Runs a git command in the worktree, returning its trimmed stdout.
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&*self.dir.root)
+            .output()
+            .map_err(|e| Error::Git(format!("could not run git: {e}")))?;
+        if !output.status.success() {
+            return Err(Error::Git(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Returns the commit HEAD points to, or None if the branch has no commits yet.
+    fn head(&self) -> Option<String> {
+        self.run(&["rev-parse", "--verify", "--quiet", "HEAD"]).ok()
+    }
+
+    /// Commits the given paths, recording the current HEAD as the base for snapshot `id`. No
+    /// commit is made if none of the paths changed.
+    pub(crate) fn commit(&mut self, id: u64, paths: &[PathBuf], message: &str) -> Result<()> {
+        let base = self.head().unwrap_or_default();
+        self.commits.push((id, base));
+        self.commit_paths(paths, message)
+    }
+
+    /// Stages and commits the given paths, if any of them changed. Paths are normalized against
+    /// the root like file operations are, and are taken literally rather than as pathspecs.
+    fn commit_paths(&self, paths: &[PathBuf], message: &str) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        let mut add = vec!["--literal-pathspecs", "add", "--all", "--"];
+        let path_strs = paths
+            .iter()
+            .map(|p| Ok(self.dir.relpath(p)?.display().to_string()))
+            .collect::<Result<Vec<String>>>()?;
+        add.extend(path_strs.iter().map(String::as_str));
+        self.run(&add)?;
+        if self.run(&["diff", "--cached", "--quiet"]).is_ok() {
+            return Ok(());
+        }
+
+        let mut commit = vec![];
+        if self.run(&["config", "user.email"]).is_err() {
+            commit.extend([
+                "-c".to_string(),
+                format!("user.name={FALLBACK_NAME}"),
+                "-c".to_string(),
+                format!("user.email={FALLBACK_EMAIL}"),
+            ]);
+        }
+        commit.extend([
+            "commit".to_string(),
+            "--quiet".to_string(),
+            "--no-verify".to_string(),
+            "-m".to_string(),
+            message.to_string(),
+        ]);
+        let commit: Vec<&str> = commit.iter().map(String::as_str).collect();
+        self.run(&commit)?;
+        Ok(())
+    }
+
+    /// Resets the branch to the commit preceding snapshot `first`, forgetting the commits for
+    /// snapshots `first..=last`. The worktree is left untouched, since the state has already
+    /// restored file contents. Changes from later snapshots that survive the revert are
+    /// re-committed from `surviving` paths, so the branch keeps matching the state.
+    pub(crate) fn reset(&mut self, first: u64, last: u64, surviving: &[PathBuf]) -> Result<()> {
+        let Some(base) = self
+            .commits
+            .iter()
+            .find(|(id, _)| *id >= first)
+            .map(|(_, base)| base.clone())
+        else {
+            return Ok(());
+        };
+        self.commits.retain(|(id, _)| *id > last);
+        if base.is_empty() {
+            // The branch had no commits when the first reverted patch was applied
+            self.run(&["update-ref", "-d", &format!("refs/heads/{}", self.branch)])?;
+            self.run(&["reset", "--quiet"])?;
+        } else {
+            self.run(&["reset", "--quiet", &base])?;
+        }
+        for (_, b) in self.commits.iter_mut() {
+            *b = base.clone();
+        }
+        self.commit_paths(
+            surviving,
+            &format!("tenx: revert snapshots {first} to {last}"),
+        )
+    }
+}
+
+impl SubStore for Git {
+    fn list(&self) -> Result<Vec<PathBuf>> {
+        self.dir.list()
+    }
+
+    fn read(&self, path: &Path) -> Result<String> {
+        self.dir.read(path)
+    }
+
+    fn write(&mut self, path: &Path, content: &str) -> Result<()> {
+        self.dir.write(path, content)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        self.dir.remove(path)
+    }
+
+    fn check_write(&self, path: &Path) -> Result<()> {
+        self.dir.check_write(path)
+    }
+}
@@ -0,0 +1,222 @@
+//! A content-addressed store for snapshot contents.
+//!
+//! Blobs are keyed by the hash of their content, so identical file contents captured in many
+//! snapshots are only stored once. A journal starts out holding blobs in memory, which means they
+//! are serialized along with the `State`. Once a directory is attached, blobs are written there
+//! instead and only their IDs are serialized.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// The identifier of a blob in the journal - the hex-encoded hash of its content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BlobId(String);
+
+impl BlobId {
+    /// Computes the ID for the given content.
+    pub fn of(content: &str) -> Self {
+        BlobId(blake3::hash(content.as_bytes()).to_hex().to_string())
+    }
+
+    /// Returns the hex-encoded hash.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A content-addressed blob store, either in memory or backed by a directory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Journal {
+    /// The directory blobs are persisted to. This is not serialized, and must be re-attached with
+    /// `set_dir` after a state is loaded.
+    #[serde(skip)]
+    dir: Option<PathBuf>,
+    /// Blobs held in memory, used when no directory is attached.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    blobs: HashMap<BlobId, String>,
+}
+
+/// Returns the path of a blob within a journal directory. Blobs are fanned out into
+/// subdirectories by the first two characters of their ID.
+fn blob_path(dir: &Path, id: &BlobId) -> PathBuf {
+    let (prefix, rest) = id.as_str().split_at(2.min(id.as_str().len()));
+    dir.join(prefix).join(rest)
+}
+
+/// Writes a blob to a journal directory, if it's not already there.
+fn write_blob(dir: &Path, id: &BlobId, content: &str) -> Result<()> {
+    let path = blob_path(dir, id);
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write to a temporary file first, so an interrupted write never leaves a truncated blob
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+impl Journal {
+    /// Attaches a directory to the journal. Any blobs held in memory are moved to the directory.
+    pub fn set_dir(&mut self, dir: impl Into<PathBuf>) -> Result<()> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        for (id, content) in self.blobs.drain() {
+            write_blob(&dir, &id, &content)?;
+        }
+        self.dir = Some(dir);
+        Ok(())
+    }
+
+    /// The directory the journal is persisted to, if any.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Stores content in the journal, returning its ID.
+    pub fn put(&mut self, content: &str) -> Result<BlobId> {
+        let id = BlobId::of(content);
+        match &self.dir {
+            Some(dir) => write_blob(dir, &id, content)?,
+            None => {
+                self.blobs
+                    .entry(id.clone())
+                    .or_insert_with(|| content.to_string());
+            }
+        }
+        Ok(id)
+    }
+
+    /// Retrieves the content for a blob ID.
+    pub fn get(&self, id: &BlobId) -> Result<String> {
+        if let Some(content) = self.blobs.get(id) {
+            return Ok(content.clone());
+        }
+        match &self.dir {
+            Some(dir) => fs::read_to_string(blob_path(dir, id)).map_err(|_| Error::NotFound {
+                msg: "Journal blob not found".to_string(),
+                path: id.to_string(),
+            }),
+            None => Err(Error::NotFound {
+                msg: "Journal blob not found (is the journal directory attached?)".to_string(),
+                path: id.to_string(),
+            }),
+        }
+    }
+
+    /// Copies blobs to a journal directory, skipping any that are already there.
+    pub fn copy_to(&self, dir: &Path, ids: &HashSet<BlobId>) -> Result<()> {
+        if self.dir.as_deref() == Some(dir) {
+            return Ok(());
+        }
+        for id in ids {
+            if !blob_path(dir, id).exists() {
+                write_blob(dir, id, &self.get(id)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops in-memory blobs that are not in the referenced set. Blobs in an attached directory
+    /// may be shared with other states, so they are left for `Journal::gc`.
+    pub(crate) fn retain(&mut self, referenced: &HashSet<BlobId>) {
+        self.blobs.retain(|id, _| referenced.contains(id));
+    }
+
+    /// Removes all blobs from a journal directory that are not in the referenced set, returning
+    /// the number of blobs removed. The caller must gather references from every state that
+    /// shares the directory.
+    pub fn gc(dir: &Path, referenced: &HashSet<BlobId>) -> Result<usize> {
+        if !dir.exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for prefix in fs::read_dir(dir)? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix_name = prefix.file_name().to_string_lossy().to_string();
+            for entry in fs::read_dir(prefix.path())? {
+                let entry = entry?;
+                let id = BlobId(format!(
+                    "{prefix_name}{}",
+                    entry.file_name().to_string_lossy()
+                ));
+                if !referenced.contains(&id) {
+                    fs::remove_file(entry.path())?;
+                    removed += 1;
+                }
+            }
+            if fs::read_dir(prefix.path())?.next().is_none() {
+                fs::remove_dir(prefix.path())?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal() -> Result<()> {
+        // In memory, identical content is stored once
+        let mut journal = Journal::default();
+        let a = journal.put("content a")?;
+        let a2 = journal.put("content a")?;
+        let b = journal.put("content b")?;
+        assert_eq!(a, a2);
+        assert_ne!(a, b);
+        assert_eq!(journal.blobs.len(), 2);
+        assert_eq!(journal.get(&a)?, "content a");
+
+        // Attaching a directory moves blobs to disk, and they are no longer serialized
+        let temp_dir = TempDir::new().unwrap();
+        journal.set_dir(temp_dir.path())?;
+        assert!(journal.blobs.is_empty());
+        assert!(blob_path(temp_dir.path(), &a).exists());
+        assert_eq!(journal.get(&b)?, "content b");
+        assert_eq!(serde_json::to_string(&journal).unwrap(), "{}");
+
+        // A deserialized journal can read blobs once the directory is re-attached
+        let mut loaded: Journal = serde_json::from_str("{}").unwrap();
+        assert!(loaded.get(&a).is_err());
+        loaded.set_dir(temp_dir.path())?;
+        assert_eq!(loaded.get(&a)?, "content a");
+
+        // Copying to another directory only writes the requested blobs
+        let other = TempDir::new().unwrap();
+        loaded.copy_to(other.path(), &HashSet::from([b.clone()]))?;
+        assert!(!blob_path(other.path(), &a).exists());
+        assert_eq!(
+            fs::read_to_string(blob_path(other.path(), &b))?,
+            "content b"
+        );
+
+        // Garbage collection removes unreferenced blobs
+        let removed = Journal::gc(temp_dir.path(), &HashSet::from([a.clone()]))?;
+        assert_eq!(removed, 1);
+        assert_eq!(loaded.get(&a)?, "content a");
+        assert!(loaded.get(&b).is_err());
+        Ok(())
+    }
+}
@@ -4,27 +4,39 @@
 //! to revert the state to a previous snapshot.
 //!
 //! The actual state consists of a filesystem directory and an in-memory store. Files in the
-//! in-memory store are prefixed with `::`.
+//! in-memory store are prefixed with `::`. The filesystem directory may optionally be a git
+//! worktree, in which case each patch is committed to a branch.
 mod directory;
 mod error;
+mod git;
 mod memory;
 
 mod abspath;
+mod branch;
+pub mod diff;
 pub mod files;
+pub mod journal;
 mod patch;
+mod preview;
+mod watch;
 
+pub use crate::branch::DEFAULT_BRANCH;
 pub use crate::error::*;
 pub use crate::patch::*;
+pub use crate::preview::Preview;
 
 use std::{
-    collections::{BTreeSet, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use globset::Glob;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use serde::{Deserialize, Serialize};
 
+use journal::{BlobId, Journal};
+
 /// Prefix for in-memory files
 pub const MEM_PREFIX: &str = "::";
 
@@ -33,6 +45,47 @@ trait SubStore: Debug {
     fn read(&self, path: &Path) -> Result<String>;
     fn write(&mut self, path: &Path, content: &str) -> Result<()>;
     fn remove(&mut self, path: &Path) -> Result<()>;
+
+    /// Checks that `write` would accept the path, without writing anything.
+    fn check_write(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Where patch operations read and write files: the state's stores when a patch is applied, or
+/// an overlay on top of them when it's previewed.
+trait Target {
+    /// The state the operations apply to, for the settings and history they consult.
+    fn state(&self) -> &State;
+    fn read(&self, path: &Path) -> Result<String>;
+    fn write(&mut self, path: &Path, content: &str) -> Result<()>;
+    fn remove(&mut self, path: &Path) -> Result<()>;
+
+    fn read_opt(&self, path: &Path) -> Result<Option<String>> {
+        match self.read(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Target for State {
+    fn state(&self) -> &State {
+        self
+    }
+
+    fn read(&self, path: &Path) -> Result<String> {
+        State::read(self, path)
+    }
+
+    fn write(&mut self, path: &Path, content: &str) -> Result<()> {
+        State::write(self, path, content)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        State::remove(self, path)
+    }
 }
 
 /// Information about a patch operation, including success/failure counts and any errors.
@@ -50,6 +103,20 @@ pub struct PatchInfo {
     /// If the patch includes view operations, we should continue to a next step to send them to
     /// the model.
     pub should_continue: bool,
+
+    /// True if the patch was applied with `PatchMode::Atomic` and an operation failed, so every
+    /// operation in the patch was undone.
+    #[serde(default)]
+    pub rolled_back: bool,
+}
+
+/// A file that `State::revert_merge` could not revert cleanly.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeConflict {
+    pub path: PathBuf,
+    /// The merged content with conflict markers. If the file was created or deleted on one side,
+    /// this is the current content.
+    pub merged: String,
 }
 
 impl PatchInfo {
@@ -63,19 +130,45 @@ impl PatchInfo {
     }
 }
 
+/// The version of the serialized state format. States written before snapshot contents moved to
+/// the journal have no version, and hold file contents inline where blob IDs now go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Format(u32);
+
+impl Format {
+    /// The format of states with inline snapshot contents.
+    const INLINE: Format = Format(0);
+    /// The current format, with snapshot contents in the journal.
+    const JOURNAL: Format = Format(1);
+
+    fn inline() -> Self {
+        Self::INLINE
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::JOURNAL
+    }
+}
+
+/// The content of a set of files prior to a patch. File contents are stored in the journal, and
+/// referenced by ID.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct Snapshot {
-    content: HashMap<PathBuf, String>,
+    content: HashMap<PathBuf, BlobId>,
     created: Vec<PathBuf>,
 }
 
 impl Snapshot {
-    pub fn insert(&mut self, path: PathBuf, content: String) {
-        self.content.insert(path, content);
+    pub fn insert(&mut self, path: PathBuf, blob: BlobId) {
+        self.content.insert(path, blob);
     }
 
-    pub fn create(&mut self, path: PathBuf) {
-        self.content.insert(path.clone(), String::new());
+    /// Marks a path as created. `empty` is the blob ID of the empty string.
+    pub fn create(&mut self, path: PathBuf, empty: BlobId) {
+        self.content.insert(path.clone(), empty);
         self.created.push(path);
     }
 
@@ -92,20 +185,118 @@ impl Snapshot {
     }
 }
 
+/// How `State::patch` treats files that were changed outside of the state since it last saw
+/// them, for instance by the user in their editor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExternalEdits {
+    /// Record the changes in a snapshot of their own before applying the patch. The edits are
+    /// preserved in history, and reverting later patches restores them.
+    #[default]
+    Fold,
+    /// Refuse to apply the patch, returning an `Error::Conflict` with a diff of the changes.
+    Fail,
+}
+
+/// How `State::patch_with_mode` treats a patch where some operations fail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchMode {
+    /// Keep the operations that succeeded, and report the ones that failed.
+    #[default]
+    Partial,
+    /// Undo every operation in the patch if any of them fails, so files are never left with a
+    /// mix of applied and missing edits.
+    Atomic,
+}
+
+/// Appended to the model-facing message of each failure in a rolled back patch.
+const ROLLED_BACK: &str =
+    "\n\nNo operations from this patch were applied. Re-send all of them, with this one fixed.";
+
 /// The state underlying a session. This is the set of resources that our models are editing. State
 /// presents a unified interface over an optional filesystem directory and a memory store.
 /// In-memory file names are prefixed with "::"
+///
+/// States serialized in an older format are upgraded as they are deserialized.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct State {
+    /// The format the state was serialized in
+    #[serde(default = "Format::inline")]
+    format: Format,
     directory: Option<directory::Directory>,
+    /// A git worktree, used in place of `directory` when set
+    #[serde(default)]
+    git: Option<git::Git>,
     memory: memory::Memory,
     snapshots: Vec<(u64, Snapshot)>,
+    /// Content-addressed storage for snapshot contents
+    #[serde(default)]
+    journal: Journal,
     next_snapshot_id: u64,
     /// Track which snapshot IDs represent actual modifications (not just views)
     modification_ids: HashSet<u64>,
+    /// Snapshot IDs that hold external edits folded into the history, rather than the changes
+    /// of a patch
+    #[serde(default)]
+    folded: HashSet<u64>,
+    /// The content of each filesystem file as of the last patch or revert that touched it, used
+    /// to detect external edits
+    #[serde(default)]
+    observed: HashMap<PathBuf, BlobId>,
+    /// What to do when a patch touches a file that was edited externally
+    #[serde(default)]
+    external_edits: ExternalEdits,
+    /// Glob patterns for files that patches may not modify
+    #[serde(default)]
+    protected: Vec<String>,
+    /// The checked out branch, or None for `DEFAULT_BRANCH`
+    #[serde(default)]
+    current_branch: Option<String>,
+    /// Branches that aren't checked out
+    #[serde(default)]
+    branches: BTreeMap<String, branch::Branch>,
+    /// Named checkpoints, mapped to snapshot IDs
+    #[serde(default)]
+    checkpoints: BTreeMap<String, u64>,
+    /// Watches the filesystem directory for external changes, if enabled
+    #[serde(skip)]
+    watcher: Option<Arc<watch::Watcher>>,
+}
+
+impl Serialize for State {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        State::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let mut state = State::deserialize(deserializer)?;
+        state.upgrade().map_err(serde::de::Error::custom)?;
+        Ok(state)
+    }
 }
 
 impl State {
+    /// Upgrades a freshly deserialized state to the current format. Inline snapshot contents are
+    /// moved into the journal, and replaced by their blob IDs.
+    fn upgrade(&mut self) -> Result<()> {
+        if self.format == Format::INLINE {
+            for (_, snap) in self.snapshots.iter_mut() {
+                for blob in snap.content.values_mut() {
+                    *blob = self.journal.put(blob.as_str())?;
+                }
+            }
+        }
+        self.format = Format::JOURNAL;
+        Ok(())
+    }
+
     /// Generate a diff of changes made to a file since the first snapshot.
     ///
     /// If the file has changed significantly (more than 50% of lines), a single
@@ -173,6 +364,54 @@ impl State {
         Ok(Patch { ops: changes })
     }
 
+    /// Returns the changes made between two points in the state's history: from just before
+    /// snapshot `from` was taken, to just before snapshot `to` was taken, or to the current state
+    /// if `to` is None. External edits folded into the history before the range first changes a
+    /// file are not counted as changes. Files are sorted by path.
+    pub fn diff(&self, from: u64, to: Option<u64>) -> Result<diff::Diff> {
+        let paths: BTreeSet<PathBuf> = self
+            .snapshots
+            .iter()
+            .filter(|(id, _)| *id >= from && to.is_none_or(|to| *id < to))
+            .filter(|(id, _)| !self.folded.contains(id))
+            .flat_map(|(_, snap)| snap.affected())
+            .collect();
+
+        let mut files = Vec::new();
+        for path in paths {
+            let old = self.content_at(&path, from, true)?;
+            let new = match to {
+                Some(to) => self.content_at(&path, to, false)?,
+                None => self.read_opt(&path)?,
+            };
+            if let Some(mut file) = diff::FileDiff::new(&path, old.as_deref(), new.as_deref()) {
+                // Modes are only known for files as they are on disk now
+                if to.is_none() && !path.to_string_lossy().starts_with(MEM_PREFIX) {
+                    file.mode = self.fs_dir().and_then(|dir| dir.mode(&path));
+                }
+                files.push(file);
+            }
+        }
+        Ok(diff::Diff { files })
+    }
+
+    /// Returns the content of a file just before snapshot `id` was taken, or None if it didn't
+    /// exist. This is the content captured by the first snapshot from `id` on that touches the
+    /// file, or the current content if there is none. With `skip_folded`, snapshots of folded
+    /// external edits are passed over, giving the content with the edits.
+    fn content_at(&self, path: &Path, id: u64, skip_folded: bool) -> Result<Option<String>> {
+        let snap = self.snapshots.iter().find(|(sid, snap)| {
+            *sid >= id
+                && snap.content.contains_key(path)
+                && !(skip_folded && self.folded.contains(sid))
+        });
+        match snap {
+            Some((_, snap)) if snap.created.iter().any(|p| p == path) => Ok(None),
+            Some((_, snap)) => Ok(Some(self.blob(&snap.content[path])?)),
+            None => self.read_opt(path),
+        }
+    }
+
     /// Set the directory path and glob patterns for file operations.
     ///
     /// Glob patterns can be positive (equivalent to --include) or negative (prefixed with `!`,
@@ -183,9 +422,179 @@ impl State {
     {
         let abs = root.into_abs_path()?;
         self.directory = Some(directory::Directory::new(abs, globs)?);
+        self.git = None;
+        Ok(self)
+    }
+
+    /// Use a git worktree as the filesystem store, in place of a plain directory. Before the first
+    /// patch that modifies files, the worktree is switched to `branch`, which is created if it
+    /// doesn't exist. The switch is refused if the worktree has uncommitted changes. Every patch
+    /// that modifies files is then committed to the branch, and reverting the state resets the
+    /// branch to the matching commit.
+    pub fn with_git<P>(mut self, root: P, globs: Vec<String>, branch: &str) -> Result<Self>
+    where
+        P: abspath::IntoAbsPath,
+    {
+        let abs = root.into_abs_path()?;
+        let dir = directory::Directory::new(abs, globs)?;
+        self.git = Some(git::Git::new(dir, branch)?);
+        self.directory = None;
+        Ok(self)
+    }
+
+    /// Returns the filesystem directory, whether it's a plain directory or a git worktree.
+    fn fs_dir(&self) -> Option<&directory::Directory> {
+        match self.git {
+            Some(ref git) => Some(&git.dir),
+            None => self.directory.as_ref(),
+        }
+    }
+
+    /// Attach a directory to persist the snapshot journal to. Snapshot contents are then stored
+    /// in the directory, deduplicated by hash, and only referenced by ID when the state is
+    /// serialized. This must be called again after a state with a journal is deserialized.
+    pub fn with_journal<P: Into<PathBuf>>(mut self, dir: P) -> Result<Self> {
+        self.set_journal(dir)?;
+        Ok(self)
+    }
+
+    /// Attach a directory to persist the snapshot journal to. See `with_journal`.
+    pub fn set_journal<P: Into<PathBuf>>(&mut self, dir: P) -> Result<()> {
+        self.journal.set_dir(dir)
+    }
+
+    /// Copies every blob the state references into a journal directory, so the directory can be
+    /// attached when the state is loaded again, wherever the blobs are kept now.
+    pub fn copy_journal(&self, dir: &Path) -> Result<()> {
+        self.journal.copy_to(dir, &self.referenced_blobs())
+    }
+
+    /// Set how patches treat files that were edited outside of the state since it last saw them.
+    pub fn with_external_edits(mut self, policy: ExternalEdits) -> Self {
+        self.external_edits = policy;
+        self
+    }
+
+    /// Watch the filesystem directory for changes made outside of the state, which are then
+    /// reported by `external_changes`. The watcher is not serialized, so this must be called again
+    /// after a state is deserialized.
+    pub fn with_watcher(mut self) -> Result<Self> {
+        self.watch()?;
+        Ok(self)
+    }
+
+    /// Start watching the filesystem directory. See `with_watcher`.
+    pub fn watch(&mut self) -> Result<()> {
+        let dir = self
+            .fs_dir()
+            .ok_or_else(|| Error::Internal("No directory to watch".to_string()))?;
+        self.watcher = Some(Arc::new(watch::Watcher::new(&dir.root, dir.filter())?));
+        Ok(())
+    }
+
+    /// Stop watching the filesystem directory.
+    pub fn unwatch(&mut self) {
+        self.watcher = None;
+    }
+
+    /// Returns the files that changed outside of the state since watching started, sorted by
+    /// path. A file is reported if it was created or deleted, or if its content differs from the
+    /// content the state last saw. Changes made by patches are not reported, and neither are
+    /// edits that a later patch has already folded into the history. Returns an empty list if the
+    /// state isn't being watched.
+    pub fn external_changes(&self) -> Result<Vec<PathBuf>> {
+        let (Some(watcher), Some(dir)) = (&self.watcher, self.fs_dir()) else {
+            return Ok(vec![]);
+        };
+        let mut changes = Vec::new();
+        for path in watcher.changed() {
+            let current = if dir.root.join(&path).is_file() && dir.is_listed(&path)? {
+                match dir.read(&path) {
+                    Ok(content) => Some(BlobId::of(&content)),
+                    Err(Error::NotFound { .. } | Error::NotText { .. }) => None,
+                    Err(e) => return Err(e),
+                }
+            } else {
+                None
+            };
+            if current.as_ref() != self.observed.get(&path) {
+                changes.push(path);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Accepts the current content of the given files as seen by the state, so they aren't
+    /// reported by `external_changes` or treated as external edits until they change again.
+    pub fn acknowledge(&mut self, paths: &[PathBuf]) -> Result<()> {
+        self.observe(paths)?;
+        if let Some(ref watcher) = self.watcher {
+            watcher.forget(paths);
+        }
+        Ok(())
+    }
+
+    /// Mark files matching any of the glob patterns as protected. Patch operations that would
+    /// modify a protected file fail with a model-facing error instead, and other operations in the
+    /// patch still apply. Views of protected files are allowed. Patterns match paths relative to
+    /// the directory root, or in-memory paths including the `::` prefix.
+    pub fn with_protected(mut self, globs: Vec<String>) -> Result<Self> {
+        self.protected = globs;
+        self.protected_set()?;
         Ok(self)
     }
 
+    /// Compiles the protected glob patterns.
+    fn protected_set(&self) -> Result<GlobSet> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.protected {
+            builder.add(Glob::new(pattern).map_err(|e| Error::Path(e.to_string()))?);
+        }
+        builder.build().map_err(|e| Error::Path(e.to_string()))
+    }
+
+    /// Returns the first protected path an operation would modify, if any. Filesystem paths are
+    /// matched as the root-relative path the directory would write, so absolute paths and
+    /// Windows-style separators can't sidestep the patterns.
+    fn protected_path<'a>(&self, protected: &GlobSet, op: &'a Operation) -> Option<&'a PathBuf> {
+        if !op.is_modification() {
+            return None;
+        }
+        op.paths().into_iter().find(|p| {
+            let target = match self.fs_dir() {
+                Some(dir) if !p.to_string_lossy().starts_with(MEM_PREFIX) => dir.relpath(p).ok(),
+                _ => None,
+            };
+            protected.is_match(target.unwrap_or_else(|| path_clean::clean(p)))
+        })
+    }
+
+    /// Returns the first path a modifying operation touches that exists but isn't a text file.
+    fn non_text_path<'a, T: Target>(target: &T, op: &'a Operation) -> Option<&'a PathBuf> {
+        if !op.is_modification() {
+            return None;
+        }
+        op.paths()
+            .into_iter()
+            .find(|p| matches!(target.read(p), Err(Error::NotText { .. })))
+    }
+
+    /// Returns the IDs of all journal blobs referenced by this state's snapshots. This is used to
+    /// garbage collect journal directories with `Journal::gc`.
+    pub fn referenced_blobs(&self) -> HashSet<BlobId> {
+        self.snapshots
+            .iter()
+            .flat_map(|(_, snap)| snap.content.values().cloned())
+            .chain(self.observed.values().cloned())
+            .chain(self.branches.values().flat_map(|b| b.blobs().cloned()))
+            .collect()
+    }
+
+    /// Reads a blob from the journal.
+    fn blob(&self, id: &BlobId) -> Result<String> {
+        self.journal.get(id)
+    }
+
     /// Initialize the state with pre-populated memory contents.
     ///
     /// This method takes a HashMap mapping file paths to their contents and
@@ -204,6 +613,8 @@ impl State {
     {
         if path.to_string_lossy().starts_with(MEM_PREFIX) {
             f(&self.memory)
+        } else if let Some(ref git) = self.git {
+            f(git)
         } else if let Some(ref fs) = self.directory {
             f(fs)
         } else {
@@ -221,6 +632,8 @@ impl State {
     {
         if path.to_string_lossy().starts_with(MEM_PREFIX) {
             f(&mut self.memory)
+        } else if let Some(ref mut git) = self.git {
+            f(git)
         } else if let Some(ref mut fs) = self.directory {
             f(fs)
         } else {
@@ -275,12 +688,20 @@ impl State {
 
     /// Creates a snapshot of the given list of paths. For each path, if the file exists, its content is captured;
     /// otherwise, the path is marked as created.
-    fn create_snapshot(&self, paths: &[PathBuf]) -> Result<Snapshot> {
+    fn create_snapshot(&mut self, paths: &[PathBuf]) -> Result<Snapshot> {
         let mut snap = Snapshot::default();
         for p in paths {
             match self.read(p) {
-                Ok(content) => snap.insert(p.clone(), content),
-                Err(Error::NotFound { .. }) => snap.create(p.clone()),
+                Ok(content) => {
+                    let blob = self.journal.put(&content)?;
+                    snap.insert(p.clone(), blob)
+                }
+                Err(Error::NotFound { .. }) => {
+                    let empty = self.journal.put("")?;
+                    snap.create(p.clone(), empty)
+                }
+                // Patches never modify files that aren't text, so there's nothing to restore
+                Err(Error::NotText { .. }) => {}
                 Err(e) => return Err(e),
             }
         }
@@ -289,13 +710,19 @@ impl State {
 
     /// Reverts the state to the given snapshot.
     /// Restores content for files or memory entries that existed and removes those that were created.
+    /// Files that were deleted or renamed away are restored from their snapshotted content.
     fn revert_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
         for path in snapshot.created.iter() {
-            self.remove(path)?;
+            match self.remove(path) {
+                // A created file may since have been deleted or renamed
+                Ok(()) | Err(Error::NotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
         }
-        for (path, content) in snapshot.content.iter() {
+        for (path, blob) in snapshot.content.iter() {
             if !snapshot.created.contains(path) {
-                self.write(path, content)?;
+                let content = self.blob(blob)?;
+                self.write(path, &content)?;
             }
         }
         Ok(())
@@ -317,22 +744,140 @@ impl State {
     /// Applies a patch by taking a snapshot of all files to be modified, then attempts to apply each change in the patch.
     /// If any change fails, the error is collected in a vector of (change, error) tuples.
     /// Returns a tuple containing the snapshot ID and a vector of failed changes.
+    ///
+    /// Before the patch is applied, files it modifies are checked for edits made outside of the
+    /// state since it last saw them, which are handled according to the `ExternalEdits` policy.
     pub fn patch(&mut self, patch: &Patch) -> Result<PatchInfo> {
+        self.patch_with_mode(patch, PatchMode::Partial)
+    }
+
+    /// Applies a patch like `patch`. With `PatchMode::Atomic`, if any operation fails or returns
+    /// an error, every file the patch touched is restored before returning. A failed atomic
+    /// patch still returns a rollback ID and its failures, with `PatchInfo::rolled_back` set.
+    pub fn patch_with_mode(&mut self, patch: &Patch, mode: PatchMode) -> Result<PatchInfo> {
+        let modified: Vec<PathBuf> = patch
+            .ops
+            .iter()
+            .filter(|op| op.is_modification())
+            .flat_map(|op| op.paths())
+            .cloned()
+            .collect();
+        // Only patches that modify the worktree need the branch
+        if modified
+            .iter()
+            .any(|p| !p.to_string_lossy().starts_with(MEM_PREFIX))
+        {
+            if let Some(ref mut git) = self.git {
+                git.switch()?;
+            }
+        }
+        // Files a patch only views don't conflict, but an external edit to them mustn't become
+        // the new baseline, or the next patch that modifies them would miss it
+        let stale: Vec<PathBuf> = match self.external_edits {
+            ExternalEdits::Fail => {
+                self.check_external_edits(&modified)?;
+                self.external_edits_in(&patch.affected_files())?
+                    .into_iter()
+                    .map(|(path, ..)| path)
+                    .collect()
+            }
+            ExternalEdits::Fold => {
+                self.check_external_edits(&patch.affected_files())?;
+                vec![]
+            }
+        };
+
         let snap = self.create_snapshot(&patch.affected_files())?;
         let mut pinfo = PatchInfo {
             rollback_id: 0,
             succeeded: 0,
             failures: Vec::new(),
             should_continue: patch.ops.iter().any(|op| op.is_view()),
+            rolled_back: false,
+        };
+
+        let res = Self::apply_ops(self, patch, &mut pinfo);
+        let has_modifications = match mode {
+            PatchMode::Atomic if res.is_err() || !pinfo.failures.is_empty() => {
+                self.revert_snapshot(snap.clone())?;
+                res?;
+                pinfo.rolled_back = true;
+                pinfo.succeeded = 0;
+                for failure in &mut pinfo.failures {
+                    failure.model.push_str(ROLLED_BACK);
+                }
+                false
+            }
+            _ => res?,
         };
 
+        let affected = snap.affected();
+        pinfo.rollback_id = self.push_snapshot(snap);
+        let observed: Vec<PathBuf> = affected
+            .iter()
+            .filter(|p| !stale.contains(p))
+            .cloned()
+            .collect();
+        self.observe(&observed)?;
+        if has_modifications {
+            if let Some(ref mut git) = self.git {
+                let paths: Vec<PathBuf> = affected
+                    .into_iter()
+                    .filter(|p| !p.to_string_lossy().starts_with(MEM_PREFIX))
+                    .collect();
+                git.commit(
+                    pinfo.rollback_id,
+                    &paths,
+                    &format!("tenx: snapshot {}", pinfo.rollback_id),
+                )?;
+            }
+        }
+
+        // Track this snapshot as a modification if it contained any modifying operations
+        if has_modifications {
+            self.modification_ids.insert(pinfo.rollback_id);
+        }
+
+        Ok(pinfo)
+    }
+
+    /// Applies each operation in a patch in turn to a target, recording successes and failures.
+    /// Returns true if any modifying operation succeeded.
+    fn apply_ops<T: Target>(target: &mut T, patch: &Patch, pinfo: &mut PatchInfo) -> Result<bool> {
         // Track if this patch contains any successfully applied modifying operations
         let mut has_modifications = false;
 
+        let protected = target.state().protected_set()?;
         for change in &patch.ops {
+            if let Some(path) = target.state().protected_path(&protected, change) {
+                pinfo.add_patch_failure(
+                    change.clone(),
+                    PatchError {
+                        user: format!("File is protected: {}", path.display()),
+                        model: format!(
+                            "The file {} is protected and must not be modified. Make your changes without editing it.",
+                            path.display()
+                        ),
+                    },
+                );
+                continue;
+            }
+            if let Some(path) = Self::non_text_path(target, change) {
+                pinfo.add_patch_failure(
+                    change.clone(),
+                    PatchError {
+                        user: format!("File is not text: {}", path.display()),
+                        model: format!(
+                            "The file {} is binary or not valid UTF-8, so it can't be edited. Make your changes without editing it.",
+                            path.display()
+                        ),
+                    },
+                );
+                continue;
+            }
             match change {
                 Operation::Write(write_file) => {
-                    if let Err(e) = self.write(write_file.path.as_path(), &write_file.content) {
+                    if let Err(e) = target.write(write_file.path.as_path(), &write_file.content) {
                         return Err(e);
                     } else {
                         pinfo.succeeded += 1;
@@ -341,12 +886,72 @@ impl State {
                         }
                     }
                 }
+                Operation::Create(create) => match target.read_opt(&create.path)? {
+                    Some(_) => {
+                        pinfo.add_patch_failure(
+                            change.clone(),
+                            PatchError {
+                                user: format!(
+                                    "File to create already exists: {}",
+                                    create.path.display()
+                                ),
+                                model: format!(
+                                    "Cannot create {} because the file already exists. Edit it instead.",
+                                    create.path.display()
+                                ),
+                            },
+                        );
+                    }
+                    None => {
+                        target.write(&create.path, &create.content)?;
+                        pinfo.succeeded += 1;
+                        has_modifications = true;
+                    }
+                },
+                Operation::Append(append) => match target.read_opt(&append.path)? {
+                    Some(original) => {
+                        target.write(&append.path, &append.apply(&original))?;
+                        pinfo.succeeded += 1;
+                        has_modifications = true;
+                    }
+                    None => {
+                        pinfo.add_patch_failure(
+                            change.clone(),
+                            PatchError {
+                                user: format!("File not found: {}", append.path.display()),
+                                model: format!(
+                                    "Cannot append to {} because the file does not exist. Create it instead.",
+                                    append.path.display()
+                                ),
+                            },
+                        );
+                    }
+                },
+                Operation::Prepend(prepend) => match target.read_opt(&prepend.path)? {
+                    Some(original) => {
+                        target.write(&prepend.path, &prepend.apply(&original))?;
+                        pinfo.succeeded += 1;
+                        has_modifications = true;
+                    }
+                    None => {
+                        pinfo.add_patch_failure(
+                            change.clone(),
+                            PatchError {
+                                user: format!("File not found: {}", prepend.path.display()),
+                                model: format!(
+                                    "Cannot prepend to {} because the file does not exist. Create it instead.",
+                                    prepend.path.display()
+                                ),
+                            },
+                        );
+                    }
+                },
                 Operation::ReplaceFuzzy(replace) => {
                     let res = (|| -> Result<()> {
-                        let original = self.read(replace.path.as_path())?;
+                        let original = target.read(replace.path.as_path())?;
                         match replace.apply(&original) {
                             Ok(new_content) => {
-                                self.write(replace.path.as_path(), &new_content)?;
+                                target.write(replace.path.as_path(), &new_content)?;
                                 pinfo.succeeded += 1;
                                 if change.is_modification() {
                                     has_modifications = true;
@@ -362,10 +967,10 @@ impl State {
                 }
                 Operation::Replace(replace) => {
                     let res = (|| -> Result<()> {
-                        let original = self.read(replace.path.as_path())?;
+                        let original = target.read(replace.path.as_path())?;
                         match replace.apply(&original) {
                             Ok(new_content) => {
-                                self.write(replace.path.as_path(), &new_content)?;
+                                target.write(replace.path.as_path(), &new_content)?;
                                 pinfo.succeeded += 1;
                                 if change.is_modification() {
                                     has_modifications = true;
@@ -379,12 +984,67 @@ impl State {
                     })();
                     res?;
                 }
+                Operation::ReplaceAll(replace) => {
+                    let res = (|| -> Result<()> {
+                        let original = target.read(replace.path.as_path())?;
+                        match replace.apply(&original) {
+                            Ok(new_content) => {
+                                target.write(replace.path.as_path(), &new_content)?;
+                                pinfo.succeeded += 1;
+                                has_modifications = true;
+                            }
+                            Err(patch_err) => {
+                                pinfo.add_patch_failure(change.clone(), patch_err);
+                            }
+                        }
+                        Ok(())
+                    })();
+                    res?;
+                }
                 Operation::Insert(insert) => {
                     let res = (|| -> Result<()> {
-                        let original = self.read(insert.path.as_path())?;
+                        let original = target.read(insert.path.as_path())?;
                         match insert.apply(&original) {
                             Ok(new_content) => {
-                                self.write(insert.path.as_path(), &new_content)?;
+                                target.write(insert.path.as_path(), &new_content)?;
+                                pinfo.succeeded += 1;
+                                if change.is_modification() {
+                                    has_modifications = true;
+                                }
+                            }
+                            Err(patch_err) => {
+                                pinfo.add_patch_failure(change.clone(), patch_err);
+                            }
+                        }
+                        Ok(())
+                    })();
+                    res?;
+                }
+                Operation::ReplaceLines(replace) => {
+                    let res = (|| -> Result<()> {
+                        let original = target.read(replace.path.as_path())?;
+                        match replace.apply(&original) {
+                            Ok(new_content) => {
+                                target.write(replace.path.as_path(), &new_content)?;
+                                pinfo.succeeded += 1;
+                                if change.is_modification() {
+                                    has_modifications = true;
+                                }
+                            }
+                            Err(patch_err) => {
+                                pinfo.add_patch_failure(change.clone(), patch_err);
+                            }
+                        }
+                        Ok(())
+                    })();
+                    res?;
+                }
+                Operation::RustItem(item) => {
+                    let res = (|| -> Result<()> {
+                        let original = target.read(item.path.as_path())?;
+                        match item.apply(&original) {
+                            Ok(new_content) => {
+                                target.write(item.path.as_path(), &new_content)?;
                                 pinfo.succeeded += 1;
                                 if change.is_modification() {
                                     has_modifications = true;
@@ -398,12 +1058,109 @@ impl State {
                     })();
                     res?;
                 }
+                Operation::UnifiedDiff(diff) => {
+                    let res = (|| -> Result<()> {
+                        let original = target.read(diff.path.as_path())?;
+                        match diff.apply(&original) {
+                            Ok((new_content, hunk_errs)) => {
+                                // Hunks that did apply are kept, even if others failed
+                                if new_content != original {
+                                    target.write(diff.path.as_path(), &new_content)?;
+                                    has_modifications = true;
+                                }
+                                if hunk_errs.is_empty() {
+                                    pinfo.succeeded += 1;
+                                }
+                                for patch_err in hunk_errs {
+                                    pinfo.add_patch_failure(change.clone(), patch_err);
+                                }
+                            }
+                            Err(patch_err) => {
+                                pinfo.add_patch_failure(change.clone(), patch_err);
+                            }
+                        }
+                        Ok(())
+                    })();
+                    res?;
+                }
+                Operation::Delete(path) => match target.read(path) {
+                    Ok(_) => {
+                        target.remove(path)?;
+                        pinfo.succeeded += 1;
+                        has_modifications = true;
+                    }
+                    Err(Error::NotFound { .. }) => {
+                        pinfo.add_patch_failure(
+                            change.clone(),
+                            PatchError {
+                                user: format!("File to delete not found: {}", path.display()),
+                                model: format!(
+                                    "Cannot delete {} because the file does not exist",
+                                    path.display()
+                                ),
+                            },
+                        );
+                    }
+                    Err(e) => return Err(e),
+                },
+                Operation::Rename(from, to) => {
+                    let res = (|| -> Result<()> {
+                        let content = match target.read(from) {
+                            Ok(content) => content,
+                            Err(Error::NotFound { .. }) => {
+                                pinfo.add_patch_failure(
+                                    change.clone(),
+                                    PatchError {
+                                        user: format!(
+                                            "File to rename not found: {}",
+                                            from.display()
+                                        ),
+                                        model: format!(
+                                            "Cannot rename {} because the file does not exist",
+                                            from.display()
+                                        ),
+                                    },
+                                );
+                                return Ok(());
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        match target.read(to) {
+                            Ok(_) => {
+                                pinfo.add_patch_failure(
+                                    change.clone(),
+                                    PatchError {
+                                        user: format!(
+                                            "Rename destination already exists: {}",
+                                            to.display()
+                                        ),
+                                        model: format!(
+                                            "Cannot rename {} to {} because the destination already exists",
+                                            from.display(),
+                                            to.display()
+                                        ),
+                                    },
+                                );
+                                return Ok(());
+                            }
+                            Err(Error::NotFound { .. }) => {}
+                            Err(e) => return Err(e),
+                        }
+                        // Renames are a copy and a remove, so they work across stores
+                        target.write(to, &content)?;
+                        target.remove(from)?;
+                        pinfo.succeeded += 1;
+                        has_modifications = true;
+                        Ok(())
+                    })();
+                    res?;
+                }
                 Operation::View(_) => {
                     pinfo.succeeded += 1;
                 }
                 Operation::Undo(path) => {
-                    if let Some(previous_content) = self.last_original(path) {
-                        self.write(path, &previous_content)?;
+                    if let Some(previous_content) = target.state().last_original(path) {
+                        target.write(path, &previous_content)?;
                         pinfo.succeeded += 1;
                         if change.is_modification() {
                             has_modifications = true;
@@ -424,14 +1181,83 @@ impl State {
                 }
             }
         }
-        pinfo.rollback_id = self.push_snapshot(snap);
+        Ok(has_modifications)
+    }
 
-        // Track this snapshot as a modification if it contained any modifying operations
-        if has_modifications {
-            self.modification_ids.insert(pinfo.rollback_id);
+    /// Records the current content of the given filesystem files as seen by the state.
+    fn observe(&mut self, paths: &[PathBuf]) -> Result<()> {
+        for path in paths {
+            if path.to_string_lossy().starts_with(MEM_PREFIX) {
+                continue;
+            }
+            match self.read_opt(path) {
+                Ok(Some(content)) => {
+                    let blob = self.journal.put(&content)?;
+                    self.observed.insert(path.clone(), blob);
+                }
+                Ok(None) | Err(Error::NotText { .. }) => {
+                    self.observed.remove(path);
+                }
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
+    }
 
-        Ok(pinfo)
+    /// Returns the files among `paths` that changed since the state last saw them, with the blob
+    /// the state last saw and their current content.
+    fn external_edits_in(
+        &self,
+        paths: &[PathBuf],
+    ) -> Result<Vec<(PathBuf, BlobId, Option<String>)>> {
+        let mut edited = Vec::new();
+        for path in paths {
+            let Some(blob) = self.observed.get(path) else {
+                continue;
+            };
+            let current = self.read_opt(path)?;
+            if current.as_ref().map(|c| BlobId::of(c)).as_ref() != Some(blob) {
+                edited.push((path.clone(), blob.clone(), current));
+            }
+        }
+        Ok(edited)
+    }
+
+    /// Checks the given files for changes made since the state last saw them. Depending on the
+    /// `ExternalEdits` policy, changes are either folded into a snapshot of their own, or
+    /// returned as an `Error::Conflict` for the first changed file.
+    fn check_external_edits(&mut self, paths: &[PathBuf]) -> Result<()> {
+        let edited = self.external_edits_in(paths)?;
+        if edited.is_empty() {
+            return Ok(());
+        }
+
+        match self.external_edits {
+            ExternalEdits::Fail => {
+                let (path, blob, current) = &edited[0];
+                let expected = self.blob(blob)?;
+                let diff = diffy::create_patch(&expected, current.as_deref().unwrap_or_default());
+                Err(Error::Conflict {
+                    path: path.display().to_string(),
+                    diff: diff.to_string(),
+                })
+            }
+            ExternalEdits::Fold => {
+                let mut snap = Snapshot::default();
+                for (path, blob, _) in &edited {
+                    snap.insert(path.clone(), blob.clone());
+                }
+                let paths = snap.affected();
+                let id = self.push_snapshot(snap);
+                self.modification_ids.insert(id);
+                self.folded.insert(id);
+                self.observe(&paths)?;
+                if let Some(ref mut git) = self.git {
+                    git.commit(id, &paths, &format!("tenx: external edits, snapshot {id}"))?;
+                }
+                Ok(())
+            }
+        }
     }
 
     /// Reverts all snapshots up to and including the given ID in reverse order, then removes them from the snapshots list.
@@ -452,19 +1278,138 @@ impl State {
         // Clean up modification IDs for reverted snapshots
         for (snap_id, _snap) in to_revert.iter() {
             self.modification_ids.remove(snap_id);
+            self.folded.remove(snap_id);
         }
 
+        let first = to_revert[0].0;
+        let last = to_revert[to_revert.len() - 1].0;
+        let reverted: Vec<PathBuf> = to_revert
+            .iter()
+            .flat_map(|(_, snap)| snap.affected())
+            .collect();
         for (_id, snap) in to_revert.into_iter().rev() {
             self.revert_snapshot(snap)?;
         }
         self.snapshots = remaining;
+        self.prune_checkpoints();
+        self.observe(&reverted)?;
+        self.journal.retain(&self.referenced_blobs());
+        if let Some(ref mut git) = self.git {
+            let surviving: Vec<PathBuf> = self
+                .snapshots
+                .iter()
+                .flat_map(|(_, snap)| snap.affected())
+                .filter(|p| !p.to_string_lossy().starts_with(MEM_PREFIX))
+                .collect();
+            git.reset(first, last, &surviving)?;
+        }
         Ok(())
     }
 
+    /// Reverts the changes made by the snapshots from `first` to `last` inclusive, while keeping
+    /// changes made to the same files since. For each file, the changes the snapshots introduced
+    /// are undone with a three-way merge against the current content. External edits folded into
+    /// the range are not part of its changes, and are kept.
+    ///
+    /// If any file can't be merged cleanly, the state is left untouched and the conflicts are
+    /// returned. Otherwise, the revert is applied as a new atomic patch, which can itself be
+    /// reverted, and an empty list is returned. If a file can't be written, for instance because
+    /// it's protected, the patch is rolled back and an `Error::Revert` is returned.
+    pub fn revert_merge(&mut self, first: u64, last: u64) -> Result<Vec<MergeConflict>> {
+        let range: Vec<&Snapshot> = self
+            .snapshots
+            .iter()
+            .filter(|(id, _)| *id >= first && *id <= last && !self.folded.contains(id))
+            .map(|(_, snap)| snap)
+            .collect();
+        if range.is_empty() {
+            return Err(Error::Internal(format!(
+                "No snapshots between {first} and {last}"
+            )));
+        }
+
+        // The content of each file before the first snapshot in the range, None if it was created
+        let mut before: BTreeMap<PathBuf, Option<BlobId>> = BTreeMap::new();
+        for snap in range {
+            for (path, blob) in &snap.content {
+                before
+                    .entry(path.clone())
+                    .or_insert_with(|| (!snap.created.contains(path)).then(|| blob.clone()));
+            }
+        }
+
+        let mut patch = Patch::default();
+        let mut conflicts = Vec::new();
+        for (path, blob) in before {
+            let before = blob.map(|b| self.blob(&b)).transpose()?;
+            // The content after the range is captured by the next snapshot touching the file
+            let after = match self
+                .snapshots
+                .iter()
+                .find(|(id, snap)| *id > last && snap.content.contains_key(&path))
+            {
+                Some((_, snap)) if snap.created.contains(&path) => None,
+                Some((_, snap)) => Some(self.blob(&snap.content[&path])?),
+                None => self.read_opt(&path)?,
+            };
+            if before == after {
+                continue;
+            }
+            let current = self.read_opt(&path)?;
+            let target = if current == after {
+                before
+            } else {
+                match (before, after, current.as_ref()) {
+                    (Some(before), Some(after), Some(current)) => {
+                        match diffy::merge(&after, current, &before) {
+                            Ok(merged) => Some(merged),
+                            Err(merged) => {
+                                conflicts.push(MergeConflict { path, merged });
+                                continue;
+                            }
+                        }
+                    }
+                    _ => {
+                        conflicts.push(MergeConflict {
+                            path,
+                            merged: current.unwrap_or_default(),
+                        });
+                        continue;
+                    }
+                }
+            };
+            if target == current {
+                continue;
+            }
+            patch = match target {
+                Some(content) => patch.with_write(&path, content),
+                None => patch.with_delete(&path),
+            };
+        }
+
+        if conflicts.is_empty() && !patch.ops.is_empty() {
+            let info = self.patch_with_mode(&patch, PatchMode::Atomic)?;
+            if !info.failures.is_empty() {
+                let reasons: Vec<String> = info.failures.iter().map(|f| f.user.clone()).collect();
+                return Err(Error::Revert(reasons.join("; ")));
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// Reads a file, returning None if it doesn't exist.
+    fn read_opt(&self, path: &Path) -> Result<Option<String>> {
+        match self.read(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Lists all files from both the memory and directory stores.
     pub fn list(&self) -> Result<Vec<PathBuf>> {
         let mut files = self.memory.list()?;
-        if let Some(ref fs) = self.directory {
+        if let Some(fs) = self.fs_dir() {
             files.extend(fs.list()?);
         }
         Ok(files)
@@ -518,14 +1463,10 @@ impl State {
         let snap = self
             .snapshots
             .iter()
-            .filter(|(_, s)| s.content.contains_key(path))
-            .next_back();
+            .rfind(|(_, s)| s.content.contains_key(path));
 
-        if let Some((_, snap)) = snap {
-            snap.content.get(path).cloned()
-        } else {
-            None
-        }
+        snap.and_then(|(_, snap)| snap.content.get(path))
+            .and_then(|blob| self.blob(blob).ok())
     }
 
     /// Returns a unique, sorted list of all files touched, changed or created in the current
@@ -560,8 +1501,8 @@ impl State {
 
         for (_, snap) in sorted_snapshots {
             // First check if there's content for this file
-            if let Some(content) = snap.content.get(path) {
-                return Some(content.clone());
+            if let Some(blob) = snap.content.get(path) {
+                return self.blob(blob).ok();
             }
             // If there's no content but file is in created list, it was a new empty file
             else if snap.created.contains(&path.to_path_buf()) {
@@ -599,7 +1540,7 @@ impl State {
         }
 
         // Then handle directory store with path normalization for non-memory patterns
-        if let Some(ref dir) = self.directory {
+        if let Some(dir) = self.fs_dir() {
             let dir_files = dir.list()?;
             for pattern in &patterns {
                 if pattern.starts_with(MEM_PREFIX) {
@@ -627,12 +1568,17 @@ impl State {
     /// Creates and dispatches a touch patch for files matching the provided patterns. Expands the
     /// patterns using the current working directory, creates a `Operatiion::View` for each matched
     /// path, and applies the patch. Returns a tuple of (snapshot ID, file count) from applying the
-    /// patch.
+    /// patch. Binary and non-UTF-8 files are skipped.
     pub fn view<P>(&mut self, cwd: P, patterns: Vec<String>) -> Result<(u64, usize)>
     where
         P: abspath::IntoAbsPath,
     {
-        let paths = self.find(cwd, patterns)?;
+        // Binary and non-UTF-8 files can't be shown to a model
+        let paths: Vec<PathBuf> = self
+            .find(cwd, patterns)?
+            .into_iter()
+            .filter(|p| !matches!(self.read(p), Err(Error::NotText { .. })))
+            .collect();
         let file_count = paths.len();
         let changes: Vec<Operation> = paths.into_iter().map(patch::Operation::View).collect();
         let patch = Patch { ops: changes };
@@ -655,8 +1601,10 @@ impl State {
     /// Returns true if any modifications have been made since that ID, false otherwise.
     /// View operations are not considered modifications.
     pub fn was_modified_since(&self, rollback_id: u64) -> bool {
-        // Check if any modification IDs exist after the given rollback_id
-        self.modification_ids.iter().any(|&id| id > rollback_id)
+        // Check if any snapshots on this branch after the given rollback_id are modifications
+        self.snapshots
+            .iter()
+            .any(|(id, _)| *id > rollback_id && self.modification_ids.contains(id))
     }
 }
 
@@ -1278,113 +2226,432 @@ mod tests {
                     Patch::default().with_write("::b.txt", "B-Version 1"), // New file
                 ],
             )
-            .expect_state(assert_last_original("::b.txt", Some(""))),
+            .expect_state(assert_last_original("::b.txt", Some(""))),
+            StateTestCase::new(
+                "file not in any snapshot",
+                vec![Patch::default().with_write("::a.txt", "A-Version 1")],
+            )
+            .with_content("::a.txt", "A-Original")
+            .expect_state(assert_last_original("::nonexistent.txt", None)),
+        ];
+
+        StateTest::run_tests(test_cases);
+    }
+
+    #[test]
+    fn test_undo() {
+        let p = "::test.txt";
+
+        let test_cases = vec![
+            StateTestCase::new("Nonexistent", vec![Patch::default().with_undo(p)])
+                .expect_patch_failure("No previous version"),
+            StateTestCase::new(
+                "Undo a single change",
+                vec![
+                    Patch::default().with_write(p, "Modified content"),
+                    Patch::default().with_undo(p),
+                ],
+            )
+            .with_content(p, "Original content")
+            .expect_content(p, "Original content"),
+            StateTestCase::new(
+                "Multiple changes and undo",
+                vec![
+                    Patch::default().with_write(p, "First modification"),
+                    Patch::default().with_write(p, "Second modification"),
+                    Patch::default().with_undo(p),
+                ],
+            )
+            .with_content(p, "Original content")
+            .expect_content(p, "First modification"),
+            StateTestCase::new(
+                "Double undo to return to original",
+                vec![
+                    Patch::default().with_write(p, "First modification"),
+                    Patch::default().with_write(p, "Second modification"),
+                    Patch::default().with_undo(p),
+                    Patch::default().with_undo(p),
+                ],
+            )
+            .with_content(p, "Original content")
+            .expect_content(p, "Second modification"),
+        ];
+
+        StateTest::run_tests(test_cases);
+    }
+
+    #[test]
+    fn test_insert() {
+        let p = "::test.txt";
+
+        let test_cases = vec![
+            StateTestCase::new(
+                "Insert at beginning",
+                vec![Patch::default().with_insert(p, 0, "Inserted line\n")],
+            )
+            .with_content(p, "Line 1\nLine 2\nLine 3")
+            .expect_content(p, "Inserted line\nLine 1\nLine 2\nLine 3"),
+            StateTestCase::new(
+                "Insert in middle",
+                vec![Patch::default().with_insert(p, 1, "Inserted line\n")],
+            )
+            .with_content(p, "Line 1\nLine 2\nLine 3")
+            .expect_content(p, "Line 1\nInserted line\nLine 2\nLine 3"),
+            StateTestCase::new(
+                "Insert at end",
+                vec![Patch::default().with_insert(p, 3, "Inserted line\n")],
+            )
+            .with_content(p, "Line 1\nLine 2\nLine 3")
+            .expect_content(p, "Line 1\nLine 2\nLine 3\nInserted line\n"),
+            StateTestCase::new(
+                "Insert into empty file",
+                vec![Patch::default().with_insert(p, 0, "First line\n")],
+            )
+            .with_content(p, "")
+            .expect_content(p, "First line\n"),
+            StateTestCase::new(
+                "Insert past end of file",
+                vec![Patch::default().with_insert(p, 10, "New line\n")],
+            )
+            .with_content(p, "Line 1\nLine 2")
+            .expect_patch_failure("out of bounds"),
+            StateTestCase::new(
+                "Multiple inserts",
+                vec![
+                    Patch::default().with_insert(p, 0, "First insert\n"),
+                    Patch::default().with_insert(p, 1, "Second insert\n"),
+                ],
+            )
+            .with_content(p, "Original line")
+            .expect_content(p, "First insert\nSecond insert\nOriginal line"),
+            StateTestCase::new(
+                "Insert with no newline",
+                vec![Patch::default().with_insert(p, 1, "Inserted")],
+            )
+            .with_content(p, "Line 1\nLine 2")
+            .expect_content(p, "Line 1\nInsertedLine 2"),
+        ];
+
+        StateTest::run_tests(test_cases);
+    }
+
+    #[test]
+    fn test_replace_lines() {
+        let p = "::test.txt";
+
+        let test_cases = vec![
+            StateTestCase::new(
+                "Replace range",
+                vec![Patch::default().with_replace_lines(p, 1, Some(2), "Two", None)],
+            )
+            .with_content(p, "Line 1\nLine 2\nLine 3\n")
+            .expect_content(p, "Line 1\nTwo\nLine 3\n"),
+            StateTestCase::new(
+                "Sequential replaces see earlier edits",
+                vec![Patch::default()
+                    .with_replace_lines(p, 0, Some(1), "A\nB", None)
+                    .with_replace_lines(p, 2, Some(3), "Two", Some("Line 2"))],
+            )
+            .with_content(p, "Line 1\nLine 2\nLine 3\n")
+            .expect_content(p, "A\nB\nTwo\nLine 3\n"),
+            StateTestCase::new(
+                "Stale guard",
+                vec![Patch::default().with_replace_lines(p, 0, Some(1), "X", Some("Line 2"))],
+            )
+            .with_content(p, "Line 1\nLine 2\nLine 3\n")
+            .expect_content(p, "Line 1\nLine 2\nLine 3\n")
+            .expect_patch_failure("does not match"),
+        ];
+
+        StateTest::run_tests(test_cases);
+    }
+
+    #[test]
+    fn test_rust_item() {
+        let p = "::lib.rs";
+        let content = "struct S;\n\nimpl S {\n    fn a() {}\n}\n";
+
+        let test_cases = vec![
+            StateTestCase::new(
+                "Replace and insert methods",
+                vec![Patch::default()
+                    .with_rust_item(p, "crate::S::a", "fn a() -> u8 {\n    1\n}")
+                    .with_rust_item(p, "S::b", "fn b() {}")],
+            )
+            .with_content(p, content)
+            .expect_content(
+                p,
+                "struct S;\n\nimpl S {\n    fn a() -> u8 {\n        1\n    }\n\n    fn b() {}\n}\n",
+            ),
+            StateTestCase::new(
+                "Missing parent",
+                vec![Patch::default().with_rust_item(p, "T::a", "fn a() {}")],
+            )
+            .with_content(p, content)
+            .expect_content(p, content)
+            .expect_patch_failure("not found"),
+        ];
+
+        StateTest::run_tests(test_cases);
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let p = "::test.rs";
+        let content = "let foo = 1;\nfoo += foo;\n";
+
+        let test_cases = vec![
+            StateTestCase::new(
+                "Rename an identifier",
+                vec![Patch::default().with_replace_all(p, "foo", "bar", Some(3))],
+            )
+            .with_content(p, content)
+            .expect_content(p, "let bar = 1;\nbar += bar;\n"),
+            StateTestCase::new(
+                "Regex with capture groups",
+                vec![Patch::default().with_replace_regex(
+                    p,
+                    r"(\w+) \+= (\w+)",
+                    "$1 = $1 + $2",
+                    None,
+                )],
+            )
+            .with_content(p, content)
+            .expect_content(p, "let foo = 1;\nfoo = foo + foo;\n"),
             StateTestCase::new(
-                "file not in any snapshot",
-                vec![Patch::default().with_write("::a.txt", "A-Version 1")],
+                "Wrong match count",
+                vec![Patch::default().with_replace_all(p, "foo", "bar", Some(2))],
             )
-            .with_content("::a.txt", "A-Original")
-            .expect_state(assert_last_original("::nonexistent.txt", None)),
+            .with_content(p, content)
+            .expect_content(p, content)
+            .expect_patch_failure("expected 2 matches, found 3"),
         ];
 
         StateTest::run_tests(test_cases);
     }
 
     #[test]
-    fn test_undo() {
+    fn test_create_append_prepend() {
         let p = "::test.txt";
 
         let test_cases = vec![
-            StateTestCase::new("Nonexistent", vec![Patch::default().with_undo(p)])
-                .expect_patch_failure("No previous version"),
             StateTestCase::new(
-                "Undo a single change",
-                vec![
-                    Patch::default().with_write(p, "Modified content"),
-                    Patch::default().with_undo(p),
-                ],
+                "Create a new file",
+                vec![Patch::default().with_create("::new.txt", "new\n")],
             )
-            .with_content(p, "Original content")
-            .expect_content(p, "Original content"),
+            .expect_content("::new.txt", "new\n"),
             StateTestCase::new(
-                "Multiple changes and undo",
-                vec![
-                    Patch::default().with_write(p, "First modification"),
-                    Patch::default().with_write(p, "Second modification"),
-                    Patch::default().with_undo(p),
-                ],
+                "Create an existing file",
+                vec![Patch::default().with_create(p, "new\n")],
             )
-            .with_content(p, "Original content")
-            .expect_content(p, "First modification"),
+            .with_content(p, "old\n")
+            .expect_content(p, "old\n")
+            .expect_patch_failure("already exists"),
             StateTestCase::new(
-                "Double undo to return to original",
-                vec![
-                    Patch::default().with_write(p, "First modification"),
-                    Patch::default().with_write(p, "Second modification"),
-                    Patch::default().with_undo(p),
-                    Patch::default().with_undo(p),
-                ],
+                "Append and prepend",
+                vec![Patch::default()
+                    .with_append(p, "three\n")
+                    .with_prepend(p, "one\n")],
             )
-            .with_content(p, "Original content")
-            .expect_content(p, "Second modification"),
+            .with_content(p, "two")
+            .expect_content(p, "one\ntwo\nthree\n"),
+            StateTestCase::new(
+                "Append to a missing file",
+                vec![Patch::default().with_append("::missing.txt", "text")],
+            )
+            .expect_patch_failure("not found"),
         ];
 
         StateTest::run_tests(test_cases);
     }
 
     #[test]
-    fn test_insert() {
+    fn test_unified_diff() {
         let p = "::test.txt";
 
         let test_cases = vec![
             StateTestCase::new(
-                "Insert at beginning",
-                vec![Patch::default().with_insert(p, 0, "Inserted line\n")],
-            )
-            .with_content(p, "Line 1\nLine 2\nLine 3")
-            .expect_content(p, "Inserted line\nLine 1\nLine 2\nLine 3"),
-            StateTestCase::new(
-                "Insert in middle",
-                vec![Patch::default().with_insert(p, 1, "Inserted line\n")],
+                "Apply diff",
+                vec![Patch::default().with_unified_diff(
+                    p,
+                    "--- a/test.txt\n+++ b/test.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n",
+                )],
             )
-            .with_content(p, "Line 1\nLine 2\nLine 3")
-            .expect_content(p, "Line 1\nInserted line\nLine 2\nLine 3"),
+            .with_content(p, "one\ntwo\nthree\n")
+            .expect_content(p, "one\nTWO\nthree\n"),
             StateTestCase::new(
-                "Insert at end",
-                vec![Patch::default().with_insert(p, 3, "Inserted line\n")],
+                "Failing hunk",
+                vec![Patch::default().with_unified_diff(p, "@@ -1,1 +1,1 @@\n-four\n+FOUR\n")],
             )
-            .with_content(p, "Line 1\nLine 2\nLine 3")
-            .expect_content(p, "Line 1\nLine 2\nLine 3\nInserted line\n"),
+            .with_content(p, "one\ntwo\nthree\n")
+            .expect_content(p, "one\ntwo\nthree\n")
+            .expect_patch_failure("hunk 1 did not apply"),
+        ];
+
+        StateTest::run_tests(test_cases);
+
+        // Diffs roll back like any other operation
+        let mut state = State::default()
+            .with_memory(HashMap::from([(
+                PathBuf::from(p),
+                "one\ntwo\nthree\n".to_string(),
+            )]))
+            .unwrap();
+        let info = state
+            .patch(&Patch::default().with_unified_diff(p, "@@ -2 +2 @@\n-two\n+TWO\n"))
+            .unwrap();
+        assert!(info.failures.is_empty());
+        assert_eq!(state.read(Path::new(p)).unwrap(), "one\nTWO\nthree\n");
+        state.revert(info.rollback_id).unwrap();
+        assert_eq!(state.read(Path::new(p)).unwrap(), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        let test_cases = vec![
             StateTestCase::new(
-                "Insert into empty file",
-                vec![Patch::default().with_insert(p, 0, "First line\n")],
+                "Delete missing file",
+                vec![Patch::default().with_delete("::missing.txt")],
             )
-            .with_content(p, "")
-            .expect_content(p, "First line\n"),
+            .expect_patch_failure("not found"),
             StateTestCase::new(
-                "Insert past end of file",
-                vec![Patch::default().with_insert(p, 10, "New line\n")],
+                "Rename missing file",
+                vec![Patch::default().with_rename("::missing.txt", "::new.txt")],
             )
-            .with_content(p, "Line 1\nLine 2")
-            .expect_patch_failure("out of bounds"),
+            .expect_patch_failure("not found"),
             StateTestCase::new(
-                "Multiple inserts",
-                vec![
-                    Patch::default().with_insert(p, 0, "First insert\n"),
-                    Patch::default().with_insert(p, 1, "Second insert\n"),
-                ],
+                "Rename onto existing file",
+                vec![Patch::default().with_rename("::a.txt", "::b.txt")],
             )
-            .with_content(p, "Original line")
-            .expect_content(p, "First insert\nSecond insert\nOriginal line"),
+            .with_content("::a.txt", "A")
+            .with_content("::b.txt", "B")
+            .expect_content("::b.txt", "B")
+            .expect_patch_failure("already exists"),
             StateTestCase::new(
-                "Insert with no newline",
-                vec![Patch::default().with_insert(p, 1, "Inserted")],
+                "Rename then edit",
+                vec![Patch::default()
+                    .with_rename("::a.txt", "::b.txt")
+                    .with_replace("::b.txt", "A", "B")],
             )
-            .with_content(p, "Line 1\nLine 2")
-            .expect_content(p, "Line 1\nInsertedLine 2"),
+            .with_content("::a.txt", "A")
+            .expect_content("::b.txt", "B"),
         ];
-
         StateTest::run_tests(test_cases);
+
+        let mut test = StateTest::new().unwrap();
+        test.write("dir.txt", "directory").unwrap();
+        test.write("::mem.txt", "memory").unwrap();
+        test.write("gone.txt", "deleted").unwrap();
+
+        let info = test
+            .state
+            .patch(
+                &Patch::default()
+                    .with_delete("gone.txt")
+                    .with_rename("dir.txt", "sub/moved.txt")
+                    .with_rename("::mem.txt", "from_mem.txt"),
+            )
+            .unwrap();
+        assert!(info.failures.is_empty());
+        assert!(test.read("gone.txt").is_err());
+        assert!(test.read("dir.txt").is_err());
+        assert!(test.read("::mem.txt").is_err());
+        assert_eq!(test.read("sub/moved.txt").unwrap(), "directory");
+        assert_eq!(test.read("from_mem.txt").unwrap(), "memory");
+        assert_eq!(
+            test.state.changed().unwrap(),
+            vec![
+                PathBuf::from("::mem.txt"),
+                PathBuf::from("dir.txt"),
+                PathBuf::from("from_mem.txt"),
+                PathBuf::from("gone.txt"),
+                PathBuf::from("sub/moved.txt"),
+            ]
+        );
+
+        // A second step deletes the renamed file, so both steps must be unwound
+        let info2 = test
+            .state
+            .patch(&Patch::default().with_delete("sub/moved.txt"))
+            .unwrap();
+        assert!(info2.failures.is_empty());
+
+        test.state.revert(info.rollback_id).unwrap();
+        assert_eq!(test.read("gone.txt").unwrap(), "deleted");
+        assert_eq!(test.read("dir.txt").unwrap(), "directory");
+        assert_eq!(test.read("::mem.txt").unwrap(), "memory");
+        assert!(test.read("sub/moved.txt").is_err());
+        assert!(test.read("from_mem.txt").is_err());
+    }
+
+    #[test]
+    fn test_journal() {
+        let p = Path::new("::big.txt");
+        let big = "line\n".repeat(1000);
+        let mut state = State::default()
+            .with_memory(HashMap::from([(p.to_path_buf(), big.clone())]))
+            .unwrap();
+
+        // Repeated snapshots of the same content are deduplicated
+        for _ in 0..5 {
+            state.patch(&Patch::default().with_view(p)).unwrap();
+        }
+        let info = state
+            .patch(&Patch::default().with_write(p, "small"))
+            .unwrap();
+        assert_eq!(state.referenced_blobs().len(), 1);
+        let serialized = serde_json::to_string(&state).unwrap();
+        assert!(serialized.len() < big.len() * 2);
+
+        // With a journal directory, contents are no longer serialized at all
+        let temp_dir = TempDir::new().unwrap();
+        state.set_journal(temp_dir.path()).unwrap();
+        let serialized = serde_json::to_string(&state).unwrap();
+        assert!(serialized.len() < big.len());
+
+        // A loaded state needs the journal re-attached before it can revert
+        let mut loaded: State = serde_json::from_str(&serialized).unwrap();
+        loaded.set_journal(temp_dir.path()).unwrap();
+        assert_eq!(loaded.original(p).unwrap(), big);
+        loaded.revert(info.rollback_id).unwrap();
+        assert_eq!(loaded.read(p).unwrap(), big);
+
+        // Reverting drops references, which lets the journal be collected
+        assert!(loaded.referenced_blobs().is_empty());
+        assert_eq!(
+            Journal::gc(temp_dir.path(), &loaded.referenced_blobs()).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_inline_format() {
+        let p = Path::new("::a.txt");
+        let mut state = State::default()
+            .with_memory(HashMap::from([(p.to_path_buf(), "original".to_string())]))
+            .unwrap();
+        let info = state
+            .patch(&Patch::default().with_write(p, "modified"))
+            .unwrap();
+
+        // Rewrite the state the way it was serialized before the journal: no format or journal,
+        // and file contents in place of blob IDs
+        let mut value = serde_json::to_value(&state).unwrap();
+        let fields = value.as_object_mut().unwrap();
+        assert_eq!(fields.remove("format"), Some(serde_json::json!(1)));
+        fields.remove("journal");
+        fields["snapshots"][0][1]["content"]["::a.txt"] = serde_json::json!("original");
+
+        let loaded: State = serde_json::from_value(value).unwrap();
+        assert_eq!(loaded.referenced_blobs(), state.referenced_blobs());
+        assert_eq!(loaded.original(p).unwrap(), "original");
+
+        // Upgraded states are saved in the current format, and aren't upgraded again
+        let mut reloaded: State =
+            serde_json::from_str(&serde_json::to_string(&loaded).unwrap()).unwrap();
+        assert_eq!(reloaded.referenced_blobs(), state.referenced_blobs());
+        reloaded.revert(info.rollback_id).unwrap();
+        assert_eq!(reloaded.read(p).unwrap(), "original");
     }
 
     #[test]
@@ -1903,4 +3170,561 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_git() {
+        use std::process::Command;
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let git = |args: &[&str]| -> String {
+            let out = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(root)
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "git {args:?} failed");
+            String::from_utf8_lossy(&out.stdout).trim().to_string()
+        };
+        git(&["init", "--quiet", "--initial-branch=main"]);
+        std::fs::write(root.join("a.txt"), "one\n").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["commit", "--quiet", "-m", "initial"]);
+        let initial = git(&["rev-parse", "HEAD"]);
+
+        // The worktree stays on its branch until a patch modifies files, and a dirty worktree
+        // is never switched
+        let mut state = State::default()
+            .with_git(AbsPath::new(root.to_path_buf()).unwrap(), vec![], "tenx")
+            .unwrap();
+        state.patch(&Patch::default().with_view("a.txt")).unwrap();
+        assert_eq!(git(&["branch", "--show-current"]), "main");
+        std::fs::write(root.join("a.txt"), "user\n").unwrap();
+        assert!(matches!(
+            state.patch(&Patch::default().with_replace("a.txt", "user", "two")),
+            Err(Error::Git(_))
+        ));
+        assert_eq!(git(&["branch", "--show-current"]), "main");
+        state
+            .patch(&Patch::default().with_write("::mem.txt", "memory"))
+            .unwrap();
+        assert_eq!(git(&["branch", "--show-current"]), "main");
+        std::fs::write(root.join("a.txt"), "one\n").unwrap();
+
+        let mut state = State::default()
+            .with_git(AbsPath::new(root.to_path_buf()).unwrap(), vec![], "tenx")
+            .unwrap();
+
+        // Each patch becomes a commit, but views and memory edits don't
+        let first = state
+            .patch(&Patch::default().with_replace("a.txt", "one", "two"))
+            .unwrap()
+            .rollback_id;
+        state
+            .patch(&Patch::default().with_write("::mem.txt", "memory"))
+            .unwrap();
+        state
+            .patch(&Patch::default().with_write("./b.txt", "new\n"))
+            .unwrap();
+        state.patch(&Patch::default().with_view("a.txt")).unwrap();
+        assert_eq!(git(&["branch", "--show-current"]), "tenx");
+        assert_eq!(git(&["rev-list", "--count", "HEAD"]), "3");
+        assert_eq!(git(&["status", "--porcelain"]), "");
+        assert_eq!(git(&["show", "HEAD:a.txt"]), "two");
+
+        // Reverting resets the branch to the commit before the reverted patch
+        state.revert(first).unwrap();
+        assert_eq!(state.read(Path::new("a.txt")).unwrap(), "one\n");
+        assert_eq!(git(&["show", "HEAD:a.txt"]), "one");
+        assert_ne!(git(&["rev-parse", "HEAD"]), initial);
+        assert_eq!(git(&["show", "HEAD:b.txt"]), "new");
+        assert_eq!(git(&["status", "--porcelain"]), "");
+
+        // Reverting everything returns the branch to its starting point
+        state.revert(first + 2).unwrap();
+        assert_eq!(git(&["rev-parse", "HEAD"]), initial);
+        assert!(!root.join("b.txt").exists());
+
+        // Paths are committed as the files they refer to, and never as pathspecs
+        std::fs::write(root.join("c.txt"), "untracked\n").unwrap();
+        state
+            .patch(&Patch::default().with_write("sub\\*.txt", "star\n"))
+            .unwrap();
+        assert_eq!(git(&["show", "HEAD:sub/*.txt"]), "star");
+        assert_eq!(git(&["status", "--porcelain"]), "?? c.txt");
+    }
+
+    #[test]
+    fn test_external_edits() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        let path = temp_dir.path().join("a.txt");
+        std::fs::write(&path, "one\n").unwrap();
+
+        // Folded edits get a snapshot of their own, so reverting the next patch keeps them
+        let mut state = State::default()
+            .with_directory(root.clone(), vec![])
+            .unwrap();
+        state
+            .patch(&Patch::default().with_replace("a.txt", "one", "two"))
+            .unwrap();
+        std::fs::write(&path, "two\nuser\n").unwrap();
+        let info = state
+            .patch(&Patch::default().with_replace("a.txt", "two", "three"))
+            .unwrap();
+        assert_eq!(info.rollback_id, 2);
+        assert_eq!(state.read(Path::new("a.txt")).unwrap(), "three\nuser\n");
+        let before = |state: &State, id: u64| {
+            let (_, snap) = state.snapshots.iter().find(|(i, _)| *i == id).unwrap();
+            state.blob(&snap.content[Path::new("a.txt")]).unwrap()
+        };
+        assert_eq!(before(&state, 1), "two\n");
+        assert_eq!(before(&state, 2), "two\nuser\n");
+        state.revert(2).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\n");
+
+        // Views don't trip the check, but they don't accept the edit either
+        std::fs::write(&path, "one\n").unwrap();
+        let mut state = State::default()
+            .with_directory(root, vec![])
+            .unwrap()
+            .with_external_edits(ExternalEdits::Fail);
+        state
+            .patch(&Patch::default().with_replace("a.txt", "one", "two"))
+            .unwrap();
+        std::fs::write(&path, "two\nuser\n").unwrap();
+        state.patch(&Patch::default().with_view("a.txt")).unwrap();
+        std::fs::write(&path, "changed\n").unwrap();
+        match state.patch(&Patch::default().with_replace("a.txt", "changed", "three")) {
+            Err(Error::Conflict { path, diff }) => {
+                assert_eq!(path, "a.txt");
+                assert!(diff.contains("-two\n+changed\n"));
+            }
+            other => panic!("expected a conflict, got {other:?}"),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "changed\n");
+    }
+
+    #[test]
+    fn test_protected() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        std::fs::create_dir(temp_dir.path().join("tests")).unwrap();
+        std::fs::write(temp_dir.path().join("tests/check.rs"), "assert!(ok);\n").unwrap();
+        std::fs::write(temp_dir.path().join("lib.rs"), "ok = false;\n").unwrap();
+
+        let mut state = State::default()
+            .with_directory(root, vec![])
+            .unwrap()
+            .with_protected(vec!["tests/**".to_string(), "::locked".to_string()])
+            .unwrap();
+        state.write(Path::new("::locked"), "memory").unwrap();
+
+        // Protected modifications fail, but the rest of the patch applies
+        let info = state
+            .patch(
+                &Patch::default()
+                    .with_replace("./tests/check.rs", "assert!(ok);", "")
+                    .with_write("::locked", "changed")
+                    .with_replace("lib.rs", "false", "true")
+                    .with_view("tests/check.rs"),
+            )
+            .unwrap();
+        assert_eq!(info.succeeded, 2);
+        assert_eq!(info.failures.len(), 2);
+        assert!(info.failures[0].user.contains("protected"));
+        assert!(info.failures[0].model.contains("tests/check.rs"));
+        assert_eq!(
+            state.read(Path::new("tests/check.rs")).unwrap(),
+            "assert!(ok);\n"
+        );
+        assert_eq!(state.read(Path::new("::locked")).unwrap(), "memory");
+        assert_eq!(state.read(Path::new("lib.rs")).unwrap(), "ok = true;\n");
+
+        // Absolute paths and Windows-style separators resolve to the same protected file
+        let abs = temp_dir.path().join("tests/check.rs");
+        let info = state
+            .patch(
+                &Patch::default()
+                    .with_write(abs.to_str().unwrap(), "")
+                    .with_write("tests\\check.rs", "")
+                    .with_write("lib/../tests/check.rs", ""),
+            )
+            .unwrap();
+        assert_eq!(info.succeeded, 0);
+        assert_eq!(info.failures.len(), 3);
+        assert_eq!(
+            state.read(Path::new("tests/check.rs")).unwrap(),
+            "assert!(ok);\n"
+        );
+
+        assert!(State::default()
+            .with_protected(vec!["[".to_string()])
+            .is_err());
+    }
+
+    #[test]
+    fn test_external_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        std::fs::write(temp_dir.path().join("a.txt"), "one\n").unwrap();
+        std::fs::write(temp_dir.path().join("b.txt"), "b\n").unwrap();
+
+        let mut state = State::default()
+            .with_directory(root, vec!["!ignored.txt".to_string()])
+            .unwrap()
+            .with_watcher()
+            .unwrap();
+        // Changes are recorded directly rather than waiting on filesystem events, which arrive
+        // after an unpredictable delay
+        let record = |state: &State, paths: &[&str]| {
+            let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+            state.watcher.as_ref().unwrap().record(&paths);
+        };
+
+        // Patches aren't external changes, and neither are ignored files
+        state
+            .patch(&Patch::default().with_replace("a.txt", "one", "two"))
+            .unwrap();
+        std::fs::write(temp_dir.path().join("b.txt"), "user\n").unwrap();
+        std::fs::write(temp_dir.path().join("c.txt"), "new\n").unwrap();
+        std::fs::write(temp_dir.path().join("ignored.txt"), "new\n").unwrap();
+        record(&state, &["a.txt", "b.txt", "c.txt", "ignored.txt"]);
+        assert_eq!(
+            state.external_changes().unwrap(),
+            vec![PathBuf::from("b.txt"), PathBuf::from("c.txt")]
+        );
+
+        // Edits folded into history by a patch, or acknowledged, are no longer reported
+        std::fs::write(temp_dir.path().join("a.txt"), "two\nuser\n").unwrap();
+        record(&state, &["a.txt"]);
+        assert_eq!(
+            state.external_changes().unwrap(),
+            vec![
+                PathBuf::from("a.txt"),
+                PathBuf::from("b.txt"),
+                PathBuf::from("c.txt"),
+            ]
+        );
+        state
+            .patch(&Patch::default().with_replace("a.txt", "two", "three"))
+            .unwrap();
+        state.acknowledge(&[PathBuf::from("c.txt")]).unwrap();
+        assert_eq!(
+            state.external_changes().unwrap(),
+            vec![PathBuf::from("b.txt")]
+        );
+
+        std::fs::remove_file(temp_dir.path().join("c.txt")).unwrap();
+        record(&state, &["c.txt"]);
+        assert_eq!(
+            state.external_changes().unwrap(),
+            vec![PathBuf::from("b.txt"), PathBuf::from("c.txt")]
+        );
+
+        state.unwatch();
+        assert!(state.external_changes().unwrap().is_empty());
+        assert!(State::default().watch().is_err());
+    }
+
+    #[test]
+    fn test_patch_atomic() {
+        let mut state = State::default();
+        state.write(Path::new("::a"), "one").unwrap();
+        state.write(Path::new("::b"), "two").unwrap();
+        let patch = Patch::default()
+            .with_replace("::a", "one", "uno")
+            .with_write("::c", "new")
+            .with_replace("::b", "missing", "dos");
+
+        // A failed atomic patch undoes the operations that succeeded
+        let info = state.patch_with_mode(&patch, PatchMode::Atomic).unwrap();
+        assert!(info.rolled_back);
+        assert_eq!(info.succeeded, 0);
+        assert_eq!(info.failures.len(), 1);
+        assert!(info.failures[0]
+            .model
+            .contains("No operations from this patch"));
+        assert_eq!(state.read(Path::new("::a")).unwrap(), "one");
+        assert!(state.read(Path::new("::c")).is_err());
+        assert!(!state.modification_ids.contains(&info.rollback_id));
+
+        // The same patch in partial mode keeps the successful operations
+        let info = state.patch(&patch).unwrap();
+        assert!(!info.rolled_back);
+        assert_eq!(info.succeeded, 2);
+        assert_eq!(state.read(Path::new("::a")).unwrap(), "uno");
+        assert_eq!(state.read(Path::new("::c")).unwrap(), "new");
+
+        // Atomic patches that succeed apply normally
+        let info = state
+            .patch_with_mode(
+                &Patch::default().with_replace("::b", "two", "dos"),
+                PatchMode::Atomic,
+            )
+            .unwrap();
+        assert!(!info.rolled_back);
+        assert_eq!(state.read(Path::new("::b")).unwrap(), "dos");
+        assert!(state.modification_ids.contains(&info.rollback_id));
+    }
+
+    #[test]
+    fn test_revert_merge() {
+        let mut state = State::default()
+            .with_memory(HashMap::from([(
+                PathBuf::from("::a.txt"),
+                "1\n2\n3\n4\n5\n6\n7\n".to_string(),
+            )]))
+            .unwrap();
+        let bad = state
+            .patch(
+                &Patch::default()
+                    .with_replace("::a.txt", "2\n", "two\n")
+                    .with_write("::new.txt", "created"),
+            )
+            .unwrap()
+            .rollback_id;
+        state
+            .patch(&Patch::default().with_replace("::a.txt", "6\n", "six\n"))
+            .unwrap();
+
+        // Only the changes made by the bad step are undone
+        let conflicts = state.revert_merge(bad, bad).unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            state.read(Path::new("::a.txt")).unwrap(),
+            "1\n2\n3\n4\n5\nsix\n7\n"
+        );
+        assert!(state.read(Path::new("::new.txt")).is_err());
+
+        // The merge revert is itself a snapshot that can be reverted
+        state.revert(state.next_snapshot_id - 1).unwrap();
+        assert_eq!(
+            state.read(Path::new("::a.txt")).unwrap(),
+            "1\n2\n3\n4\n5\n6\n7\n"
+        );
+
+        // Later changes to the same lines conflict, and leave the state untouched
+        let mut state = State::default()
+            .with_memory(HashMap::from([(
+                PathBuf::from("::a.txt"),
+                "1\n2\n3\n".to_string(),
+            )]))
+            .unwrap();
+        let bad = state
+            .patch(&Patch::default().with_replace("::a.txt", "2\n", "two\n"))
+            .unwrap()
+            .rollback_id;
+        state
+            .patch(&Patch::default().with_replace("::a.txt", "two\n", "TWO\n"))
+            .unwrap();
+        let conflicts = state.revert_merge(bad, bad).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, PathBuf::from("::a.txt"));
+        assert!(conflicts[0].merged.contains("<<<<<<<"));
+        assert_eq!(state.read(Path::new("::a.txt")).unwrap(), "1\nTWO\n3\n");
+        assert!(state.revert_merge(100, 200).is_err());
+
+        // A revert that can't write a file fails as a whole, and changes nothing
+        let mut state = State::default()
+            .with_memory(HashMap::from([
+                (PathBuf::from("::a.txt"), "a\n".to_string()),
+                (PathBuf::from("::b.txt"), "b\n".to_string()),
+            ]))
+            .unwrap();
+        let bad = state
+            .patch(
+                &Patch::default()
+                    .with_write("::a.txt", "A\n")
+                    .with_write("::b.txt", "B\n"),
+            )
+            .unwrap()
+            .rollback_id;
+        state.protected = vec!["::b.txt".to_string()];
+        assert!(matches!(
+            state.revert_merge(bad, bad),
+            Err(Error::Revert(_))
+        ));
+        assert_eq!(state.read(Path::new("::a.txt")).unwrap(), "A\n");
+        assert_eq!(state.read(Path::new("::b.txt")).unwrap(), "B\n");
+    }
+
+    #[test]
+    fn test_revert_merge_keeps_folded_edits() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        let path = temp_dir.path().join("a.txt");
+        std::fs::write(&path, "1\n2\n3\n4\n5\n").unwrap();
+        let mut state = State::default().with_directory(root, vec![]).unwrap();
+        state
+            .patch(&Patch::default().with_replace("a.txt", "1\n", "one\n"))
+            .unwrap();
+
+        // The user edits the file, and the next step's patch folds the edit into its range
+        std::fs::write(&path, "one\n2\nuser\n4\n5\n").unwrap();
+        let step = state.mark().unwrap();
+        state
+            .patch(&Patch::default().with_replace("a.txt", "5\n", "five\n"))
+            .unwrap();
+        assert!(state.folded.contains(&(step + 1)));
+
+        let diff = state.diff(step, None).unwrap();
+        assert_eq!((diff.added(), diff.removed()), (1, 1));
+        assert!(diff.unified().contains("-5\n+five\n"));
+
+        let conflicts = state.revert_merge(step, u64::MAX).unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "one\n2\nuser\n4\n5\n"
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut state = State::default()
+            .with_memory(HashMap::from([(
+                PathBuf::from("::a.txt"),
+                "1\n2\n3\n".to_string(),
+            )]))
+            .unwrap();
+        let first = state
+            .patch(&Patch::default().with_replace("::a.txt", "2\n", "two\n"))
+            .unwrap()
+            .rollback_id;
+        let second = state
+            .patch(
+                &Patch::default()
+                    .with_write("::b.txt", "new\n")
+                    .with_view("::a.txt"),
+            )
+            .unwrap()
+            .rollback_id;
+        state
+            .patch(&Patch::default().with_delete("::b.txt"))
+            .unwrap();
+
+        // A single step
+        let diff = state.diff(first, Some(second)).unwrap();
+        assert_eq!(diff.files.len(), 1);
+        assert_eq!((diff.added(), diff.removed()), (1, 1));
+        assert!(diff.unified().contains("-2\n+two\n"));
+
+        // Up to the current state, the created and deleted file cancels out
+        let diff = state.diff(first, None).unwrap();
+        assert_eq!(diff.files.len(), 1);
+        assert_eq!(diff.files[0].path, PathBuf::from("::a.txt"));
+
+        let diff = state.diff(second, None).unwrap();
+        assert!(diff.is_empty());
+        let diff = state.diff(second, Some(second + 1)).unwrap();
+        assert_eq!(diff.files[0].status, diff::FileStatus::Added);
+    }
+
+    #[test]
+    fn test_diff_export_import() {
+        use std::process::Command;
+
+        let originals = [
+            ("a.txt", "1\n2\n3\n"),
+            ("b.txt", "b\n"),
+            ("c.txt", "c\n"),
+            ("tab\there.txt", "x\n"),
+        ];
+        let mut source = StateTest::new().unwrap();
+        let mut target = StateTest::new().unwrap();
+        for (path, content) in originals {
+            source.write(path, content).unwrap();
+            target.write(path, content).unwrap();
+        }
+
+        let first = source
+            .state
+            .patch(
+                &Patch::default()
+                    .with_replace("a.txt", "2\n", "two\n")
+                    .with_delete("b.txt")
+                    .with_write("new.txt", "new")
+                    .with_write("empty.txt", "")
+                    .with_write("new file.txt", "spaced\n")
+                    .with_replace("tab\there.txt", "x", "y")
+                    .with_write("::mem", "mem"),
+            )
+            .unwrap()
+            .rollback_id;
+        let exported = source.state.diff(first, None).unwrap().git();
+        assert!(!exported.contains("::mem"));
+        assert!(exported.contains("diff --git \"a/tab\\there.txt\" \"b/tab\\there.txt\"\n"));
+
+        let info = target
+            .state
+            .patch(&Patch::from_diff(&exported).unwrap())
+            .unwrap();
+        assert!(info.failures.is_empty());
+        let changed = [
+            "a.txt",
+            "new.txt",
+            "empty.txt",
+            "new file.txt",
+            "tab\there.txt",
+        ];
+        for path in changed {
+            assert_eq!(target.read(path).unwrap(), source.read(path).unwrap());
+        }
+        assert!(target.read("b.txt").is_err());
+
+        // git applies the export too
+        let repo = TempDir::new().unwrap();
+        for (path, content) in originals {
+            std::fs::write(repo.path().join(path), content).unwrap();
+        }
+        std::fs::write(repo.path().join("export.patch"), &exported).unwrap();
+        let out = Command::new("git")
+            .args(["apply", "export.patch"])
+            .current_dir(repo.path())
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        for path in changed {
+            assert_eq!(
+                std::fs::read_to_string(repo.path().join(path)).unwrap(),
+                source.read(path).unwrap()
+            );
+        }
+        assert!(!repo.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn test_view_skips_binary() {
+        let mut test = StateTest::new().unwrap();
+        test.write("a.txt", "text").unwrap();
+        let root = test.state.fs_dir().unwrap().root.clone();
+        std::fs::write(root.join("b.bin"), [0u8, 1, 2]).unwrap();
+
+        let (_, count) = test
+            .state
+            .view(root.clone(), vec!["*".to_string()])
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // Editing a binary file fails like any other operation, and the rest of the patch applies
+        let info = test
+            .state
+            .patch(
+                &Patch::default()
+                    .with_write("b.bin", "text")
+                    .with_write("a.txt", "new"),
+            )
+            .unwrap();
+        assert_eq!(info.succeeded, 1);
+        assert_eq!(info.failures.len(), 1);
+        assert!(info.failures[0].user.contains("b.bin"));
+        assert_eq!(test.read("a.txt").unwrap(), "new");
+        assert_eq!(std::fs::read(root.join("b.bin")).unwrap(), [0u8, 1, 2]);
+    }
 }
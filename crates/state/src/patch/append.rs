@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Adds content to the end of a file, without reproducing the existing content.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Append {
+    pub path: PathBuf,
+    pub content: String,
+}
+
+impl Append {
+    /// Applies the append operation to the given input string. If the input doesn't end with a
+    /// newline, one is added before the new content.
+    pub(crate) fn apply(&self, input: &str) -> String {
+        let mut result = input.to_string();
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str(&self.content);
+        result
+    }
+}
+
+/// Adds content to the start of a file, without reproducing the existing content.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Prepend {
+    pub path: PathBuf,
+    pub content: String,
+}
+
+impl Prepend {
+    /// Applies the prepend operation to the given input string. If the new content doesn't end
+    /// with a newline, one is added before the existing content.
+    pub(crate) fn apply(&self, input: &str) -> String {
+        let mut result = self.content.clone();
+        if !input.is_empty() && !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str(input);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_append_apply() {
+        let append = |content: &str| Append {
+            path: PathBuf::from("file.txt"),
+            content: content.to_string(),
+        };
+        assert_eq!(append("three\n").apply("one\ntwo\n"), "one\ntwo\nthree\n");
+        assert_eq!(append("three").apply("one\ntwo"), "one\ntwo\nthree");
+        assert_eq!(append("one\n").apply(""), "one\n");
+    }
+
+    #[test]
+    fn test_prepend_apply() {
+        let prepend = |content: &str| Prepend {
+            path: PathBuf::from("file.txt"),
+            content: content.to_string(),
+        };
+        assert_eq!(prepend("zero\n").apply("one\ntwo\n"), "zero\none\ntwo\n");
+        assert_eq!(prepend("zero").apply("one\n"), "zero\none\n");
+        assert_eq!(prepend("zero").apply(""), "zero");
+        assert_eq!(prepend("").apply("one\n"), "one\n");
+    }
+}
@@ -0,0 +1,207 @@
+//! Finds the region of a file most similar to text that a replace operation failed to find, so
+//! the model can see what the file actually contains instead of retrying blind.
+
+/// Matches less similar than this aren't worth showing.
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// The number of candidates, ranked by a cheap line-wise similarity, that are re-ranked by edit
+/// distance.
+const RERANK: usize = 5;
+
+/// A region of the input, as a 0-based line offset and the lines in the region.
+#[derive(Debug)]
+pub(crate) struct ClosestMatch<'a> {
+    pub start: usize,
+    pub lines: Vec<&'a str>,
+}
+
+/// Formats lines with 1-based line numbers, for model-facing error messages.
+pub(crate) fn numbered(lines: &[&str], first: usize) -> String {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, l)| format!("{:>4}: {l}\n", first + i + 1))
+        .collect()
+}
+
+/// The character bigrams of a line with whitespace removed, sorted so that two lines can be
+/// compared by merging.
+struct Bigrams {
+    text: String,
+    pairs: Vec<(char, char)>,
+}
+
+impl Bigrams {
+    fn new(line: &str) -> Self {
+        let text: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        let chars: Vec<char> = text.chars().collect();
+        let mut pairs: Vec<(char, char)> = chars.windows(2).map(|w| (w[0], w[1])).collect();
+        pairs.sort_unstable();
+        Self { text, pairs }
+    }
+
+    /// The Sørensen–Dice coefficient of the two lines' bigrams, matching
+    /// `strsim::sorensen_dice`.
+    fn similarity(&self, other: &Bigrams) -> f64 {
+        if self.text == other.text {
+            return 1.0;
+        }
+        if self.pairs.is_empty() || other.pairs.is_empty() {
+            return 0.0;
+        }
+        let (mut i, mut j, mut shared) = (0, 0, 0);
+        while i < self.pairs.len() && j < other.pairs.len() {
+            match self.pairs[i].cmp(&other.pairs[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    shared += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        (2 * shared) as f64 / (self.pairs.len() + other.pairs.len()) as f64
+    }
+}
+
+/// Returns the window of lines in the input that is most similar to `old`, comparing lines with
+/// leading and trailing whitespace removed. Returns None if nothing is similar enough.
+pub(crate) fn closest_match<'a>(input: &'a str, old: &str) -> Option<ClosestMatch<'a>> {
+    let old_lines: Vec<&str> = old.trim_matches('\n').lines().map(str::trim).collect();
+    let input_lines: Vec<&str> = input.lines().collect();
+    if old_lines.is_empty() || input_lines.is_empty() {
+        return None;
+    }
+    let size = old_lines.len().min(input_lines.len());
+
+    // Rank all windows by mean per-line bigram similarity. Bigrams are computed once per line, so
+    // each comparison is a linear merge without allocating.
+    let old_bigrams: Vec<Bigrams> = old_lines.iter().map(|l| Bigrams::new(l)).collect();
+    let input_bigrams: Vec<Bigrams> = input_lines.iter().map(|l| Bigrams::new(l)).collect();
+    let mut ranked: Vec<(usize, f64)> = input_bigrams
+        .windows(size)
+        .enumerate()
+        .map(|(i, window)| {
+            let total: f64 = old_bigrams
+                .iter()
+                .zip(window)
+                .map(|(a, b)| a.similarity(b))
+                .sum();
+            (i, total / old_lines.len() as f64)
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    // Re-rank the best candidates by edit distance over the whole window
+    let old_text = old_lines.join("\n");
+    ranked
+        .into_iter()
+        .take(RERANK)
+        .map(|(i, _)| {
+            let window: Vec<&str> = input_lines[i..i + size].iter().map(|l| l.trim()).collect();
+            (
+                i,
+                strsim::normalized_levenshtein(&old_text, &window.join("\n")),
+            )
+        })
+        .filter(|(_, score)| *score >= MIN_SIMILARITY)
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(start, _)| ClosestMatch {
+            start,
+            lines: input_lines[start..start + size].to_vec(),
+        })
+}
+
+/// Describes the closest match to `old` in the input, for appending to a model-facing error. The
+/// description shows the matching lines with line numbers, and a diff from the requested text to
+/// the file's text. Returns an empty string if nothing is similar enough.
+pub(crate) fn describe(input: &str, old: &str) -> String {
+    let Some(m) = closest_match(input, old) else {
+        return String::new();
+    };
+    let mut found = m.lines.join("\n");
+    found.push('\n');
+    let mut requested = old.trim_matches('\n').to_string();
+    requested.push('\n');
+    let mut opts = diffy::DiffOptions::new();
+    opts.set_original_filename("requested")
+        .set_modified_filename("file");
+    let diff = opts.create_patch(&requested, &found);
+    format!(
+        "\n\nThe most similar text in the file is at lines {} to {}:\n{}\nDifferences between the text you specified and the file:\n{}",
+        m.start + 1,
+        m.start + m.lines.len(),
+        numbered(&m.lines, m.start),
+        diff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    const INPUT: &str = indoc! {"
+        fn main() {
+            let config = Config::load();
+            let state = config.state()?;
+            run(state);
+        }
+
+        fn run(state: State) {
+            state.apply();
+        }
+    "};
+
+    #[test]
+    fn test_closest_match() {
+        let m = closest_match(
+            INPUT,
+            "let config = Config::load();\nlet state = config.state();\n",
+        )
+        .unwrap();
+        assert_eq!(m.start, 1);
+        assert_eq!(m.lines.len(), 2);
+
+        // Longer than the file
+        let long = format!("{INPUT}\nextra\n");
+        assert_eq!(closest_match(INPUT, &long).unwrap().start, 0);
+
+        assert!(closest_match(INPUT, "something else entirely").is_none());
+        assert!(closest_match(INPUT, "").is_none());
+        assert!(closest_match("", "text").is_none());
+    }
+
+    #[test]
+    fn test_bigrams() {
+        let lines = [
+            "let config = Config::load();",
+            "let state = config.state()?;",
+            "  fn main() {",
+            "fn main(){",
+            "a",
+            "",
+            "aaab",
+            "aab",
+        ];
+        for a in lines {
+            for b in lines {
+                assert_eq!(
+                    Bigrams::new(a).similarity(&Bigrams::new(b)),
+                    strsim::sorensen_dice(a, b),
+                    "{a:?} {b:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        let desc = describe(INPUT, "fn run(state: &State) {\n    state.apply();\n}");
+        assert!(desc.contains("at lines 7 to 9"));
+        assert!(desc.contains("   7: fn run(state: State) {\n"));
+        assert!(desc.contains("-fn run(state: &State) {\n+fn run(state: State) {\n"));
+        assert_eq!(describe(INPUT, "unrelated"), "");
+    }
+}
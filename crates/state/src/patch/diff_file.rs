@@ -0,0 +1,403 @@
+//! Importing patch files, as produced by `git diff` or `diff -u`, into patches.
+use std::path::{Component, Path, PathBuf};
+
+use super::{unified_diff::parse_header, Operation, Patch, UnifiedDiff, WriteFile};
+use crate::{
+    diff::unquote_path,
+    error::{Error, Result},
+};
+
+/// The changes to one file in a patch file.
+#[derive(Debug, Default)]
+struct FileSection {
+    /// Paths from a `diff --git` line, used when there are no `---`/`+++` headers
+    git_paths: Option<(PathBuf, PathBuf)>,
+    /// Paths from the `---`/`+++` headers. None is `/dev/null`.
+    old: Option<Option<PathBuf>>,
+    new: Option<Option<PathBuf>>,
+    created: bool,
+    deleted: bool,
+    rename_from: Option<PathBuf>,
+    rename_to: Option<PathBuf>,
+    /// The file headers and hunks
+    body: String,
+    has_hunks: bool,
+}
+
+impl FileSection {
+    fn old_path(&self) -> Option<PathBuf> {
+        match &self.old {
+            Some(old) => old.clone(),
+            None => self.git_paths.as_ref().map(|(old, _)| old.clone()),
+        }
+    }
+
+    fn new_path(&self) -> Option<PathBuf> {
+        match &self.new {
+            Some(new) => new.clone(),
+            None => self.git_paths.as_ref().map(|(_, new)| new.clone()),
+        }
+    }
+
+    /// Converts the section to operations, appending them to `ops`.
+    fn into_ops(self, ops: &mut Vec<Operation>) -> Result<()> {
+        let old = self.old_path();
+        let new = self.new_path();
+        if self.deleted || (self.old.is_some() && new.is_none()) {
+            let path = old.ok_or_else(|| Error::Diff("deleted file has no path".into()))?;
+            ops.push(Operation::Delete(path));
+        } else if self.created || (self.new.is_some() && old.is_none()) {
+            let path = new.ok_or_else(|| Error::Diff("created file has no path".into()))?;
+            let content = if self.has_hunks {
+                let diff = UnifiedDiff {
+                    path: path.clone(),
+                    diff: self.body,
+                };
+                let (content, failures) = diff
+                    .apply("")
+                    .map_err(|e| Error::Diff(format!("{}: {}", path.display(), e.user)))?;
+                if let Some(e) = failures.first() {
+                    return Err(Error::Diff(format!("{}: {}", path.display(), e.user)));
+                }
+                content
+            } else {
+                String::new()
+            };
+            ops.push(Operation::Create(WriteFile { path, content }));
+        } else {
+            let mut path = new
+                .or(old)
+                .ok_or_else(|| Error::Diff("changed file has no path".into()))?;
+            if let (Some(from), Some(to)) = (self.rename_from, self.rename_to) {
+                ops.push(Operation::Rename(from, to.clone()));
+                path = to;
+            }
+            if self.has_hunks {
+                ops.push(Operation::UnifiedDiff(UnifiedDiff {
+                    path,
+                    diff: self.body,
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a path from a patch header, dropping any timestamp after a tab and the first path
+/// component, as `git apply` does by default. Returns None for `/dev/null`.
+fn header_path(s: &str) -> Option<PathBuf> {
+    let s = s.split('\t').next().unwrap_or_default().trim();
+    if s == "/dev/null" {
+        return None;
+    }
+    Some(strip_prefix(Path::new(&unquote_path(s))))
+}
+
+/// Parses the old and new paths from the rest of a `diff --git` line, where either path may be
+/// quoted. The first path component is dropped from both.
+fn git_paths(rest: &str) -> Option<(PathBuf, PathBuf)> {
+    let (old, new) = if let Some(quoted) = rest.strip_prefix('"') {
+        // The old path ends at the first quote that isn't escaped
+        let mut escaped = false;
+        let (end, _) = quoted.char_indices().find(|&(_, c)| {
+            let end = c == '"' && !escaped;
+            escaped = c == '\\' && !escaped;
+            end
+        })?;
+        (&rest[..end + 2], rest[end + 2..].trim_start())
+    } else if rest.ends_with('"') {
+        let (old, new) = rest.rsplit_once(" \"")?;
+        (old, &rest[rest.len() - new.len() - 1..])
+    } else {
+        let (old, _) = rest.split_once(" b/")?;
+        (old, &rest[old.len() + 1..])
+    };
+    Some((
+        strip_prefix(Path::new(&unquote_path(old))),
+        strip_prefix(Path::new(&unquote_path(new))),
+    ))
+}
+
+/// Drops the first component of a relative path with more than one component, so `a/src/lib.rs`
+/// becomes `src/lib.rs`.
+fn strip_prefix(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Normal(_)) if components.clone().next().is_some() => {
+            components.as_path().to_path_buf()
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+impl Patch {
+    /// Imports a patch file in unified diff format, as produced by `git diff` or `diff -u`, and
+    /// accepted by `git apply`. Each changed file becomes a `UnifiedDiff` operation, created and
+    /// deleted files become `Create` and `Delete` operations, and git renames become `Rename`
+    /// operations. Paths have their first component stripped, like `git apply -p1`. Text before
+    /// the first file, such as a commit message, is ignored.
+    pub fn from_diff(diff: &str) -> Result<Self> {
+        let mut sections: Vec<FileSection> = Vec::new();
+        // The old and new lines left in the current hunk, by its header. Lines in a hunk are
+        // content, even if they look like file headers.
+        let mut hunk: Option<(usize, usize)> = None;
+        let mut lines = diff.lines().peekable();
+        while let Some(line) = lines.next() {
+            if let (Some(left), Some(section)) = (hunk.as_mut(), sections.last_mut()) {
+                if *left != (0, 0) || line.starts_with('\\') {
+                    match line.chars().next() {
+                        Some('-') => left.0 = left.0.saturating_sub(1),
+                        Some('+') => left.1 = left.1.saturating_sub(1),
+                        Some('\\') => {}
+                        _ => *left = (left.0.saturating_sub(1), left.1.saturating_sub(1)),
+                    }
+                    section.body.push_str(line);
+                    section.body.push('\n');
+                    continue;
+                }
+                hunk = None;
+            }
+            let next_is_new_header = lines.peek().is_some_and(|l| l.starts_with("+++ "));
+            if let Some(rest) = line.strip_prefix("diff --git ") {
+                sections.push(FileSection {
+                    git_paths: git_paths(rest),
+                    ..Default::default()
+                });
+                continue;
+            }
+            if line.starts_with("--- ") && next_is_new_header {
+                let new_line = lines.next().unwrap_or_default();
+                // Plain diffs have no `diff --git` line, so headers start a new file
+                if sections
+                    .last()
+                    .is_none_or(|s| s.old.is_some() || s.has_hunks)
+                {
+                    sections.push(FileSection::default());
+                }
+                let section = sections.last_mut().unwrap();
+                section.old = Some(header_path(&line[4..]));
+                section.new = Some(header_path(&new_line[4..]));
+                section.body.push_str(&format!("{line}\n{new_line}\n"));
+                continue;
+            }
+            let Some(section) = sections.last_mut() else {
+                continue;
+            };
+            if line.starts_with("@@") {
+                hunk = parse_header(line).map(|(_, old, new)| (old, new));
+                section.has_hunks = true;
+                section.body.push_str(line);
+                section.body.push('\n');
+            } else if section.old.is_none() && !section.has_hunks {
+                // Extended git headers
+                if line.starts_with("new file mode") {
+                    section.created = true;
+                } else if line.starts_with("deleted file mode") {
+                    section.deleted = true;
+                } else if let Some(from) = line.strip_prefix("rename from ") {
+                    section.rename_from = Some(PathBuf::from(unquote_path(from)));
+                } else if let Some(to) = line.strip_prefix("rename to ") {
+                    section.rename_to = Some(PathBuf::from(unquote_path(to)));
+                }
+            }
+            // Anything else after a hunk, like a format-patch signature, isn't part of the diff
+        }
+
+        if sections.is_empty() {
+            return Err(Error::Diff("no files found in diff".into()));
+        }
+        let mut ops = Vec::new();
+        for section in sections {
+            section.into_ops(&mut ops)?;
+        }
+        Ok(Patch { ops })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_from_diff() {
+        let diff = indoc! {"
+            Subject: [PATCH] An example change
+
+            diff --git a/src/lib.rs b/src/lib.rs
+            index 1111111..2222222 100644
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -1,2 +1,2 @@
+             fn a() {}
+            -fn b() {}
+            +fn c() {}
+            diff --git a/src/new.rs b/src/new.rs
+            new file mode 100644
+            index 0000000..3333333
+            --- /dev/null
+            +++ b/src/new.rs
+            @@ -0,0 +1,2 @@
+            +one
+            +two
+            diff --git a/empty.txt b/empty.txt
+            new file mode 100644
+            index 0000000..e69de29
+            diff --git a/old.rs b/old.rs
+            deleted file mode 100644
+            index 4444444..0000000
+            --- a/old.rs
+            +++ /dev/null
+            @@ -1 +0,0 @@
+            -old
+            diff --git a/from.rs b/to.rs
+            similarity index 100%
+            rename from from.rs
+            rename to to.rs
+        "};
+        let patch = Patch::from_diff(diff).unwrap();
+        assert_eq!(
+            patch.ops,
+            vec![
+                Operation::UnifiedDiff(UnifiedDiff {
+                    path: PathBuf::from("src/lib.rs"),
+                    diff: "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,2 +1,2 @@\n fn a() {}\n-fn b() {}\n+fn c() {}\n".into(),
+                }),
+                Operation::Create(WriteFile {
+                    path: PathBuf::from("src/new.rs"),
+                    content: "one\ntwo\n".into(),
+                }),
+                Operation::Create(WriteFile {
+                    path: PathBuf::from("empty.txt"),
+                    content: String::new(),
+                }),
+                Operation::Delete(PathBuf::from("old.rs")),
+                Operation::Rename(PathBuf::from("from.rs"), PathBuf::from("to.rs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_plain_diff() {
+        let diff = indoc! {"
+            --- one.txt.orig\t2024-01-01 00:00:00
+            +++ one.txt\t2024-01-01 00:00:00
+            @@ -1 +1 @@
+            -a
+            +b
+            --- a/dir/two.txt
+            +++ b/dir/two.txt
+            @@ -1 +1 @@
+            -c
+            +d
+        "};
+        let patch = Patch::from_diff(diff).unwrap();
+        let paths: Vec<_> = patch.ops.iter().map(|op| op.path()).collect();
+        assert_eq!(paths, vec![Path::new("one.txt"), Path::new("dir/two.txt")]);
+
+        assert!(Patch::from_diff("no diff here").is_err());
+    }
+
+    #[test]
+    fn test_from_format_patch() {
+        // The output of `git format-patch`, with a changed line that looks like file headers,
+        // and a signature after the last hunk
+        let diff = indoc! {"
+            From 0e446b5085873b163ae278a8772a67f25228abfc Mon Sep 17 00:00:00 2001
+            From: A U Thor <a@b.c>
+            Date: Tue, 2 Jan 2024 00:00:00 +0000
+            Subject: [PATCH] Update the notes
+
+            A longer description.
+            ---
+             empty.txt    | 0
+             new file.txt | 2 ++
+             notes.txt    | 2 +-
+             old.txt      | 1 -
+             4 files changed, 3 insertions(+), 2 deletions(-)
+             create mode 100644 empty.txt
+             create mode 100644 new file.txt
+             delete mode 100644 old.txt
+
+            diff --git a/empty.txt b/empty.txt
+            new file mode 100644
+            index 0000000..e69de29
+            diff --git a/new file.txt b/new file.txt
+            new file mode 100644
+            index 0000000..422c2b7
+            --- /dev/null
+            +++ b/new file.txt\t
+            @@ -0,0 +1,2 @@
+            +a
+            +b
+            diff --git a/notes.txt b/notes.txt
+            index 7900cc9..1cd7cd1 100644
+            --- a/notes.txt
+            +++ b/notes.txt
+            @@ -1,3 +1,3 @@
+             title
+            --- old
+            +++ new
+             end
+            diff --git a/old.txt b/old.txt
+            deleted file mode 100644
+            index 2fa992c..0000000
+            --- a/old.txt
+            +++ /dev/null
+            @@ -1 +0,0 @@
+            -keep
+            --\x20
+            2.39.5
+
+        "};
+        let patch = Patch::from_diff(diff).unwrap();
+        assert_eq!(
+            patch.ops,
+            vec![
+                Operation::Create(WriteFile {
+                    path: PathBuf::from("empty.txt"),
+                    content: String::new(),
+                }),
+                Operation::Create(WriteFile {
+                    path: PathBuf::from("new file.txt"),
+                    content: "a\nb\n".into(),
+                }),
+                Operation::UnifiedDiff(UnifiedDiff {
+                    path: PathBuf::from("notes.txt"),
+                    diff: "--- a/notes.txt\n+++ b/notes.txt\n@@ -1,3 +1,3 @@\n title\n--- old\n+++ new\n end\n".into(),
+                }),
+                Operation::Delete(PathBuf::from("old.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_diff_quoted_paths() {
+        let diff = indoc! {r#"
+            diff --git "a/tab\there" "b/tab\there"
+            --- "a/tab\there"
+            +++ "b/tab\there"
+            @@ -1 +1 @@
+            -a
+            +b
+            diff --git "a/caf\303\251" b/cafe
+            similarity index 100%
+            rename from "caf\303\251"
+            rename to cafe
+            diff --git a/cafe "b/caf\303\251"
+            similarity index 100%
+            rename from cafe
+            rename to "caf\303\251"
+        "#};
+        let patch = Patch::from_diff(diff).unwrap();
+        assert_eq!(patch.ops[0].path(), Path::new("tab\there"));
+        assert_eq!(
+            patch.ops[1..],
+            [
+                Operation::Rename(PathBuf::from("café"), PathBuf::from("cafe")),
+                Operation::Rename(PathBuf::from("cafe"), PathBuf::from("café")),
+            ]
+        );
+    }
+}
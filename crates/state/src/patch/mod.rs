@@ -1,13 +1,25 @@
 //! Patch operations that modify state. View operations are also included here, which lets us
 //! sequence them with other operations.
+mod append;
+mod closest;
+mod diff_file;
 mod insert;
 mod replace;
+mod replace_all;
 mod replace_fuzzy;
+mod replace_lines;
+mod rust_item;
+mod unified_diff;
 mod write;
 
+pub use append::*;
 pub use insert::*;
 pub use replace::*;
+pub use replace_all::*;
 pub use replace_fuzzy::*;
+pub use replace_lines::*;
+pub use rust_item::*;
+pub use unified_diff::*;
 pub use write::*;
 
 /// Internal error type for patch operations
@@ -33,15 +45,42 @@ pub enum Operation {
     /// Write or create a complete file.
     Write(write::WriteFile),
 
+    /// Create a new file. Fails if the file already exists.
+    Create(write::WriteFile),
+
+    /// Add content to the end of a file.
+    Append(append::Append),
+
+    /// Add content to the start of a file.
+    Prepend(append::Prepend),
+
     /// Replace one piece of text with another, with fuzzy matching.
     ReplaceFuzzy(replace_fuzzy::ReplaceFuzzy),
 
     /// Replace one piece of text with another, requiring an exact match.
     Replace(replace::Replace),
 
+    /// Replace every match of a literal or regex pattern, optionally checking the match count.
+    ReplaceAll(replace_all::ReplaceAll),
+
     /// Insert text at a specific line in a file.
     Insert(insert::Insert),
 
+    /// Replace a range of lines in a file, addressed like ViewRange.
+    ReplaceLines(replace_lines::ReplaceLines),
+
+    /// Apply a unified diff to a file.
+    UnifiedDiff(unified_diff::UnifiedDiff),
+
+    /// Replace or insert a whole Rust item, addressed by its path.
+    RustItem(rust_item::RustItem),
+
+    /// Delete a file.
+    Delete(PathBuf),
+
+    /// Rename a file from the first path to the second. The destination must not exist.
+    Rename(PathBuf, PathBuf),
+
     /// View just enters the path as an affected file without modifying it.
     View(PathBuf),
 
@@ -59,36 +98,72 @@ impl Operation {
     pub fn name(&self) -> &str {
         match self {
             Operation::Write(_) => "write",
+            Operation::Create(_) => "create",
+            Operation::Append(_) => "append",
+            Operation::Prepend(_) => "prepend",
             Operation::ReplaceFuzzy(_) => "replace_fuzzy",
             Operation::Replace(_) => "replace",
+            Operation::ReplaceAll(_) => "replace_all",
             Operation::Insert(_) => "insert",
+            Operation::ReplaceLines(_) => "replace_lines",
+            Operation::UnifiedDiff(_) => "unified_diff",
+            Operation::RustItem(_) => "rust_item",
+            Operation::Delete(_) => "delete",
+            Operation::Rename(_, _) => "rename",
             Operation::View(_) => "view",
             Operation::ViewRange(_, _, _) => "view_range",
             Operation::Undo(_) => "undo",
         }
     }
 
-    /// Returns the path of the file affected by this operation.
+    /// Returns the path of the file affected by this operation. For a rename, this is the source
+    /// path - use `paths` to get both.
     pub fn path(&self) -> &PathBuf {
         match self {
             Operation::Write(write_file) => &write_file.path,
+            Operation::Create(write_file) => &write_file.path,
+            Operation::Append(append) => &append.path,
+            Operation::Prepend(prepend) => &prepend.path,
             Operation::ReplaceFuzzy(replace) => &replace.path,
             Operation::Replace(replace) => &replace.path,
+            Operation::ReplaceAll(replace) => &replace.path,
             Operation::Insert(insert) => &insert.path,
+            Operation::ReplaceLines(replace) => &replace.path,
+            Operation::UnifiedDiff(diff) => &diff.path,
+            Operation::RustItem(item) => &item.path,
+            Operation::Delete(path) => path,
+            Operation::Rename(from, _) => from,
             Operation::View(path) => path,
             Operation::ViewRange(path, _, _) => path,
             Operation::Undo(path) => path,
         }
     }
 
+    /// Returns all paths affected by this operation.
+    pub fn paths(&self) -> Vec<&PathBuf> {
+        match self {
+            Operation::Rename(from, to) => vec![from, to],
+            _ => vec![self.path()],
+        }
+    }
+
     /// Returns true if this operation modifies the state, false if it's read-only.
     /// View and ViewRange operations are not considered modifications.
     pub fn is_modification(&self) -> bool {
         match self {
             Operation::Write(_) => true,
+            Operation::Create(_) => true,
+            Operation::Append(_) => true,
+            Operation::Prepend(_) => true,
             Operation::ReplaceFuzzy(_) => true,
             Operation::Replace(_) => true,
+            Operation::ReplaceAll(_) => true,
             Operation::Insert(_) => true,
+            Operation::ReplaceLines(_) => true,
+            Operation::UnifiedDiff(_) => true,
+            Operation::RustItem(_) => true,
+            Operation::Delete(_) => true,
+            Operation::Rename(_, _) => true,
             Operation::View(_) => false,
             Operation::ViewRange(_, _, _) => false,
             Operation::Undo(_) => true,
@@ -111,6 +186,30 @@ impl Operation {
                 renderer.pop();
                 renderer.pop();
             }
+            Operation::Create(write_file) => {
+                let path_str = write_file.path.to_string_lossy();
+                renderer.push("create");
+                renderer.push(&format!("create: {path_str}"));
+                renderer.para(&write_file.content);
+                renderer.pop();
+                renderer.pop();
+            }
+            Operation::Append(append) => {
+                let path_str = append.path.to_string_lossy();
+                renderer.push("append");
+                renderer.push(&format!("append to file: {path_str}"));
+                renderer.para(&append.content);
+                renderer.pop();
+                renderer.pop();
+            }
+            Operation::Prepend(prepend) => {
+                let path_str = prepend.path.to_string_lossy();
+                renderer.push("prepend");
+                renderer.push(&format!("prepend to file: {path_str}"));
+                renderer.para(&prepend.content);
+                renderer.pop();
+                renderer.pop();
+            }
             Operation::ReplaceFuzzy(replace) => {
                 let path_str = replace.path.to_string_lossy();
                 renderer.push("replace_fuzzy");
@@ -137,6 +236,24 @@ impl Operation {
                 renderer.pop();
                 renderer.pop();
             }
+            Operation::ReplaceAll(replace) => {
+                let path_str = replace.path.to_string_lossy();
+                let kind = if replace.regex { "regex" } else { "text" };
+                let count = match replace.count {
+                    Some(c) => format!(", expecting {c} matches"),
+                    None => String::new(),
+                };
+                renderer.push("replace_all");
+                renderer.push(&format!("replace all ({kind}{count}) in file: {path_str}"));
+                renderer.push("pattern:");
+                renderer.para(&replace.pattern);
+                renderer.pop();
+                renderer.push("new:");
+                renderer.para(&replace.new);
+                renderer.pop();
+                renderer.pop();
+                renderer.pop();
+            }
             Operation::Insert(insert) => {
                 let path_str = insert.path.to_string_lossy();
                 renderer.push("insert");
@@ -150,6 +267,59 @@ impl Operation {
                 renderer.pop();
                 renderer.pop();
             }
+            Operation::ReplaceLines(replace) => {
+                let path_str = replace.path.to_string_lossy();
+                let end_str = match replace.end {
+                    Some(e) => e.to_string(),
+                    None => "end".to_string(),
+                };
+                renderer.push("replace_lines");
+                renderer.push(&format!(
+                    "replace lines {} to {end_str} in file: {path_str}",
+                    replace.start
+                ));
+                if let Some(expected) = &replace.expected {
+                    renderer.push("expected:");
+                    renderer.para(expected);
+                    renderer.pop();
+                }
+                renderer.push("new:");
+                renderer.para(&replace.new);
+                renderer.pop();
+                renderer.pop();
+                renderer.pop();
+            }
+            Operation::UnifiedDiff(diff) => {
+                let path_str = diff.path.to_string_lossy();
+                renderer.push("unified_diff");
+                renderer.push(&format!("unified diff for file: {path_str}"));
+                renderer.para(&diff.diff);
+                renderer.pop();
+                renderer.pop();
+            }
+            Operation::RustItem(item) => {
+                let path_str = item.path.to_string_lossy();
+                renderer.push("rust_item");
+                renderer.push(&format!("rust item {} in file: {path_str}", item.item));
+                renderer.para(&item.new);
+                renderer.pop();
+                renderer.pop();
+            }
+            Operation::Delete(path) => {
+                let path_str = path.to_string_lossy();
+                renderer.push("delete");
+                renderer.para(&format!("delete file: {path_str}"));
+                renderer.pop();
+            }
+            Operation::Rename(from, to) => {
+                renderer.push("rename");
+                renderer.para(&format!(
+                    "rename file: {} to {}",
+                    from.to_string_lossy(),
+                    to.to_string_lossy()
+                ));
+                renderer.pop();
+            }
             Operation::View(_) => {
                 renderer.para("view");
             }
@@ -201,6 +371,45 @@ impl Patch {
         self
     }
 
+    /// Adds a Create operation to the patch
+    pub fn with_create<P, S>(mut self, path: P, content: S) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S: AsRef<str>,
+    {
+        self.ops.push(Operation::Create(WriteFile {
+            path: path.as_ref().to_path_buf(),
+            content: content.as_ref().to_string(),
+        }));
+        self
+    }
+
+    /// Adds an Append operation to the patch
+    pub fn with_append<P, S>(mut self, path: P, content: S) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S: AsRef<str>,
+    {
+        self.ops.push(Operation::Append(Append {
+            path: path.as_ref().to_path_buf(),
+            content: content.as_ref().to_string(),
+        }));
+        self
+    }
+
+    /// Adds a Prepend operation to the patch
+    pub fn with_prepend<P, S>(mut self, path: P, content: S) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S: AsRef<str>,
+    {
+        self.ops.push(Operation::Prepend(Prepend {
+            path: path.as_ref().to_path_buf(),
+            content: content.as_ref().to_string(),
+        }));
+        self
+    }
+
     /// Adds a ReplaceFuzzy operation to the patch
     pub fn with_replace_fuzzy<P, S1, S2>(mut self, path: P, old: S1, new: S2) -> Self
     where
@@ -231,6 +440,54 @@ impl Patch {
         self
     }
 
+    /// Adds a ReplaceAll operation to the patch, replacing every occurrence of literal text. If
+    /// `count` is set, the operation fails unless there are exactly that many occurrences.
+    pub fn with_replace_all<P, S1, S2>(
+        mut self,
+        path: P,
+        pattern: S1,
+        new: S2,
+        count: Option<usize>,
+    ) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.ops.push(Operation::ReplaceAll(ReplaceAll {
+            path: path.as_ref().to_path_buf(),
+            pattern: pattern.as_ref().to_string(),
+            new: new.as_ref().to_string(),
+            regex: false,
+            count,
+        }));
+        self
+    }
+
+    /// Adds a ReplaceAll operation to the patch, replacing every match of a regular expression.
+    /// The replacement can refer to capture groups as `$1` or `${name}`.
+    pub fn with_replace_regex<P, S1, S2>(
+        mut self,
+        path: P,
+        pattern: S1,
+        new: S2,
+        count: Option<usize>,
+    ) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.ops.push(Operation::ReplaceAll(ReplaceAll {
+            path: path.as_ref().to_path_buf(),
+            pattern: pattern.as_ref().to_string(),
+            new: new.as_ref().to_string(),
+            regex: true,
+            count,
+        }));
+        self
+    }
+
     /// Adds an Insert operation to the patch
     pub fn with_insert<P, S>(mut self, path: P, line: usize, content: S) -> Self
     where
@@ -245,6 +502,58 @@ impl Patch {
         self
     }
 
+    /// Adds a ReplaceLines operation to the patch. Offsets are 0-based and exclusive, and an
+    /// optional expected text guards against stale line numbers.
+    pub fn with_replace_lines<P, S>(
+        mut self,
+        path: P,
+        start: usize,
+        end: Option<usize>,
+        new: S,
+        expected: Option<S>,
+    ) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S: AsRef<str>,
+    {
+        self.ops.push(Operation::ReplaceLines(ReplaceLines {
+            path: path.as_ref().to_path_buf(),
+            start,
+            end,
+            new: new.as_ref().to_string(),
+            expected: expected.map(|e| e.as_ref().to_string()),
+        }));
+        self
+    }
+
+    /// Adds a RustItem operation to the patch, replacing or inserting the item at the given path
+    pub fn with_rust_item<P, S1, S2>(mut self, path: P, item: S1, new: S2) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.ops.push(Operation::RustItem(RustItem {
+            path: path.as_ref().to_path_buf(),
+            item: item.as_ref().to_string(),
+            new: new.as_ref().to_string(),
+        }));
+        self
+    }
+
+    /// Adds a UnifiedDiff operation to the patch
+    pub fn with_unified_diff<P, S>(mut self, path: P, diff: S) -> Self
+    where
+        P: AsRef<std::path::Path>,
+        S: AsRef<str>,
+    {
+        self.ops.push(Operation::UnifiedDiff(UnifiedDiff {
+            path: path.as_ref().to_path_buf(),
+            diff: diff.as_ref().to_string(),
+        }));
+        self
+    }
+
     /// Adds a Touch operation to the patch
     pub fn with_view<P: AsRef<std::path::Path>>(mut self, path: P) -> Self {
         self.ops.push(Operation::View(path.as_ref().to_path_buf()));
@@ -288,6 +597,26 @@ impl Patch {
         self.with_view_range(path, start_zero_based as usize, end_opt)
     }
 
+    /// Adds a Delete operation to the patch
+    pub fn with_delete<P: AsRef<std::path::Path>>(mut self, path: P) -> Self {
+        self.ops
+            .push(Operation::Delete(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Adds a Rename operation to the patch
+    pub fn with_rename<P1, P2>(mut self, from: P1, to: P2) -> Self
+    where
+        P1: AsRef<std::path::Path>,
+        P2: AsRef<std::path::Path>,
+    {
+        self.ops.push(Operation::Rename(
+            from.as_ref().to_path_buf(),
+            to.as_ref().to_path_buf(),
+        ));
+        self
+    }
+
     /// Adds an Undo operation to the patch
     pub fn with_undo<P: AsRef<std::path::Path>>(mut self, path: P) -> Self {
         self.ops.push(Operation::Undo(path.as_ref().to_path_buf()));
@@ -298,7 +627,9 @@ impl Patch {
     pub fn affected_files(&self) -> Vec<PathBuf> {
         let mut paths = HashMap::new();
         for op in &self.ops {
-            paths.insert(op.path().clone(), ());
+            for path in op.paths() {
+                paths.insert(path.clone(), ());
+            }
         }
         paths.into_keys().collect()
     }
@@ -375,19 +706,40 @@ mod tests {
             .with_replace_fuzzy("file2.txt", "old", "new")
             .with_replace("file3.txt", "old", "new")
             .with_insert("file6.txt", 3, "inserted content")
+            .with_unified_diff("file7.txt", "@@ -1 +1 @@\n-a\n+b\n")
             .with_view("file4.txt")
-            .with_undo("file5.txt");
-
-        assert_eq!(patch.ops.len(), 6);
+            .with_undo("file5.txt")
+            .with_delete("file8.txt")
+            .with_rename("file9.txt", "file10.txt")
+            .with_replace_lines("file11.txt", 0, Some(1), "new", None)
+            .with_rust_item("file12.rs", "Config::new", "fn new() {}")
+            .with_create("file13.txt", "content")
+            .with_append("file14.txt", "content")
+            .with_prepend("file15.txt", "content")
+            .with_replace_all("file16.txt", "old", "new", Some(2))
+            .with_replace_regex("file17.txt", "o(l)d", "$1", None);
+
+        assert_eq!(patch.ops.len(), 16);
 
         let affected_files = patch.affected_files();
-        assert_eq!(affected_files.len(), 6);
+        assert_eq!(affected_files.len(), 17);
         assert!(affected_files.contains(&PathBuf::from("file1.txt")));
         assert!(affected_files.contains(&PathBuf::from("file2.txt")));
         assert!(affected_files.contains(&PathBuf::from("file3.txt")));
         assert!(affected_files.contains(&PathBuf::from("file4.txt")));
         assert!(affected_files.contains(&PathBuf::from("file5.txt")));
         assert!(affected_files.contains(&PathBuf::from("file6.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file7.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file8.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file9.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file10.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file11.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file12.rs")));
+        assert!(affected_files.contains(&PathBuf::from("file13.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file14.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file15.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file16.txt")));
+        assert!(affected_files.contains(&PathBuf::from("file17.txt")));
     }
 
     #[test]
@@ -413,6 +765,15 @@ mod tests {
         })
         .is_modification());
 
+        assert!(Operation::ReplaceAll(replace_all::ReplaceAll {
+            path: PathBuf::from("test.txt"),
+            pattern: "old".to_string(),
+            new: "new".to_string(),
+            regex: false,
+            count: None,
+        })
+        .is_modification());
+
         assert!(Operation::Insert(insert::Insert {
             path: PathBuf::from("test.txt"),
             line: 0,
@@ -420,7 +781,52 @@ mod tests {
         })
         .is_modification());
 
+        assert!(Operation::ReplaceLines(replace_lines::ReplaceLines {
+            path: PathBuf::from("test.txt"),
+            start: 0,
+            end: None,
+            new: "content".to_string(),
+            expected: None,
+        })
+        .is_modification());
+
+        assert!(Operation::UnifiedDiff(unified_diff::UnifiedDiff {
+            path: PathBuf::from("test.txt"),
+            diff: "@@ -1 +1 @@\n-a\n+b\n".to_string(),
+        })
+        .is_modification());
+
+        assert!(Operation::RustItem(rust_item::RustItem {
+            path: PathBuf::from("test.rs"),
+            item: "main".to_string(),
+            new: "fn main() {}".to_string(),
+        })
+        .is_modification());
+
+        assert!(Operation::Create(write::WriteFile {
+            path: PathBuf::from("test.txt"),
+            content: "content".to_string(),
+        })
+        .is_modification());
+
+        assert!(Operation::Append(append::Append {
+            path: PathBuf::from("test.txt"),
+            content: "content".to_string(),
+        })
+        .is_modification());
+
+        assert!(Operation::Prepend(append::Prepend {
+            path: PathBuf::from("test.txt"),
+            content: "content".to_string(),
+        })
+        .is_modification());
+
         assert!(Operation::Undo(PathBuf::from("test.txt")).is_modification());
+        assert!(Operation::Delete(PathBuf::from("test.txt")).is_modification());
+        assert!(
+            Operation::Rename(PathBuf::from("test.txt"), PathBuf::from("new.txt"))
+                .is_modification()
+        );
 
         // Test non-modifying operations
         assert!(!Operation::View(PathBuf::from("test.txt")).is_modification());
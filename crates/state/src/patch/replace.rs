@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
-use super::PatchError;
+use super::{closest, PatchError};
 
 /// An exact replace operation that replaces one occurrence of a string with another.
 /// The match must be exact and appear exactly once in the file.
@@ -23,8 +23,9 @@ impl Replace {
             0 => Err(PatchError {
                 user: "Text to replace not found".to_string(),
                 model: format!(
-                    "Could not find the specified text in the source file:\n{}",
-                    self.old
+                    "Could not find the specified text in the source file:\n{}{}",
+                    self.old,
+                    closest::describe(input, &self.old)
                 ),
             }),
             1 => Ok(input.replace(&self.old, &self.new)),
@@ -65,6 +66,15 @@ mod tests {
         };
         assert!(replace.apply(input).is_err());
 
+        // A near miss shows the closest match to the model
+        let replace = Replace {
+            path: PathBuf::from("/path/to/file.txt"),
+            old: "before old contents after".to_string(),
+            new: "new".to_string(),
+        };
+        let err = replace.apply(input).unwrap_err();
+        assert!(err.model.contains("   1: before old content after"));
+
         // Multiple occurrences
         let replace = Replace {
             path: PathBuf::from("/path/to/file.txt"),
@@ -0,0 +1,165 @@
+use std::path::PathBuf;
+
+use regex::{NoExpand, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use super::{closest, PatchError};
+
+/// The number of matching lines listed when the match count is wrong.
+const MAX_LISTED: usize = 10;
+
+/// Replaces every occurrence of a pattern in a file. The pattern is literal text, unless `regex`
+/// is set, in which case it's a regular expression where `^` and `$` match at line boundaries,
+/// and the replacement can refer to capture groups as `$1` or `${name}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReplaceAll {
+    pub path: PathBuf,
+    pub pattern: String,
+    pub new: String,
+    #[serde(default)]
+    pub regex: bool,
+    /// If set, the operation fails unless the pattern matches exactly this many times.
+    #[serde(default)]
+    pub count: Option<usize>,
+}
+
+impl ReplaceAll {
+    /// Compiles the pattern, escaping it if it's literal text.
+    fn compile(&self) -> Result<Regex, PatchError> {
+        let pattern = if self.regex {
+            self.pattern.clone()
+        } else {
+            regex::escape(&self.pattern)
+        };
+        RegexBuilder::new(&pattern)
+            .multi_line(true)
+            .build()
+            .map_err(|e| PatchError {
+                user: "Invalid regular expression".to_string(),
+                model: format!(
+                    "The pattern {} is not a valid regular expression: {e}",
+                    self.pattern
+                ),
+            })
+    }
+
+    /// Applies the replacement to the given input string.
+    ///
+    /// Returns an error if the pattern doesn't match, or if a count was given and the number of
+    /// matches differs.
+    pub(crate) fn apply(&self, input: &str) -> Result<String, PatchError> {
+        let re = self.compile()?;
+        let matches: Vec<_> = re.find_iter(input).collect();
+        if matches.is_empty() {
+            let closest = if self.regex {
+                String::new()
+            } else {
+                closest::describe(input, &self.pattern)
+            };
+            return Err(PatchError {
+                user: "Pattern to replace not found".to_string(),
+                model: format!(
+                    "Could not find the pattern in the source file:\n{}{closest}",
+                    self.pattern
+                ),
+            });
+        }
+
+        if let Some(count) = self.count {
+            if matches.len() != count {
+                let lines: Vec<String> = matches
+                    .iter()
+                    .take(MAX_LISTED)
+                    .map(|m| (input[..m.start()].matches('\n').count() + 1).to_string())
+                    .collect();
+                let more = if matches.len() > MAX_LISTED {
+                    ", ..."
+                } else {
+                    ""
+                };
+                return Err(PatchError {
+                    user: format!("Expected {count} matches, found {}", matches.len()),
+                    model: format!(
+                        "Expected the pattern to match {count} times, but it matched {} times, at lines {}{more}. No replacements were made. Pattern:\n{}",
+                        matches.len(),
+                        lines.join(", "),
+                        self.pattern
+                    ),
+                });
+            }
+        }
+
+        let result = if self.regex {
+            re.replace_all(input, self.new.as_str())
+        } else {
+            re.replace_all(input, NoExpand(&self.new))
+        };
+        Ok(result.into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn replace_all(pattern: &str, new: &str, regex: bool, count: Option<usize>) -> ReplaceAll {
+        ReplaceAll {
+            path: PathBuf::from("file.rs"),
+            pattern: pattern.to_string(),
+            new: new.to_string(),
+            regex,
+            count,
+        }
+    }
+
+    #[test]
+    fn test_replace_all_literal() {
+        let input = "let foo = 1;\nfoo += foo;\n";
+        assert_eq!(
+            replace_all("foo", "bar", false, None).apply(input).unwrap(),
+            "let bar = 1;\nbar += bar;\n"
+        );
+        assert_eq!(
+            replace_all("foo", "$0", false, Some(3))
+                .apply(input)
+                .unwrap(),
+            "let $0 = 1;\n$0 += $0;\n"
+        );
+        assert_eq!(
+            replace_all("a.b", "x", false, None)
+                .apply("a.b axb")
+                .unwrap(),
+            "x axb"
+        );
+
+        let err = replace_all("foo", "bar", false, Some(2))
+            .apply(input)
+            .unwrap_err();
+        assert!(err.model.contains("matched 3 times, at lines 1, 2, 2"));
+
+        assert!(replace_all("baz", "bar", false, None).apply(input).is_err());
+    }
+
+    #[test]
+    fn test_replace_all_regex() {
+        let input = "fn get_a() {}\nfn get_b() {}\n";
+        assert_eq!(
+            replace_all(r"fn get_(\w+)", "fn ${1}_value", true, Some(2))
+                .apply(input)
+                .unwrap(),
+            "fn a_value() {}\nfn b_value() {}\n"
+        );
+
+        // Anchors match at line boundaries
+        assert_eq!(
+            replace_all("^fn", "pub fn", true, None)
+                .apply(input)
+                .unwrap(),
+            "pub fn get_a() {}\npub fn get_b() {}\n"
+        );
+
+        let err = replace_all("(", "x", true, None).apply(input).unwrap_err();
+        assert!(err.user.contains("Invalid regular expression"));
+    }
+}
@@ -1,12 +1,14 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use super::PatchError;
+use super::{closest, PatchError};
 
 /// An replace operation that replaces once occurrence of a string with another. This operation is
 /// fuzzy - meaning it tries really hard to make the replacement by ignoring leading and trailing
-/// whitespace.
+/// whitespace. If that fails and the file is prose, it falls back to matching words, ignoring line
+/// breaks and runs of whitespace within paragraphs, so edits to text that the model has re-wrapped
+/// still apply.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ReplaceFuzzy {
     pub path: PathBuf,
@@ -14,6 +16,63 @@ pub struct ReplaceFuzzy {
     pub new: String,
 }
 
+/// Extensions of prose files, where re-wrapping text doesn't change its meaning. Word matching
+/// isn't safe for code and config, where indentation and line breaks are significant.
+const PROSE_EXTENSIONS: &[&str] = &[
+    "md", "markdown", "txt", "rst", "adoc", "asciidoc", "org", "tex",
+];
+
+/// Returns true if the path has a prose file extension.
+fn is_prose(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| PROSE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+/// A word in a text, or a paragraph break, with its byte span in the text.
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    /// The word, or None for a paragraph break
+    word: Option<&'a str>,
+    start: usize,
+    end: usize,
+}
+
+/// Splits text into whitespace-separated words. Whitespace containing a blank line becomes a
+/// paragraph break token.
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens: Vec<Token<'_>> = Vec::new();
+    let mut word_start = None;
+    let mut newlines = 0;
+    for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        if !c.is_whitespace() {
+            if word_start.is_none() {
+                if newlines > 1 && !tokens.is_empty() {
+                    tokens.push(Token {
+                        word: None,
+                        start: i,
+                        end: i,
+                    });
+                }
+                newlines = 0;
+                word_start = Some(i);
+            }
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            tokens.push(Token {
+                word: Some(&text[start..i]),
+                start,
+                end: i,
+            });
+        }
+        if c == '\n' {
+            newlines += 1;
+        }
+    }
+    tokens
+}
+
 impl ReplaceFuzzy {
     /// Applies the replacement operation to the given input string.
     ///
@@ -36,21 +95,54 @@ impl ReplaceFuzzy {
             {
                 result.extend(new_lines.iter().cloned());
                 result.extend(input_lines[i + old_lines.len()..].iter().cloned());
-                return Ok(result.join("\n"));
+                let mut output = result.join("\n");
+                if input.ends_with('\n') && !output.is_empty() {
+                    output.push('\n');
+                }
+                return Ok(output);
             } else {
                 result.push(input_lines[i]);
                 i += 1;
             }
         }
 
+        if is_prose(&self.path) {
+            if let Some(output) = self.apply_words(input) {
+                return Ok(output);
+            }
+        }
+
         Err(PatchError {
             user: "Could not find the text to replace".to_string(),
             model: format!(
-                "Invalid replace specification - could not find the following text in the source file:\n{}",
-                self.old
+                "Invalid replace specification - could not find the following text in the source file:\n{}{}",
+                self.old,
+                closest::describe(input, &self.old)
             )
         })
     }
+
+    /// Replaces the first run of words in the input that matches the words of the old text,
+    /// ignoring how whitespace and line breaks fall within paragraphs. Text outside the matched
+    /// span is left untouched.
+    fn apply_words(&self, input: &str) -> Option<String> {
+        let old: Vec<Option<&str>> = tokenize(&self.old).iter().map(|t| t.word).collect();
+        if old.is_empty() {
+            return None;
+        }
+        let tokens = tokenize(input);
+        let pos = tokens
+            .windows(old.len())
+            .position(|w| w.iter().map(|t| t.word).eq(old.iter().copied()))?;
+        let start = tokens[pos].start;
+        let end = tokens[pos + old.len() - 1].end;
+        Some(format!(
+            "{}{}{}",
+            &input[..start],
+            self.new.trim(),
+            &input[end..]
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -138,7 +230,92 @@ mod tests {
             let result = replace
                 .apply(input)
                 .unwrap_or_else(|_| panic!("Failed to apply replace: {name}"));
-            assert_eq!(result, expected_output.trim_end(), "Test case: {}", name);
+            assert_eq!(result, expected_output, "Test case: {}", name);
+        }
+    }
+
+    #[test]
+    fn test_replace_rewrapped_prose() {
+        let input = indoc! {"
+            # Title
+
+            This paragraph was wrapped at one width by the user, and
+            the model remembers it wrapped at
+            another width entirely.
+
+            Second paragraph.
+        "};
+        let test_cases = vec![
+            (
+                "Rewrapped paragraph",
+                "This paragraph was wrapped at one width by the user,\nand the model remembers it wrapped at another\nwidth entirely.",
+                Some(indoc! {"
+                    # Title
+
+                    New text.
+
+                    Second paragraph.
+                "}),
+            ),
+            (
+                "Span within a paragraph",
+                "user, and the   model",
+                Some(indoc! {"
+                    # Title
+
+                    This paragraph was wrapped at one width by the New text. remembers it wrapped at
+                    another width entirely.
+
+                    Second paragraph.
+                "}),
+            ),
+            (
+                "Across a paragraph break",
+                "entirely.\n\nSecond",
+                Some(indoc! {"
+                    # Title
+
+                    This paragraph was wrapped at one width by the user, and
+                    the model remembers it wrapped at
+                    another width New text. paragraph.
+                "}),
+            ),
+            ("Paragraph break doesn't match a line break", "and\n\nthe model", None),
+            ("Different words", "wrapped at one size", None),
+        ];
+
+        for (name, old, expected) in test_cases {
+            let replace = ReplaceFuzzy {
+                path: "/path/to/file.md".into(),
+                old: old.to_string(),
+                new: "New text.".to_string(),
+            };
+            match expected {
+                Some(expected) => assert_eq!(
+                    replace
+                        .apply(input)
+                        .unwrap_or_else(|e| panic!("[{name}] {}", e.user)),
+                    expected,
+                    "[{name}]"
+                ),
+                None => assert!(replace.apply(input).is_err(), "[{name}]"),
+            }
         }
     }
+
+    #[test]
+    fn test_replace_rewrapped_code() {
+        let input = "def f():\n    if x:\n        return 1\n";
+        let replace = ReplaceFuzzy {
+            path: "/path/to/file.py".into(),
+            old: "def f(): if x: return 1".to_string(),
+            new: "pass".to_string(),
+        };
+        assert!(replace.apply(input).is_err());
+        let replace = ReplaceFuzzy {
+            path: "/path/to/README.MD".into(),
+            ..replace
+        };
+        assert!(replace.apply(input).is_ok());
+    }
 }
@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::{closest::numbered, PatchError};
+
+/// Replaces a span of lines in a file, using the same addressing as `Operation::ViewRange`:
+/// offsets are 0-based and the end is exclusive, with a None end meaning the end of the file.
+///
+/// If `expected` is set, the current content of the span must match it (ignoring trailing
+/// whitespace on each line), which guards against line numbers that have gone stale since the
+/// model last viewed the file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReplaceLines {
+    pub path: PathBuf,
+    pub start: usize,
+    pub end: Option<usize>,
+    pub new: String,
+    pub expected: Option<String>,
+}
+
+impl ReplaceLines {
+    /// Applies the line replacement to the given input string.
+    ///
+    /// Returns an error if the range is out of bounds, or if the guard text does not match the
+    /// current content of the range.
+    pub(crate) fn apply(&self, input: &str) -> Result<String, PatchError> {
+        let lines: Vec<&str> = input.lines().collect();
+        let end = self.end.unwrap_or(lines.len());
+
+        if self.start > end || end > lines.len() {
+            return Err(PatchError {
+                user: format!("Line range {}..{} is out of bounds", self.start, end),
+                model: format!(
+                    "Cannot replace lines {} to {} because the file only has {} lines",
+                    self.start + 1,
+                    end,
+                    lines.len()
+                ),
+            });
+        }
+
+        let current = &lines[self.start..end];
+        if let Some(expected) = &self.expected {
+            let matches = expected.lines().count() == current.len()
+                && expected
+                    .lines()
+                    .zip(current)
+                    .all(|(a, b)| a.trim_end() == b.trim_end());
+            if !matches {
+                return Err(PatchError {
+                    user: format!(
+                        "Content of lines {}..{} does not match the expected text",
+                        self.start, end
+                    ),
+                    model: format!(
+                        "The content of lines {} to {} has changed since you last viewed the file. The lines currently read:\n{}",
+                        self.start + 1,
+                        end,
+                        numbered(current, self.start)
+                    ),
+                });
+            }
+        }
+
+        let mut result: Vec<&str> = Vec::with_capacity(lines.len());
+        result.extend(&lines[..self.start]);
+        result.extend(self.new.lines());
+        result.extend(&lines[end..]);
+
+        let mut output = result.join("\n");
+        if input.ends_with('\n') && !output.is_empty() {
+            output.push('\n');
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn replace_lines(
+        start: usize,
+        end: Option<usize>,
+        new: &str,
+        expected: Option<&str>,
+    ) -> ReplaceLines {
+        ReplaceLines {
+            path: PathBuf::from("/path/to/file.txt"),
+            start,
+            end,
+            new: new.to_string(),
+            expected: expected.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_replace_lines_apply() {
+        let input = "line 1\nline 2\nline 3\nline 4\n";
+
+        let test_cases = vec![
+            (
+                "replace middle",
+                replace_lines(1, Some(3), "new 2\nnew 3\n", None),
+                "line 1\nnew 2\nnew 3\nline 4\n",
+            ),
+            (
+                "replace with more lines",
+                replace_lines(0, Some(1), "a\nb\nc", None),
+                "a\nb\nc\nline 2\nline 3\nline 4\n",
+            ),
+            (
+                "delete lines",
+                replace_lines(1, Some(3), "", None),
+                "line 1\nline 4\n",
+            ),
+            (
+                "replace to end",
+                replace_lines(2, None, "end", None),
+                "line 1\nline 2\nend\n",
+            ),
+            (
+                "empty range inserts",
+                replace_lines(2, Some(2), "inserted", None),
+                "line 1\nline 2\ninserted\nline 3\nline 4\n",
+            ),
+            (
+                "matching guard",
+                replace_lines(1, Some(2), "new 2", Some("line 2  \n")),
+                "line 1\nnew 2\nline 3\nline 4\n",
+            ),
+        ];
+
+        for (name, op, expected) in test_cases {
+            let result = op
+                .apply(input)
+                .unwrap_or_else(|e| panic!("[{name}] failed: {}", e.user));
+            assert_eq!(result, expected, "[{name}]");
+        }
+    }
+
+    #[test]
+    fn test_replace_lines_errors() {
+        let input = "line 1\nline 2\nline 3\n";
+
+        // Out of bounds
+        let err = replace_lines(2, Some(5), "x", None)
+            .apply(input)
+            .unwrap_err();
+        assert!(err.user.contains("out of bounds"));
+
+        // Start after end
+        assert!(replace_lines(2, Some(1), "x", None).apply(input).is_err());
+
+        // Stale guard shows the current content to the model
+        let err = replace_lines(1, Some(2), "x", Some("line 1"))
+            .apply(input)
+            .unwrap_err();
+        assert!(err.user.contains("does not match"));
+        assert!(err.model.contains("   2: line 2"));
+    }
+}
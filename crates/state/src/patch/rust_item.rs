@@ -0,0 +1,563 @@
+use std::path::PathBuf;
+
+use proc_macro2::LineColumn;
+use serde::{Deserialize, Serialize};
+use syn::spanned::Spanned;
+
+use super::PatchError;
+
+/// Replaces a whole Rust item - a function, struct, enum, trait, module, impl method and so on -
+/// identified by its path, e.g. `Config::state`. Methods are addressed through the name of the
+/// type they are implemented on. Leading path segments that don't exist in the file, like
+/// `crate::config` in `crate::config::Config::state`, are ignored.
+///
+/// If no item matches the path, the new item is inserted at the end of its parent, which must
+/// exist. The name of an inserted item must match the last segment of the path.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RustItem {
+    pub path: PathBuf,
+    pub item: String,
+    pub new: String,
+}
+
+/// The kind of block an item lives in, which determines how a replacement item is parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Module,
+    Impl,
+    Trait,
+}
+
+/// An addressable item in a file.
+struct Located {
+    path: Vec<String>,
+    start: LineColumn,
+    end: LineColumn,
+    scope: Scope,
+}
+
+/// A block that items can be inserted into. The file itself is a container with an empty path.
+struct Container {
+    path: Vec<String>,
+    /// The closing brace of the block, None for the file root
+    close: Option<LineColumn>,
+    /// The start of the first item in the block, if any
+    first: Option<LineColumn>,
+    scope: Scope,
+    /// False for trait impls, so inherent impls are preferred when inserting methods
+    inherent: bool,
+}
+
+/// All items and containers in a file.
+#[derive(Default)]
+struct Index {
+    items: Vec<Located>,
+    containers: Vec<Container>,
+}
+
+/// Returns the name an item is addressed by, if it has one.
+fn item_name(item: &syn::Item) -> Option<String> {
+    let ident = match item {
+        syn::Item::Const(i) => &i.ident,
+        syn::Item::Enum(i) => &i.ident,
+        syn::Item::Fn(i) => &i.sig.ident,
+        syn::Item::Macro(i) => i.ident.as_ref()?,
+        syn::Item::Mod(i) => &i.ident,
+        syn::Item::Static(i) => &i.ident,
+        syn::Item::Struct(i) => &i.ident,
+        syn::Item::Trait(i) => &i.ident,
+        syn::Item::TraitAlias(i) => &i.ident,
+        syn::Item::Type(i) => &i.ident,
+        syn::Item::Union(i) => &i.ident,
+        _ => return None,
+    };
+    Some(ident.to_string())
+}
+
+fn impl_item_name(item: &syn::ImplItem) -> Option<String> {
+    match item {
+        syn::ImplItem::Const(i) => Some(i.ident.to_string()),
+        syn::ImplItem::Fn(i) => Some(i.sig.ident.to_string()),
+        syn::ImplItem::Type(i) => Some(i.ident.to_string()),
+        _ => None,
+    }
+}
+
+fn trait_item_name(item: &syn::TraitItem) -> Option<String> {
+    match item {
+        syn::TraitItem::Const(i) => Some(i.ident.to_string()),
+        syn::TraitItem::Fn(i) => Some(i.sig.ident.to_string()),
+        syn::TraitItem::Type(i) => Some(i.ident.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the name of the type an impl block is for.
+fn type_name(ty: &syn::Type) -> Option<String> {
+    match ty {
+        syn::Type::Path(p) => p.path.segments.last().map(|s| s.ident.to_string()),
+        syn::Type::Reference(r) => type_name(&r.elem),
+        _ => None,
+    }
+}
+
+fn child(prefix: &[String], name: String) -> Vec<String> {
+    let mut path = prefix.to_vec();
+    path.push(name);
+    path
+}
+
+/// Returns true if `path` matches the trailing segments of `want`, and the remaining leading
+/// segments look like module names, which are taken to name the file's own module.
+fn matches(path: &[String], want: &[&str]) -> bool {
+    if path.len() > want.len() {
+        return false;
+    }
+    let (prefix, rest) = want.split_at(want.len() - path.len());
+    path.iter().zip(rest).all(|(a, b)| a == b)
+        && prefix.iter().all(|s| {
+            s.chars()
+                .all(|c| c.is_lowercase() || c.is_ascii_digit() || c == '_')
+        })
+}
+
+impl Index {
+    fn new(file: &syn::File) -> Self {
+        let mut index = Index::default();
+        index.containers.push(Container {
+            path: vec![],
+            close: None,
+            first: file.items.first().map(|i| i.span().start()),
+            scope: Scope::Module,
+            inherent: true,
+        });
+        index.collect(&file.items, &[]);
+        index
+    }
+
+    fn push(&mut self, path: Vec<String>, span: proc_macro2::Span, scope: Scope) {
+        self.items.push(Located {
+            path,
+            start: span.start(),
+            end: span.end(),
+            scope,
+        });
+    }
+
+    fn collect(&mut self, items: &[syn::Item], prefix: &[String]) {
+        for item in items {
+            match item {
+                syn::Item::Impl(imp) => {
+                    let Some(name) = type_name(&imp.self_ty) else {
+                        continue;
+                    };
+                    let path = child(prefix, name);
+                    self.containers.push(Container {
+                        path: path.clone(),
+                        close: Some(imp.brace_token.span.close().start()),
+                        first: imp.items.first().map(|i| i.span().start()),
+                        scope: Scope::Impl,
+                        inherent: imp.trait_.is_none(),
+                    });
+                    for i in &imp.items {
+                        if let Some(name) = impl_item_name(i) {
+                            self.push(child(&path, name), i.span(), Scope::Impl);
+                        }
+                    }
+                }
+                syn::Item::Trait(t) => {
+                    let path = child(prefix, t.ident.to_string());
+                    self.push(path.clone(), item.span(), Scope::Module);
+                    self.containers.push(Container {
+                        path: path.clone(),
+                        close: Some(t.brace_token.span.close().start()),
+                        first: t.items.first().map(|i| i.span().start()),
+                        scope: Scope::Trait,
+                        inherent: true,
+                    });
+                    for i in &t.items {
+                        if let Some(name) = trait_item_name(i) {
+                            self.push(child(&path, name), i.span(), Scope::Trait);
+                        }
+                    }
+                }
+                syn::Item::Mod(m) => {
+                    let path = child(prefix, m.ident.to_string());
+                    self.push(path.clone(), item.span(), Scope::Module);
+                    if let Some((brace, items)) = &m.content {
+                        self.containers.push(Container {
+                            path: path.clone(),
+                            close: Some(brace.span.close().start()),
+                            first: items.first().map(|i| i.span().start()),
+                            scope: Scope::Module,
+                            inherent: true,
+                        });
+                        self.collect(items, &path);
+                    }
+                }
+                _ => {
+                    if let Some(name) = item_name(item) {
+                        self.push(child(prefix, name), item.span(), Scope::Module);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the items with the longest path matching `want`.
+    fn find(&self, want: &[&str]) -> Vec<&Located> {
+        let matches: Vec<&Located> = self
+            .items
+            .iter()
+            .filter(|l| matches(&l.path, want))
+            .collect();
+        let longest = matches.iter().map(|l| l.path.len()).max().unwrap_or(0);
+        matches
+            .into_iter()
+            .filter(|l| l.path.len() == longest)
+            .collect()
+    }
+
+    /// Returns the container with the longest path matching `want`, preferring
+    /// inherent impls over trait impls.
+    fn find_container(&self, want: &[&str]) -> Option<&Container> {
+        let longest = self
+            .containers
+            .iter()
+            .filter(|c| matches(&c.path, want))
+            .map(|c| c.path.len())
+            .max()?;
+        let mut matches = self
+            .containers
+            .iter()
+            .filter(|c| c.path.len() == longest && matches(&c.path, want));
+        let first = matches.clone().next();
+        matches.find(|c| c.inherent).or(first)
+    }
+
+    /// A comma-separated list of all item paths, for model-facing error messages.
+    fn listing(&self) -> String {
+        self.items
+            .iter()
+            .map(|l| l.path.join("::"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Converts a line and character column into a byte offset.
+fn offset(content: &str, lc: LineColumn) -> usize {
+    let line_start: usize = content
+        .split_inclusive('\n')
+        .take(lc.line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    content[line_start..]
+        .char_indices()
+        .nth(lc.column)
+        .map(|(i, _)| line_start + i)
+        .unwrap_or(content.len())
+}
+
+/// Returns the byte offset of the start of the line containing `pos`.
+fn line_start(content: &str, pos: usize) -> usize {
+    content[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+/// Returns the leading whitespace of the line containing `pos`.
+fn indent_at(content: &str, pos: usize) -> &str {
+    let start = line_start(content, pos);
+    let line = &content[start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Strips the common indentation from text, then indents every line but the first with `indent`.
+fn reindent(text: &str, indent: &str) -> String {
+    let text = text.trim_matches('\n').trim_end();
+    let common = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    text.lines()
+        .enumerate()
+        .map(|(i, l)| {
+            let l = l.get(common..).unwrap_or_else(|| l.trim_start());
+            if i == 0 || l.is_empty() {
+                l.to_string()
+            } else {
+                format!("{indent}{l}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl RustItem {
+    fn error(&self, user: String, model: String) -> PatchError {
+        PatchError { user, model }
+    }
+
+    /// Parses the new item in the given scope, returning its name.
+    fn parse_new(&self, scope: Scope) -> Result<Option<String>, PatchError> {
+        let parsed = match scope {
+            Scope::Module => syn::parse_str::<syn::Item>(&self.new).map(|i| item_name(&i)),
+            Scope::Impl => syn::parse_str::<syn::ImplItem>(&self.new).map(|i| impl_item_name(&i)),
+            Scope::Trait => {
+                syn::parse_str::<syn::TraitItem>(&self.new).map(|i| trait_item_name(&i))
+            }
+        };
+        parsed.map_err(|e| {
+            self.error(
+                format!("Replacement for {} is not a valid Rust item", self.item),
+                format!(
+                    "The new content for {} is not a single valid Rust item: {e}",
+                    self.item
+                ),
+            )
+        })
+    }
+
+    /// Applies the item replacement or insertion to the given input string.
+    pub(crate) fn apply(&self, input: &str) -> Result<String, PatchError> {
+        let file = syn::parse_file(input).map_err(|e| {
+            self.error(
+                format!("Could not parse {} as Rust", self.path.display()),
+                format!(
+                    "Cannot edit items in {} because it does not parse as Rust: {e}",
+                    self.path.display()
+                ),
+            )
+        })?;
+        let index = Index::new(&file);
+        let want: Vec<&str> = self
+            .item
+            .split("::")
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+        let Some(last) = want.last() else {
+            return Err(self.error(
+                "Empty Rust item path".to_string(),
+                "The Rust item path must not be empty".to_string(),
+            ));
+        };
+
+        let found = index.find(&want);
+        if found.len() > 1 {
+            return Err(self.error(
+                format!("Rust item {} is ambiguous", self.item),
+                format!(
+                    "The path {} matches {} items in {}. Use a replace operation instead.",
+                    self.item,
+                    found.len(),
+                    self.path.display()
+                ),
+            ));
+        }
+
+        if let Some(located) = found.first() {
+            self.parse_new(located.scope)?;
+            let start = offset(input, located.start);
+            let end = offset(input, located.end);
+            let new = reindent(&self.new, indent_at(input, start));
+            return Ok(format!("{}{new}{}", &input[..start], &input[end..]));
+        }
+
+        let parent = &want[..want.len() - 1];
+        let Some(container) = index.find_container(parent) else {
+            return Err(self.error(
+                format!("Rust item {} not found", self.item),
+                format!(
+                    "Could not find {} or its parent in {}. Items in the file are: {}",
+                    self.item,
+                    self.path.display(),
+                    index.listing()
+                ),
+            ));
+        };
+        if self.parse_new(container.scope)?.as_deref() != Some(*last) {
+            return Err(self.error(
+                format!("Rust item {} not found", self.item),
+                format!(
+                    "Could not find {} in {}, and the new content does not define an item named {last} to insert. Items in the file are: {}",
+                    self.item,
+                    self.path.display(),
+                    index.listing()
+                ),
+            ));
+        }
+
+        let Some(close) = container.close else {
+            let mut output = input.to_string();
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            if !output.is_empty() {
+                output.push('\n');
+            }
+            output.push_str(&reindent(&self.new, ""));
+            output.push('\n');
+            return Ok(output);
+        };
+
+        let close = offset(input, close);
+        let brace_indent = indent_at(input, close);
+        let indent = match container.first {
+            Some(first) => indent_at(input, offset(input, first)).to_string(),
+            None => format!("{brace_indent}    "),
+        };
+        let separator = if container.first.is_some() { "\n" } else { "" };
+        let new = reindent(&self.new, &indent);
+        let start = line_start(input, close);
+        if input[start..close].trim().is_empty() {
+            // The closing brace is on its own line
+            Ok(format!(
+                "{}{separator}{indent}{new}\n{}",
+                &input[..start],
+                &input[start..]
+            ))
+        } else {
+            Ok(format!(
+                "{}\n{indent}{new}\n{brace_indent}{}",
+                input[..close].trim_end(),
+                &input[close..]
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    const INPUT: &str = indoc! {r#"
+        use std::path::PathBuf;
+
+        /// A config
+        pub struct Config {
+            root: PathBuf,
+        }
+
+        impl Config {
+            /// The root
+            pub fn root(&self) -> &PathBuf {
+                &self.root
+            }
+        }
+
+        impl Default for Config {
+            fn default() -> Self {
+                Config { root: PathBuf::new() }
+            }
+        }
+
+        mod tests {
+            fn test_root() {}
+        }
+    "#};
+
+    fn rust_item(item: &str, new: &str) -> RustItem {
+        RustItem {
+            path: PathBuf::from("src/config.rs"),
+            item: item.to_string(),
+            new: new.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rust_item_replace() {
+        let result = rust_item(
+            "crate::config::Config::root",
+            "/// The root, renamed\npub fn root(&self) -> &PathBuf {\n    &self.root\n}\n",
+        )
+        .apply(INPUT)
+        .unwrap();
+        assert!(result.contains(indoc! {"
+            impl Config {
+                /// The root, renamed
+                pub fn root(&self) -> &PathBuf {
+                    &self.root
+                }
+            }
+        "}));
+        assert!(!result.contains("/// The root\n"));
+
+        // Doc comments are part of the replaced item
+        let result = rust_item("Config", "pub struct Config {}")
+            .apply(INPUT)
+            .unwrap();
+        assert!(result.contains("use std::path::PathBuf;\n\npub struct Config {}\n\nimpl"));
+
+        let result = rust_item("tests::test_root", "fn test_root() { assert!(true) }")
+            .apply(INPUT)
+            .unwrap();
+        assert!(result.contains("    fn test_root() { assert!(true) }\n}"));
+    }
+
+    #[test]
+    fn test_rust_item_insert() {
+        // Methods go into the inherent impl, not the trait impl
+        let result = rust_item(
+            "Config::is_empty",
+            "fn is_empty(&self) -> bool {\n    false\n}",
+        )
+        .apply(INPUT)
+        .unwrap();
+        assert!(result.contains(indoc! {"
+                    &self.root
+                }
+
+                fn is_empty(&self) -> bool {
+                    false
+                }
+            }
+
+            impl Default"}));
+
+        let result = rust_item("helper", "fn helper() {}").apply(INPUT).unwrap();
+        assert!(result.ends_with("}\n\nfn helper() {}\n"));
+
+        let result = rust_item("Empty::new", "fn new() {}")
+            .apply("struct Empty;\nimpl Empty {}\n")
+            .unwrap();
+        assert_eq!(result, "struct Empty;\nimpl Empty {\n    fn new() {}\n}\n");
+    }
+
+    #[test]
+    fn test_rust_item_errors() {
+        let cases = vec![
+            (
+                "missing parent",
+                rust_item("Missing::new", "fn new() {}"),
+                "not found",
+            ),
+            (
+                "name mismatch",
+                rust_item("Config::other", "fn new() {}"),
+                "not found",
+            ),
+            (
+                "invalid item",
+                rust_item("Config::root", "fn root("),
+                "not a valid",
+            ),
+        ];
+        for (name, op, expected) in cases {
+            let err = op.apply(INPUT).unwrap_err();
+            assert!(err.user.contains(expected), "[{name}] {}", err.user);
+        }
+
+        let input = "impl A for S { fn name() {} }\nimpl B for S { fn name() {} }\n";
+        let err = rust_item("S::name", "fn name() {}")
+            .apply(input)
+            .unwrap_err();
+        assert!(err.user.contains("ambiguous"));
+
+        let err = rust_item("foo", "fn foo() {}").apply("fn (").unwrap_err();
+        assert!(err.user.contains("Could not parse"));
+    }
+}
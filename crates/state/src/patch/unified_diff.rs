@@ -0,0 +1,518 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::PatchError;
+
+/// The maximum number of leading and trailing context lines that may be ignored when a hunk does
+/// not apply cleanly.
+const MAX_FUZZ: usize = 2;
+
+/// A unified diff operation, applying a standard `---`/`+++`/`@@` hunk-based diff to a single
+/// file. The file headers are optional and ignored - the target is always `path`.
+///
+/// Hunks are located by searching outwards from the line numbers in the hunk header, so diffs with
+/// stale line numbers still apply. If a hunk's context doesn't match, up to `MAX_FUZZ` leading and
+/// trailing context lines are dropped before trying again.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UnifiedDiff {
+    pub path: PathBuf,
+    pub diff: String,
+}
+
+/// A single parsed hunk from a unified diff.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Hunk {
+    /// The 1-based starting line in the original file, as given in the hunk header.
+    pub old_start: usize,
+    /// The lines of the hunk, in order.
+    pub lines: Vec<HunkLine>,
+    /// If set, indicates whether the new file should end with a newline when this hunk touches
+    /// the end of the file.
+    pub eof_newline: Option<bool>,
+    /// The raw text of the hunk, used in error messages.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HunkLine {
+    Context(String),
+    Delete(String),
+    Insert(String),
+}
+
+impl Hunk {
+    /// The lines this hunk expects to find in the original file.
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Delete(s) => Some(s.as_str()),
+                HunkLine::Insert(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines this hunk produces in the new file.
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Insert(s) => Some(s.as_str()),
+                HunkLine::Delete(_) => None,
+            })
+            .collect()
+    }
+
+    /// Returns a copy of this hunk with up to `fuzz` leading and trailing context lines removed.
+    /// The starting line is adjusted to match.
+    fn fuzzed(&self, fuzz: usize) -> Hunk {
+        let leading = self
+            .lines
+            .iter()
+            .take_while(|l| matches!(l, HunkLine::Context(_)))
+            .count()
+            .min(fuzz);
+        let trailing = self
+            .lines
+            .iter()
+            .rev()
+            .take_while(|l| matches!(l, HunkLine::Context(_)))
+            .count()
+            .min(fuzz);
+        let end = self.lines.len().saturating_sub(trailing).max(leading);
+        Hunk {
+            old_start: self.old_start + leading,
+            lines: self.lines[leading..end].to_vec(),
+            eof_newline: if trailing > 0 { None } else { self.eof_newline },
+            text: self.text.clone(),
+        }
+    }
+}
+
+/// Parses a hunk header range like `-12,5`, returning the start line and the line count. A
+/// missing count means one line.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let s = &s[1..];
+    let mut parts = s.split(',');
+    let start = parts.next()?.parse().ok()?;
+    let count = match parts.next() {
+        Some(c) => c.parse().ok()?,
+        None => 1,
+    };
+    Some((start, count))
+}
+
+/// Parses a hunk header like `@@ -12,5 +12,6 @@`, returning the old start line, and the old and
+/// new line counts.
+pub(crate) fn parse_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = line.split_whitespace().skip(1);
+    let (old_start, old_count) = parts
+        .next()
+        .filter(|p| p.starts_with('-'))
+        .and_then(parse_range)?;
+    let (_, new_count) = parts
+        .next()
+        .filter(|p| p.starts_with('+'))
+        .and_then(parse_range)?;
+    Some((old_start, old_count, new_count))
+}
+
+/// A hunk being parsed, with the number of old and new lines the header says are still to come.
+struct OpenHunk {
+    hunk: Hunk,
+    old_left: usize,
+    new_left: usize,
+    /// The number of trailing context lines that were empty lines in the diff text
+    blank_tail: usize,
+}
+
+impl OpenHunk {
+    fn is_complete(&self) -> bool {
+        self.old_left == 0 && self.new_left == 0
+    }
+
+    fn push(&mut self, line: HunkLine, raw: &str) {
+        match line {
+            HunkLine::Context(_) => {
+                self.old_left = self.old_left.saturating_sub(1);
+                self.new_left = self.new_left.saturating_sub(1);
+            }
+            HunkLine::Delete(_) => self.old_left = self.old_left.saturating_sub(1),
+            HunkLine::Insert(_) => self.new_left = self.new_left.saturating_sub(1),
+        }
+        self.blank_tail = if raw.is_empty() {
+            self.blank_tail + 1
+        } else {
+            0
+        };
+        self.hunk.text.push_str(raw);
+        self.hunk.text.push('\n');
+        self.hunk.lines.push(line);
+    }
+
+    /// Closes the hunk. If it ended before its header said it would, trailing empty lines are
+    /// taken to be padding after the diff rather than context.
+    fn finish(mut self) -> Hunk {
+        if !self.is_complete() {
+            let keep = self.hunk.lines.len() - self.blank_tail;
+            self.hunk.lines.truncate(keep);
+            let text_len = self.hunk.text.len() - self.blank_tail;
+            self.hunk.text.truncate(text_len);
+        }
+        self.hunk
+    }
+}
+
+/// Parses a unified diff into hunks. File headers and any leading junk (e.g. `diff --git` or
+/// `index` lines) are skipped.
+///
+/// Each hunk ends after the number of lines given in its header, so anything that follows - the
+/// next file's headers, or a trailer like the signature `git format-patch` appends - is ignored.
+/// If a hunk is cut short by the next `@@` header, a `diff` line or the end of the input, it ends
+/// there.
+pub(crate) fn parse_hunks(diff: &str) -> Result<Vec<Hunk>, PatchError> {
+    let mut hunks = Vec::new();
+    let mut current: Option<OpenHunk> = None;
+
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            if let Some(h) = current.take() {
+                hunks.push(h.finish());
+            }
+            let (old_start, old_left, new_left) = parse_header(line).ok_or_else(|| PatchError {
+                user: format!("Invalid hunk header: {line}"),
+                model: format!("Invalid unified diff - could not parse the hunk header:\n{line}"),
+            })?;
+            current = Some(OpenHunk {
+                hunk: Hunk {
+                    old_start,
+                    text: format!("{line}\n"),
+                    ..Default::default()
+                },
+                old_left,
+                new_left,
+                blank_tail: 0,
+            });
+            continue;
+        }
+        let Some(open) = current.as_mut() else {
+            // Headers, preamble and trailers outside a hunk
+            continue;
+        };
+        if line.starts_with('\\') {
+            // "\ No newline at end of file" applies to the line immediately before it
+            let hunk = &mut open.hunk;
+            match hunk.lines.last() {
+                Some(HunkLine::Insert(_)) | Some(HunkLine::Context(_)) => {
+                    hunk.eof_newline = Some(false)
+                }
+                Some(HunkLine::Delete(_)) if hunk.eof_newline.is_none() => {
+                    hunk.eof_newline = Some(true)
+                }
+                _ => {}
+            }
+            continue;
+        }
+        if open.is_complete() || line.starts_with("diff ") {
+            hunks.push(current.take().unwrap().finish());
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix('+') {
+            open.push(HunkLine::Insert(rest.to_string()), line);
+        } else if let Some(rest) = line.strip_prefix('-') {
+            open.push(HunkLine::Delete(rest.to_string()), line);
+        } else if let Some(rest) = line.strip_prefix(' ') {
+            open.push(HunkLine::Context(rest.to_string()), line);
+        } else if line.is_empty() {
+            // Some tools strip the trailing space from empty context lines
+            open.push(HunkLine::Context(String::new()), line);
+        } else {
+            return Err(PatchError {
+                user: format!("Invalid line in diff hunk: {line}"),
+                model: format!(
+                    "Invalid unified diff - lines in a hunk must start with ' ', '+' or '-':\n{line}"
+                ),
+            });
+        }
+    }
+    if let Some(h) = current.take() {
+        hunks.push(h.finish());
+    }
+    if hunks.is_empty() {
+        return Err(PatchError {
+            user: "No hunks found in diff".to_string(),
+            model: "Invalid unified diff - no hunks starting with '@@' were found".to_string(),
+        });
+    }
+    Ok(hunks)
+}
+
+/// Returns true if `needle` matches `haystack` at `pos`. Trailing whitespace is ignored.
+fn matches_at(haystack: &[String], needle: &[&str], pos: usize) -> bool {
+    if pos + needle.len() > haystack.len() {
+        return false;
+    }
+    haystack[pos..pos + needle.len()]
+        .iter()
+        .zip(needle)
+        .all(|(a, b)| a.trim_end() == b.trim_end())
+}
+
+/// Searches outwards from `expected` for a position where `needle` matches.
+fn find_offset(haystack: &[String], needle: &[&str], expected: usize) -> Option<usize> {
+    let max = haystack.len().saturating_sub(needle.len());
+    let expected = expected.min(max);
+    for delta in 0..=haystack.len() {
+        if expected + delta <= max && matches_at(haystack, needle, expected + delta) {
+            return Some(expected + delta);
+        }
+        if delta > 0 && delta <= expected && matches_at(haystack, needle, expected - delta) {
+            return Some(expected - delta);
+        }
+        if expected + delta > max && delta > expected {
+            break;
+        }
+    }
+    None
+}
+
+impl UnifiedDiff {
+    /// Applies the diff to the given input string.
+    ///
+    /// Hunks are applied independently, so a failing hunk does not prevent the others from
+    /// applying. Returns the new content along with an error for each hunk that could not be
+    /// applied. If the diff itself can't be parsed, an error is returned.
+    pub(crate) fn apply(&self, input: &str) -> Result<(String, Vec<PatchError>), PatchError> {
+        let hunks = parse_hunks(&self.diff)?;
+        let mut lines: Vec<String> = input.lines().map(String::from).collect();
+        let mut trailing_newline = input.is_empty() || input.ends_with('\n');
+        let mut failures = Vec::new();
+        // The cumulative shift in line numbers caused by previously applied hunks
+        let mut shift: isize = 0;
+
+        for (i, hunk) in hunks.iter().enumerate() {
+            let mut applied = false;
+            for fuzz in 0..=MAX_FUZZ {
+                let h = if fuzz == 0 {
+                    hunk.clone()
+                } else {
+                    hunk.fuzzed(fuzz)
+                };
+                let old = h.old_lines();
+                let new = h.new_lines();
+                // A zero-length old range means "insert after line N"
+                let start = if old.is_empty() {
+                    h.old_start
+                } else {
+                    h.old_start.saturating_sub(1)
+                };
+                let expected = (start as isize + shift).max(0) as usize;
+                let pos = if old.is_empty() {
+                    Some(expected.min(lines.len()))
+                } else {
+                    find_offset(&lines, &old, expected)
+                };
+                if let Some(pos) = pos {
+                    let at_eof = pos + old.len() == lines.len();
+                    lines.splice(pos..pos + old.len(), new.iter().map(|s| s.to_string()));
+                    shift += new.len() as isize - old.len() as isize;
+                    if at_eof {
+                        if let Some(nl) = h.eof_newline {
+                            trailing_newline = nl;
+                        }
+                    }
+                    applied = true;
+                    break;
+                }
+                if !hunk.lines.iter().any(|l| matches!(l, HunkLine::Context(_))) {
+                    // No context to fuzz away
+                    break;
+                }
+            }
+            if !applied {
+                failures.push(PatchError {
+                    user: format!("Hunk {} did not apply", i + 1),
+                    model: format!(
+                        "Could not apply hunk {} of the diff - the lines it removes or uses as context were not found in the file:\n{}",
+                        i + 1,
+                        hunk.text
+                    ),
+                });
+            }
+        }
+
+        let mut result = lines.join("\n");
+        if trailing_newline && !result.is_empty() {
+            result.push('\n');
+        }
+        Ok((result, failures))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn udiff(diff: &str) -> UnifiedDiff {
+        UnifiedDiff {
+            path: PathBuf::from("/path/to/file.txt"),
+            diff: diff.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_unified_diff_apply() {
+        let input = indoc! {"
+            one
+            two
+            three
+            four
+            five
+            six
+            seven
+        "};
+
+        let test_cases = vec![
+            (
+                "simple replacement",
+                indoc! {"
+                    --- a/file.txt
+                    +++ b/file.txt
+                    @@ -2,3 +2,3 @@
+                     two
+                    -three
+                    +THREE
+                     four
+                "},
+                "one\ntwo\nTHREE\nfour\nfive\nsix\nseven\n",
+            ),
+            (
+                "offset search",
+                indoc! {"
+                    @@ -10,3 +10,3 @@
+                     five
+                    -six
+                    +SIX
+                     seven
+                "},
+                "one\ntwo\nthree\nfour\nfive\nSIX\nseven\n",
+            ),
+            (
+                "multiple hunks",
+                indoc! {"
+                    @@ -1,2 +1,3 @@
+                     one
+                    +one and a half
+                     two
+                    @@ -6,2 +7,1 @@
+                    -six
+                     seven
+                "},
+                "one\none and a half\ntwo\nthree\nfour\nfive\nseven\n",
+            ),
+            (
+                "fuzzy context",
+                indoc! {"
+                    @@ -3,5 +3,5 @@
+                     not three
+                     four
+                    -five
+                    +FIVE
+                     six
+                     not seven
+                "},
+                "one\ntwo\nthree\nfour\nFIVE\nsix\nseven\n",
+            ),
+            (
+                "insert into empty range",
+                indoc! {"
+                    @@ -7,0 +8,1 @@
+                    +eight
+                "},
+                "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\n",
+            ),
+            (
+                "no newline at end of file",
+                indoc! {"
+                    @@ -7,1 +7,1 @@
+                    -seven
+                    +SEVEN
+                    \\ No newline at end of file
+                "},
+                "one\ntwo\nthree\nfour\nfive\nsix\nSEVEN",
+            ),
+        ];
+
+        for (name, diff, expected) in test_cases {
+            let (result, failures) = udiff(diff)
+                .apply(input)
+                .unwrap_or_else(|e| panic!("[{name}] failed to parse: {}", e.user));
+            assert!(failures.is_empty(), "[{name}] unexpected failures");
+            assert_eq!(result, expected, "[{name}]");
+        }
+    }
+
+    #[test]
+    fn test_unified_diff_failures() {
+        let input = "one\ntwo\nthree\n";
+
+        // One good hunk and one bad hunk - the good hunk still applies
+        let (result, failures) = udiff(indoc! {"
+            @@ -1,1 +1,1 @@
+            -one
+            +ONE
+            @@ -3,1 +3,1 @@
+            -nonexistent
+            +whatever
+        "})
+        .apply(input)
+        .unwrap();
+        assert_eq!(result, "ONE\ntwo\nthree\n");
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].user, "Hunk 2 did not apply");
+        assert!(failures[0].model.contains("-nonexistent"));
+
+        // Not a diff at all
+        assert!(udiff("just some text").apply(input).is_err());
+
+        // Invalid line inside a hunk
+        assert!(udiff("@@ -1,1 +1,1 @@\n*one\n").apply(input).is_err());
+    }
+
+    #[test]
+    fn test_unified_diff_hunk_ends() {
+        let input = "one\n-- x\nthree\n";
+
+        // The signature git format-patch appends after the last hunk is ignored
+        let (result, failures) = udiff(indoc! {"
+            @@ -1,2 +1,2 @@
+            -one
+            +ONE
+             -- x
+            --\x20
+            2.43.0
+
+        "})
+        .apply(input)
+        .unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(result, "ONE\n-- x\nthree\n");
+
+        // As is any other text after the diff
+        let (result, failures) = udiff("@@ -3 +3 @@\n-three\n+THREE\nThat's all, folks\n")
+            .apply(input)
+            .unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(result, "one\n-- x\nTHREE\n");
+
+        // Deleted and added lines that look like file headers are part of the hunk
+        let (result, failures) = udiff("@@ -2,2 +2,2 @@\n--- x\n++ y\n three\n")
+            .apply(input)
+            .unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(result, "one\n+ y\nthree\n");
+    }
+}
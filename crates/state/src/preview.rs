@@ -0,0 +1,199 @@
+//! Dry runs of patches, showing what a patch would do without applying it.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diff,
+    error::{Error, Result},
+    Patch, PatchFailure, PatchInfo, State, Target,
+};
+
+/// The predicted outcome of applying a patch, as returned by `State::preview`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Preview {
+    /// The content each file written or removed by the patch would have afterwards, or None if
+    /// it would be removed.
+    pub files: BTreeMap<PathBuf, Option<String>>,
+    /// The changes the patch would make, against the current content
+    pub diff: diff::Diff,
+    /// Number of operations that would succeed
+    pub succeeded: usize,
+    /// Operations that would fail, with the messages applying the patch would report
+    pub failures: Vec<PatchFailure>,
+}
+
+impl Preview {
+    /// True if every operation in the patch would succeed.
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Captures the writes and removes of a previewed patch on top of a state, so the state itself
+/// isn't touched. Paths are still checked by the state's stores, so a preview fails where a real
+/// write would.
+struct Overlay<'a> {
+    state: &'a State,
+    /// The content of each file written or removed, or None if it was removed
+    files: BTreeMap<PathBuf, Option<String>>,
+}
+
+impl Target for Overlay<'_> {
+    fn state(&self) -> &State {
+        self.state
+    }
+
+    fn read(&self, path: &Path) -> Result<String> {
+        match self.files.get(path) {
+            Some(Some(content)) => Ok(content.clone()),
+            Some(None) => Err(Error::NotFound {
+                msg: "File removed by the patch".to_string(),
+                path: path.display().to_string(),
+            }),
+            None => self.state.read(path),
+        }
+    }
+
+    fn write(&mut self, path: &Path, content: &str) -> Result<()> {
+        self.state
+            .dispatch_ro(path, |store| store.check_write(path))?;
+        self.files
+            .insert(path.to_path_buf(), Some(content.to_string()));
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        self.read(path)?;
+        self.files.insert(path.to_path_buf(), None);
+        Ok(())
+    }
+}
+
+impl State {
+    /// Applies a patch to an in-memory overlay on top of the current content, and returns the
+    /// result. Nothing is written to the stores, no snapshot is taken, and the external edit
+    /// policy isn't applied.
+    pub fn preview(&self, patch: &Patch) -> Result<Preview> {
+        let mut pinfo = PatchInfo {
+            rollback_id: 0,
+            succeeded: 0,
+            failures: Vec::new(),
+            should_continue: false,
+            rolled_back: false,
+        };
+
+        let mut overlay = Overlay {
+            state: self,
+            files: BTreeMap::new(),
+        };
+        Self::apply_ops(&mut overlay, patch, &mut pinfo)?;
+        let files = overlay.files;
+
+        let mut diff = diff::Diff::default();
+        for (path, new) in &files {
+            let old = self.read_opt(path)?;
+            diff.files
+                .extend(diff::FileDiff::new(path, old.as_deref(), new.as_deref()));
+        }
+
+        Ok(Preview {
+            files,
+            diff,
+            succeeded: pinfo.succeeded,
+            failures: pinfo.failures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{abspath::AbsPath, diff::FileStatus};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_preview() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        std::fs::write(temp_dir.path().join("a.txt"), "one\ntwo\n").unwrap();
+        std::fs::write(temp_dir.path().join("b.txt"), "b\n").unwrap();
+        let mut state = State::default().with_directory(root, vec![]).unwrap();
+
+        let patch = Patch::default()
+            .with_replace("a.txt", "two", "three")
+            .with_replace("a.txt", "three", "four")
+            .with_rename("b.txt", "c.txt")
+            .with_write("::mem", "mem")
+            .with_replace("a.txt", "missing", "x");
+        let preview = state.preview(&patch).unwrap();
+
+        // Later operations see the effects of earlier ones
+        assert_eq!(
+            preview.files[Path::new("a.txt")].as_deref(),
+            Some("one\nfour\n")
+        );
+        assert_eq!(preview.files[Path::new("b.txt")], None);
+        assert_eq!(preview.files[Path::new("c.txt")].as_deref(), Some("b\n"));
+        assert_eq!(preview.succeeded, 4);
+        assert_eq!(preview.failures.len(), 1);
+        assert!(!preview.is_clean());
+
+        let statuses: Vec<_> = preview
+            .diff
+            .files
+            .iter()
+            .map(|f| (f.path.display().to_string(), f.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("::mem".to_string(), FileStatus::Added),
+                ("a.txt".to_string(), FileStatus::Modified),
+                ("b.txt".to_string(), FileStatus::Removed),
+                ("c.txt".to_string(), FileStatus::Added),
+            ]
+        );
+
+        // Nothing was applied
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join("a.txt")).unwrap(),
+            "one\ntwo\n"
+        );
+        assert!(temp_dir.path().join("b.txt").exists());
+        assert!(!temp_dir.path().join("c.txt").exists());
+        assert!(state.read(Path::new("::mem")).is_err());
+        assert_eq!(state.mark().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_preview_checks_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = AbsPath::new(temp_dir.path().to_path_buf()).unwrap();
+        std::fs::write(temp_dir.path().join("bin"), [0x7f, b'E', b'L', b'F', 0, 1]).unwrap();
+        let state = State::default().with_directory(root, vec![]).unwrap();
+
+        // Writes that applying the patch would refuse fail the preview too
+        assert!(state
+            .preview(&Patch::default().with_write("../escape.txt", "x"))
+            .is_err());
+        let preview = state
+            .preview(&Patch::default().with_write("bin", "x"))
+            .unwrap();
+        assert_eq!((preview.succeeded, preview.failures.len()), (0, 1));
+        assert!(preview.files.is_empty());
+        assert!(matches!(
+            State::default().preview(&Patch::default().with_write("a.txt", "x")),
+            Err(Error::NotFound { .. })
+        ));
+        assert!(!temp_dir
+            .path()
+            .parent()
+            .unwrap()
+            .join("escape.txt")
+            .exists());
+    }
+}
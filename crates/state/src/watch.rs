@@ -0,0 +1,93 @@
+//! Watches the filesystem directory of a state for changes made outside of it, for instance by
+//! the user in their editor while a session is active.
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use notify::{event::ModifyKind, EventKind, RecursiveMode, Watcher as _};
+
+use crate::error::{Error, Result};
+
+/// A recursive watcher on a directory, recording the paths that change under it. Paths are
+/// recorded relative to the directory, and only if they pass a filter, so that churn in ignored
+/// directories like build output doesn't accumulate. Recorded paths include changes the state
+/// makes itself - the state filters those out by comparing against the content it last saw.
+pub(crate) struct Watcher {
+    _watcher: notify::RecommendedWatcher,
+    changed: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+impl Watcher {
+    /// Starts watching the directory at `root`, recording changed paths for which `filter`
+    /// returns true.
+    pub fn new<F>(root: &Path, filter: F) -> Result<Self>
+    where
+        F: Fn(&Path) -> bool + Send + 'static,
+    {
+        // Some platforms report events against the canonical path
+        let root = root.canonicalize()?;
+        let changed = Arc::new(Mutex::new(BTreeSet::new()));
+
+        let sink = changed.clone();
+        let prefix = root.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let Ok(event) = res else {
+                return;
+            };
+            if matches!(
+                event.kind,
+                EventKind::Access(_) | EventKind::Modify(ModifyKind::Metadata(_))
+            ) {
+                return;
+            }
+            let paths: Vec<PathBuf> = event
+                .paths
+                .iter()
+                .filter_map(|p| p.strip_prefix(&prefix).ok())
+                .filter(|rel| filter(rel))
+                .map(Path::to_path_buf)
+                .collect();
+            sink.lock().unwrap().extend(paths);
+        })
+        .map_err(|e| Error::Io(format!("could not create watcher: {e}")))?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(|e| Error::Io(format!("could not watch {}: {e}", root.display())))?;
+
+        Ok(Self {
+            _watcher: watcher,
+            changed,
+        })
+    }
+
+    /// Returns the paths that changed since the watcher started, or since they were last
+    /// forgotten.
+    pub fn changed(&self) -> BTreeSet<PathBuf> {
+        self.changed.lock().unwrap().clone()
+    }
+
+    /// Records paths as changed, as if the watcher had seen them change.
+    #[cfg(test)]
+    pub fn record(&self, paths: &[PathBuf]) {
+        self.changed.lock().unwrap().extend(paths.iter().cloned());
+    }
+
+    /// Stops reporting the given paths, until they change again.
+    pub fn forget(&self, paths: &[PathBuf]) {
+        let mut changed = self.changed.lock().unwrap();
+        for path in paths {
+            changed.remove(path);
+        }
+    }
+}
+
+impl fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watcher")
+            .field("changed", &self.changed())
+            .finish()
+    }
+}
@@ -215,6 +215,18 @@ enum Commands {
         #[clap(subcommand)]
         command: ContextCommands,
     },
+    /// Show the changes made by an action or step
+    Diff {
+        /// The action or step to show, in format "action" or "action:step" (e.g. "0:3").
+        /// Defaults to the last action.
+        step_offset: Option<String>,
+        /// Only show the number of lines added and removed per file
+        #[clap(long)]
+        stat: bool,
+        /// Output a patch that `git apply` accepts, leaving out in-memory files
+        #[clap(long, conflicts_with = "stat")]
+        git: bool,
+    },
     /// Add editable files to a session
     Edit {
         /// Specifies files to edit, glob patterns accepted
@@ -247,11 +259,19 @@ enum Commands {
         #[clap(value_parser)]
         files: Option<Vec<String>>,
     },
+    /// Apply a patch file to the session, as if the model had produced it
+    Import {
+        /// The patch file, in unified diff format (reads from stdin if not specified)
+        file: Option<PathBuf>,
+    },
     /// List configured models
     Models {
         /// Show full configuration details
         #[clap(short, long)]
         full: bool,
+        /// Ask local servers which models they have available
+        #[clap(long)]
+        available: bool,
     },
     /// Create a new session
     New {
@@ -283,6 +303,9 @@ enum Commands {
         /// Reset all steps in the session
         #[clap(long)]
         all: bool,
+        /// Undo only the changes made by this step, keeping later steps
+        #[clap(long)]
+        only: bool,
     },
     /// Retry a prompt
     Retry {
@@ -397,13 +420,23 @@ async fn main() -> anyhow::Result<()> {
     let result = match &cli.command {
         Some(cmd) => {
             match cmd {
-                Commands::Models { full } => {
+                Commands::Models { full, available } => {
                     for model in &config.model_confs() {
                         println!("{}", model.name().blue().bold());
                         println!("    kind: {}", model.kind());
                         for line in model.text_config(*full).lines() {
                             println!("    {line}");
                         }
+                        if *available {
+                            if let Ok(libtenx::model::Model::Local(local)) =
+                                model.to_model(config.models.no_stream)
+                            {
+                                match local.list_models().await {
+                                    Ok(names) => println!("    available = {}", names.join(", ")),
+                                    Err(e) => println!("    available = ({e})"),
+                                }
+                            }
+                        }
                         println!();
                     }
                     Ok(())
@@ -567,6 +600,66 @@ async fn main() -> anyhow::Result<()> {
                     }
                     Ok(())
                 }
+                Commands::Diff {
+                    step_offset,
+                    stat,
+                    git,
+                } => {
+                    let session = tx.load_session()?;
+                    let (action_idx, step_idx) = match step_offset {
+                        Some(offset) => parse_step_offset(offset)?,
+                        None => (
+                            session
+                                .actions
+                                .len()
+                                .checked_sub(1)
+                                .ok_or_else(|| anyhow!("Session has no actions"))?,
+                            None,
+                        ),
+                    };
+                    let action = session
+                        .actions
+                        .get(action_idx)
+                        .ok_or_else(|| anyhow!("Invalid action index: {action_idx}"))?;
+                    let diff = action.diff(step_idx)?;
+                    if *stat {
+                        for file in &diff.files {
+                            println!("{} +{} -{}", file.path.display(), file.added, file.removed);
+                        }
+                        println!(
+                            "{} files changed, {} insertions(+), {} deletions(-)",
+                            diff.files.len(),
+                            diff.added(),
+                            diff.removed()
+                        );
+                    } else if *git {
+                        print!("{}", diff.git());
+                    } else {
+                        print!("{}", diff.unified());
+                    }
+                    Ok(())
+                }
+                Commands::Import { file } => {
+                    let text = if let Some(path) = file {
+                        fs::read_to_string(path).context("Failed to read patch file")?
+                    } else {
+                        let mut buffer = String::new();
+                        std::io::stdin().read_to_string(&mut buffer)?;
+                        buffer
+                    };
+                    let patch = state::Patch::from_diff(&text)?;
+                    let mut session = tx.load_session()?;
+                    let info = tx.import_patch(&mut session, patch)?;
+                    for failure in &info.failures {
+                        println!("{}: {}", "failed".red(), failure.user);
+                    }
+                    println!(
+                        "{} operations applied, {} failed",
+                        info.succeeded,
+                        info.failures.len()
+                    );
+                    Ok(())
+                }
                 Commands::Edit { files } => {
                     let mut session = tx.load_session()?;
                     let total = tx.edit(&mut session, files)?;
@@ -631,14 +724,45 @@ async fn main() -> anyhow::Result<()> {
                     tx.save_session(&session)?;
                     Ok(())
                 }
-                Commands::Reset { step_offset, all } => {
+                Commands::Reset {
+                    step_offset,
+                    all,
+                    only,
+                } => {
                     if *all && step_offset.is_some() {
                         return Err(anyhow!("Cannot specify both --all and a step offset"));
                     }
+                    if *all && *only {
+                        return Err(anyhow!("Cannot specify both --all and --only"));
+                    }
                     let mut session = tx.load_session()?;
                     if *all {
                         tx.reset_all(&mut session)?;
                         println!("All steps reset");
+                    } else if *only {
+                        let offset_str = step_offset.as_ref().ok_or_else(|| {
+                            anyhow!("--only requires a step offset in format 'action:step'")
+                        })?;
+                        let (action_idx, step_idx) = parse_step_offset(offset_str)?;
+                        let step_idx = step_idx.ok_or_else(|| {
+                            anyhow!("--only requires a step offset in format 'action:step'")
+                        })?;
+                        let conflicts = tx.revert_step(&mut session, action_idx, step_idx)?;
+                        if conflicts.is_empty() {
+                            println!("Reverted changes from step {offset_str}");
+                        } else {
+                            for conflict in &conflicts {
+                                println!(
+                                    "Conflict in {}:\n{}",
+                                    conflict.path.display(),
+                                    conflict.merged
+                                );
+                            }
+                            return Err(anyhow!(
+                                "Could not revert step {offset_str}: {} conflicting files, nothing was changed",
+                                conflicts.len()
+                            ));
+                        }
                     } else {
                         let offset_str = step_offset
                         .as_ref()